@@ -0,0 +1,69 @@
+//! Times the same workload on a `Nat` and on a `ConstNat` with the same flags.
+//!
+//! Run with `cargo run --release --example const_nat_timing`.
+use nat_emulation::predefines::SYMMETRIC_NAT;
+use nat_emulation::{port_ranges::PRIVATE, ConstNat, Nat};
+use rand::{rngs::StdRng, SeedableRng};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const HOSTS: u32 = 64;
+const SERVERS: u32 = 16;
+const ROUNDS: i64 = 500;
+const TIMEOUT: i64 = 1000 * 60 * 2;
+
+/// Sends a packet from every host to every server and has each server reply, once per round.
+/// `round_trip` routes a packet from the given host and port to the given server, routes the
+/// server's reply back, and returns whether the reply was delivered.
+fn run(hosts: &[u32], mut round_trip: impl FnMut(u32, u16, u32, i64) -> bool) -> (Duration, u64) {
+    let mut delivered = 0;
+    let start = Instant::now();
+    for time in 0..ROUNDS {
+        for (i, &host) in hosts.iter().enumerate() {
+            for server in 0..SERVERS {
+                let server_addr = 22222 + server;
+                delivered += black_box(round_trip(host, 25565 + i as u16, server_addr, time)) as u64;
+            }
+        }
+    }
+    (start.elapsed(), delivered)
+}
+
+fn main() {
+    let mut nat = Nat::new(
+        SYMMETRIC_NAT,
+        [11111],
+        90000..=99999,
+        PRIVATE,
+        StdRng::seed_from_u64(0),
+        usize::MAX,
+        TIMEOUT,
+    );
+    let hosts: Vec<u32> = (0..HOSTS).map(|_| nat.assign_internal_address()).collect();
+    let mut const_nat = ConstNat::<SYMMETRIC_NAT, _, 1>::new([11111], 90000..=99999, PRIVATE, StdRng::seed_from_u64(0), usize::MAX, TIMEOUT);
+    for _ in 0..HOSTS {
+        const_nat.assign_internal_address();
+    }
+
+    let (nat_time, nat_delivered) = run(&hosts, |host, port, server_addr, time| {
+        let translation = nat.send_internal_packet(host, port, server_addr, 443, time);
+        let Some((ex_addr, ex_port)) = translation.as_external() else {
+            return false;
+        };
+        nat.receive_external_packet(server_addr, 443, ex_addr, ex_port, false, time).is_some()
+    });
+    let (const_time, const_delivered) = run(&hosts, |host, port, server_addr, time| {
+        let translation = const_nat.send_internal_packet(host, port, server_addr, 443, time);
+        let Some((ex_addr, ex_port)) = translation.as_external() else {
+            return false;
+        };
+        const_nat
+            .receive_external_packet(server_addr, 443, ex_addr, ex_port, false, time)
+            .is_some()
+    });
+    assert_eq!(nat_delivered, const_delivered, "Nat and ConstNat must route identically");
+
+    let packets = (ROUNDS * (HOSTS * SERVERS) as i64 * 2) as f64;
+    println!("Nat:      {:?} ({:.1} ns/packet)", nat_time, nat_time.as_nanos() as f64 / packets);
+    println!("ConstNat: {:?} ({:.1} ns/packet)", const_time, const_time.as_nanos() as f64 / packets);
+}
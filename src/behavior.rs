@@ -0,0 +1,330 @@
+use crate::flags::*;
+
+/// The mapping behavior of a NAT as classified by rfc4787, section 4.1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MappingBehavior {
+    /// The NAT reuses the same external address and port for all packets from the same internal
+    /// address and port, regardless of their destination.
+    EndpointIndependent,
+    /// The NAT reuses the same external address and port only for packets sent to the same
+    /// destination address.
+    AddressDependent,
+    /// The NAT reuses the same external address and port only for packets sent to the same
+    /// destination port. This is not described by rfc4787 but it is expressible with our flags.
+    PortDependent,
+    /// The NAT reuses the same external address and port only for packets sent to the same
+    /// destination address and port. This is a "symmetric" NAT.
+    AddressAndPortDependent,
+}
+/// The filtering behavior of a NAT as classified by rfc4787, section 5.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FilteringBehavior {
+    /// Any external endpoint may send packets through an existing mapping.
+    EndpointIndependent,
+    /// Only external endpoints with an address the mapping has sent to may send packets through it.
+    AddressDependent,
+    /// Only external endpoints with a port the mapping has sent to may send packets through it.
+    /// This is not described by rfc4787 but it is expressible with our flags.
+    PortDependent,
+    /// Only the exact external endpoints the mapping has sent to may send packets through it.
+    AddressAndPortDependent,
+}
+
+/// A decoded summary of every individual behavior specified by a set of NAT flags.
+///
+/// Each field is phrased so that `true` describes the behavior the NAT actually exhibits, which
+/// means some fields are the negation of their corresponding flag. This is the inverse of
+/// composing flags and predefines together, and is mostly useful for assertions and for
+/// displaying a NAT's configuration to a user.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BehaviorSet {
+    /// New mappings depend on the destination address, see `ADDRESS_DEPENDENT_MAPPING`.
+    pub address_dependent_mapping: bool,
+    /// New mappings depend on the destination port, see `PORT_DEPENDENT_MAPPING`.
+    pub port_dependent_mapping: bool,
+    /// Inbound packets are filtered by source address, see `ADDRESS_DEPENDENT_FILTERING`.
+    pub address_dependent_filtering: bool,
+    /// Inbound packets are filtered by source port, see `PORT_DEPENDENT_FILTERING`.
+    pub port_dependent_filtering: bool,
+    /// Filtering is disabled for a mapping once it has seen bidirectional traffic, see
+    /// `RELAX_FILTERING_AFTER_BIDIRECTIONAL`.
+    pub relax_filtering_after_bidirectional: bool,
+    /// Filtered inbound packets destroy the mapping they targeted, see
+    /// `FILTERED_INBOUND_DESTROYS_MAPPING`.
+    pub filtered_inbound_destroys_mapping: bool,
+    /// Intranet to intranet packets addressed to an external address are hairpinned, see
+    /// `NO_HAIRPINNING`.
+    pub hairpinning: bool,
+    /// Hairpinned packets keep the internal address and port of the sender, see
+    /// `INTERNAL_ADDRESS_AND_PORT_HAIRPINNING`.
+    pub internal_address_and_port_hairpinning: bool,
+    /// Packets are only hairpinned if their sender already has a mapping, see
+    /// `HAIRPINNING_REQUIRES_MAPPING`.
+    pub hairpinning_requires_mapping: bool,
+    /// The source port of inbound packets is rewritten, see `INBOUND_SOURCE_PORT_REWRITE`.
+    pub inbound_source_port_rewrite: bool,
+    /// Mappings are removed once they have delivered one inbound packet, see
+    /// `SINGLE_USE_MAPPINGS`.
+    pub single_use_mappings: bool,
+    /// Packets of different traffic classes are given separate mappings, see
+    /// `CLASS_DEPENDENT_MAPPING`.
+    pub class_dependent_mapping: bool,
+    /// Inbound packets refresh the mapping timeout, see `INBOUND_REFRESH_BEHAVIOR_FALSE`.
+    pub inbound_refresh: bool,
+    /// Outbound packets refresh the mapping timeout, see `OUTBOUND_REFRESH_BEHAVIOR_FALSE`.
+    pub outbound_refresh: bool,
+    /// The NAT attempts to preserve source ports, see `NO_PORT_PRESERVATION`.
+    pub port_preservation: bool,
+    /// The NAT preserves the parity of source ports, see `NO_PORT_PARITY`.
+    pub port_parity: bool,
+    /// Port preservation overwrites older mappings, see `PORT_PRESERVATION_OVERRIDE`.
+    pub port_preservation_override: bool,
+    /// Port preservation shares ports between intranet addresses, see
+    /// `PORT_PRESERVATION_OVERLOAD`.
+    pub port_preservation_overload: bool,
+    /// Unpreserved ports are derived from a hash of the mapping instead of drawn at random, see
+    /// `DETERMINISTIC_PORT_HASH`.
+    pub deterministic_port_hash: bool,
+    /// Unpreserved ports are the closest free port above the source port regardless of parity,
+    /// see `PORT_PRESERVATION_OVER_PARITY`.
+    pub port_preservation_over_parity: bool,
+    /// Well-known source ports are mapped to well-known external ports, see
+    /// `NO_WELL_KNOWN_PRESERVATION`.
+    pub well_known_preservation: bool,
+    /// The NAT has an "IP address pooling" behavior of "Arbitrary" instead of "Paired", see
+    /// `IP_POOLING_BEHAVIOR_ARBITRARY`.
+    pub arbitrary_pooling: bool,
+}
+impl BehaviorSet {
+    /// Decodes the given set of flags into the individual behaviors they specify.
+    pub fn from_flags(flags: u32) -> Self {
+        Self {
+            address_dependent_mapping: flags & ADDRESS_DEPENDENT_MAPPING > 0,
+            port_dependent_mapping: flags & PORT_DEPENDENT_MAPPING > 0,
+            address_dependent_filtering: flags & ADDRESS_DEPENDENT_FILTERING > 0,
+            port_dependent_filtering: flags & PORT_DEPENDENT_FILTERING > 0,
+            relax_filtering_after_bidirectional: flags & RELAX_FILTERING_AFTER_BIDIRECTIONAL > 0,
+            filtered_inbound_destroys_mapping: flags & FILTERED_INBOUND_DESTROYS_MAPPING > 0,
+            hairpinning: flags & NO_HAIRPINNING == 0,
+            internal_address_and_port_hairpinning: flags & NO_HAIRPINNING == 0 && flags & INTERNAL_ADDRESS_AND_PORT_HAIRPINNING > 0,
+            hairpinning_requires_mapping: flags & NO_HAIRPINNING == 0 && flags & HAIRPINNING_REQUIRES_MAPPING > 0,
+            inbound_source_port_rewrite: flags & INBOUND_SOURCE_PORT_REWRITE > 0,
+            single_use_mappings: flags & SINGLE_USE_MAPPINGS > 0,
+            class_dependent_mapping: flags & CLASS_DEPENDENT_MAPPING > 0,
+            inbound_refresh: flags & INBOUND_REFRESH_BEHAVIOR_FALSE == 0,
+            outbound_refresh: flags & OUTBOUND_REFRESH_BEHAVIOR_FALSE == 0,
+            port_preservation: flags & NO_PORT_PRESERVATION == 0,
+            port_parity: flags & NO_PORT_PARITY == 0,
+            port_preservation_override: flags & NO_PORT_PRESERVATION == 0
+                && flags & PORT_PRESERVATION_OVERRIDE > 0
+                && flags & PORT_PRESERVATION_OVERLOAD == 0,
+            port_preservation_overload: flags & NO_PORT_PRESERVATION == 0 && flags & PORT_PRESERVATION_OVERLOAD > 0,
+            deterministic_port_hash: flags & DETERMINISTIC_PORT_HASH > 0,
+            port_preservation_over_parity: flags & NO_PORT_PRESERVATION == 0 && flags & PORT_PRESERVATION_OVER_PARITY > 0,
+            well_known_preservation: flags & NO_WELL_KNOWN_PRESERVATION == 0,
+            arbitrary_pooling: flags & IP_POOLING_BEHAVIOR_ARBITRARY > 0,
+        }
+    }
+    /// The mapping behavior dimension of this set of behaviors.
+    pub fn mapping(&self) -> MappingBehavior {
+        match (self.address_dependent_mapping, self.port_dependent_mapping) {
+            (false, false) => MappingBehavior::EndpointIndependent,
+            (true, false) => MappingBehavior::AddressDependent,
+            (false, true) => MappingBehavior::PortDependent,
+            (true, true) => MappingBehavior::AddressAndPortDependent,
+        }
+    }
+    /// The filtering behavior dimension of this set of behaviors.
+    pub fn filtering(&self) -> FilteringBehavior {
+        match (self.address_dependent_filtering, self.port_dependent_filtering) {
+            (false, false) => FilteringBehavior::EndpointIndependent,
+            (true, false) => FilteringBehavior::AddressDependent,
+            (false, true) => FilteringBehavior::PortDependent,
+            (true, true) => FilteringBehavior::AddressAndPortDependent,
+        }
+    }
+}
+
+/// How a NAT attempts to preserve source ports, see `FlagsBuilder::port_preservation`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PortPreservationBehavior {
+    /// Source ports are never preserved, see `NO_PORT_PRESERVATION`.
+    Disabled,
+    /// Source ports are preserved whenever they are free.
+    Enabled,
+    /// Source ports are always preserved, overwriting the older mapping using the port, see
+    /// `PORT_PRESERVATION_OVERRIDE`.
+    Override,
+    /// Source ports are always preserved, sharing the port with the older mapping using it, see
+    /// `PORT_PRESERVATION_OVERLOAD`.
+    Overload,
+}
+/// How a NAT handles intranet to intranet packets addressed to one of its external addresses,
+/// see `FlagsBuilder::hairpinning`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HairpinningBehavior {
+    /// Such packets are dropped, see `NO_HAIRPINNING`.
+    Disabled,
+    /// Such packets are hairpinned with the external address and port of the sender.
+    ExternalSource,
+    /// Such packets are hairpinned with the internal address and port of the sender, see
+    /// `INTERNAL_ADDRESS_AND_PORT_HAIRPINNING`.
+    InternalSource,
+}
+
+/// A typed builder for a set of NAT flags, which cannot express mutually exclusive behaviors, nor
+/// flags that would have no effect. Every behavior starts out as it is in `EASY_NAT`.
+///
+/// # Example
+/// ```
+/// use nat_emulation::predefines::SYMMETRIC_NAT;
+/// use nat_emulation::{BehaviorSet, FilteringBehavior, FlagsBuilder, MappingBehavior, PortPreservationBehavior};
+/// let flags = FlagsBuilder::new()
+///     .mapping(MappingBehavior::AddressAndPortDependent)
+///     .filtering(FilteringBehavior::AddressAndPortDependent)
+///     .port_preservation(PortPreservationBehavior::Disabled)
+///     .build();
+/// assert_eq!(BehaviorSet::from_flags(flags), BehaviorSet::from_flags(SYMMETRIC_NAT));
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FlagsBuilder {
+    mapping: MappingBehavior,
+    filtering: FilteringBehavior,
+    port_preservation: PortPreservationBehavior,
+    hairpinning: HairpinningBehavior,
+    /// Every flag that is independent of the behaviors above, or that depends on them but is
+    /// stripped by `build` when it would have no effect.
+    independent_flags: u32,
+}
+impl Default for FlagsBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl FlagsBuilder {
+    pub const fn new() -> Self {
+        Self {
+            mapping: MappingBehavior::EndpointIndependent,
+            filtering: FilteringBehavior::EndpointIndependent,
+            port_preservation: PortPreservationBehavior::Enabled,
+            hairpinning: HairpinningBehavior::ExternalSource,
+            independent_flags: 0,
+        }
+    }
+    /// Sets or clears a flag that is independent of the other behaviors.
+    const fn set(mut self, flag: u32, enabled: bool) -> Self {
+        if enabled {
+            self.independent_flags |= flag;
+        } else {
+            self.independent_flags &= !flag;
+        }
+        self
+    }
+    pub const fn mapping(mut self, mapping: MappingBehavior) -> Self {
+        self.mapping = mapping;
+        self
+    }
+    pub const fn filtering(mut self, filtering: FilteringBehavior) -> Self {
+        self.filtering = filtering;
+        self
+    }
+    pub const fn port_preservation(mut self, port_preservation: PortPreservationBehavior) -> Self {
+        self.port_preservation = port_preservation;
+        self
+    }
+    pub const fn hairpinning(mut self, hairpinning: HairpinningBehavior) -> Self {
+        self.hairpinning = hairpinning;
+        self
+    }
+    /// See `IP_POOLING_BEHAVIOR_ARBITRARY`.
+    pub const fn arbitrary_pooling(self, enabled: bool) -> Self {
+        self.set(IP_POOLING_BEHAVIOR_ARBITRARY, enabled)
+    }
+    /// See `INBOUND_REFRESH_BEHAVIOR_FALSE`.
+    pub const fn inbound_refresh(self, enabled: bool) -> Self {
+        self.set(INBOUND_REFRESH_BEHAVIOR_FALSE, !enabled)
+    }
+    /// See `OUTBOUND_REFRESH_BEHAVIOR_FALSE`.
+    pub const fn outbound_refresh(self, enabled: bool) -> Self {
+        self.set(OUTBOUND_REFRESH_BEHAVIOR_FALSE, !enabled)
+    }
+    /// See `FILTERED_INBOUND_DESTROYS_MAPPING`.
+    pub const fn filtered_inbound_destroys_mapping(self, enabled: bool) -> Self {
+        self.set(FILTERED_INBOUND_DESTROYS_MAPPING, enabled)
+    }
+    /// See `RELAX_FILTERING_AFTER_BIDIRECTIONAL`. This has no effect unless the filtering is
+    /// dependent on the address or port of the sender.
+    pub const fn relax_filtering_after_bidirectional(self, enabled: bool) -> Self {
+        self.set(RELAX_FILTERING_AFTER_BIDIRECTIONAL, enabled)
+    }
+    /// See `NO_PORT_PARITY`.
+    pub const fn port_parity(self, enabled: bool) -> Self {
+        self.set(NO_PORT_PARITY, !enabled)
+    }
+    /// See `PORT_PRESERVATION_OVER_PARITY`. This has no effect if port preservation is disabled.
+    pub const fn port_preservation_over_parity(self, enabled: bool) -> Self {
+        self.set(PORT_PRESERVATION_OVER_PARITY, enabled)
+    }
+    /// See `NO_WELL_KNOWN_PRESERVATION`.
+    pub const fn well_known_preservation(self, enabled: bool) -> Self {
+        self.set(NO_WELL_KNOWN_PRESERVATION, !enabled)
+    }
+    /// See `DETERMINISTIC_PORT_HASH`.
+    pub const fn deterministic_port_hash(self, enabled: bool) -> Self {
+        self.set(DETERMINISTIC_PORT_HASH, enabled)
+    }
+    /// See `HAIRPINNING_REQUIRES_MAPPING`. This has no effect if hairpinning is disabled.
+    pub const fn hairpinning_requires_mapping(self, enabled: bool) -> Self {
+        self.set(HAIRPINNING_REQUIRES_MAPPING, enabled)
+    }
+    /// See `INBOUND_SOURCE_PORT_REWRITE`.
+    pub const fn inbound_source_port_rewrite(self, enabled: bool) -> Self {
+        self.set(INBOUND_SOURCE_PORT_REWRITE, enabled)
+    }
+    /// See `SINGLE_USE_MAPPINGS`.
+    pub const fn single_use_mappings(self, enabled: bool) -> Self {
+        self.set(SINGLE_USE_MAPPINGS, enabled)
+    }
+    /// See `CLASS_DEPENDENT_MAPPING`.
+    pub const fn class_dependent_mapping(self, enabled: bool) -> Self {
+        self.set(CLASS_DEPENDENT_MAPPING, enabled)
+    }
+    /// Return value is the set of flags with the chosen behaviors, which never has a
+    /// `FlagConflict`. Flags that would have no effect given the other behaviors are left unset.
+    pub const fn build(&self) -> u32 {
+        let mut flags = self.independent_flags;
+        flags |= match self.mapping {
+            MappingBehavior::EndpointIndependent => 0,
+            MappingBehavior::AddressDependent => ADDRESS_DEPENDENT_MAPPING,
+            MappingBehavior::PortDependent => PORT_DEPENDENT_MAPPING,
+            MappingBehavior::AddressAndPortDependent => ADDRESS_AND_PORT_DEPENDENT_MAPPING,
+        };
+        flags |= match self.filtering {
+            FilteringBehavior::EndpointIndependent => 0,
+            FilteringBehavior::AddressDependent => ADDRESS_DEPENDENT_FILTERING,
+            FilteringBehavior::PortDependent => PORT_DEPENDENT_FILTERING,
+            FilteringBehavior::AddressAndPortDependent => ADDRESS_AND_PORT_DEPENDENT_FILTERING,
+        };
+        if matches!(self.filtering, FilteringBehavior::EndpointIndependent) {
+            flags &= !RELAX_FILTERING_AFTER_BIDIRECTIONAL;
+        }
+        flags |= match self.port_preservation {
+            PortPreservationBehavior::Disabled => NO_PORT_PRESERVATION,
+            PortPreservationBehavior::Enabled => 0,
+            PortPreservationBehavior::Override => PORT_PRESERVATION_OVERRIDE,
+            PortPreservationBehavior::Overload => PORT_PRESERVATION_OVERLOAD,
+        };
+        if matches!(self.port_preservation, PortPreservationBehavior::Disabled) {
+            flags &= !PORT_PRESERVATION_OVER_PARITY;
+        }
+        flags |= match self.hairpinning {
+            HairpinningBehavior::Disabled => NO_HAIRPINNING,
+            HairpinningBehavior::ExternalSource => 0,
+            HairpinningBehavior::InternalSource => INTERNAL_ADDRESS_AND_PORT_HAIRPINNING,
+        };
+        if matches!(self.hairpinning, HairpinningBehavior::Disabled) {
+            flags &= !HAIRPINNING_REQUIRES_MAPPING;
+        }
+        flags
+    }
+}
@@ -0,0 +1,86 @@
+use crate::nat::{DestType, Nat};
+use rand::RngCore;
+
+/// A handle to a single flow between an internal host and an external peer, which pairs the
+/// outbound and inbound halves of the flow so test code can read like ordinary socket usage.
+///
+/// Like a connected UDP socket, creating a connection with `Nat::open_connection` does not send
+/// anything. The connection learns its external address and port from the NAT the first time
+/// `send` succeeds, after which the peer can reply to it with `recv`. A connection is only a
+/// description of its flow, it holds no state within the NAT, so it may be freely copied and
+/// discarded. Its methods are thin wrappers around `send_internal_packet`,
+/// `receive_external_packet` and `is_inbound_open`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Connection {
+    internal_addr: u32,
+    internal_port: u16,
+    peer_addr: u32,
+    peer_port: u16,
+    external: Option<(u32, u16)>,
+}
+impl Connection {
+    #[inline]
+    pub fn internal(&self) -> (u32, u16) {
+        (self.internal_addr, self.internal_port)
+    }
+    #[inline]
+    pub fn peer(&self) -> (u32, u16) {
+        (self.peer_addr, self.peer_port)
+    }
+    /// The external address and port the NAT translated the last successfully sent packet to.
+    /// This is `None` if no packet of this connection has reached the external network yet.
+    #[inline]
+    pub fn external(&self) -> Option<(u32, u16)> {
+        self.external
+    }
+    /// Sends a packet from the internal endpoint of this connection to its peer through `nat`.
+    /// If the packet reaches the external network the external address and port it was
+    /// translated to is recorded, so that later calls to `recv` target the current mapping.
+    ///
+    /// Return value is the same as `Nat::send_internal_packet`.
+    pub fn send<R: RngCore, const M: usize>(&mut self, nat: &mut Nat<R, M>, current_time: i64) -> DestType {
+        let translation = nat.send_internal_packet(self.internal_addr, self.internal_port, self.peer_addr, self.peer_port, current_time);
+        if let Some(external) = translation.as_external() {
+            self.external = Some(external);
+        }
+        translation
+    }
+    /// Sends a packet from the peer of this connection back to the external address and port of
+    /// this connection through `nat`.
+    ///
+    /// Return value is true if the packet was delivered to the internal endpoint of this
+    /// connection. It is false if the packet was dropped, if it was delivered to some other
+    /// internal endpoint, or if this connection has never successfully sent a packet.
+    pub fn recv<R: RngCore, const M: usize>(&self, nat: &mut Nat<R, M>, current_time: i64) -> bool {
+        let Some((external_addr, external_port)) = self.external else {
+            return false;
+        };
+        let translation = nat.receive_external_packet(self.peer_addr, self.peer_port, external_addr, external_port, false, current_time);
+        translation == Some((self.internal_addr, self.internal_port))
+    }
+    /// Returns whether the peer of this connection could currently reach it through `nat`,
+    /// without modifying the NAT. See `Nat::is_inbound_open`.
+    pub fn is_alive<R: RngCore, const M: usize>(&self, nat: &Nat<R, M>, current_time: i64) -> bool {
+        self.external.is_some_and(|(external_addr, external_port)| {
+            nat.is_inbound_open(external_addr, external_port, self.peer_addr, self.peer_port, current_time)
+        })
+    }
+}
+
+impl<R: RngCore, const M: usize> Nat<R, M> {
+    /// Creates a handle to the flow between the given internal address and port and the given
+    /// external peer address and port. No packet is sent, see `Connection::send`.
+    pub fn open_connection(&self, internal_addr: u32, internal_port: u16, peer_addr: u32, peer_port: u16) -> Connection {
+        debug_assert!(
+            self.internal_addresses().contains(&internal_addr),
+            "The internal address must be within the NAT's internal address range"
+        );
+        Connection {
+            internal_addr,
+            internal_port,
+            peer_addr,
+            peer_port,
+            external: None,
+        }
+    }
+}
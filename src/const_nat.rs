@@ -0,0 +1,165 @@
+use crate::nat::{DestType, Nat};
+use crate::nat_flags::validate_flags;
+use rand::RngCore;
+use std::ops::{Deref, RangeInclusive};
+
+/// A `Nat` whose flags are fixed at compile time by the const parameter `FLAGS`.
+///
+/// The routing calls of a `ConstNat` are inlined with `FLAGS` passed in as a constant, so the
+/// optimizer is free to eliminate the mapping, filtering and hairpinning branches for behaviors
+/// this NAT does not have. This is useful in tight simulations where the type of NAT never
+/// changes. Contradictory flags are rejected at compile time, see `validate_flags`. See
+/// `examples/const_nat_timing.rs` for a comparison against `Nat`.
+///
+/// The state of the wrapped NAT can be read through `Deref`, but it cannot be modified, since its
+/// flags would no longer match `FLAGS`. Configure a `Nat` first and wrap it with `from_inner`
+/// instead.
+pub struct ConstNat<const FLAGS: u32, R: RngCore, const M: usize> {
+    inner: Nat<R, M>,
+}
+impl<const FLAGS: u32, R: RngCore, const M: usize> ConstNat<FLAGS, R, M> {
+    const VALID_FLAGS: () = assert!(
+        match validate_flags(FLAGS) {
+            Ok(()) => true,
+            Err(conflict) => conflict.is_no_op(),
+        },
+        "The flags must not contain contradictory behaviors"
+    );
+    /// Creates a new NAT struct with the behaviors specified by `FLAGS`.
+    /// See `Nat::new` for more details.
+    #[inline]
+    pub fn new(
+        external_addresses: [u32; M],
+        internal_addresses: RangeInclusive<u32>,
+        external_dynamic_ports: RangeInclusive<u16>,
+        rng: R,
+        mapping_max_size: usize,
+        mapping_timeout: i64,
+    ) -> Self {
+        let () = Self::VALID_FLAGS;
+        Self {
+            inner: Nat::new(
+                FLAGS,
+                external_addresses,
+                internal_addresses,
+                external_dynamic_ports,
+                rng,
+                mapping_max_size,
+                mapping_timeout,
+            ),
+        }
+    }
+    /// Creates a new NAT struct with the behaviors specified by `FLAGS` and a total number of
+    /// external addresses that is less than the constant `M`.
+    /// See `Nat::with_capacity` for more details.
+    #[inline]
+    pub fn with_capacity(
+        external_addresses: &[u32],
+        internal_addresses: RangeInclusive<u32>,
+        external_dynamic_ports: RangeInclusive<u16>,
+        rng: R,
+        mapping_max_size: usize,
+        mapping_timeout: i64,
+    ) -> Self {
+        let () = Self::VALID_FLAGS;
+        Self {
+            inner: Nat::with_capacity(
+                FLAGS,
+                external_addresses,
+                internal_addresses,
+                external_dynamic_ports,
+                rng,
+                mapping_max_size,
+                mapping_timeout,
+            ),
+        }
+    }
+    /// Wraps an already configured `Nat`, replacing its `flags` with `FLAGS` and removing its
+    /// `adaptive_flags`.
+    #[inline]
+    pub fn from_inner(mut inner: Nat<R, M>) -> Self {
+        let () = Self::VALID_FLAGS;
+        inner.flags = FLAGS;
+        inner.adaptive_flags = None;
+        Self { inner }
+    }
+    /// Unwraps this into a `Nat` whose flags may be changed at runtime.
+    #[inline]
+    pub fn into_inner(self) -> Nat<R, M> {
+        self.inner
+    }
+    /// See `Nat::assign_internal_address`.
+    #[inline]
+    pub fn assign_internal_address(&mut self) -> u32 {
+        self.inner.assign_internal_address()
+    }
+    /// See `Nat::send_internal_packet`.
+    #[inline(always)]
+    pub fn send_internal_packet(
+        &mut self,
+        internal_src_addr: u32,
+        internal_src_port: u16,
+        external_dest_addr: u32,
+        external_dest_port: u16,
+        current_time: i64,
+    ) -> DestType {
+        let Some(current_time) = self.inner.observe_time(current_time) else {
+            return DestType::Drop;
+        };
+        self.inner.send_outbound_with_flags(
+            FLAGS,
+            internal_src_addr,
+            internal_src_port,
+            external_dest_addr,
+            external_dest_port,
+            None,
+            0,
+            current_time,
+        )
+    }
+    /// See `Nat::receive_external_packet`.
+    #[inline(always)]
+    pub fn receive_external_packet(
+        &mut self,
+        external_src_addr: u32,
+        external_src_port: u16,
+        external_dest_addr: u32,
+        external_dest_port: u16,
+        disable_filtering: bool,
+        current_time: i64,
+    ) -> Option<(u32, u16)> {
+        let current_time = self.inner.observe_time(current_time)?;
+        self.inner
+            .receive_external_packet_with_flags(
+                FLAGS,
+                external_src_addr,
+                external_src_port,
+                external_dest_addr,
+                external_dest_port,
+                disable_filtering,
+                current_time,
+            )
+            .as_internal()
+    }
+    /// See `Nat::open_mapping`.
+    #[inline(always)]
+    pub fn open_mapping(
+        &mut self,
+        internal_addr: u32,
+        internal_port: u16,
+        endpoint_addr: u32,
+        endpoint_port: u16,
+        current_time: i64,
+    ) -> Option<(u32, u16)> {
+        let current_time = self.inner.observe_time(current_time)?;
+        self.inner
+            .open_mapping_with_flags(FLAGS, internal_addr, internal_port, endpoint_addr, endpoint_port, current_time)
+    }
+}
+impl<const FLAGS: u32, R: RngCore, const M: usize> Deref for ConstNat<FLAGS, R, M> {
+    type Target = Nat<R, M>;
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
@@ -0,0 +1,188 @@
+use crate::flags::{ADDRESS_AND_PORT_DEPENDENT_FILTERING, ADDRESS_AND_PORT_DEPENDENT_MAPPING};
+use crate::nat::Nat;
+use rand::RngCore;
+
+/// One of the two hosts taking part in a `HolePunch`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Peer {
+    /// The host behind the first NAT given to `HolePunch::run`.
+    A,
+    /// The host behind the second NAT given to `HolePunch::run`.
+    B,
+}
+/// A single step of a `HolePunch`, in the order it happened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HolePunchStep {
+    /// The peer asked the STUN server for its server reflexive candidate. The candidate is `None`
+    /// if the peer's NAT dropped the request.
+    Stun { peer: Peer, reflexive: Option<(u32, u16)> },
+    /// The peer sent a connectivity check to the other peer's candidate.
+    Check {
+        from: Peer,
+        time: i64,
+        /// The external address and port the check left the sender's NAT from, or `None` if the
+        /// sender's NAT dropped it.
+        external_src: Option<(u32, u16)>,
+        /// Whether the receiver's NAT delivered the check to the receiver.
+        delivered: bool,
+    },
+}
+/// Why a `HolePunch` failed to connect its peers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HolePunchFailure {
+    /// The peer's NAT dropped the request to the STUN server.
+    StunDropped(Peer),
+    /// The peer's NAT dropped its connectivity check.
+    CheckDropped(Peer),
+    /// The peer's NAT sent its connectivity check from a different external address or port than
+    /// its server reflexive candidate, so the other peer's NAT had no reason to admit it.
+    /// `flags` are the mapping flags of the peer's NAT that allocate a mapping per destination.
+    MappingChanged { peer: Peer, flags: u32 },
+    /// The peer's NAT filtered the connectivity check sent to it.
+    /// `flags` are the filtering flags of the peer's NAT.
+    Filtered { peer: Peer, flags: u32 },
+}
+/// The outcome of a `HolePunch`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct HolePunchReport {
+    /// The internal address and port of peer A.
+    pub internal_a: (u32, u16),
+    /// The internal address and port of peer B.
+    pub internal_b: (u32, u16),
+    /// Every step of the hole punch, in the order it happened.
+    pub steps: Vec<HolePunchStep>,
+    /// `Ok` if a connectivity check was delivered in both directions, and otherwise the most
+    /// fundamental reason the last round of checks failed.
+    pub result: Result<(), HolePunchFailure>,
+}
+impl HolePunchReport {
+    #[inline]
+    pub fn is_connected(&self) -> bool {
+        self.result.is_ok()
+    }
+}
+
+/// An executable model of the ICE connectivity check process between one host behind each of two
+/// NATs. Each host learns its server reflexive candidate from a STUN server, the candidates are
+/// exchanged, and then both hosts repeatedly send checks to each other's candidate at the same
+/// time, which is a simultaneous open. The hosts are connected once a check has been delivered in
+/// both directions.
+///
+/// This exercises nearly every part of the NATs involved, and serves as an example of how to
+/// compose them into a larger simulation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct HolePunch {
+    pub stun_server_addr: u32,
+    pub stun_server_port: u16,
+    /// The internal port both hosts send from. This is 5000 by default.
+    pub internal_port: u16,
+    /// How many rounds of checks are sent before giving up. At least one round is always sent.
+    /// This is 4 by default.
+    pub rounds: usize,
+    /// The time between the STUN requests and the first round of checks, and between every
+    /// following round. This is 100 by default.
+    pub interval: i64,
+}
+impl HolePunch {
+    pub fn new(stun_server_addr: u32, stun_server_port: u16) -> Self {
+        Self {
+            stun_server_addr,
+            stun_server_port,
+            internal_port: 5000,
+            rounds: 4,
+            interval: 100,
+        }
+    }
+    /// Assigns a new internal host on each NAT and attempts to connect them, starting at
+    /// `current_time`. The STUN server must be on the external network of both NATs.
+    /// Panics if either NAT's internal address range is exhausted.
+    ///
+    /// Return value is a report of every step of the attempt, along with whether it succeeded.
+    pub fn run<RA: RngCore, const MA: usize, RB: RngCore, const MB: usize>(
+        &self,
+        nat_a: &mut Nat<RA, MA>,
+        nat_b: &mut Nat<RB, MB>,
+        current_time: i64,
+    ) -> HolePunchReport {
+        let internal_a = (nat_a.assign_internal_address(), self.internal_port);
+        let internal_b = (nat_b.assign_internal_address(), self.internal_port);
+        let mut report = HolePunchReport { internal_a, internal_b, steps: Vec::new(), result: Ok(()) };
+
+        let reflexive_a = nat_a.reflexive_address(internal_a.0, internal_a.1, self.stun_server_addr, self.stun_server_port, current_time);
+        report.steps.push(HolePunchStep::Stun { peer: Peer::A, reflexive: reflexive_a });
+        let reflexive_b = nat_b.reflexive_address(internal_b.0, internal_b.1, self.stun_server_addr, self.stun_server_port, current_time);
+        report.steps.push(HolePunchStep::Stun { peer: Peer::B, reflexive: reflexive_b });
+        let (reflexive_a, reflexive_b) = match (reflexive_a, reflexive_b) {
+            (Some(a), Some(b)) => (a, b),
+            (None, _) => {
+                report.result = Err(HolePunchFailure::StunDropped(Peer::A));
+                return report;
+            }
+            (_, None) => {
+                report.result = Err(HolePunchFailure::StunDropped(Peer::B));
+                return report;
+            }
+        };
+
+        let mut delivered_to_a = false;
+        let mut delivered_to_b = false;
+        let mut time = current_time;
+        for _ in 0..self.rounds.max(1) {
+            time += self.interval;
+            let mut failure = None;
+            // Peer A's check.
+            let external_src = nat_a
+                .send_internal_packet(internal_a.0, internal_a.1, reflexive_b.0, reflexive_b.1, time)
+                .as_external();
+            let delivered = external_src.is_some_and(|(src_addr, src_port)| {
+                nat_b.receive_external_packet(src_addr, src_port, reflexive_b.0, reflexive_b.1, false, time) == Some(internal_b)
+            });
+            report.steps.push(HolePunchStep::Check { from: Peer::A, time, external_src, delivered });
+            delivered_to_b |= delivered;
+            if !delivered {
+                failure = Some(diagnose(Peer::A, external_src, reflexive_a, nat_a.flags, nat_b.flags));
+            }
+            // Peer B's check.
+            let external_src = nat_b
+                .send_internal_packet(internal_b.0, internal_b.1, reflexive_a.0, reflexive_a.1, time)
+                .as_external();
+            let delivered = external_src.is_some_and(|(src_addr, src_port)| {
+                nat_a.receive_external_packet(src_addr, src_port, reflexive_a.0, reflexive_a.1, false, time) == Some(internal_a)
+            });
+            report.steps.push(HolePunchStep::Check { from: Peer::B, time, external_src, delivered });
+            delivered_to_a |= delivered;
+            if !delivered {
+                let b_failure = diagnose(Peer::B, external_src, reflexive_b, nat_b.flags, nat_a.flags);
+                // A filtered check is only a symptom if the other check reveals why.
+                if !matches!((failure, b_failure), (Some(_), HolePunchFailure::Filtered { .. })) {
+                    failure = Some(b_failure);
+                }
+            }
+            if delivered_to_a && delivered_to_b {
+                report.result = Ok(());
+                return report;
+            }
+            // Some check of this round was not delivered, or the peers would be connected.
+            report.result = failure.map_or(Ok(()), Err);
+        }
+        report
+    }
+}
+/// Finds out why a check from `sender` was not delivered.
+fn diagnose(sender: Peer, external_src: Option<(u32, u16)>, reflexive: (u32, u16), sender_flags: u32, receiver_flags: u32) -> HolePunchFailure {
+    let receiver = match sender {
+        Peer::A => Peer::B,
+        Peer::B => Peer::A,
+    };
+    match external_src {
+        None => HolePunchFailure::CheckDropped(sender),
+        Some(src) if src != reflexive => HolePunchFailure::MappingChanged {
+            peer: sender,
+            flags: sender_flags & ADDRESS_AND_PORT_DEPENDENT_MAPPING,
+        },
+        Some(_) => HolePunchFailure::Filtered {
+            peer: receiver,
+            flags: receiver_flags & ADDRESS_AND_PORT_DEPENDENT_FILTERING,
+        },
+    }
+}
@@ -1,13 +1,35 @@
 #[warn(missing_docs)]
 mod nat_flags;
-pub use nat_flags::{flags, port_ranges, predefines};
+pub use nat_flags::{flags, hardness_score, port_ranges, predefines, validate_flags, FlagConflict};
 mod nat;
-pub use nat::{DestType, Nat};
+pub use nat::{
+    check_external_dynamic_ports, min_external_dynamic_ports, Accounting, AdaptiveFlags, AddressOrder, ConstructError, DecodeError, DestType,
+    Direction, EndpointUpdate, EvictionPolicy, FilterExpiry, ForwardConflict, ForwardConflictPolicy, InboundDestType, InternalFlow, Mapping,
+    MappingDiff, Nat, NatConfig, NonMonotonicTime, OracleConflict, Packet, PacketSink, PacketTranslation, PortAllocation, PortDistribution,
+    PortPreservation, PortRangeTooSmall, PreloadError, RemovalReason, RemovedMapping, CONFIG_FORMAT_VERSION, MAPPING_FORMAT_VERSION,
+};
+mod behavior;
+pub use behavior::{BehaviorSet, FilteringBehavior, FlagsBuilder, HairpinningBehavior, MappingBehavior, PortPreservationBehavior};
+mod token_bucket;
+pub use token_bucket::TokenBucket;
+mod const_nat;
+pub use const_nat::ConstNat;
+mod nat64;
+pub use nat64::{Nat64, NAT64_WELL_KNOWN_PREFIX};
+mod connection;
+pub use connection::Connection;
+mod multi_tenant;
+pub use multi_tenant::{MultiTenantNat, SubscriberError};
+mod hole_punch;
+pub use hole_punch::{HolePunch, HolePunchFailure, HolePunchReport, HolePunchStep, Peer};
+mod scenario;
+pub use scenario::Scenario;
 
 #[cfg(test)]
 mod examples {
     use crate as nat_emulation;
     #[test]
+    #[allow(clippy::assertions_on_constants)]
     fn stateful_firewall() {
         use nat_emulation::predefines::STATEFUL_FIREWALL;
         use nat_emulation::{DestType, Nat};
@@ -30,6 +52,8 @@ mod examples {
         match firewall.send_internal_packet(client_addr, client_port, server_addr, server_port, time) {
             DestType::Internal { .. } => assert!(false),
             DestType::Drop => assert!(false),
+            DestType::Deferred { .. } => assert!(false),
+            DestType::Router => assert!(false),
             DestType::External { external_src_addr, external_src_port } => {
                 assert_eq!(external_src_addr, client_addr);
                 assert_eq!(external_src_port, client_port);
@@ -99,6 +123,7 @@ mod examples {
         assert!(translation.is_none());
     }
     #[test]
+    #[allow(clippy::assertions_on_constants)]
     fn easy_nat() {
         use nat_emulation::predefines::EASY_NAT;
         use nat_emulation::{port_ranges::PRIVATE, DestType, Nat};
@@ -121,6 +146,8 @@ mod examples {
         match nat.send_internal_packet(client_in_addr, client_in_port, server_ex_addr, server_ex_port, time) {
             DestType::Internal { .. } => assert!(false),
             DestType::Drop => assert!(false),
+            DestType::Deferred { .. } => assert!(false),
+            DestType::Router => assert!(false),
             DestType::External { external_src_addr, external_src_port } => {
                 assert_eq!(external_src_addr, nat_ex_addr);
                 // Note that the NAT gave us an external port outside of its assigned port range. NATs
@@ -142,6 +169,7 @@ mod examples {
         assert!(translation.is_none());
     }
     #[test]
+    #[allow(clippy::assertions_on_constants)]
     fn full_cone_nat() {
         use nat_emulation::predefines::FULL_CONE_NAT;
         use nat_emulation::{port_ranges::PRIVATE, DestType, Nat};
@@ -160,6 +188,8 @@ mod examples {
         match nat.send_internal_packet(client_in_addr, client_in_port, server_ex_addr, server_ex_port, time) {
             DestType::Internal { .. } => assert!(false),
             DestType::Drop => assert!(false),
+            DestType::Deferred { .. } => assert!(false),
+            DestType::Router => assert!(false),
             DestType::External { external_src_addr, external_src_port } => {
                 assert_eq!(external_src_addr, nat_ex_addr);
                 // Our definition of a full cone NAT does not have port preservation.
@@ -173,6 +203,7 @@ mod examples {
         }
     }
     #[test]
+    #[allow(clippy::assertions_on_constants)]
     fn symmetric_nat() {
         use nat_emulation::predefines::SYMMETRIC_NAT;
         use nat_emulation::{port_ranges::PRIVATE, DestType::*, Nat};
@@ -214,6 +245,7 @@ mod examples {
         }
     }
     #[test]
+    #[allow(clippy::assertions_on_constants)]
     fn hard_nat() {
         use nat_emulation::predefines::HARD_NAT;
         use nat_emulation::{port_ranges::PRIVATE, DestType::*, Nat};
@@ -262,6 +294,7 @@ mod examples {
         }
     }
     #[test]
+    #[allow(clippy::assertions_on_constants)]
     fn misbehaving_nat() {
         use nat_emulation::predefines::MISBEHAVING_NAT;
         use nat_emulation::{port_ranges::PRIVATE, DestType, Nat};
@@ -282,6 +315,8 @@ mod examples {
         match nat.send_internal_packet(client_in_addr, client_in_port, server_ex_addr, server_ex_port0, time) {
             DestType::Internal { .. } => assert!(false),
             DestType::Drop => assert!(false),
+            DestType::Deferred { .. } => assert!(false),
+            DestType::Router => assert!(false),
             DestType::External { external_src_addr, external_src_port } => {
                 assert_eq!(external_src_addr, nat_ex_addr);
 
@@ -296,4 +331,3506 @@ mod examples {
             }
         }
     }
+    #[test]
+    fn effective_behaviors() {
+        use nat_emulation::predefines::{HARD_NAT, RESTRICTED_CONE_NAT};
+        use nat_emulation::{port_ranges::PRIVATE, FilteringBehavior, MappingBehavior, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let timeout = 1000 * 60 * 2;
+
+        let mut nat = Nat::new(RESTRICTED_CONE_NAT, [11111], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        let behaviors = nat.effective_behaviors();
+        assert_eq!(behaviors.mapping(), MappingBehavior::EndpointIndependent);
+        assert_eq!(behaviors.filtering(), FilteringBehavior::AddressDependent);
+        assert!(!behaviors.port_preservation);
+        assert!(behaviors.port_parity);
+        assert!(behaviors.hairpinning);
+        assert!(!behaviors.arbitrary_pooling);
+
+        nat.flags = HARD_NAT;
+        let behaviors = nat.effective_behaviors();
+        assert_eq!(behaviors.mapping(), MappingBehavior::AddressAndPortDependent);
+        assert_eq!(behaviors.filtering(), FilteringBehavior::AddressAndPortDependent);
+        assert!(!behaviors.inbound_refresh);
+        assert!(behaviors.outbound_refresh);
+        assert!(behaviors.arbitrary_pooling);
+    }
+    #[test]
+    fn flag_conflicts() {
+        use nat_emulation::flags::*;
+        use nat_emulation::predefines::*;
+        use nat_emulation::{hardness_score, validate_flags, BehaviorSet, FlagConflict};
+        assert_eq!(validate_flags(EASY_NAT), Ok(()));
+        assert_eq!(validate_flags(STATEFUL_FIREWALL), Ok(()));
+        assert_eq!(validate_flags(MISBEHAVING_FIREWALL), Ok(()));
+        assert_eq!(MISBEHAVING_FIREWALL, ADDRESS_AND_PORT_DEPENDENT_FILTERING | PORT_PRESERVATION_OVERLOAD);
+        // Cone NATs are layered on top of firewalls so they contain a no-op override flag.
+        assert_eq!(validate_flags(SYMMETRIC_NAT), Err(FlagConflict::OverrideWithoutPreservation));
+        assert!(validate_flags(MISBEHAVING_NAT).unwrap_err().is_no_op());
+
+        let conflict = validate_flags(PORT_PRESERVATION_OVERRIDE | PORT_PRESERVATION_OVERLOAD).unwrap_err();
+        assert_eq!(conflict, FlagConflict::OverrideAndOverload);
+        assert!(!conflict.is_no_op());
+        assert!(!BehaviorSet::from_flags(MISBEHAVING_FIREWALL).port_preservation_override);
+        assert_eq!(hardness_score(MISBEHAVING_FIREWALL), hardness_score(PORT_RESTRICTED_FIREWALL));
+        assert_eq!(
+            validate_flags(NO_PORT_PRESERVATION | PORT_PRESERVATION_OVERLOAD),
+            Err(FlagConflict::OverloadWithoutPreservation)
+        );
+        assert_eq!(
+            validate_flags(NO_HAIRPINNING | INTERNAL_ADDRESS_AND_PORT_HAIRPINNING),
+            Err(FlagConflict::InternalHairpinningWithoutHairpinning)
+        );
+    }
+    #[test]
+    fn rate_limit() {
+        use nat_emulation::predefines::EASY_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat, TokenBucket};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let nat_ex_addr = 11111;
+        let mut nat = Nat::new(EASY_NAT, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        // Allow a burst of 4 packets, refilling at 4 packets every 1000 time units.
+        nat.rate_limit = Some(TokenBucket::new(4, 1000));
+        let client_in_addr = nat.assign_internal_address();
+        let client_in_port = 25565;
+        let server_ex_addr = 22222;
+        let server_ex_port = 80;
+
+        for _ in 0..4 {
+            let translation = nat.send_internal_packet(client_in_addr, client_in_port, server_ex_addr, server_ex_port, time);
+            assert!(translation.is_external());
+        }
+        let translation = nat.send_internal_packet(client_in_addr, client_in_port, server_ex_addr, server_ex_port, time);
+        assert!(translation.is_drop());
+        let translation = nat.receive_external_packet(server_ex_addr, server_ex_port, nat_ex_addr, client_in_port, false, time);
+        assert!(translation.is_none());
+
+        // Enough time for exactly one packet to be refilled.
+        time += 250;
+        let translation = nat.receive_external_packet(server_ex_addr, server_ex_port, nat_ex_addr, client_in_port, false, time);
+        assert!(translation.is_some());
+        let translation = nat.send_internal_packet(client_in_addr, client_in_port, server_ex_addr, server_ex_port, time);
+        assert!(translation.is_drop());
+
+        time += 1000;
+        let translation = nat.send_internal_packet(client_in_addr, client_in_port, server_ex_addr, server_ex_port, time);
+        assert!(translation.is_external());
+    }
+    #[test]
+    #[allow(clippy::assertions_on_constants)]
+    fn round_robin_pooling() {
+        use nat_emulation::predefines::HARD_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, AddressOrder, DestType, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let nat_ex_addrs = [11110, 11111, 11112];
+        let mut nat = Nat::new(HARD_NAT, nat_ex_addrs, 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        nat.arbitrary_pooling_order = AddressOrder::RoundRobin;
+        let client_in_addr = nat.assign_internal_address();
+        let client_in_port = 25565;
+        let server_ex_addr = 22222;
+
+        for i in 0..6 {
+            match nat.send_internal_packet(client_in_addr, client_in_port, server_ex_addr, 1000 + i as u16, time) {
+                DestType::External { external_src_addr, .. } => assert_eq!(external_src_addr, nat_ex_addrs[i % 3]),
+                _ => assert!(false),
+            }
+        }
+    }
+    #[test]
+    fn open_mapping() {
+        use nat_emulation::predefines::PORT_RESTRICTED_CONE_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let nat_ex_addr = 11111;
+        let mut nat = Nat::new(PORT_RESTRICTED_CONE_NAT, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        let client_in_addr = nat.assign_internal_address();
+        let client_in_port = 25565;
+        let peer_ex_addr = 22222;
+        let peer_ex_port = 3000;
+
+        assert!(nat.open_mapping(nat_ex_addr, client_in_port, peer_ex_addr, peer_ex_port, time).is_none());
+        let (ex_addr, ex_port) = nat
+            .open_mapping(client_in_addr, client_in_port, peer_ex_addr, peer_ex_port, time)
+            .unwrap();
+        assert_eq!(ex_addr, nat_ex_addr);
+
+        time += 100;
+        let translation = nat.receive_external_packet(peer_ex_addr, peer_ex_port, ex_addr, ex_port, false, time);
+        assert_eq!(translation, Some((client_in_addr, client_in_port)));
+        // The mapping is the same one a real outbound packet would use.
+        let translation = nat.send_internal_packet(client_in_addr, client_in_port, peer_ex_addr, peer_ex_port, time);
+        assert_eq!(translation.as_external(), Some((ex_addr, ex_port)));
+    }
+    #[test]
+    fn relax_filtering_after_bidirectional() {
+        use nat_emulation::flags::RELAX_FILTERING_AFTER_BIDIRECTIONAL;
+        use nat_emulation::predefines::PORT_RESTRICTED_CONE_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let nat_ex_addr = 11111;
+        let flags = PORT_RESTRICTED_CONE_NAT | RELAX_FILTERING_AFTER_BIDIRECTIONAL;
+        let mut nat = Nat::new(flags, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        let client_in_addr = nat.assign_internal_address();
+        let client_in_port = 25565;
+        let server_ex_addr = 22222;
+        let server_ex_port = 80;
+        let peer_ex_addr = 33333;
+        let peer_ex_port = 3000;
+
+        time += 100;
+        let translation = nat.send_internal_packet(client_in_addr, client_in_port, server_ex_addr, server_ex_port, time);
+        let (ex_addr, ex_port) = translation.as_external().unwrap();
+        // Before any reply a new peer is filtered.
+        time += 100;
+        let translation = nat.receive_external_packet(peer_ex_addr, peer_ex_port, ex_addr, ex_port, false, time);
+        assert!(translation.is_none());
+        time += 100;
+        let translation = nat.receive_external_packet(server_ex_addr, server_ex_port, ex_addr, ex_port, false, time);
+        assert!(translation.is_some());
+        // Now that the flow is bidirectional the new peer can join it.
+        time += 100;
+        let translation = nat.receive_external_packet(peer_ex_addr, peer_ex_port, ex_addr, ex_port, false, time);
+        assert_eq!(translation, Some((client_in_addr, client_in_port)));
+    }
+    #[test]
+    fn port_range_too_small() {
+        use nat_emulation::flags::NO_PORT_PARITY;
+        use nat_emulation::predefines::EASY_NAT;
+        use nat_emulation::{check_external_dynamic_ports, min_external_dynamic_ports, port_ranges::PRIVATE, PortRangeTooSmall};
+        assert_eq!(min_external_dynamic_ports(EASY_NAT, 1, usize::MAX), PRIVATE.len());
+        assert_eq!(min_external_dynamic_ports(NO_PORT_PARITY, 1, usize::MAX), PRIVATE.len());
+        // The minimum scales with the number of mappings each external address may hold.
+        assert_eq!(min_external_dynamic_ports(NO_PORT_PARITY, 1, 10000), 10000);
+        assert_eq!(min_external_dynamic_ports(EASY_NAT, 1, 10000), PRIVATE.len());
+        assert_eq!(min_external_dynamic_ports(NO_PORT_PARITY, 4, 10000), 2500);
+        // A tiny routing table only needs enough ports to hold every mapping.
+        assert_eq!(min_external_dynamic_ports(EASY_NAT, 2, 8), 8);
+
+        assert_eq!(check_external_dynamic_ports(EASY_NAT, &PRIVATE, 1, usize::MAX), Ok(()));
+        assert_eq!(
+            check_external_dynamic_ports(EASY_NAT, &(5000..=5009), 1, usize::MAX),
+            Err(PortRangeTooSmall { actual: 10, required: PRIVATE.len() })
+        );
+        assert!(check_external_dynamic_ports(NO_PORT_PARITY, &(5000..=5063), 1, 10000).is_err());
+    }
+    #[test]
+    #[should_panic]
+    fn port_range_too_small_panics() {
+        use nat_emulation::predefines::EASY_NAT;
+        use nat_emulation::Nat;
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let _ = Nat::new(EASY_NAT, [11111], 90000..=99999, 5000..=5009, rng, usize::MAX, 1000);
+    }
+    #[test]
+    #[allow(clippy::assertions_on_constants)]
+    fn deterministic_port_hash() {
+        use nat_emulation::flags::DETERMINISTIC_PORT_HASH;
+        use nat_emulation::predefines::SYMMETRIC_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, DestType, Nat};
+        let timeout = 1000 * 60 * 2;
+        let nat_ex_addr = 11111;
+        let client_in_port = 25565;
+        let server_ex_addr = 22222;
+
+        let run = |seed: u64| {
+            let rng = rand::rngs::mock::StepRng::new(seed, 1);
+            let mut nat = Nat::new(
+                SYMMETRIC_NAT | DETERMINISTIC_PORT_HASH,
+                [nat_ex_addr],
+                90000..=99999,
+                PRIVATE,
+                rng,
+                usize::MAX,
+                timeout,
+            );
+            let client_in_addr = nat.assign_internal_address();
+            let mut ports = Vec::new();
+            for server_ex_port in [80, 443, 80] {
+                match nat.send_internal_packet(client_in_addr, client_in_port, server_ex_addr, server_ex_port, 100) {
+                    DestType::External { external_src_port, .. } => {
+                        assert!(PRIVATE.contains(&external_src_port));
+                        assert_eq!(external_src_port & 1, client_in_port & 1);
+                        ports.push(external_src_port);
+                    }
+                    _ => assert!(false),
+                }
+            }
+            ports
+        };
+        let ports = run(0);
+        // Still destination dependent, but reproducible.
+        assert!(ports[0] != ports[1]);
+        assert_eq!(ports[0], ports[2]);
+        assert_eq!(ports, run(0));
+        assert!(ports != run(12345));
+    }
+    #[test]
+    fn dest_type_accessors() {
+        use nat_emulation::predefines::EASY_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let nat_ex_addr = 11111;
+        let mut nat = Nat::new(EASY_NAT, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        let client0_in_addr = nat.assign_internal_address();
+        let client1_in_addr = nat.assign_internal_address();
+        let client_in_port = 25565;
+
+        let translation = nat.send_internal_packet(client0_in_addr, client_in_port, 22222, 80, time);
+        assert_eq!(translation.as_external(), Some((nat_ex_addr, client_in_port)));
+        assert_eq!(translation.as_internal(), None);
+
+        let translation = nat.send_internal_packet(client1_in_addr, client_in_port, client0_in_addr, client_in_port, time);
+        assert_eq!(translation.as_external(), None);
+        assert_eq!(
+            translation.as_internal(),
+            Some((client1_in_addr, client_in_port, client0_in_addr, client_in_port))
+        );
+    }
+    #[test]
+    fn unreplied_timeout() {
+        use nat_emulation::predefines::EASY_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut time = 100;
+        let timeout = 1000 * 60 * 2;
+        let unreplied_timeout = 1000 * 30;
+
+        let nat_ex_addr = 11111;
+        let mut nat = Nat::new(EASY_NAT, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        nat.unreplied_timeout = Some(unreplied_timeout);
+        let client_in_addr = nat.assign_internal_address();
+        let server_ex_addr = 22222;
+        let server_ex_port = 80;
+
+        // This mapping never receives a reply and so expires early.
+        let translation = nat.send_internal_packet(client_in_addr, 1000, server_ex_addr, server_ex_port, time);
+        let (ex_addr0, ex_port0) = translation.as_external().unwrap();
+        // This mapping receives a reply and so lasts the full mapping timeout.
+        let translation = nat.send_internal_packet(client_in_addr, 2000, server_ex_addr, server_ex_port, time);
+        let (ex_addr1, ex_port1) = translation.as_external().unwrap();
+        time += 100;
+        assert!(nat
+            .receive_external_packet(server_ex_addr, server_ex_port, ex_addr1, ex_port1, false, time)
+            .is_some());
+
+        time += unreplied_timeout + 1;
+        assert!(nat
+            .receive_external_packet(server_ex_addr, server_ex_port, ex_addr0, ex_port0, false, time)
+            .is_none());
+        assert!(nat
+            .receive_external_packet(server_ex_addr, server_ex_port, ex_addr1, ex_port1, false, time)
+            .is_some());
+    }
+    #[test]
+    fn static_mapping() {
+        use nat_emulation::predefines::SYMMETRIC_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, ForwardConflictPolicy, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let nat_ex_addr = 11111;
+        let mut nat = Nat::new(SYMMETRIC_NAT, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        let server_in_addr = nat.assign_internal_address();
+        let server_in_port = 80;
+        let forwarded_port = 8080;
+        let peer_ex_addr = 22222;
+        let peer_ex_port = 3000;
+        let result = nat.add_static_mapping(
+            nat_ex_addr,
+            forwarded_port,
+            server_in_addr,
+            server_in_port,
+            ForwardConflictPolicy::Reject,
+            time,
+        );
+        assert!(result.is_ok());
+
+        // The peer can reach the server without it ever sending a packet.
+        time += 100;
+        let translation = nat.receive_external_packet(peer_ex_addr, peer_ex_port, nat_ex_addr, forwarded_port, false, time);
+        assert_eq!(translation, Some((server_in_addr, server_in_port)));
+        // The inbound packet created a mapping, so the server's reply uses the forwarded port.
+        time += 100;
+        let translation = nat.send_internal_packet(server_in_addr, server_in_port, peer_ex_addr, peer_ex_port, time);
+        assert_eq!(translation.as_external(), Some((nat_ex_addr, forwarded_port)));
+        // Other flows are never allocated the forwarded port.
+        let translation = nat.send_internal_packet(server_in_addr, forwarded_port, peer_ex_addr, peer_ex_port, time);
+        assert!(translation.as_external().unwrap().1 != forwarded_port);
+
+        assert_eq!(
+            nat.remove_static_mapping(nat_ex_addr, forwarded_port),
+            Some((server_in_addr, server_in_port))
+        );
+        time += 100;
+        let translation = nat.receive_external_packet(33333, peer_ex_port, nat_ex_addr, forwarded_port, false, time);
+        assert!(translation.is_none());
+    }
+    #[test]
+    fn extreme_timestamps() {
+        use nat_emulation::predefines::SYMMETRIC_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let timeout = i64::MAX;
+
+        let nat_ex_addr = 11111;
+        let mut nat = Nat::new(SYMMETRIC_NAT, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        nat.unreplied_timeout = Some(i64::MAX);
+        let client_in_addr = nat.assign_internal_address();
+        let server_ex_addr = 22222;
+        let server_ex_port = 80;
+
+        for time in [i64::MIN, 0, i64::MAX, i64::MIN] {
+            let translation = nat.send_internal_packet(client_in_addr, 25565, server_ex_addr, server_ex_port, time);
+            let (ex_addr, ex_port) = translation.as_external().unwrap();
+            let translation = nat.receive_external_packet(server_ex_addr, server_ex_port, ex_addr, ex_port, false, time);
+            assert_eq!(translation, Some((client_in_addr, 25565)));
+        }
+    }
+    #[test]
+    fn port_blocks() {
+        use nat_emulation::predefines::SYMMETRIC_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let nat_ex_addr = 11111;
+        let mut nat = Nat::new(SYMMETRIC_NAT, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        nat.port_block_size = Some(256);
+        let client0_in_addr = nat.assign_internal_address();
+        let client1_in_addr = nat.assign_internal_address();
+        let block0 = nat.port_block_for(client0_in_addr).unwrap().clone();
+        let block1 = nat.port_block_for(client1_in_addr).unwrap().clone();
+        assert_eq!(block0, 49152..=49407);
+        assert_eq!(block1, 49408..=49663);
+
+        for server_ex_port in 1..=100 {
+            for (client_in_addr, block) in [(client0_in_addr, &block0), (client1_in_addr, &block1)] {
+                let translation = nat.send_internal_packet(client_in_addr, 25565, 22222, server_ex_port, time);
+                assert!(block.contains(&translation.as_external().unwrap().1));
+            }
+        }
+        let ports0 = nat.external_ports_for(client0_in_addr);
+        let ports1 = nat.external_ports_for(client1_in_addr);
+        assert!(ports0.iter().all(|port| !ports1.contains(port)));
+    }
+    #[test]
+    fn is_inbound_open() {
+        use nat_emulation::predefines::PORT_RESTRICTED_CONE_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let nat_ex_addr = 11111;
+        let mut nat = Nat::new(PORT_RESTRICTED_CONE_NAT, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        let client_in_addr = nat.assign_internal_address();
+        let server_ex_addr = 22222;
+        let server_ex_port = 80;
+
+        let translation = nat.send_internal_packet(client_in_addr, 25565, server_ex_addr, server_ex_port, time);
+        let (ex_addr, ex_port) = translation.as_external().unwrap();
+        time += 100;
+        assert!(nat.is_inbound_open(ex_addr, ex_port, server_ex_addr, server_ex_port, time));
+        assert!(!nat.is_inbound_open(ex_addr, ex_port, server_ex_addr, server_ex_port + 1, time));
+        assert!(!nat.is_inbound_open(ex_addr, ex_port + 2, server_ex_addr, server_ex_port, time));
+        // Probing does not refresh the mapping.
+        time += timeout - 100;
+        assert!(nat.is_inbound_open(ex_addr, ex_port, server_ex_addr, server_ex_port, time));
+        time += 1;
+        assert!(!nat.is_inbound_open(ex_addr, ex_port, server_ex_addr, server_ex_port, time));
+    }
+    #[test]
+    fn port_distribution() {
+        use nat_emulation::predefines::SYMMETRIC_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat, PortDistribution};
+        use rand::SeedableRng;
+        let rng = rand::rngs::StdRng::seed_from_u64(7);
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let nat_ex_addr = 11111;
+        let mut nat = Nat::new(SYMMETRIC_NAT, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        nat.port_distribution = PortDistribution::Window { offset: 100, width: 200 };
+        let client_in_addr = nat.assign_internal_address();
+        for server_ex_port in 1..=50 {
+            let translation = nat.send_internal_packet(client_in_addr, 25565, 22222, server_ex_port, time);
+            let (_, ex_port) = translation.as_external().unwrap();
+            assert!((PRIVATE.start() + 100..PRIVATE.start() + 300).contains(&ex_port));
+        }
+    }
+    #[test]
+    fn assign_n_internal_addresses() {
+        use nat_emulation::predefines::EASY_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let timeout = 1000 * 60 * 2;
+
+        let mut nat = Nat::new(EASY_NAT, [11111], 90000..=90009, PRIVATE, rng, usize::MAX, timeout);
+        let mut hosts = nat.assign_n_internal_addresses(4);
+        assert_eq!(hosts.len(), 4);
+        hosts.extend(nat.assign_n_internal_addresses(10));
+        assert_eq!(hosts.len(), 10);
+        hosts.sort_unstable();
+        assert_eq!(hosts, (90000..=90009).collect::<Vec<_>>());
+        assert!(nat.try_assign_internal_address().is_none());
+    }
+    #[test]
+    fn loss_rate() {
+        use nat_emulation::predefines::EASY_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        use rand::SeedableRng;
+        let rng = rand::rngs::StdRng::seed_from_u64(7);
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let nat_ex_addr = 11111;
+        let mut nat = Nat::new(EASY_NAT, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        let client_in_addr = nat.assign_internal_address();
+        nat.loss_rate = 0.5;
+        let mut delivered = 0;
+        for _ in 0..10000 {
+            if nat.send_internal_packet(client_in_addr, 25565, 22222, 80, time).is_external() {
+                delivered += 1;
+            }
+        }
+        assert!((4500..5500).contains(&delivered));
+    }
+
+    #[test]
+    fn endpoint_update() {
+        use nat_emulation::predefines::PORT_RESTRICTED_CONE_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, EndpointUpdate, Nat};
+        let timeout = 1000 * 60 * 2;
+        let nat_ex_addr = 11111;
+        let server_ex_addr = 22222;
+        let server_ex_port = 80;
+        let peer_ex_addr = 33333;
+        let peer_ex_port = 3000;
+
+        for endpoint_update in [EndpointUpdate::FixedAtCreation, EndpointUpdate::UpdateOnSend] {
+            let rng = rand::rngs::mock::StepRng::new(0, 1);
+            let mut time = 100;
+            let mut nat = Nat::new(PORT_RESTRICTED_CONE_NAT, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+            nat.endpoint_update = endpoint_update;
+            let client_in_addr = nat.assign_internal_address();
+            let client_in_port = 25565;
+
+            time += 100;
+            let translation = nat.send_internal_packet(client_in_addr, client_in_port, server_ex_addr, server_ex_port, time);
+            let (ex_addr, ex_port) = translation.as_external().unwrap();
+            time += 100;
+            let translation = nat.send_internal_packet(client_in_addr, client_in_port, peer_ex_addr, peer_ex_port, time);
+            // Endpoint-independent mapping reuses the external port either way.
+            assert_eq!(translation.as_external(), Some((ex_addr, ex_port)));
+
+            time += 100;
+            let translation = nat.receive_external_packet(peer_ex_addr, peer_ex_port, ex_addr, ex_port, false, time);
+            assert!(translation.is_some());
+            // Only a NAT that fixes endpoints at creation still remembers the first destination.
+            time += 100;
+            let translation = nat.receive_external_packet(server_ex_addr, server_ex_port, ex_addr, ex_port, false, time);
+            assert_eq!(translation.is_some(), endpoint_update == EndpointUpdate::FixedAtCreation);
+        }
+    }
+
+    #[test]
+    fn predict_next_port() {
+        use nat_emulation::flags::DETERMINISTIC_PORT_HASH;
+        use nat_emulation::predefines::{EASY_NAT, SYMMETRIC_NAT};
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let nat_ex_addr = 11111;
+        let client_in_port = 25565;
+        let server_ex_addr = 22222;
+        let mut nat = Nat::new(
+            SYMMETRIC_NAT | DETERMINISTIC_PORT_HASH,
+            [nat_ex_addr],
+            90000..=99999,
+            PRIVATE,
+            rng,
+            usize::MAX,
+            timeout,
+        );
+        let client_in_addr = nat.assign_internal_address();
+        for server_ex_port in [80, 443, 8080] {
+            let predicted = nat.predict_next_port(client_in_addr, client_in_port, server_ex_addr, server_ex_port, time);
+            let translation = nat.send_internal_packet(client_in_addr, client_in_port, server_ex_addr, server_ex_port, time);
+            assert!(predicted.is_some());
+            assert_eq!(predicted, translation.as_external().map(|(_, port)| port));
+        }
+        assert!(nat.predict_next_port(12345, client_in_port, server_ex_addr, 80, time).is_none());
+
+        // Random allocation is unpredictable, but reused and preserved ports are not.
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut nat = Nat::new(SYMMETRIC_NAT, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        let client_in_addr = nat.assign_internal_address();
+        assert!(nat.predict_next_port(client_in_addr, client_in_port, server_ex_addr, 80, time).is_none());
+        let (_, ex_port) = nat
+            .send_internal_packet(client_in_addr, client_in_port, server_ex_addr, 80, time)
+            .as_external()
+            .unwrap();
+        assert_eq!(
+            nat.predict_next_port(client_in_addr, client_in_port, server_ex_addr, 80, time),
+            Some(ex_port)
+        );
+
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut nat = Nat::new(EASY_NAT, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        let client_in_addr = nat.assign_internal_address();
+        assert_eq!(
+            nat.predict_next_port(client_in_addr, client_in_port, server_ex_addr, 80, time),
+            Some(client_in_port)
+        );
+    }
+
+    #[test]
+    fn simultaneous_open() {
+        use nat_emulation::predefines::{EASY_NAT, FULL_CONE_NAT, PORT_RESTRICTED_CONE_NAT, RESTRICTED_CONE_NAT};
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        let timeout = 1000 * 60 * 2;
+        let nat_ex_addr = 11111;
+        let client_in_port = 25565;
+        let peer_ex_addr = 22222;
+        let peer_ex_port = 3000;
+
+        for flags in [EASY_NAT, FULL_CONE_NAT, RESTRICTED_CONE_NAT, PORT_RESTRICTED_CONE_NAT] {
+            let rng = rand::rngs::mock::StepRng::new(0, 1);
+            let mut time = 100;
+            let mut nat = Nat::new(flags, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+            let client_in_addr = nat.assign_internal_address();
+            // The peer's packet arrives before the client's outbound packet has opened a mapping.
+            let predicted_port = nat.predict_next_port(client_in_addr, client_in_port, peer_ex_addr, peer_ex_port, time);
+            let guessed_port = predicted_port.unwrap_or(client_in_port);
+            time += 1;
+            let translation = nat.receive_external_packet(peer_ex_addr, peer_ex_port, nat_ex_addr, guessed_port, false, time);
+            assert!(translation.is_none());
+            // The client's packet crosses it and opens the mapping.
+            time += 1;
+            let translation = nat.send_internal_packet(client_in_addr, client_in_port, peer_ex_addr, peer_ex_port, time);
+            let (ex_addr, ex_port) = translation.as_external().unwrap();
+            if let Some(port) = predicted_port {
+                assert_eq!(port, ex_port);
+            }
+            // The peer's retry now makes it through.
+            time += 1;
+            let translation = nat.receive_external_packet(peer_ex_addr, peer_ex_port, ex_addr, ex_port, false, time);
+            assert_eq!(translation, Some((client_in_addr, client_in_port)));
+        }
+    }
+
+    #[test]
+    fn max_endpoints_per_flow() {
+        use nat_emulation::predefines::EASY_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let nat_ex_addr = 11111;
+        let mut nat = Nat::new(EASY_NAT, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        nat.max_endpoints_per_flow = Some(2);
+        let client_in_addr = nat.assign_internal_address();
+        let client_in_port = 25565;
+
+        time += 100;
+        assert!(nat.send_internal_packet(client_in_addr, client_in_port, 22222, 80, time).is_external());
+        assert!(nat.send_internal_packet(client_in_addr, client_in_port, 33333, 80, time).is_external());
+        // A third endpoint exceeds the limit, but the existing endpoints and other ports are fine.
+        assert!(nat.send_internal_packet(client_in_addr, client_in_port, 44444, 80, time).is_drop());
+        assert!(nat.send_internal_packet(client_in_addr, client_in_port, 22222, 80, time).is_external());
+        assert!(nat
+            .send_internal_packet(client_in_addr, client_in_port + 1, 44444, 80, time)
+            .is_external());
+        // Once the existing mappings time out the third endpoint can be reached.
+        time += timeout + 1;
+        assert!(nat.send_internal_packet(client_in_addr, client_in_port, 44444, 80, time).is_external());
+    }
+
+    #[test]
+    fn excluded_external_ports() {
+        use nat_emulation::predefines::{EASY_NAT, FULL_CONE_NAT};
+        use nat_emulation::Nat;
+        use rand::SeedableRng;
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+        let nat_ex_addr = 11111;
+        let server_ex_addr = 22222;
+        let server_ex_port = 80;
+        let excluded = 50010..=50019;
+
+        // An excluded port is not preserved.
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut nat = Nat::new(EASY_NAT, [nat_ex_addr], 90000..=99999, 50000..=50063, rng, 32, timeout);
+        nat.excluded_external_ports.push(excluded.clone());
+        let client_in_addr = nat.assign_internal_address();
+        let translation = nat.send_internal_packet(client_in_addr, 50012, server_ex_addr, server_ex_port, time);
+        let (_, ex_port) = translation.as_external().unwrap();
+        assert!(!excluded.contains(&ex_port));
+
+        // Nor is an excluded port ever drawn at random.
+        let rng = rand::rngs::StdRng::seed_from_u64(1);
+        let mut nat = Nat::new(FULL_CONE_NAT, [nat_ex_addr], 90000..=99999, 50000..=50063, rng, 32, timeout);
+        nat.excluded_external_ports.push(excluded.clone());
+        let client_in_addr = nat.assign_internal_address();
+        for client_in_port in 1024..1064 {
+            let translation = nat.send_internal_packet(client_in_addr, client_in_port, server_ex_addr, server_ex_port, time);
+            let (_, ex_port) = translation.as_external().unwrap();
+            assert!(!excluded.contains(&ex_port));
+        }
+    }
+
+    #[test]
+    fn const_nat() {
+        use nat_emulation::predefines::{FULL_CONE_NAT, SYMMETRIC_NAT};
+        use nat_emulation::{port_ranges::PRIVATE, AdaptiveFlags, ConstNat, Nat};
+        let timeout = 1000 * 60 * 2;
+        let nat_ex_addr = 11111;
+        let client_in_port = 25565;
+        let server_ex_addr = 22222;
+
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut const_nat = ConstNat::<SYMMETRIC_NAT, _, 1>::new([nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut nat = Nat::new(SYMMETRIC_NAT, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        let client_in_addr = nat.assign_internal_address();
+        assert_eq!(const_nat.assign_internal_address(), client_in_addr);
+        for server_ex_port in [80, 443, 80] {
+            let translation = const_nat.send_internal_packet(client_in_addr, client_in_port, server_ex_addr, server_ex_port, 100);
+            assert_eq!(
+                translation,
+                nat.send_internal_packet(client_in_addr, client_in_port, server_ex_addr, server_ex_port, 100)
+            );
+            let (ex_addr, ex_port) = translation.as_external().unwrap();
+            let translation = const_nat.receive_external_packet(server_ex_addr, server_ex_port, ex_addr, ex_port, false, 200);
+            assert_eq!(translation, Some((client_in_addr, client_in_port)));
+        }
+        assert_eq!(const_nat.into_inner().flags, SYMMETRIC_NAT);
+
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut nat = Nat::new(FULL_CONE_NAT, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        nat.adaptive_flags = Some(AdaptiveFlags {
+            normal_flags: FULL_CONE_NAT,
+            loaded_flags: FULL_CONE_NAT,
+            high_water: 1,
+            low_water: 0,
+        });
+        let const_nat = ConstNat::<SYMMETRIC_NAT, _, 1>::from_inner(nat);
+        assert_eq!(const_nat.flags, SYMMETRIC_NAT);
+        assert!(const_nat.adaptive_flags.is_none());
+    }
+
+    #[test]
+    fn peak_mapping_count() {
+        use nat_emulation::predefines::SYMMETRIC_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let nat_ex_addr = 11111;
+        let mut nat = Nat::new(SYMMETRIC_NAT, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        let client_in_addr = nat.assign_internal_address();
+        assert_eq!(nat.peak_mapping_count(), 0);
+        for server_ex_port in 80..85 {
+            nat.send_internal_packet(client_in_addr, 25565, 22222, server_ex_port, time);
+        }
+        assert_eq!(nat.peak_mapping_count(), 5);
+        // Expired mappings are swept before the next one is created, so the peak holds.
+        time += timeout + 1;
+        nat.send_internal_packet(client_in_addr, 25565, 22222, 80, time);
+        assert_eq!(nat.peak_mapping_count(), 5);
+        nat.reset_peak_mapping_count();
+        assert_eq!(nat.peak_mapping_count(), 1);
+    }
+
+    #[test]
+    fn remove_internal_address() {
+        use nat_emulation::predefines::EASY_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let nat_ex_addr = 11111;
+        let server_ex_addr = 22222;
+        let server_ex_port = 80;
+        let mut nat = Nat::new(EASY_NAT, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        let client_in_addr = nat.assign_internal_address();
+        let other_in_addr = nat.assign_internal_address();
+
+        time += 100;
+        let translation = nat.send_internal_packet(client_in_addr, 25565, server_ex_addr, server_ex_port, time);
+        let (ex_addr, ex_port) = translation.as_external().unwrap();
+        let translation = nat.send_internal_packet(other_in_addr, 25566, server_ex_addr, server_ex_port, time);
+        let (other_ex_addr, other_ex_port) = translation.as_external().unwrap();
+        nat.remove_internal_address(client_in_addr);
+
+        // The removed host's mapping is gone immediately, but other hosts are unaffected.
+        time += 100;
+        let translation = nat.receive_external_packet(server_ex_addr, server_ex_port, ex_addr, ex_port, false, time);
+        assert!(translation.is_none());
+        let translation = nat.receive_external_packet(server_ex_addr, server_ex_port, other_ex_addr, other_ex_port, false, time);
+        assert_eq!(translation, Some((other_in_addr, 25566)));
+        assert!(nat.external_ports_for(client_in_addr).is_empty());
+    }
+
+    #[test]
+    fn inbound_whitelist() {
+        use nat_emulation::predefines::SYMMETRIC_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let nat_ex_addr = 11111;
+        let server_ex_addr = 22222;
+        let trusted_ex_addr = 33333;
+        let mut nat = Nat::new(SYMMETRIC_NAT, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        nat.add_inbound_whitelist(trusted_ex_addr);
+        let client_in_addr = nat.assign_internal_address();
+
+        time += 100;
+        let translation = nat.send_internal_packet(client_in_addr, 25565, server_ex_addr, 80, time);
+        let (ex_addr, ex_port) = translation.as_external().unwrap();
+        // The trusted source was never contacted but is not filtered, unlike any other source.
+        time += 100;
+        assert!(nat.is_inbound_open(ex_addr, ex_port, trusted_ex_addr, 4000, time));
+        let translation = nat.receive_external_packet(trusted_ex_addr, 4000, ex_addr, ex_port, false, time);
+        assert_eq!(translation, Some((client_in_addr, 25565)));
+        let translation = nat.receive_external_packet(44444, 4000, ex_addr, ex_port, false, time);
+        assert!(translation.is_none());
+
+        assert!(nat.remove_inbound_whitelist(trusted_ex_addr));
+        let translation = nat.receive_external_packet(trusted_ex_addr, 4000, ex_addr, ex_port, false, time);
+        assert!(translation.is_none());
+    }
+
+    #[test]
+    fn nat64() {
+        use nat_emulation::predefines::PORT_RESTRICTED_CONE_NAT;
+        use nat_emulation::Nat64;
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let nat_ex_addr = 11111;
+        let server_ex_addr = 22222;
+        let server_ex_port = 80;
+        let mut nat = Nat64::with_well_known_prefix(PORT_RESTRICTED_CONE_NAT, nat_ex_addr, rng, usize::MAX, timeout);
+        let client_in_addr: u128 = 0x2001_0db8 << 96 | 1;
+        let other_in_addr: u128 = 0x2001_0db8 << 96 | 2;
+        nat.add_internal_address(client_in_addr);
+        nat.add_internal_address(other_in_addr);
+        let server_in_addr = nat.synthesize(server_ex_addr);
+
+        time += 100;
+        let (ex_addr, ex_port, dest_addr) = nat
+            .send_internal_packet(client_in_addr, 5000, server_in_addr, server_ex_port, time)
+            .unwrap();
+        assert_eq!((ex_addr, dest_addr), (nat_ex_addr, server_ex_addr));
+        let (_, other_ex_port, _) = nat
+            .send_internal_packet(other_in_addr, 5000, server_in_addr, server_ex_port, time)
+            .unwrap();
+        // Both hosts share one IPv4 address and are told apart by port.
+        assert!(ex_port != other_ex_port);
+
+        time += 100;
+        let translation = nat.receive_external_packet(server_ex_addr, server_ex_port, ex_addr, ex_port, false, time);
+        assert_eq!(translation, Some((server_in_addr, client_in_addr, 5000)));
+        let translation = nat.receive_external_packet(server_ex_addr, server_ex_port, ex_addr, other_ex_port, false, time);
+        assert_eq!(translation, Some((server_in_addr, other_in_addr, 5000)));
+        // Destinations outside the prefix and unknown hosts are not translated.
+        assert!(nat.send_internal_packet(client_in_addr, 5000, 0x2001_0db8 << 96 | 3, 80, time).is_none());
+        assert!(nat.send_internal_packet(0x2001_0db8 << 96 | 3, 5000, server_in_addr, 80, time).is_none());
+
+        // Removing a host removes its mappings from the wrapped NAT too.
+        nat.remove_internal_address(other_in_addr);
+        assert_eq!(nat.mappings().len(), 1);
+        let translation = nat.receive_external_packet(server_ex_addr, server_ex_port, ex_addr, other_ex_port, false, time);
+        assert!(translation.is_none());
+        assert!(nat
+            .send_internal_packet(other_in_addr, 5000, server_in_addr, server_ex_port, time)
+            .is_none());
+    }
+
+    #[test]
+    fn stable_address_shuffle() {
+        use nat_emulation::flags::IP_POOLING_BEHAVIOR_ARBITRARY;
+        use nat_emulation::predefines::EASY_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let nat_ex_addrs = [11111, 11112, 11113];
+        let mut nat = Nat::new(
+            EASY_NAT | IP_POOLING_BEHAVIOR_ARBITRARY,
+            nat_ex_addrs,
+            90000..=99999,
+            PRIVATE,
+            rng,
+            usize::MAX,
+            timeout,
+        );
+        let client_in_addr = nat.assign_internal_address();
+        let chosen: Vec<u32> = (0..6)
+            .map(|i| {
+                nat.send_internal_packet(client_in_addr, 25565 + i, 22222, 80, time)
+                    .as_external()
+                    .unwrap()
+                    .0
+            })
+            .collect();
+        // These exact choices are part of the NAT's stable behavior for a given seed.
+        assert_eq!(chosen, [11111, 11111, 11113, 11111, 11111, 11113]);
+    }
+
+    #[test]
+    fn dmz_host() {
+        use nat_emulation::predefines::PORT_RESTRICTED_CONE_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let nat_ex_addr = 11111;
+        let server_ex_addr = 22222;
+        let mut nat = Nat::new(PORT_RESTRICTED_CONE_NAT, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        let dmz_in_addr = nat.assign_internal_address();
+        let client_in_addr = nat.assign_internal_address();
+        let translation = nat.receive_external_packet(server_ex_addr, 80, nat_ex_addr, 7777, false, time);
+        assert!(translation.is_none());
+        nat.dmz_host = Some(dmz_in_addr);
+
+        // Unmatched inbound packets go to the DMZ host.
+        time += 100;
+        assert!(nat.is_inbound_open(nat_ex_addr, 7777, server_ex_addr, 80, time));
+        let translation = nat.receive_external_packet(server_ex_addr, 80, nat_ex_addr, 7777, false, time);
+        assert_eq!(translation, Some((dmz_in_addr, 7777)));
+        // But packets filtered by another host's mapping do not.
+        let translation = nat.send_internal_packet(client_in_addr, 25565, server_ex_addr, 80, time);
+        let (ex_addr, ex_port) = translation.as_external().unwrap();
+        time += 100;
+        let translation = nat.receive_external_packet(33333, 80, ex_addr, ex_port, false, time);
+        assert!(translation.is_none());
+        let translation = nat.receive_external_packet(server_ex_addr, 80, ex_addr, ex_port, false, time);
+        assert_eq!(translation, Some((client_in_addr, 25565)));
+    }
+
+    #[test]
+    fn rng_checkpoint() {
+        use nat_emulation::predefines::SYMMETRIC_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        use rand::SeedableRng;
+        let rng = rand::rngs::StdRng::seed_from_u64(7);
+        let mut time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let nat_ex_addr = 11111;
+        let mut nat = Nat::new(SYMMETRIC_NAT, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        let client_in_addr = nat.assign_internal_address();
+        let checkpoint = nat.rng().clone();
+        let send_all = |nat: &mut Nat<_, 1>, time| -> Vec<u16> {
+            (80..84)
+                .map(|server_ex_port| {
+                    nat.send_internal_packet(client_in_addr, 25565, 22222, server_ex_port, time)
+                        .as_external()
+                        .unwrap()
+                        .1
+                })
+                .collect()
+        };
+        let ports = send_all(&mut nat, time);
+        // Once the mappings expire, restoring the rng replays the same random ports.
+        time += timeout + 1;
+        *nat.rng_mut() = checkpoint;
+        assert_eq!(send_all(&mut nat, time), ports);
+    }
+
+    #[test]
+    fn restricted_cone_multiple_peers() {
+        use nat_emulation::predefines::RESTRICTED_CONE_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let nat_ex_addr = 11111;
+        let client_in_port = 25565;
+        let peer_a_ex_addr = 22222;
+        let peer_b_ex_addr = 33333;
+        let stranger_ex_addr = 44444;
+        let mut nat = Nat::new(RESTRICTED_CONE_NAT, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        let client_in_addr = nat.assign_internal_address();
+
+        time += 100;
+        let translation = nat.send_internal_packet(client_in_addr, client_in_port, peer_a_ex_addr, 80, time);
+        let (ex_addr, ex_port) = translation.as_external().unwrap();
+        time += 100;
+        let translation = nat.send_internal_packet(client_in_addr, client_in_port, peer_b_ex_addr, 80, time);
+        assert_eq!(translation.as_external(), Some((ex_addr, ex_port)));
+
+        // Every peer contacted through the mapping may reply from any port, in any order.
+        for peer_ex_addr in [peer_a_ex_addr, peer_b_ex_addr, peer_a_ex_addr] {
+            time += 100;
+            let translation = nat.receive_external_packet(peer_ex_addr, 3000, ex_addr, ex_port, false, time);
+            assert_eq!(translation, Some((client_in_addr, client_in_port)));
+        }
+        time += 100;
+        let translation = nat.receive_external_packet(stranger_ex_addr, 80, ex_addr, ex_port, false, time);
+        assert!(translation.is_none());
+    }
+
+    #[test]
+    fn hardness_score() {
+        use nat_emulation::flags::*;
+        use nat_emulation::hardness_score;
+        use nat_emulation::predefines::*;
+        assert_eq!(hardness_score(EASY_NAT), 0);
+        let ranked = [
+            EASY_NAT,
+            FULL_CONE_NAT,
+            RESTRICTED_CONE_NAT,
+            PORT_RESTRICTED_CONE_NAT,
+            SYMMETRIC_NAT,
+            HARD_NAT,
+            MISBEHAVING_NAT,
+        ];
+        for pair in ranked.windows(2) {
+            assert!(hardness_score(pair[0]) < hardness_score(pair[1]));
+        }
+        // Flags with no effect add nothing.
+        assert_eq!(
+            hardness_score(NO_PORT_PRESERVATION | PORT_PRESERVATION_OVERRIDE),
+            hardness_score(NO_PORT_PRESERVATION)
+        );
+        assert_eq!(hardness_score(RELAX_FILTERING_AFTER_BIDIRECTIONAL), 0);
+        // Adding a flag never lowers the score, even when it makes other flags a no-op.
+        let hairpinning = INTERNAL_ADDRESS_AND_PORT_HAIRPINNING | HAIRPINNING_REQUIRES_MAPPING;
+        assert!(hardness_score(hairpinning | NO_HAIRPINNING) >= hardness_score(hairpinning));
+        let masking = [
+            NO_PORT_PRESERVATION,
+            PORT_PRESERVATION_OVERRIDE,
+            PORT_PRESERVATION_OVERLOAD,
+            NO_HAIRPINNING,
+            INTERNAL_ADDRESS_AND_PORT_HAIRPINNING,
+            HAIRPINNING_REQUIRES_MAPPING,
+        ];
+        for subset in 0..1u32 << masking.len() {
+            let flags = (0..masking.len()).filter(|i| subset & 1 << i > 0).fold(0, |flags, i| flags | masking[i]);
+            for bit in 0..32 {
+                assert!(hardness_score(flags | 1 << bit) >= hardness_score(flags));
+            }
+        }
+    }
+
+    #[test]
+    fn receive_external_packet_all() {
+        use nat_emulation::flags::PORT_PRESERVATION_OVERLOAD;
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let nat_ex_addr = 11111;
+        let server_ex_addr = 22222;
+        let mut nat = Nat::new(
+            PORT_PRESERVATION_OVERLOAD,
+            [nat_ex_addr],
+            90000..=99999,
+            PRIVATE,
+            rng,
+            usize::MAX,
+            timeout,
+        );
+        let client0_in_addr = nat.assign_internal_address();
+        let client1_in_addr = nat.assign_internal_address();
+
+        time += 100;
+        let translation0 = nat.send_internal_packet(client0_in_addr, 5000, server_ex_addr, 80, time);
+        let translation1 = nat.send_internal_packet(client1_in_addr, 5000, server_ex_addr, 80, time);
+        nat.send_internal_packet(client1_in_addr, 5000, 33333, 80, time);
+        // Both clients are overloaded onto the same external port.
+        assert_eq!(translation0, translation1);
+        let (ex_addr, ex_port) = translation0.as_external().unwrap();
+
+        time += 100;
+        let mut recipients = nat.receive_external_packet_all(server_ex_addr, 80, ex_addr, ex_port, false, time);
+        recipients.sort_unstable();
+        let mut expected = vec![(client0_in_addr, 5000), (client1_in_addr, 5000)];
+        expected.sort_unstable();
+        assert_eq!(recipients, expected);
+        let translation = nat.receive_external_packet(server_ex_addr, 80, ex_addr, ex_port, false, time);
+        assert!(expected.contains(&translation.unwrap()));
+        assert!(nat
+            .receive_external_packet_all(server_ex_addr, 80, ex_addr, ex_port + 1, false, time)
+            .is_empty());
+    }
+
+    #[test]
+    fn static_mapping_conflicts() {
+        use nat_emulation::predefines::EASY_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, ForwardConflict, ForwardConflictPolicy, Nat};
+        let timeout = 1000 * 60 * 2;
+        let nat_ex_addr = 11111;
+        let server_ex_addr = 22222;
+        let peer_ex_addr = 33333;
+
+        for policy in [ForwardConflictPolicy::Evict, ForwardConflictPolicy::Reject, ForwardConflictPolicy::Shadow] {
+            let rng = rand::rngs::mock::StepRng::new(0, 1);
+            let mut time = 100;
+            let mut nat = Nat::new(EASY_NAT, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+            let client_in_addr = nat.assign_internal_address();
+            let server_in_addr = nat.assign_internal_address();
+            // The client's mapping holds the port the server wants forwarded.
+            let translation = nat.send_internal_packet(client_in_addr, 8080, server_ex_addr, 80, time);
+            assert_eq!(translation.as_external(), Some((nat_ex_addr, 8080)));
+
+            time += 100;
+            let result = nat.add_static_mapping(nat_ex_addr, 8080, server_in_addr, 80, policy, time);
+            let translation = nat.receive_external_packet(peer_ex_addr, 3000, nat_ex_addr, 8080, false, time);
+            match policy {
+                ForwardConflictPolicy::Evict => {
+                    assert!(result.is_ok());
+                    assert_eq!(translation, Some((server_in_addr, 80)));
+                }
+                ForwardConflictPolicy::Reject => {
+                    assert_eq!(result, Err(ForwardConflict::InUse { mappings: 1 }));
+                    assert_eq!(translation, Some((client_in_addr, 8080)));
+                }
+                ForwardConflictPolicy::Shadow => {
+                    assert!(result.is_ok());
+                    assert_eq!(translation, Some((client_in_addr, 8080)));
+                    // Once the client's mapping times out the port forward takes over.
+                    time += timeout + 1;
+                    let translation = nat.receive_external_packet(peer_ex_addr, 3000, nat_ex_addr, 8080, false, time);
+                    assert_eq!(translation, Some((server_in_addr, 80)));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sequential_port_allocation() {
+        use nat_emulation::predefines::SYMMETRIC_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat, PortAllocation};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let nat_ex_addrs = [11111, 11112];
+        let mut nat = Nat::new(SYMMETRIC_NAT, nat_ex_addrs, 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        nat.port_allocation = PortAllocation::Sequential;
+        let client_in_addr = nat.assign_internal_address();
+        let mut previous = None;
+        for server_ex_port in 80..90 {
+            let predicted = nat.predict_next_port(client_in_addr, 25565, 22222, server_ex_port, time);
+            let translation = nat.send_internal_packet(client_in_addr, 25565, 22222, server_ex_port, time);
+            let (ex_addr, ex_port) = translation.as_external().unwrap();
+            assert_eq!(predicted, Some(ex_port));
+            // The address stays paired while the port climbs with every new destination.
+            if let Some((previous_addr, previous_port)) = previous {
+                assert_eq!(ex_addr, previous_addr);
+                assert!(ex_port > previous_port);
+            }
+            previous = Some((ex_addr, ex_port));
+        }
+    }
+
+    #[test]
+    fn last_port_preservation() {
+        use nat_emulation::predefines::{EASY_NAT, FULL_CONE_NAT};
+        use nat_emulation::{port_ranges::PRIVATE, Nat, PortPreservation};
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+        let nat_ex_addr = 11111;
+        let server_ex_addr = 22222;
+
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut nat = Nat::new(EASY_NAT, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        let client0_in_addr = nat.assign_internal_address();
+        let client1_in_addr = nat.assign_internal_address();
+        assert_eq!(nat.last_port_preservation(), None);
+        nat.send_internal_packet(client0_in_addr, 25565, server_ex_addr, 80, time);
+        assert_eq!(nat.last_port_preservation(), Some(PortPreservation::Preserved));
+        nat.send_internal_packet(client0_in_addr, 25565, server_ex_addr, 443, time);
+        assert_eq!(nat.last_port_preservation(), Some(PortPreservation::Reused));
+        let translation = nat.send_internal_packet(client1_in_addr, 25565, server_ex_addr, 80, time);
+        assert!(translation.as_external().unwrap().1 != 25565);
+        assert_eq!(nat.last_port_preservation(), Some(PortPreservation::Collision));
+
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut nat = Nat::new(FULL_CONE_NAT, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        let client_in_addr = nat.assign_internal_address();
+        nat.send_internal_packet(client_in_addr, 25565, server_ex_addr, 80, time);
+        assert_eq!(nat.last_port_preservation(), Some(PortPreservation::Disabled));
+    }
+
+    #[test]
+    fn port_oracle() {
+        use nat_emulation::predefines::SYMMETRIC_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat, OracleConflict};
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+        let nat_ex_addr = 11111;
+        let server_ex_addr = 22222;
+
+        for on_conflict in [OracleConflict::Fallback, OracleConflict::Drop] {
+            let rng = rand::rngs::mock::StepRng::new(0, 1);
+            let mut nat = Nat::new(SYMMETRIC_NAT, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+            // Every flow is scripted to 50000 plus its destination port.
+            nat.set_port_oracle(|flow| 50000 + flow.endpoint_port, on_conflict);
+            let client0_in_addr = nat.assign_internal_address();
+            let client1_in_addr = nat.assign_internal_address();
+            for server_ex_port in [80, 443] {
+                let translation = nat.send_internal_packet(client0_in_addr, 25565, server_ex_addr, server_ex_port, time);
+                assert_eq!(translation.as_external(), Some((nat_ex_addr, 50000 + server_ex_port)));
+            }
+            // The second client is scripted to a port that is taken.
+            let translation = nat.send_internal_packet(client1_in_addr, 25565, server_ex_addr, 80, time);
+            match on_conflict {
+                OracleConflict::Fallback => assert!(translation.as_external().is_some_and(|(_, port)| port != 50080)),
+                OracleConflict::Drop => assert!(translation.is_drop()),
+            }
+        }
+    }
+
+    #[test]
+    fn filter_timeout() {
+        use nat_emulation::predefines::PORT_RESTRICTED_CONE_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, FilterExpiry, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let nat_ex_addr = 11111;
+        let server_ex_addr = 22222;
+        let stranger_ex_addr = 33333;
+        let mut nat = Nat::new(PORT_RESTRICTED_CONE_NAT, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        nat.filter_timeout = Some(1000 * 30);
+        let client_in_addr = nat.assign_internal_address();
+
+        time += 100;
+        let translation = nat.send_internal_packet(client_in_addr, 25565, server_ex_addr, 80, time);
+        let (ex_addr, ex_port) = translation.as_external().unwrap();
+        time += 100;
+        assert!(nat.is_inbound_open(ex_addr, ex_port, server_ex_addr, 80, time));
+        assert!(!nat.is_inbound_open(ex_addr, ex_port, stranger_ex_addr, 4000, time));
+
+        // The filter window has lapsed but the mapping has not, so the mapping is fully open.
+        time += 1000 * 60;
+        let translation = nat.receive_external_packet(stranger_ex_addr, 4000, ex_addr, ex_port, false, time);
+        assert_eq!(translation, Some((client_in_addr, 25565)));
+        // Sending through the mapping again restores its filtering.
+        let translation = nat.send_internal_packet(client_in_addr, 25565, server_ex_addr, 80, time);
+        assert_eq!(translation.as_external(), Some((ex_addr, ex_port)));
+        assert!(!nat.is_inbound_open(ex_addr, ex_port, stranger_ex_addr, 4000, time));
+
+        // A closed filter rejects every source, even the server, while outbound packets still use the mapping.
+        nat.filter_expiry = FilterExpiry::Closed;
+        time += 1000 * 60;
+        let translation = nat.receive_external_packet(server_ex_addr, 80, ex_addr, ex_port, false, time);
+        assert!(translation.is_none());
+        let translation = nat.send_internal_packet(client_in_addr, 25565, server_ex_addr, 80, time);
+        assert_eq!(translation.as_external(), Some((ex_addr, ex_port)));
+        let translation = nat.receive_external_packet(server_ex_addr, 80, ex_addr, ex_port, false, time);
+        assert_eq!(translation, Some((client_in_addr, 25565)));
+    }
+
+    #[test]
+    fn connection() {
+        use nat_emulation::predefines::PORT_RESTRICTED_CONE_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let nat_ex_addr = 11111;
+        let server_ex_addr = 22222;
+        let mut nat = Nat::new(PORT_RESTRICTED_CONE_NAT, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        let client_in_addr = nat.assign_internal_address();
+
+        let mut conn = nat.open_connection(client_in_addr, 25565, server_ex_addr, 80);
+        // Nothing has been sent yet, so the server cannot reach the client.
+        time += 100;
+        assert!(!conn.is_alive(&nat, time));
+        assert!(!conn.recv(&mut nat, time));
+
+        assert!(conn.send(&mut nat, time).is_external());
+        assert_eq!(conn.external().unwrap().0, nat_ex_addr);
+        time += 100;
+        assert!(conn.is_alive(&nat, time));
+        assert!(conn.recv(&mut nat, time));
+
+        time += timeout + 1;
+        assert!(!conn.is_alive(&nat, time));
+        assert!(!conn.recv(&mut nat, time));
+    }
+
+    #[test]
+    fn set_mapping_timeout() {
+        use nat_emulation::predefines::SYMMETRIC_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, ConstructError, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let nat_ex_addr = 11111;
+        let server_ex_addr = 22222;
+        let mut nat = Nat::new(SYMMETRIC_NAT, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        let client_in_addr = nat.assign_internal_address();
+
+        let translation = nat.send_internal_packet(client_in_addr, 25565, server_ex_addr, 80, time);
+        let (ex_addr, old_ex_port) = translation.as_external().unwrap();
+        time += 1000 * 60;
+        let translation = nat.send_internal_packet(client_in_addr, 25565, server_ex_addr, 81, time);
+        let (_, new_ex_port) = translation.as_external().unwrap();
+        time += 1000 * 30;
+        assert!(nat.is_inbound_open(ex_addr, old_ex_port, server_ex_addr, 80, time));
+
+        // Only the older mapping is past the new timeout, and it is removed right away.
+        assert_eq!(nat.set_mapping_timeout(-1, time), Err(ConstructError::NegativeMappingTimeout));
+        assert!(nat.is_inbound_open(ex_addr, old_ex_port, server_ex_addr, 80, time));
+        nat.set_mapping_timeout(1000 * 60, time).unwrap();
+        nat.reset_peak_mapping_count();
+        assert_eq!(nat.peak_mapping_count(), 1);
+        assert!(!nat.is_inbound_open(ex_addr, old_ex_port, server_ex_addr, 80, time));
+        assert!(nat.is_inbound_open(ex_addr, new_ex_port, server_ex_addr, 81, time));
+    }
+
+    #[test]
+    fn port_preservation_over_parity() {
+        use nat_emulation::flags::PORT_PRESERVATION_OVER_PARITY;
+        use nat_emulation::predefines::EASY_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat, PortPreservation};
+        use rand::SeedableRng;
+        let server_ex_addr = 22222;
+        let timeout = 1000 * 60 * 2;
+        let time = 100;
+
+        for over_parity in [false, true] {
+            let rng = rand::rngs::StdRng::seed_from_u64(7);
+            let flags = if over_parity {
+                EASY_NAT | PORT_PRESERVATION_OVER_PARITY
+            } else {
+                EASY_NAT
+            };
+            let mut nat = Nat::new(flags, [11111], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+            let client_in_addrs = nat.assign_n_internal_addresses(3);
+            let translation = nat.send_internal_packet(client_in_addrs[0], 50000, server_ex_addr, 80, time);
+            assert_eq!(translation.as_external().unwrap().1, 50000);
+
+            // The source port is taken, so either its parity or its proximity must be given up.
+            let predicted = nat.predict_next_port(client_in_addrs[1], 50000, server_ex_addr, 80, time);
+            let translation = nat.send_internal_packet(client_in_addrs[1], 50000, server_ex_addr, 80, time);
+            let (_, ex_port) = translation.as_external().unwrap();
+            assert_eq!(nat.last_port_preservation(), Some(PortPreservation::Collision));
+            if over_parity {
+                assert_eq!(predicted, Some(50001));
+                assert_eq!(ex_port, 50001);
+                let translation = nat.send_internal_packet(client_in_addrs[2], 50000, server_ex_addr, 80, time);
+                assert_eq!(translation.as_external().unwrap().1, 50002);
+            } else {
+                assert_eq!(predicted, None);
+                assert_ne!(ex_port, 50000);
+                assert_eq!(ex_port % 2, 0);
+            }
+        }
+    }
+
+    #[test]
+    fn non_monotonic_time() {
+        use nat_emulation::predefines::PORT_RESTRICTED_CONE_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat, NonMonotonicTime};
+        let timeout = 1000 * 60 * 2;
+        let server_ex_addr = 22222;
+
+        for policy in [NonMonotonicTime::Trust, NonMonotonicTime::Clamp, NonMonotonicTime::Ignore] {
+            let rng = rand::rngs::mock::StepRng::new(0, 1);
+            let mut time = 1000 * 60 * 60;
+            let mut nat = Nat::new(PORT_RESTRICTED_CONE_NAT, [11111], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+            nat.non_monotonic_time = policy;
+            let client_in_addr = nat.assign_internal_address();
+            let translation = nat.send_internal_packet(client_in_addr, 25565, server_ex_addr, 80, time);
+            let (ex_addr, ex_port) = translation.as_external().unwrap();
+
+            // The clock jumps back by far more than the timeout for a single packet.
+            let translation = nat.send_internal_packet(client_in_addr, 25565, server_ex_addr, 80, time - 1000 * 60 * 30);
+            assert_eq!(translation.is_drop(), policy == NonMonotonicTime::Ignore);
+
+            time += timeout / 2;
+            let open = nat.is_inbound_open(ex_addr, ex_port, server_ex_addr, 80, time);
+            // No policy lets the backward timestamp expire the mapping early.
+            assert!(open);
+            time += timeout;
+            assert!(!nat.is_inbound_open(ex_addr, ex_port, server_ex_addr, 80, time));
+        }
+    }
+
+    #[test]
+    fn external_address_capacity() {
+        use nat_emulation::predefines::EASY_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let timeout = 1000 * 60 * 2;
+
+        let nat = Nat::<_, 4>::with_capacity(EASY_NAT, &[11111, 11112], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        assert_eq!(nat.external_address_count(), 2);
+        assert_eq!(nat.external_address_capacity(), 4);
+        assert_eq!(nat.external_addresses(), &[11111, 11112]);
+    }
+
+    #[test]
+    fn internal_subnets() {
+        use nat_emulation::predefines::EASY_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let printer_in_addr = 190001;
+        let mut nat = Nat::new(EASY_NAT, [11111], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        let client_in_addr = nat.assign_internal_address();
+        // Until the second subnet is known the printer is assumed to be on the internet.
+        let translation = nat.send_internal_packet(client_in_addr, 25565, printer_in_addr, 631, time);
+        assert!(translation.is_external());
+
+        nat.internal_subnets.push(190000..=199999);
+        let translation = nat.send_internal_packet(client_in_addr, 25565, printer_in_addr, 631, time);
+        assert_eq!(translation.as_internal(), Some((client_in_addr, 25565, printer_in_addr, 631)));
+    }
+
+    #[test]
+    fn send_internal_packet_with_port() {
+        use nat_emulation::predefines::SYMMETRIC_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let nat_ex_addr = 11111;
+        let server_ex_addr = 22222;
+        let mut nat = Nat::new(SYMMETRIC_NAT, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        let client_in_addrs = nat.assign_n_internal_addresses(2);
+        let translation = nat.send_internal_packet(client_in_addrs[0], 25565, server_ex_addr, 80, time);
+        let (_, taken_ex_port) = translation.as_external().unwrap();
+
+        // The forced port is taken by the first client, whose mapping is evicted.
+        time += 100;
+        let translation = nat.send_internal_packet_with_port(client_in_addrs[1], 25565, server_ex_addr, 80, taken_ex_port, time);
+        assert_eq!(translation.as_external(), Some((nat_ex_addr, taken_ex_port)));
+        let translation = nat.receive_external_packet(server_ex_addr, 80, nat_ex_addr, taken_ex_port, false, time);
+        assert_eq!(translation, Some((client_in_addrs[1], 25565)));
+        // Later packets of the flow keep using the forced port.
+        let translation = nat.send_internal_packet(client_in_addrs[1], 25565, server_ex_addr, 80, time);
+        assert_eq!(translation.as_external(), Some((nat_ex_addr, taken_ex_port)));
+
+        // Ports outside of the dynamic port range are refused.
+        let translation = nat.send_internal_packet_with_port(client_in_addrs[1], 25565, server_ex_addr, 81, 8080, time);
+        assert!(translation.is_drop());
+    }
+
+    #[test]
+    fn hairpinning_requires_mapping() {
+        use nat_emulation::flags::HAIRPINNING_REQUIRES_MAPPING;
+        use nat_emulation::predefines::EASY_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        let timeout = 1000 * 60 * 2;
+        let time = 100;
+        let nat_ex_addr = 11111;
+        let server_ex_addr = 22222;
+
+        for requires_mapping in [false, true] {
+            let rng = rand::rngs::mock::StepRng::new(0, 1);
+            let flags = if requires_mapping {
+                EASY_NAT | HAIRPINNING_REQUIRES_MAPPING
+            } else {
+                EASY_NAT
+            };
+            let mut nat = Nat::new(flags, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+            let client_in_addrs = nat.assign_n_internal_addresses(2);
+            let translation = nat.send_internal_packet(client_in_addrs[0], 25565, server_ex_addr, 80, time);
+            let (_, ex_port) = translation.as_external().unwrap();
+
+            // The second client has never sent anything to the internet.
+            let translation = nat.send_internal_packet(client_in_addrs[1], 25566, nat_ex_addr, ex_port, time);
+            assert_eq!(translation.is_drop(), requires_mapping);
+
+            nat.send_internal_packet(client_in_addrs[1], 25566, server_ex_addr, 80, time);
+            let translation = nat.send_internal_packet(client_in_addrs[1], 25566, nat_ex_addr, ex_port, time);
+            assert_eq!(translation.as_internal().map(|t| (t.2, t.3)), Some((client_in_addrs[0], 25565)));
+        }
+    }
+
+    #[test]
+    #[allow(clippy::reversed_empty_ranges)]
+    fn try_new() {
+        use nat_emulation::flags::{PORT_PRESERVATION_OVERLOAD, PORT_PRESERVATION_OVERRIDE};
+        use nat_emulation::predefines::EASY_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, ConstructError, FlagConflict, Nat};
+        let rng = || rand::rngs::mock::StepRng::new(0, 1);
+        let timeout = 1000 * 60 * 2;
+
+        assert!(Nat::try_new(EASY_NAT, [11111], 90000..=99999, PRIVATE, rng(), usize::MAX, timeout).is_ok());
+        let result = Nat::try_new(EASY_NAT, [11111], 99999..=90000, PRIVATE, rng(), usize::MAX, timeout);
+        assert_eq!(result.err(), Some(ConstructError::EmptyInternalRange));
+        let result = Nat::try_new(EASY_NAT, [11111], 90000..=99999, 65535..=49152, rng(), usize::MAX, timeout);
+        assert_eq!(result.err(), Some(ConstructError::EmptyPortRange));
+        let result = Nat::try_new(EASY_NAT, [11111], 90000..=99999, 5000..=5009, rng(), usize::MAX, timeout);
+        assert!(matches!(result.err(), Some(ConstructError::PortRangeTooSmall(_))));
+        let result = Nat::try_new(EASY_NAT, [11111], 90000..=99999, PRIVATE, rng(), 0, timeout);
+        assert_eq!(result.err(), Some(ConstructError::ZeroMappingMaxSize));
+        let flags = PORT_PRESERVATION_OVERRIDE | PORT_PRESERVATION_OVERLOAD;
+        let result = Nat::try_new(flags, [11111], 90000..=99999, PRIVATE, rng(), usize::MAX, timeout);
+        assert_eq!(result.err(), Some(ConstructError::ContradictoryFlags(FlagConflict::OverrideAndOverload)));
+
+        let result = Nat::<_, 2>::try_with_capacity(EASY_NAT, &[11111, 11112, 11113], 90000..=99999, PRIVATE, rng(), usize::MAX, timeout);
+        assert_eq!(result.err(), Some(ConstructError::TooManyExternalAddresses { count: 3, capacity: 2 }));
+        let result = Nat::<_, 2>::try_with_capacity(EASY_NAT, &[], 90000..=99999, PRIVATE, rng(), usize::MAX, timeout);
+        assert_eq!(result.err(), Some(ConstructError::NoExternalAddresses));
+    }
+
+    #[test]
+    fn port_allocation_histogram() {
+        use nat_emulation::predefines::SYMMETRIC_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        use rand::SeedableRng;
+        let rng = rand::rngs::StdRng::seed_from_u64(3);
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let mut nat = Nat::new(SYMMETRIC_NAT, [11111], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        let client_in_addr = nat.assign_internal_address();
+        nat.send_internal_packet(client_in_addr, 25565, 22222, 80, time);
+        assert!(nat.port_allocation_histogram().is_empty());
+
+        nat.track_port_allocations = true;
+        for server_ex_port in 1000..1100 {
+            nat.send_internal_packet(client_in_addr, 25565, 22222, server_ex_port, time);
+            // Reusing a mapping does not allocate a port.
+            nat.send_internal_packet(client_in_addr, 25565, 22222, server_ex_port, time);
+        }
+        let histogram = nat.port_allocation_histogram();
+        assert_eq!(histogram.values().sum::<usize>(), 100);
+        assert!(histogram.keys().all(|port| PRIVATE.contains(port)));
+
+        nat.reset_port_allocation_histogram();
+        assert!(nat.port_allocation_histogram().is_empty());
+    }
+
+    #[test]
+    fn router_listen_ports() {
+        use nat_emulation::predefines::PORT_RESTRICTED_CONE_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, ForwardConflictPolicy, InboundDestType, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let nat_ex_addr = 11111;
+        let server_ex_addr = 22222;
+        let mut nat = Nat::new(PORT_RESTRICTED_CONE_NAT, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        nat.router_listen_ports.insert(443);
+        let client_in_addr = nat.assign_internal_address();
+        let translation = nat.send_internal_packet(client_in_addr, 25565, server_ex_addr, 80, time);
+        let (_, ex_port) = translation.as_external().unwrap();
+
+        let translation = nat.receive_external_packet_dest(server_ex_addr, 80, nat_ex_addr, 443, false, time);
+        assert_eq!(translation, InboundDestType::Router);
+        // The router's own services are not NAT'd clients.
+        assert!(nat.receive_external_packet(server_ex_addr, 80, nat_ex_addr, 443, false, time).is_none());
+        let translation = nat.receive_external_packet_dest(server_ex_addr, 80, nat_ex_addr, 444, false, time);
+        assert_eq!(translation, InboundDestType::Drop);
+        let translation = nat.receive_external_packet_dest(server_ex_addr, 80, nat_ex_addr, ex_port, false, time);
+        assert_eq!(translation.as_internal(), Some((client_in_addr, 25565)));
+
+        // A port forward takes precedence over the router.
+        nat.add_static_mapping(nat_ex_addr, 443, client_in_addr, 443, ForwardConflictPolicy::Reject, time)
+            .unwrap();
+        let translation = nat.receive_external_packet_dest(server_ex_addr, 80, nat_ex_addr, 443, false, time);
+        assert_eq!(translation.as_internal(), Some((client_in_addr, 443)));
+    }
+
+    #[test]
+    fn advance_time() {
+        use nat_emulation::predefines::SYMMETRIC_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let mut nat = Nat::new(SYMMETRIC_NAT, [11111], 90000..=99999, PRIVATE, rng, 2, timeout);
+        let client_in_addr = nat.assign_internal_address();
+        let translation = nat.send_internal_packet(client_in_addr, 25565, 22222, 80, time);
+        let (ex_addr, ex_port) = translation.as_external().unwrap();
+        time += timeout / 2;
+        nat.send_internal_packet(client_in_addr, 25565, 22222, 81, time);
+
+        // Ticking the clock removes the first mapping while the second one is still live.
+        time += timeout / 2 + 1;
+        nat.advance_time(time);
+        nat.reset_peak_mapping_count();
+        assert_eq!(nat.peak_mapping_count(), 1);
+        assert!(!nat.is_inbound_open(ex_addr, ex_port, 22222, 80, time));
+        time += timeout;
+        nat.advance_time(time);
+        nat.reset_peak_mapping_count();
+        assert_eq!(nat.peak_mapping_count(), 0);
+    }
+
+    #[test]
+    fn disjoint_external_dynamic_ports() {
+        use nat_emulation::flags::NO_PORT_PARITY;
+        use nat_emulation::predefines::SYMMETRIC_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat, PortAllocation};
+        use rand::SeedableRng;
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let rng = rand::rngs::StdRng::seed_from_u64(5);
+        let mut nat = Nat::new(SYMMETRIC_NAT, [11111], 90000..=99999, PRIVATE, rng, 100, timeout);
+        nat.set_external_dynamic_ports(vec![60000..=60099, 50000..=50049, 50050..=50099]).unwrap();
+        assert_eq!(nat.external_dynamic_ports(), &[50000..=50099, 60000..=60099]);
+        let client_in_addr = nat.assign_internal_address();
+        let mut ports = Vec::new();
+        for server_ex_port in 0..150 {
+            let translation = nat.send_internal_packet(client_in_addr, 25565, 22222, server_ex_port, time);
+            ports.push(translation.as_external().unwrap().1);
+        }
+        assert!(ports.iter().all(|port| (50000..=50099).contains(port) || (60000..=60099).contains(port)));
+        assert!(ports.iter().any(|port| *port < 60000) && ports.iter().any(|port| *port >= 60000));
+        // Parity is preserved even though the draws span two ranges.
+        assert!(ports.iter().all(|port| port % 2 == 1));
+
+        // Sequential allocation moves from one range to the next.
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut nat = Nat::new(SYMMETRIC_NAT | NO_PORT_PARITY, [11111], 90000..=99999, PRIVATE, rng, 8, timeout);
+        nat.port_allocation = PortAllocation::Sequential;
+        assert!(nat.set_external_dynamic_ports(vec![50000..=50001]).is_err());
+        nat.set_external_dynamic_ports(vec![50000..=50003, 60000..=60003]).unwrap();
+        let client_in_addr = nat.assign_internal_address();
+        let ports: Vec<u16> = (0..6)
+            .map(|server_ex_port| {
+                nat.send_internal_packet(client_in_addr, 25565, 22222, server_ex_port, time)
+                    .as_external()
+                    .unwrap()
+                    .1
+            })
+            .collect();
+        assert_eq!(ports, [50000, 50001, 50002, 50003, 60000, 60001]);
+    }
+
+    #[test]
+    fn always_preserved_ports() {
+        use nat_emulation::predefines::SYMMETRIC_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat, PortPreservation};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let mut nat = Nat::new(SYMMETRIC_NAT, [11111], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        nat.always_preserved_ports.insert(5060);
+        let client_in_addr = nat.assign_internal_address();
+
+        assert_eq!(nat.predict_next_port(client_in_addr, 5060, 22222, 5060, time), Some(5060));
+        let translation = nat.send_internal_packet(client_in_addr, 5060, 22222, 5060, time);
+        assert_eq!(translation.as_external(), Some((11111, 5060)));
+        assert_eq!(nat.last_port_preservation(), Some(PortPreservation::Preserved));
+        // Every other port is still randomized.
+        let translation = nat.send_internal_packet(client_in_addr, 5061, 22222, 5060, time);
+        assert_ne!(translation.as_external().unwrap().1, 5061);
+        assert_eq!(nat.last_port_preservation(), Some(PortPreservation::Disabled));
+    }
+
+    #[test]
+    fn diff_mappings() {
+        use nat_emulation::predefines::SYMMETRIC_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+        let new_nat = || {
+            Nat::new(
+                SYMMETRIC_NAT,
+                [11111],
+                90000..=99999,
+                PRIVATE,
+                rand::rngs::mock::StepRng::new(0, 1),
+                usize::MAX,
+                timeout,
+            )
+        };
+
+        let mut nat_a = new_nat();
+        let mut nat_b = new_nat();
+        let client_in_addr = nat_a.assign_internal_address();
+        assert_eq!(nat_b.assign_internal_address(), client_in_addr);
+        for nat in [&mut nat_a, &mut nat_b] {
+            nat.send_internal_packet(client_in_addr, 5000, 22222, 80, time);
+            nat.send_internal_packet(client_in_addr, 5001, 22222, 80, time);
+        }
+        assert!(nat_a.diff_mappings(&nat_b).is_empty());
+        assert_eq!(nat_a.mappings(), nat_b.mappings());
+
+        // The runs diverge.
+        nat_a.send_internal_packet(client_in_addr, 5000, 22222, 80, time + 1);
+        nat_a.send_internal_packet(client_in_addr, 5002, 22222, 80, time);
+        nat_b.send_internal_packet(client_in_addr, 5003, 22222, 80, time);
+        let diff = nat_a.diff_mappings(&nat_b);
+        assert_eq!(diff.only_in_self.len(), 1);
+        assert_eq!(diff.only_in_self[0].internal_port, 5002);
+        assert_eq!(diff.only_in_other.len(), 1);
+        assert_eq!(diff.only_in_other[0].internal_port, 5003);
+        assert_eq!(diff.changed.len(), 1);
+        let (mine, theirs) = diff.changed[0];
+        assert_eq!((mine.internal_port, mine.last_used_time, theirs.last_used_time), (5000, time + 1, time));
+
+        let reverse = nat_b.diff_mappings(&nat_a);
+        assert_eq!(reverse.only_in_self, diff.only_in_other);
+        assert_eq!(reverse.only_in_other, diff.only_in_self);
+    }
+
+    #[test]
+    fn drop_unassigned_internal_destinations() {
+        use nat_emulation::predefines::EASY_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, DestType, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let mut nat = Nat::new(EASY_NAT, [11111], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        let client_in_addr = nat.assign_internal_address();
+        let peer_in_addr = nat.assign_internal_address();
+        let unassigned_addr = (90000..=99999).find(|addr| *addr != client_in_addr && *addr != peer_in_addr).unwrap();
+
+        assert!(nat.send_internal_packet(client_in_addr, 5000, unassigned_addr, 5000, time).is_internal());
+        nat.drop_unassigned_internal_destinations = true;
+        assert_eq!(
+            nat.send_internal_packet(client_in_addr, 5000, unassigned_addr, 5000, time),
+            DestType::Drop
+        );
+        assert!(nat.send_internal_packet(client_in_addr, 5000, peer_in_addr, 5000, time).is_internal());
+        nat.remove_internal_address(peer_in_addr);
+        assert_eq!(nat.send_internal_packet(client_in_addr, 5000, peer_in_addr, 5000, time), DestType::Drop);
+    }
+
+    #[test]
+    fn recent_removals() {
+        use nat_emulation::predefines::SYMMETRIC_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat, RemovalReason};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let mut nat = Nat::new(SYMMETRIC_NAT, [11111], 90000..=99999, PRIVATE, rng, 2, timeout);
+        let client_in_addr = nat.assign_internal_address();
+        for port in 5000..5003 {
+            nat.send_internal_packet(client_in_addr, port, 22222, 80, time);
+        }
+        // The NAT was full so one of the first two mappings had to go.
+        assert_eq!(nat.recent_removals().len(), 1);
+        let removal = nat.recent_removals()[0];
+        assert_eq!((removal.reason, removal.time), (RemovalReason::Evicted, time));
+        assert!(removal.mapping.internal_port < 5002);
+
+        time += timeout + 1;
+        nat.advance_time(time);
+        assert_eq!(nat.recent_removals().len(), 3);
+        assert!(nat.recent_removals()[1..]
+            .iter()
+            .all(|removal| removal.reason == RemovalReason::Expired && removal.time == time));
+
+        nat.send_internal_packet(client_in_addr, 5000, 22222, 80, time);
+        nat.remove_internal_address(client_in_addr);
+        let removals = nat.drain_recent_removals();
+        assert_eq!(removals.len(), 4);
+        assert_eq!(removals[3].reason, RemovalReason::HostRemoved);
+        assert_eq!(removals[3].mapping.internal_port, 5000);
+        assert!(nat.recent_removals().is_empty());
+
+        // The history only keeps the most recent removals.
+        nat.removal_history_size = 3;
+        let client_in_addr = nat.assign_internal_address();
+        for port in 6000..6010 {
+            nat.send_internal_packet(client_in_addr, port, 22222, 80, time);
+        }
+        let ports: Vec<u16> = nat.recent_removals().iter().map(|removal| removal.mapping.internal_port).collect();
+        assert_eq!(ports.len(), 3);
+        assert!(ports.iter().all(|port| *port >= 6005));
+    }
+
+    #[test]
+    fn adaptive_flags() {
+        use nat_emulation::flags::ADDRESS_AND_PORT_DEPENDENT_MAPPING;
+        use nat_emulation::predefines::EASY_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, AdaptiveFlags, MappingBehavior, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let mut nat = Nat::new(EASY_NAT, [11111], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        nat.adaptive_flags = Some(AdaptiveFlags {
+            normal_flags: EASY_NAT,
+            loaded_flags: EASY_NAT | ADDRESS_AND_PORT_DEPENDENT_MAPPING,
+            high_water: 3,
+            low_water: 1,
+        });
+        let client_in_addr = nat.assign_internal_address();
+
+        let translation = nat.send_internal_packet(client_in_addr, 5000, 22222, 80, time);
+        assert_eq!(translation.as_external(), Some((11111, 5000)));
+        nat.send_internal_packet(client_in_addr, 5002, 22222, 80, time);
+        // The NAT is not loaded yet, so the mapping is reused for a new destination.
+        assert_eq!(nat.send_internal_packet(client_in_addr, 5000, 33333, 80, time), translation);
+        assert_eq!(nat.effective_behaviors().mapping(), MappingBehavior::EndpointIndependent);
+
+        nat.send_internal_packet(client_in_addr, 5004, 22222, 80, time);
+        // The NAT holds at least three mappings so it switched to address and port dependent mapping.
+        assert_ne!(nat.send_internal_packet(client_in_addr, 5000, 44444, 80, time), translation);
+        assert_eq!(nat.effective_behaviors().mapping(), MappingBehavior::AddressAndPortDependent);
+
+        time += timeout + 1;
+        nat.advance_time(time);
+        assert_eq!(nat.effective_behaviors().mapping(), MappingBehavior::EndpointIndependent);
+    }
+
+    #[test]
+    fn is_mapping_reusable() {
+        use nat_emulation::predefines::{PORT_RESTRICTED_CONE_NAT, SYMMETRIC_NAT};
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        for (flags, reusable) in [(PORT_RESTRICTED_CONE_NAT, true), (SYMMETRIC_NAT, false)] {
+            let rng = rand::rngs::mock::StepRng::new(0, 1);
+            let mut nat = Nat::new(flags, [11111], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+            let client_in_addr = nat.assign_internal_address();
+            assert!(!nat.is_mapping_reusable(client_in_addr, 5000, time));
+
+            let translation = nat.send_internal_packet(client_in_addr, 5000, 22222, 3478, time);
+            assert_eq!(nat.is_mapping_reusable(client_in_addr, 5000, time), reusable);
+            assert!(!nat.is_mapping_reusable(client_in_addr, 5000, time + timeout + 1));
+            let next_translation = nat.send_internal_packet(client_in_addr, 5000, 33333, 9993, time);
+            assert_eq!(next_translation == translation, reusable);
+        }
+    }
+
+    #[test]
+    fn inbound_source_port_rewrite() {
+        use nat_emulation::flags::INBOUND_SOURCE_PORT_REWRITE;
+        use nat_emulation::predefines::FULL_CONE_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let mut nat = Nat::new(FULL_CONE_NAT, [11111], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        let client_in_addr = nat.assign_internal_address();
+        let (client_ex_addr, client_ex_port) = nat.send_internal_packet(client_in_addr, 5000, 22222, 80, time).as_external().unwrap();
+
+        let dest = nat.receive_external_packet_dest(22222, 80, client_ex_addr, client_ex_port, false, time);
+        assert_eq!(dest.as_internal_with_source(), Some((client_in_addr, 5000, 22222, 80)));
+
+        nat.flags |= INBOUND_SOURCE_PORT_REWRITE;
+        let dest = nat.receive_external_packet_dest(22222, 80, client_ex_addr, client_ex_port, false, time);
+        let (_, _, src_addr, src_port) = dest.as_internal_with_source().unwrap();
+        assert_eq!(dest.as_internal(), Some((client_in_addr, 5000)));
+        assert_eq!(src_addr, 22222);
+        assert!(PRIVATE.contains(&src_port));
+        // The same source is always rewritten the same way.
+        let dest = nat.receive_external_packet_dest(22222, 80, client_ex_addr, client_ex_port, false, time);
+        assert_eq!(dest.as_internal_with_source(), Some((client_in_addr, 5000, 22222, src_port)));
+    }
+
+    #[test]
+    fn encode_mappings() {
+        use nat_emulation::predefines::SYMMETRIC_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, DecodeError, Nat, MAPPING_FORMAT_VERSION};
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+        let new_nat = || {
+            Nat::new(
+                SYMMETRIC_NAT,
+                [11111],
+                90000..=99999,
+                PRIVATE,
+                rand::rngs::mock::StepRng::new(0, 1),
+                8,
+                timeout,
+            )
+        };
+
+        let mut nat = new_nat();
+        let client_in_addr = nat.assign_internal_address();
+        let (_, client_ex_port) = nat.send_internal_packet(client_in_addr, 5000, 22222, 80, time).as_external().unwrap();
+        nat.send_internal_packet(client_in_addr, 5002, 33333, 443, time);
+        nat.receive_external_packet(22222, 80, 11111, client_ex_port, false, time);
+
+        let data = nat.encode_mappings();
+        assert_eq!(data.len(), 5 + 2 * 27);
+        assert_eq!(data[..5], [MAPPING_FORMAT_VERSION, 0, 0, 0, 2]);
+
+        let mut copy = new_nat();
+        assert_eq!(copy.decode_mappings(&data), Ok(()));
+        assert!(copy.diff_mappings(&nat).is_empty());
+        // The decoded mappings route packets.
+        assert_eq!(
+            copy.receive_external_packet(22222, 80, 11111, client_ex_port, false, time),
+            Some((client_in_addr, 5000))
+        );
+
+        assert_eq!(copy.decode_mappings(&data[..data.len() - 1]), Err(DecodeError::InvalidLength));
+        assert_eq!(copy.decode_mappings(&[2, 0, 0, 0, 0]), Err(DecodeError::UnsupportedVersion(2)));
+        let mut other_addr = data.clone();
+        other_addr[5..9].copy_from_slice(&44444u32.to_be_bytes());
+        assert_eq!(copy.decode_mappings(&other_addr), Err(DecodeError::UnknownExternalAddress(44444)));
+        assert_eq!(copy.mappings(), nat.mappings());
+    }
+
+    #[test]
+    fn least_packets_eviction() {
+        use nat_emulation::predefines::SYMMETRIC_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, EvictionPolicy, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let mut nat = Nat::new(SYMMETRIC_NAT, [11111], 90000..=99999, PRIVATE, rng, 3, timeout);
+        nat.eviction_policy = EvictionPolicy::LeastPackets;
+        let client_in_addr = nat.assign_internal_address();
+        let mut translations = Vec::new();
+        for port in [5000, 5002, 5004] {
+            translations.push(nat.send_internal_packet(client_in_addr, port, 22222, 80, time).as_external().unwrap());
+        }
+        // The flow on 5002 is the quietest, even though it is the most recently used.
+        for _ in 0..3 {
+            time += 1;
+            nat.send_internal_packet(client_in_addr, 5000, 22222, 80, time);
+            nat.receive_external_packet(22222, 80, 11111, translations[2].1, false, time);
+        }
+        time += 1;
+        nat.send_internal_packet(client_in_addr, 5002, 22222, 80, time);
+        nat.send_internal_packet(client_in_addr, 5006, 22222, 80, time);
+
+        assert_eq!(nat.recent_removals().len(), 1);
+        assert_eq!(nat.recent_removals()[0].mapping.internal_port, 5002);
+        assert_eq!(
+            nat.receive_external_packet(22222, 80, 11111, translations[0].1, false, time),
+            Some((client_in_addr, 5000))
+        );
+        assert_eq!(
+            nat.receive_external_packet(22222, 80, 11111, translations[2].1, false, time),
+            Some((client_in_addr, 5004))
+        );
+    }
+
+    #[test]
+    fn reflexive_address() {
+        use nat_emulation::predefines::SYMMETRIC_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let mut nat = Nat::new(SYMMETRIC_NAT, [11111], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        let client_in_addr = nat.assign_internal_address();
+        let candidate = nat.reflexive_address(client_in_addr, 5000, 22222, 3478, time).unwrap();
+        assert_eq!(candidate.0, 11111);
+        // The mapping was created, so the server's response is delivered.
+        assert_eq!(
+            nat.receive_external_packet(22222, 3478, 11111, candidate.1, false, time),
+            Some((client_in_addr, 5000))
+        );
+        assert_eq!(nat.reflexive_address(client_in_addr, 5000, 22222, 3478, time), Some(candidate));
+        // A symmetric NAT presents a different candidate to every server.
+        assert_ne!(nat.reflexive_address(client_in_addr, 5000, 33333, 3478, time), Some(candidate));
+        // Unassigned hosts get no candidate.
+        assert_eq!(nat.reflexive_address(client_in_addr + 1, 5000, 22222, 3478, time), None);
+    }
+
+    #[test]
+    fn sweep_expired_on_receive() {
+        use nat_emulation::predefines::EASY_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let mut nat = Nat::new(EASY_NAT, [11111], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        nat.sweep_expired_on_receive = false;
+        let client_in_addr = nat.assign_internal_address();
+        let (_, client_ex_port) = nat.send_internal_packet(client_in_addr, 5000, 22222, 80, time).as_external().unwrap();
+
+        time += timeout + 1;
+        assert!(!nat.is_inbound_open(11111, client_ex_port, 22222, 80, time));
+        assert_eq!(nat.receive_external_packet(22222, 80, 11111, client_ex_port, false, time), None);
+        // The timed out mapping was skipped but not removed.
+        assert_eq!(nat.mappings().len(), 1);
+        assert!(nat.recent_removals().is_empty());
+
+        nat.sweep_expired_on_receive = true;
+        assert_eq!(nat.receive_external_packet(22222, 80, 11111, client_ex_port, false, time), None);
+        assert!(nat.mappings().is_empty());
+        assert_eq!(nat.recent_removals().len(), 1);
+    }
+
+    #[test]
+    fn free_ports() {
+        use nat_emulation::predefines::EASY_NAT;
+        use nat_emulation::Nat;
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let mut nat = Nat::new(EASY_NAT, [11111], 90000..=99999, 50000..=50009, rng, 5, timeout);
+        let client_in_addr = nat.assign_internal_address();
+        assert_eq!(nat.free_port_count(11111), 10);
+        assert_eq!(nat.next_free_port(11111, 0), Some(50000));
+
+        nat.send_internal_packet(client_in_addr, 50000, 22222, 80, time);
+        nat.send_internal_packet(client_in_addr, 50001, 22222, 80, time);
+        // A preserved port outside of the dynamic range does not take a free port.
+        nat.send_internal_packet(client_in_addr, 5000, 22222, 80, time);
+        nat.excluded_external_ports.push(50003..=50003);
+        assert_eq!(nat.free_port_count(11111), 7);
+        assert_eq!(nat.next_free_port(11111, 0), Some(50002));
+        assert_eq!(nat.next_free_port(11111, 50003), Some(50004));
+        assert_eq!(nat.next_free_port(11111, 50010), None);
+
+        assert_eq!(nat.free_port_count(33333), 0);
+        assert_eq!(nat.next_free_port(33333, 0), None);
+    }
+
+    #[test]
+    fn defer_when_full() {
+        use nat_emulation::predefines::SYMMETRIC_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, DestType, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+
+        let mut nat = Nat::new(SYMMETRIC_NAT, [11111], 90000..=99999, PRIVATE, rng, 2, timeout);
+        nat.defer_when_full = true;
+        let client_in_addr = nat.assign_internal_address();
+        assert!(nat.send_internal_packet(client_in_addr, 5000, 22222, 80, time).is_external());
+        assert!(nat.send_internal_packet(client_in_addr, 5002, 22222, 80, time + 10).is_external());
+
+        let translation = nat.send_internal_packet(client_in_addr, 5004, 22222, 80, time + 20);
+        assert_eq!(translation, DestType::Deferred { retry_after: timeout - 19 });
+        // Existing mappings are still usable, and nothing was evicted.
+        assert!(nat.send_internal_packet(client_in_addr, 5002, 22222, 80, time + 20).is_external());
+        assert_eq!(nat.mappings().len(), 2);
+
+        let retry_time = time + 20 + timeout - 19;
+        assert!(nat.send_internal_packet(client_in_addr, 5004, 22222, 80, retry_time).is_external());
+    }
+
+    #[test]
+    fn multi_tenant() {
+        use nat_emulation::predefines::{EASY_NAT, SYMMETRIC_NAT};
+        use nat_emulation::{MultiTenantNat, SubscriberError};
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+        let rng = || rand::rngs::mock::StepRng::new(0, 1);
+
+        let mut cgnat = MultiTenantNat::new(11111, 50000..=51999, 1000);
+        cgnat.add_subscriber("alice", EASY_NAT, 90000..=99999, rng(), 64, timeout).unwrap();
+        cgnat.add_subscriber("bob", SYMMETRIC_NAT, 90000..=99999, rng(), 64, timeout).unwrap();
+        assert_eq!(cgnat.port_block_of(&"alice"), Some(50000..=50999));
+        assert_eq!(cgnat.port_block_of(&"bob"), Some(51000..=51999));
+        assert_eq!(
+            cgnat.add_subscriber("carol", EASY_NAT, 90000..=99999, rng(), 64, timeout).err(),
+            Some(SubscriberError::NoFreePortBlock)
+        );
+
+        // Both subscribers may use the same internal address and port.
+        let alice_in_addr = cgnat.subscriber_mut(&"alice").unwrap().assign_internal_address();
+        let bob_in_addr = cgnat.subscriber_mut(&"bob").unwrap().assign_internal_address();
+        let (_, alice_ex_port) = cgnat
+            .send_internal_packet(&"alice", alice_in_addr, 5000, 22222, 80, time)
+            .as_external()
+            .unwrap();
+        let (_, bob_ex_port) = cgnat
+            .send_internal_packet(&"bob", bob_in_addr, 5000, 22222, 80, time)
+            .as_external()
+            .unwrap();
+        // Source ports are never preserved outside of a subscriber's port block.
+        assert!((50000..=50999).contains(&alice_ex_port));
+        assert!((51000..=51999).contains(&bob_ex_port));
+
+        assert_eq!(
+            cgnat.receive_external_packet(22222, 80, 11111, alice_ex_port, false, time),
+            Some(("alice", alice_in_addr, 5000))
+        );
+        assert_eq!(
+            cgnat.receive_external_packet(22222, 80, 11111, bob_ex_port, false, time),
+            Some(("bob", bob_in_addr, 5000))
+        );
+        assert_eq!(cgnat.receive_external_packet(22222, 80, 11111, 52000, false, time), None);
+
+        assert!(cgnat.remove_subscriber(&"alice").is_some());
+        assert_eq!(cgnat.receive_external_packet(22222, 80, 11111, alice_ex_port, false, time), None);
+        cgnat.add_subscriber("carol", EASY_NAT, 90000..=99999, rng(), 64, timeout).unwrap();
+        assert_eq!(cgnat.port_block_of(&"carol"), Some(50000..=50999));
+    }
+
+    #[test]
+    fn observe_mapping_behavior() {
+        use crate as nat_emulation;
+        use nat_emulation::flags::{ADDRESS_DEPENDENT_MAPPING, NO_PORT_PRESERVATION, PORT_DEPENDENT_MAPPING};
+        use nat_emulation::{port_ranges::PRIVATE, predefines, BehaviorSet, MappingBehavior, Nat};
+        let endpoints = [(22222, 80), (33333, 80), (22222, 443)];
+        let observe = |flags: u32| {
+            let rng = rand::rngs::mock::StepRng::new(0, 1);
+            let mut nat = Nat::<_, 1>::new(flags, [11111], 90000..=99999, PRIVATE, rng, 64, 1000 * 60 * 2);
+            let in_addr = nat.assign_internal_address();
+            nat.observe_mapping_behavior(in_addr, 5000, &endpoints, 100)
+        };
+        for &(name, flags) in predefines::ALL {
+            assert_eq!(observe(flags), BehaviorSet::from_flags(flags).mapping(), "{}", name);
+        }
+        assert_eq!(
+            observe(ADDRESS_DEPENDENT_MAPPING | NO_PORT_PRESERVATION),
+            MappingBehavior::AddressDependent
+        );
+        assert_eq!(observe(PORT_DEPENDENT_MAPPING | NO_PORT_PRESERVATION), MappingBehavior::PortDependent);
+    }
+
+    #[test]
+    fn single_use_mappings() {
+        use crate as nat_emulation;
+        use nat_emulation::flags::SINGLE_USE_MAPPINGS;
+        use nat_emulation::{port_ranges::PRIVATE, predefines::EASY_NAT, Nat, RemovalReason};
+        let mut time = 100;
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut nat = Nat::<_, 1>::new(EASY_NAT | SINGLE_USE_MAPPINGS, [11111], 90000..=99999, PRIVATE, rng, 64, 1000 * 60 * 2);
+        let in_addr = nat.assign_internal_address();
+
+        let (ex_addr, ex_port) = nat.send_internal_packet(in_addr, 5000, 22222, 53, time).as_external().unwrap();
+        time += 10;
+        assert_eq!(
+            nat.receive_external_packet(22222, 53, ex_addr, ex_port, false, time),
+            Some((in_addr, 5000))
+        );
+        assert_eq!(nat.recent_removals().last().map(|removal| removal.reason), Some(RemovalReason::Consumed));
+        time += 10;
+        assert_eq!(nat.receive_external_packet(22222, 53, ex_addr, ex_port, false, time), None);
+
+        // A new outbound packet opens a new mapping for exactly one more reply.
+        let (ex_addr, ex_port) = nat.send_internal_packet(in_addr, 5000, 22222, 53, time).as_external().unwrap();
+        time += 10;
+        assert_eq!(
+            nat.receive_external_packet(22222, 53, ex_addr, ex_port, false, time),
+            Some((in_addr, 5000))
+        );
+        assert_eq!(nat.receive_external_packet(22222, 53, ex_addr, ex_port, false, time), None);
+    }
+
+    #[test]
+    fn preload_hosts() {
+        use crate as nat_emulation;
+        use nat_emulation::{port_ranges::PRIVATE, predefines::EASY_NAT, Nat, PreloadError};
+        let time = 100;
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut nat = Nat::<_, 2>::new(EASY_NAT, [11111, 11112], 90000..=99999, PRIVATE, rng, 64, 1000 * 60 * 2);
+
+        assert_eq!(
+            nat.preload_hosts(&[(90001, 0), (100000, 1)]),
+            Err(PreloadError::InternalAddressOutOfRange(100000))
+        );
+        assert_eq!(nat.preload_hosts(&[(90001, 0), (90001, 1)]), Err(PreloadError::AlreadyAssigned(90001)));
+        assert_eq!(nat.preload_hosts(&[(90001, 0), (90002, 2)]), Err(PreloadError::InvalidExternalIndex(2)));
+        // Failed preloads add no hosts.
+        assert!(nat.send_internal_packet(90001, 5000, 22222, 80, time).is_drop());
+
+        nat.preload_hosts(&[(90001, 0), (90002, 1), (90003, 1)]).unwrap();
+        assert_eq!(nat.preload_hosts(&[(90002, 0)]), Err(PreloadError::AlreadyAssigned(90002)));
+        assert_eq!(
+            nat.send_internal_packet(90001, 5000, 22222, 80, time).as_external().map(|ex| ex.0),
+            Some(11111)
+        );
+        assert_eq!(
+            nat.send_internal_packet(90002, 5000, 22222, 80, time).as_external().map(|ex| ex.0),
+            Some(11112)
+        );
+        assert_eq!(
+            nat.send_internal_packet(90003, 5000, 22222, 80, time).as_external().map(|ex| ex.0),
+            Some(11112)
+        );
+    }
+
+    #[test]
+    fn address_dependent_mapping_across_ports() {
+        use crate as nat_emulation;
+        use nat_emulation::flags::{ADDRESS_AND_PORT_DEPENDENT_FILTERING, ADDRESS_DEPENDENT_MAPPING, NO_PORT_PRESERVATION};
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        let time = 100;
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let flags = ADDRESS_DEPENDENT_MAPPING | ADDRESS_AND_PORT_DEPENDENT_FILTERING | NO_PORT_PRESERVATION;
+        let mut nat = Nat::<_, 1>::new(flags, [11111], 90000..=99999, PRIVATE, rng, 64, 1000 * 60 * 2);
+        let in_addr = nat.assign_internal_address();
+
+        // Every port of one endpoint address shares a single mapping.
+        let (_, ex_port) = nat.send_internal_packet(in_addr, 5000, 22222, 80, time).as_external().unwrap();
+        assert_eq!(
+            nat.send_internal_packet(in_addr, 5000, 22222, 443, time).as_external(),
+            Some((11111, ex_port))
+        );
+        assert_eq!(
+            nat.mappings().iter().map(|mapping| mapping.external_port).collect::<Vec<_>>(),
+            vec![ex_port, ex_port]
+        );
+        // Filtering is still per endpoint.
+        assert_eq!(
+            nat.receive_external_packet(22222, 443, 11111, ex_port, false, time),
+            Some((in_addr, 5000))
+        );
+        assert_eq!(nat.receive_external_packet(22222, 8080, 11111, ex_port, false, time), None);
+
+        // A different endpoint address gets a different mapping.
+        let (_, other_ex_port) = nat.send_internal_packet(in_addr, 5000, 33333, 80, time).as_external().unwrap();
+        assert_ne!(other_ex_port, ex_port);
+    }
+
+    #[test]
+    fn external_address_timeouts() {
+        use crate as nat_emulation;
+        use nat_emulation::{port_ranges::PRIVATE, predefines::EASY_NAT, Nat};
+        let mut time = 100;
+        let timeout = 1000 * 60 * 2;
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut nat = Nat::<_, 2>::new(EASY_NAT, [11111, 11112], 90000..=99999, PRIVATE, rng, 64, timeout);
+        // The second address is a backup link that ages mappings four times faster.
+        nat.external_address_timeouts[1] = Some(timeout / 4);
+        nat.preload_hosts(&[(90001, 0), (90002, 1)]).unwrap();
+
+        let primary_ex = nat.send_internal_packet(90001, 5000, 22222, 80, time).as_external().unwrap();
+        let backup_ex = nat.send_internal_packet(90002, 5000, 22222, 80, time).as_external().unwrap();
+        assert_eq!((primary_ex.0, backup_ex.0), (11111, 11112));
+
+        time += timeout / 4;
+        assert!(nat.is_inbound_open(backup_ex.0, backup_ex.1, 22222, 80, time));
+        time += 1;
+        assert!(!nat.is_inbound_open(backup_ex.0, backup_ex.1, 22222, 80, time));
+        assert_eq!(nat.receive_external_packet(22222, 80, backup_ex.0, backup_ex.1, false, time), None);
+        assert_eq!(
+            nat.receive_external_packet(22222, 80, primary_ex.0, primary_ex.1, false, time),
+            Some((90001, 5000))
+        );
+
+        time += timeout + 1;
+        assert_eq!(nat.receive_external_packet(22222, 80, primary_ex.0, primary_ex.1, false, time), None);
+    }
+
+    #[test]
+    fn port_parity() {
+        use crate as nat_emulation;
+        use nat_emulation::flags::{NO_PORT_PARITY, NO_PORT_PRESERVATION};
+        use nat_emulation::{predefines::EASY_NAT, Nat};
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+        // The range starts on an odd port and ends on an even port, so masking a drawn port to the
+        // parity of the source port must never step outside of it.
+        let ports = 49999..=50100;
+
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut nat = Nat::<_, 1>::new(EASY_NAT, [11111], 90000..=99999, ports.clone(), rng, 32, timeout);
+        for src_port in [7000, 7001] {
+            // The first host's port is preserved, and every other host collides with it.
+            for i in 0..10 {
+                let in_addr = nat.assign_internal_address();
+                let (_, ex_port) = nat.send_internal_packet(in_addr, src_port, 22222, 80, time).as_external().unwrap();
+                assert_eq!(ex_port & 1, src_port & 1);
+                if i == 0 {
+                    assert_eq!(ex_port, src_port);
+                } else {
+                    assert!(ports.contains(&ex_port));
+                }
+            }
+        }
+
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut nat = Nat::<_, 1>::new(
+            NO_PORT_PRESERVATION | NO_PORT_PARITY,
+            [11111],
+            90000..=99999,
+            ports.clone(),
+            rng,
+            32,
+            timeout,
+        );
+        let in_addr = nat.assign_internal_address();
+        let mut parities = [0; 2];
+        for src_port in (7000..7020).step_by(2) {
+            let (_, ex_port) = nat.send_internal_packet(in_addr, src_port, 22222, 80, time).as_external().unwrap();
+            assert!(ports.contains(&ex_port));
+            parities[ex_port as usize & 1] += 1;
+        }
+        assert!(parities[0] > 0 && parities[1] > 0);
+    }
+
+    #[test]
+    fn parity_within_odd_aligned_ranges() {
+        use crate as nat_emulation;
+        use nat_emulation::{flags::NO_PORT_PRESERVATION, Nat};
+        let time = 100;
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut nat = Nat::<_, 1>::new(NO_PORT_PRESERVATION, [11111], 90000..=99999, 50001..=50004, rng, 1, 1000 * 60 * 2);
+        let in_addr = nat.assign_internal_address();
+        for src_port in (5000..5020).step_by(2) {
+            let (_, ex_port) = nat.send_internal_packet(in_addr, src_port, 22222, 80, time).as_external().unwrap();
+            assert!([50002, 50004].contains(&ex_port));
+        }
+
+        // The odd port cannot be moved to an even port within its range, so it is never used for
+        // even source ports.
+        nat.set_external_dynamic_ports(vec![50001..=50001, 50004..=50007]).unwrap();
+        for src_port in (5000..5020).step_by(2) {
+            let (_, ex_port) = nat.send_internal_packet(in_addr, src_port, 22222, 80, time).as_external().unwrap();
+            assert!([50004, 50006].contains(&ex_port));
+        }
+    }
+
+    #[test]
+    fn full_cone_accepts_unrelated_sources() {
+        use crate as nat_emulation;
+        use nat_emulation::predefines::{FULL_CONE_NAT, RESTRICTED_CONE_NAT};
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        let time = 100;
+        for (flags, accepted) in [(FULL_CONE_NAT, true), (RESTRICTED_CONE_NAT, false)] {
+            let rng = rand::rngs::mock::StepRng::new(0, 1);
+            let mut nat = Nat::<_, 1>::new(flags, [11111], 90000..=99999, PRIVATE, rng, 64, 1000 * 60 * 2);
+            let in_addr = nat.assign_internal_address();
+            let (ex_addr, ex_port) = nat.send_internal_packet(in_addr, 5000, 22222, 80, time).as_external().unwrap();
+            // 33333 was never contacted by the internal host.
+            let translation = nat.receive_external_packet(33333, 4444, ex_addr, ex_port, false, time);
+            assert_eq!(translation, accepted.then_some((in_addr, 5000)));
+        }
+    }
+
+    #[test]
+    fn port_dependent_filtering_endpoints() {
+        use crate as nat_emulation;
+        use nat_emulation::{port_ranges::PRIVATE, predefines::PORT_RESTRICTED_CONE_NAT, EndpointUpdate, Nat};
+        let time = 100;
+        for endpoint_update in [EndpointUpdate::FixedAtCreation, EndpointUpdate::UpdateOnSend] {
+            let rng = rand::rngs::mock::StepRng::new(0, 1);
+            let mut nat = Nat::<_, 1>::new(PORT_RESTRICTED_CONE_NAT, [11111], 90000..=99999, PRIVATE, rng, 64, 1000 * 60 * 2);
+            nat.endpoint_update = endpoint_update;
+            let in_addr = nat.assign_internal_address();
+            let (ex_addr, ex_port) = nat.send_internal_packet(in_addr, 5000, 22222, 80, time).as_external().unwrap();
+            assert_eq!(
+                nat.send_internal_packet(in_addr, 5000, 22222, 443, time).as_external(),
+                Some((ex_addr, ex_port))
+            );
+
+            // Mappings filter on the destination ports of the outbound packets.
+            let mut endpoint_ports: Vec<u16> = nat.mappings().iter().map(|mapping| mapping.endpoint_port).collect();
+            endpoint_ports.sort();
+            let first_admitted = endpoint_update == EndpointUpdate::FixedAtCreation;
+            assert_eq!(
+                endpoint_ports,
+                if first_admitted {
+                    vec![80, 443]
+                } else {
+                    vec![443]
+                }
+            );
+
+            assert_eq!(
+                nat.receive_external_packet(22222, 80, ex_addr, ex_port, false, time).is_some(),
+                first_admitted
+            );
+            assert!(nat.receive_external_packet(22222, 443, ex_addr, ex_port, false, time).is_some());
+            assert!(nat.receive_external_packet(22222, 8080, ex_addr, ex_port, false, time).is_none());
+        }
+    }
+
+    #[test]
+    fn hole_punch() {
+        use crate as nat_emulation;
+        use nat_emulation::flags::ADDRESS_AND_PORT_DEPENDENT_MAPPING;
+        use nat_emulation::predefines::{EASY_NAT, PORT_RESTRICTED_CONE_NAT, RESTRICTED_CONE_NAT, SYMMETRIC_NAT};
+        use nat_emulation::{port_ranges::PRIVATE, HolePunch, HolePunchFailure, HolePunchStep, Nat, Peer};
+        let punch = |flags_a: u32, flags_b: u32| {
+            let rng = rand::rngs::mock::StepRng::new(0, 1);
+            let mut nat_a = Nat::<_, 1>::new(flags_a, [11111], 90000..=99999, PRIVATE, rng, 64, 1000 * 60 * 2);
+            let rng = rand::rngs::mock::StepRng::new(0, 1);
+            let mut nat_b = Nat::<_, 1>::new(flags_b, [33333], 90000..=99999, PRIVATE, rng, 64, 1000 * 60 * 2);
+            HolePunch::new(22222, 3478).run(&mut nat_a, &mut nat_b, 100)
+        };
+
+        let report = punch(EASY_NAT, EASY_NAT);
+        assert!(report.is_connected());
+        assert_eq!(report.steps[0], HolePunchStep::Stun { peer: Peer::A, reflexive: Some((11111, 5000)) });
+        // Endpoint-independent filtering admits the very first check.
+        assert_eq!(report.steps.len(), 4);
+
+        // The first check is filtered since the other peer has not sent to it yet, but that
+        // check opens the way for the reply.
+        let report = punch(RESTRICTED_CONE_NAT, PORT_RESTRICTED_CONE_NAT);
+        assert!(report.is_connected());
+        assert!(matches!(report.steps[2], HolePunchStep::Check { from: Peer::A, delivered: false, .. }));
+        assert!(matches!(report.steps[3], HolePunchStep::Check { from: Peer::B, delivered: true, .. }));
+        assert_eq!(report.steps.len(), 6);
+
+        let report = punch(SYMMETRIC_NAT, PORT_RESTRICTED_CONE_NAT);
+        assert_eq!(
+            report.result,
+            Err(HolePunchFailure::MappingChanged { peer: Peer::A, flags: ADDRESS_AND_PORT_DEPENDENT_MAPPING })
+        );
+        assert_eq!(report.steps.len(), 2 + 2 * 4);
+    }
+
+    #[test]
+    fn nat_loopback() {
+        use crate as nat_emulation;
+        use nat_emulation::{flags::NO_HAIRPINNING, port_ranges::PRIVATE, predefines::EASY_NAT, DestType, ForwardConflictPolicy, Nat};
+        let time = 100;
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut nat = Nat::<_, 1>::new(EASY_NAT, [11111], 90000..=99999, PRIVATE, rng, 64, 1000 * 60 * 2);
+        nat.router_listen_ports.insert(443);
+        let alice_in_addr = nat.assign_internal_address();
+        let bob_in_addr = nat.assign_internal_address();
+        let server_in_addr = nat.assign_internal_address();
+        nat.add_static_mapping(11111, 8080, server_in_addr, 80, ForwardConflictPolicy::Reject, time)
+            .unwrap();
+        let (_, bob_ex_port) = nat.send_internal_packet(bob_in_addr, 6000, 22222, 80, time).as_external().unwrap();
+
+        // A port mapped to another host is hairpinned to it.
+        let translation = nat.send_internal_packet(alice_in_addr, 5000, 11111, bob_ex_port, time);
+        assert_eq!(translation.as_internal().map(|t| (t.2, t.3)), Some((bob_in_addr, 6000)));
+        // A forwarded port is hairpinned to the host it is forwarded to.
+        let translation = nat.send_internal_packet(alice_in_addr, 5000, 11111, 8080, time);
+        assert_eq!(translation.as_internal().map(|t| (t.2, t.3)), Some((server_in_addr, 80)));
+        // A port the router listens on reaches the router.
+        assert_eq!(nat.send_internal_packet(alice_in_addr, 5000, 11111, 443, time), DestType::Router);
+        // An unused port is dropped.
+        assert!(nat.send_internal_packet(alice_in_addr, 5000, 11111, 444, time).is_drop());
+
+        nat.router_loopback = false;
+        assert!(nat.send_internal_packet(alice_in_addr, 5000, 11111, 443, time).is_drop());
+        nat.router_loopback = true;
+        nat.flags |= NO_HAIRPINNING;
+        for port in [bob_ex_port, 8080, 443, 444] {
+            assert!(nat.send_internal_packet(alice_in_addr, 5000, 11111, port, time).is_drop());
+        }
+    }
+
+    #[test]
+    fn scenario() {
+        use crate as nat_emulation;
+        use nat_emulation::predefines::{HARD_NAT, RESTRICTED_CONE_NAT};
+        use nat_emulation::Scenario;
+        let server = (22222, 80);
+        let other_server = (33333, 80);
+
+        let mut scenario = Scenario::new()
+            .nat("cone", RESTRICTED_CONE_NAT)
+            .host("alice", "cone")
+            .at(100)
+            .send("alice", server)
+            .send("alice", other_server)
+            .expect_external_port_unchanged()
+            .after(100)
+            .receive("alice", server)
+            .expect_delivered()
+            .receive("alice", (44444, 80))
+            .expect_dropped();
+        assert_eq!(scenario.nat_mut("cone").mappings().len(), 2);
+
+        // A hard NAT has inbound refresh disabled, so only alice's packets keep its mapping open.
+        Scenario::new()
+            .nat("hard", HARD_NAT)
+            .host("alice", "hard")
+            .send("alice", server)
+            .send("alice", other_server)
+            .expect_external_port_changes()
+            .after(1000 * 60)
+            .receive("alice", other_server)
+            .expect_delivered()
+            .after(1000 * 60 + 1)
+            .receive("alice", other_server)
+            .expect_dropped();
+    }
+    #[test]
+    fn alg() {
+        use nat_emulation::predefines::EASY_NAT;
+        use nat_emulation::{Direction, Nat, PacketTranslation};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+        let nat_addr = 11111;
+        let server_addr = 22222;
+        let mut nat = Nat::new(
+            EASY_NAT,
+            [nat_addr],
+            90000..=99999,
+            nat_emulation::port_ranges::PRIVATE,
+            rng,
+            usize::MAX,
+            timeout,
+        );
+        let (ia, ip) = (nat.assign_internal_address(), 5000);
+
+        fn encode(addr: (u32, u16)) -> Vec<u8> {
+            let mut bytes = addr.0.to_be_bytes().to_vec();
+            bytes.extend_from_slice(&addr.1.to_be_bytes());
+            bytes
+        }
+        // Like an FTP ALG, rewrite the embedded address of whichever side of the translation
+        // is on the far side of the NAT.
+        nat.register_alg(21, |payload: &mut Vec<u8>, translation: &PacketTranslation| {
+            let (from, to) = match translation.direction {
+                Direction::Outbound => (translation.original_src, translation.translated_src),
+                Direction::Inbound => (translation.original_dest, translation.translated_dest),
+            };
+            if *payload == encode(from) {
+                *payload = encode(to);
+            }
+        });
+
+        let mut payload = encode((ia, ip));
+        let dest = nat.send_internal_packet_with_payload(ia, ip, server_addr, 21, &mut payload, time);
+        let external = dest.as_external().unwrap();
+        assert_eq!(payload, encode(external));
+
+        // The reply carries the external address back to the client, and is rewritten to its
+        // internal address.
+        let mut payload = encode(external);
+        let dest = nat.receive_external_packet_with_payload(server_addr, 21, external.0, external.1, false, &mut payload, time);
+        assert_eq!(dest.as_internal(), Some((ia, ip)));
+        assert_eq!(payload, encode((ia, ip)));
+
+        // Packets that are dropped or that do not match the ALG's port are left alone.
+        let mut payload = encode(external);
+        let dest = nat.receive_external_packet_with_payload(server_addr, 21, external.0, external.1 + 1, false, &mut payload, time);
+        assert!(dest.is_drop());
+        assert_eq!(payload, encode(external));
+        let mut payload = encode((ia, ip));
+        nat.send_internal_packet_with_payload(ia, ip, server_addr, 80, &mut payload, time);
+        assert_eq!(payload, encode((ia, ip)));
+
+        assert!(nat.unregister_alg(21));
+        assert!(!nat.unregister_alg(21));
+        let mut payload = encode((ia, ip));
+        nat.send_internal_packet_with_payload(ia, ip, server_addr, 21, &mut payload, time);
+        assert_eq!(payload, encode((ia, ip)));
+    }
+    #[test]
+    fn inbound_mapping_rate_limit() {
+        use nat_emulation::predefines::EASY_NAT;
+        use nat_emulation::{Nat, TokenBucket};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut time = 100;
+        let timeout = 1000 * 60 * 2;
+        let nat_addr = 11111;
+        let server_addr = 22222;
+        let mut nat = Nat::new(
+            EASY_NAT,
+            [nat_addr],
+            90000..=99999,
+            nat_emulation::port_ranges::PRIVATE,
+            rng,
+            usize::MAX,
+            timeout,
+        );
+        nat.inbound_mapping_rate_limit = Some(TokenBucket::new(4, 1000));
+        let (ia, ip) = (nat.assign_internal_address(), 5000);
+        let (ib, ib_port) = (nat.assign_internal_address(), 5000);
+
+        let (_, flooded_port) = nat.send_internal_packet(ia, ip, server_addr, 80, time).as_external().unwrap();
+        let (_, other_port) = nat.send_internal_packet(ib, ib_port, server_addr, 80, time).as_external().unwrap();
+        let delivered = (0..10)
+            .filter(|_| {
+                nat.receive_external_packet(server_addr, 80, nat_addr, flooded_port, false, time)
+                    .is_some()
+            })
+            .count();
+        assert_eq!(delivered, 4);
+        // Other mappings have their own buckets.
+        for _ in 0..4 {
+            assert_eq!(
+                nat.receive_external_packet(server_addr, 80, nat_addr, other_port, false, time),
+                Some((ib, ib_port))
+            );
+        }
+        // Outbound packets are never throttled, and the throttled mapping survives.
+        for _ in 0..10 {
+            assert_eq!(
+                nat.send_internal_packet(ia, ip, server_addr, 80, time).as_external(),
+                Some((nat_addr, flooded_port))
+            );
+        }
+        // The bucket refills over time.
+        time += 500;
+        let delivered = (0..10)
+            .filter(|_| {
+                nat.receive_external_packet(server_addr, 80, nat_addr, flooded_port, false, time)
+                    .is_some()
+            })
+            .count();
+        assert_eq!(delivered, 2);
+
+        // Without the limit the flood is delivered.
+        nat.inbound_mapping_rate_limit = None;
+        for _ in 0..10 {
+            assert_eq!(
+                nat.receive_external_packet(server_addr, 80, nat_addr, flooded_port, false, time),
+                Some((ia, ip))
+            );
+        }
+    }
+    #[test]
+    fn paired_pooling_round_robin() {
+        use nat_emulation::predefines::EASY_NAT;
+        use nat_emulation::{AddressOrder, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+        let nat_addrs = [11111, 11112, 11113];
+        let server_addr = 22222;
+        let mut nat = Nat::new(
+            EASY_NAT,
+            nat_addrs,
+            90000..=99999,
+            nat_emulation::port_ranges::PRIVATE,
+            rng,
+            usize::MAX,
+            timeout,
+        );
+        nat.paired_pooling_order = AddressOrder::RoundRobin;
+
+        for n in 0..7 {
+            let internal_addr = nat.assign_internal_address();
+            let (external_addr, _) = nat
+                .send_internal_packet(internal_addr, 5000, server_addr, 80, time)
+                .as_external()
+                .unwrap();
+            assert_eq!(external_addr, nat_addrs[n % nat_addrs.len()]);
+            // Every destination is reached from the paired address.
+            let (external_addr, _) = nat
+                .send_internal_packet(internal_addr, 5000, server_addr + 1, 80, time)
+                .as_external()
+                .unwrap();
+            assert_eq!(external_addr, nat_addrs[n % nat_addrs.len()]);
+        }
+    }
+    #[test]
+    fn is_external_port_allocated() {
+        use nat_emulation::predefines::EASY_NAT;
+        use nat_emulation::Nat;
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut time = 100;
+        let timeout = 1000 * 60 * 2;
+        let nat_addr = 11111;
+        let server_addr = 22222;
+        let mut nat = Nat::new(
+            EASY_NAT,
+            [nat_addr],
+            90000..=99999,
+            nat_emulation::port_ranges::PRIVATE,
+            rng,
+            usize::MAX,
+            timeout,
+        );
+        let ia = nat.assign_internal_address();
+        let ib = nat.assign_internal_address();
+
+        let (_, port_a) = nat.send_internal_packet(ia, 5000, server_addr, 80, time).as_external().unwrap();
+        let (_, port_b) = nat.send_internal_packet(ib, 5000, server_addr, 80, time).as_external().unwrap();
+        // The flows collided on the preserved source port, so one of them was moved.
+        assert_ne!(port_a, port_b);
+        assert!(nat.is_external_port_allocated(nat_addr, port_a, time));
+        assert!(nat.is_external_port_allocated(nat_addr, port_b, time));
+        let unused = (port_a + 1..).find(|port| *port != port_b).unwrap();
+        assert!(!nat.is_external_port_allocated(nat_addr, unused, time));
+        assert!(!nat.is_external_port_allocated(nat_addr + 1, port_a, time));
+
+        // Expired mappings are free even before they are removed.
+        time += timeout / 2;
+        nat.send_internal_packet(ib, 5000, server_addr, 80, time);
+        time += timeout / 2 + 1;
+        assert!(!nat.is_external_port_allocated(nat_addr, port_a, time));
+        assert!(nat.is_external_port_allocated(nat_addr, port_b, time));
+        assert_eq!(nat.mappings().len(), 2);
+    }
+    #[test]
+    fn reboot_rebuilds_identical_mappings() {
+        use nat_emulation::predefines::{EASY_NAT, HARD_NAT};
+        use nat_emulation::{Nat, PortAllocation};
+        let timeout = 1000 * 60 * 2;
+        let nat_addrs = [11111, 11112];
+        let server_addr = 22222;
+        for (flags, port_allocation) in [
+            (HARD_NAT, PortAllocation::Random),
+            (HARD_NAT, PortAllocation::Sequential),
+            (EASY_NAT, PortAllocation::Random),
+        ] {
+            let rng = rand::rngs::mock::StepRng::new(7, 0x9e37_79b9_7f4a_7c15);
+            let mut nat = Nat::new(
+                flags,
+                nat_addrs,
+                90000..=99999,
+                nat_emulation::port_ranges::PRIVATE,
+                rng,
+                usize::MAX,
+                timeout,
+            );
+            nat.port_allocation = port_allocation;
+            let hosts = nat.assign_n_internal_addresses(4);
+            let seed = nat.rng().clone();
+
+            let replay = |nat: &mut Nat<_, 2>| {
+                let mut external = Vec::new();
+                let mut time = 100;
+                for &host in &hosts {
+                    for dest_port in 80..84 {
+                        time += 10;
+                        external.push(nat.send_internal_packet(host, 5000, server_addr, dest_port, time).as_external().unwrap());
+                    }
+                }
+                external
+            };
+            let before = replay(&mut nat);
+            let mappings = nat.mappings();
+
+            nat.reboot();
+            assert!(nat.mappings().is_empty());
+            *nat.rng_mut() = seed;
+            assert_eq!(replay(&mut nat), before);
+            assert_eq!(nat.mappings(), mappings);
+        }
+    }
+    #[test]
+    fn packet_sink() {
+        use nat_emulation::predefines::EASY_NAT;
+        use nat_emulation::{Nat, Packet, PacketSink};
+        #[derive(Default)]
+        struct Log(Vec<(&'static str, Packet)>);
+        impl PacketSink for Log {
+            fn deliver_external(&mut self, packet: Packet) {
+                self.0.push(("external", packet));
+            }
+            fn deliver_internal(&mut self, packet: Packet) {
+                self.0.push(("internal", packet));
+            }
+            fn drop(&mut self, packet: Packet) {
+                self.0.push(("drop", packet));
+            }
+        }
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+        let nat_addr = 11111;
+        let server_addr = 22222;
+        let mut nat = Nat::new(
+            EASY_NAT,
+            [nat_addr],
+            90000..=99999,
+            nat_emulation::port_ranges::PRIVATE,
+            rng,
+            usize::MAX,
+            timeout,
+        );
+        nat.router_listen_ports.insert(80);
+        let ia = nat.assign_internal_address();
+        let ib = nat.assign_internal_address();
+        let mut sink = Log::default();
+
+        let to_server = Packet {
+            src_addr: ia,
+            src_port: 5000,
+            dest_addr: server_addr,
+            dest_port: 80,
+        };
+        nat.route_internal_packet_into(to_server, time, &mut sink);
+        let (_, translated) = sink.0[0];
+        assert_eq!(sink.0[0], ("external", Packet { src_addr: nat_addr, ..to_server }));
+        // Hairpinned packets are delivered to the internal network.
+        let hairpin = Packet {
+            src_addr: ib,
+            src_port: 6000,
+            dest_addr: nat_addr,
+            dest_port: translated.src_port,
+        };
+        nat.route_internal_packet_into(hairpin, time, &mut sink);
+        let (kind, delivered) = sink.0[1];
+        assert_eq!(kind, "internal");
+        assert_eq!((delivered.dest_addr, delivered.dest_port), (ia, 5000));
+        // Packets for the router are dropped by default.
+        let to_router = Packet {
+            src_addr: server_addr,
+            src_port: 80,
+            dest_addr: nat_addr,
+            dest_port: 80,
+        };
+        nat.route_external_packet_into(to_router, false, time, &mut sink);
+        assert_eq!(sink.0[2], ("drop", to_router));
+    }
+    #[test]
+    fn mapping_filtering_matrix() {
+        use nat_emulation::flags::{ADDRESS_DEPENDENT_FILTERING, ADDRESS_DEPENDENT_MAPPING, PORT_DEPENDENT_FILTERING, PORT_DEPENDENT_MAPPING};
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        let time = 100;
+        let (s1, s2) = (22222, 33333);
+        for mapping in [
+            0,
+            ADDRESS_DEPENDENT_MAPPING,
+            PORT_DEPENDENT_MAPPING,
+            ADDRESS_DEPENDENT_MAPPING | PORT_DEPENDENT_MAPPING,
+        ] {
+            for filtering in [
+                0,
+                ADDRESS_DEPENDENT_FILTERING,
+                PORT_DEPENDENT_FILTERING,
+                ADDRESS_DEPENDENT_FILTERING | PORT_DEPENDENT_FILTERING,
+            ] {
+                let flags = mapping | filtering;
+                let rng = rand::rngs::mock::StepRng::new(0, 1);
+                let mut nat = Nat::<_, 1>::new(flags, [11111], 90000..=99999, PRIVATE, rng, 64, 1000 * 60 * 2);
+                let in_addr = nat.assign_internal_address();
+                let (ex_addr, ex_port) = nat.send_internal_packet(in_addr, 5000, s1, 80, time).as_external().unwrap();
+
+                // Filtering only depends on the endpoints the mapping has sent to.
+                let mut receive = |src_addr, src_port| nat.receive_external_packet(src_addr, src_port, ex_addr, ex_port, false, time).is_some();
+                assert_eq!(receive(s2, 80), filtering & ADDRESS_DEPENDENT_FILTERING == 0, "{:#x}", flags);
+                assert_eq!(receive(s1, 443), filtering & PORT_DEPENDENT_FILTERING == 0, "{:#x}", flags);
+                assert_eq!(receive(s2, 443), filtering == 0, "{:#x}", flags);
+                assert!(receive(s1, 80), "{:#x}", flags);
+
+                // Mapping only depends on the endpoints the host sends to.
+                let new_port = nat.send_internal_packet(in_addr, 5000, s1, 443, time).as_external().unwrap();
+                assert_eq!(new_port != (ex_addr, ex_port), mapping & PORT_DEPENDENT_MAPPING > 0, "{:#x}", flags);
+                let new_addr = nat.send_internal_packet(in_addr, 5000, s2, 80, time).as_external().unwrap();
+                assert_eq!(new_addr != (ex_addr, ex_port), mapping & ADDRESS_DEPENDENT_MAPPING > 0, "{:#x}", flags);
+                let new_both = nat.send_internal_packet(in_addr, 5000, s2, 443, time).as_external().unwrap();
+                assert_eq!(new_both != (ex_addr, ex_port), mapping > 0, "{:#x}", flags);
+                // Every mapping's own endpoint is admitted through it.
+                let mut receive = |src_addr, src_port, (dest_addr, dest_port)| {
+                    nat.receive_external_packet(src_addr, src_port, dest_addr, dest_port, false, time)
+                        .is_some()
+                };
+                assert!(receive(s1, 443, new_port), "{:#x}", flags);
+                assert!(receive(s2, 80, new_addr), "{:#x}", flags);
+                assert!(receive(s2, 443, new_both), "{:#x}", flags);
+            }
+        }
+    }
+    #[test]
+    fn reordered_refresh() {
+        use nat_emulation::predefines::PORT_RESTRICTED_CONE_NAT;
+        use nat_emulation::Scenario;
+        let timeout = 1000 * 60 * 2;
+        let server = (22222, 80);
+        let refreshed = 100 + timeout / 2;
+        let mut scenario = Scenario::new()
+            .nat("nat", PORT_RESTRICTED_CONE_NAT)
+            .host("alice", "nat")
+            .at(100)
+            .send("alice", server)
+            .at(refreshed)
+            .send("alice", server)
+            // A keep-alive sent before the last packet arrives after it.
+            .send_at("alice", server, 200)
+            .expect_external_port_unchanged()
+            .receive_at("alice", server, 150)
+            .expect_delivered();
+        assert_eq!(scenario.nat_mut("nat").mappings()[0].last_used_time, refreshed);
+        // The mapping lives for a full timeout after the latest refresh.
+        scenario
+            .at(refreshed + timeout)
+            .receive("alice", server)
+            .expect_delivered()
+            .after(timeout + 1)
+            .receive("alice", server)
+            .expect_dropped();
+    }
+    #[test]
+    fn dump_table() {
+        use nat_emulation::predefines::PORT_RESTRICTED_CONE_NAT;
+        use nat_emulation::Nat;
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+        let nat_addr = 11111;
+        let server_addr = 22222;
+        let mut nat = Nat::new(
+            PORT_RESTRICTED_CONE_NAT,
+            [nat_addr],
+            90000..=99999,
+            nat_emulation::port_ranges::PRIVATE,
+            rng,
+            usize::MAX,
+            timeout,
+        );
+        let alice = nat.assign_internal_address();
+        nat.label(alice, "alice");
+        nat.label(nat_addr, "nat");
+        nat.label(server_addr, "server");
+        assert_eq!(nat.label_of(alice), Some("alice"));
+        assert_eq!(nat.dump_table(), "");
+
+        let (_, ex_port) = nat.send_internal_packet(alice, 5000, server_addr, 80, time).as_external().unwrap();
+        nat.send_internal_packet(alice, 5000, 33333, 80, time);
+        nat.receive_external_packet(server_addr, 80, nat_addr, ex_port, false, time + 10);
+        assert_eq!(
+            nat.dump_table(),
+            format!(
+                "nat:{0} -> alice:5000 via server:80, last used at 110, replied\n\
+                 nat:{0} -> alice:5000 via 33333:80, last used at 100\n",
+                ex_port
+            )
+        );
+    }
+    #[test]
+    fn icmp_unreachable() {
+        use nat_emulation::predefines::SYMMETRIC_NAT;
+        use nat_emulation::{InboundDestType, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+        let nat_addr = 11111;
+        let server_addr = 22222;
+        let mut nat = Nat::new(
+            SYMMETRIC_NAT,
+            [nat_addr],
+            90000..=99999,
+            nat_emulation::port_ranges::PRIVATE,
+            rng,
+            usize::MAX,
+            timeout,
+        );
+        let in_addr = nat.assign_internal_address();
+        let (_, ex_port) = nat.send_internal_packet(in_addr, 5000, server_addr, 80, time).as_external().unwrap();
+
+        // Filtered packets are silently dropped by default.
+        assert_eq!(
+            nat.receive_external_packet_dest(33333, 80, nat_addr, ex_port, false, time),
+            InboundDestType::Drop
+        );
+        nat.icmp_unreachable = true;
+        assert_eq!(
+            nat.receive_external_packet_dest(33333, 80, nat_addr, ex_port, false, time),
+            InboundDestType::Unreachable
+        );
+        assert_eq!(
+            nat.receive_external_packet_dest(server_addr, 443, nat_addr, ex_port, false, time),
+            InboundDestType::Unreachable
+        );
+        // So are packets to ports without a mapping.
+        assert!(nat
+            .receive_external_packet_dest(server_addr, 80, nat_addr, ex_port + 1, false, time)
+            .is_unreachable());
+        assert_eq!(nat.receive_external_packet(33333, 80, nat_addr, ex_port, false, time), None);
+        // Packets for another address are not the NAT's to reject.
+        assert!(nat
+            .receive_external_packet_dest(server_addr, 80, nat_addr + 1, ex_port, false, time)
+            .is_drop());
+        assert!(nat
+            .receive_external_packet_dest(server_addr, 80, nat_addr, ex_port, false, time)
+            .is_internal());
+    }
+    #[test]
+    fn flags_builder() {
+        use nat_emulation::{
+            validate_flags, BehaviorSet, FilteringBehavior, FlagsBuilder, HairpinningBehavior, MappingBehavior, PortPreservationBehavior,
+        };
+        let mappings = [
+            MappingBehavior::EndpointIndependent,
+            MappingBehavior::AddressDependent,
+            MappingBehavior::PortDependent,
+            MappingBehavior::AddressAndPortDependent,
+        ];
+        let filterings = [
+            FilteringBehavior::EndpointIndependent,
+            FilteringBehavior::AddressDependent,
+            FilteringBehavior::PortDependent,
+            FilteringBehavior::AddressAndPortDependent,
+        ];
+        let preservations = [
+            PortPreservationBehavior::Disabled,
+            PortPreservationBehavior::Enabled,
+            PortPreservationBehavior::Override,
+            PortPreservationBehavior::Overload,
+        ];
+        let hairpinnings = [
+            HairpinningBehavior::Disabled,
+            HairpinningBehavior::ExternalSource,
+            HairpinningBehavior::InternalSource,
+        ];
+        assert_eq!(FlagsBuilder::new().build(), nat_emulation::predefines::EASY_NAT);
+        for mapping in mappings {
+            for filtering in filterings {
+                for port_preservation in preservations {
+                    for hairpinning in hairpinnings {
+                        let flags = FlagsBuilder::new()
+                            .mapping(mapping)
+                            .filtering(filtering)
+                            .port_preservation(port_preservation)
+                            .hairpinning(hairpinning)
+                            .relax_filtering_after_bidirectional(true)
+                            .port_preservation_over_parity(true)
+                            .hairpinning_requires_mapping(true)
+                            .build();
+                        // Every combination is valid and without flags that have no effect.
+                        assert_eq!(validate_flags(flags), Ok(()), "{:#x}", flags);
+                        let behaviors = BehaviorSet::from_flags(flags);
+                        assert_eq!(behaviors.mapping(), mapping);
+                        assert_eq!(behaviors.filtering(), filtering);
+                        assert_eq!(behaviors.port_preservation, port_preservation != PortPreservationBehavior::Disabled);
+                        assert_eq!(
+                            behaviors.port_preservation_over_parity,
+                            port_preservation != PortPreservationBehavior::Disabled
+                        );
+                        assert_eq!(
+                            behaviors.port_preservation_override,
+                            port_preservation == PortPreservationBehavior::Override
+                        );
+                        assert_eq!(
+                            behaviors.port_preservation_overload,
+                            port_preservation == PortPreservationBehavior::Overload
+                        );
+                        assert_eq!(behaviors.hairpinning, hairpinning != HairpinningBehavior::Disabled);
+                        assert_eq!(
+                            behaviors.internal_address_and_port_hairpinning,
+                            hairpinning == HairpinningBehavior::InternalSource
+                        );
+                        assert_eq!(behaviors.hairpinning_requires_mapping, hairpinning != HairpinningBehavior::Disabled);
+                        assert_eq!(
+                            behaviors.relax_filtering_after_bidirectional,
+                            filtering != FilteringBehavior::EndpointIndependent
+                        );
+                    }
+                }
+            }
+        }
+        let flags = FlagsBuilder::new()
+            .arbitrary_pooling(true)
+            .inbound_refresh(false)
+            .port_parity(false)
+            .well_known_preservation(false)
+            .mapping(MappingBehavior::AddressAndPortDependent)
+            .filtering(FilteringBehavior::AddressAndPortDependent)
+            .port_preservation(PortPreservationBehavior::Disabled)
+            .build();
+        assert_eq!(
+            BehaviorSet::from_flags(flags),
+            BehaviorSet::from_flags(nat_emulation::predefines::HARD_NAT)
+        );
+        assert_eq!(FlagsBuilder::new().single_use_mappings(true).single_use_mappings(false).build(), 0);
+    }
+    #[test]
+    fn least_recently_freed_ports() {
+        use nat_emulation::flags::{NO_PORT_PARITY, NO_PORT_PRESERVATION};
+        use nat_emulation::{Nat, PortAllocation};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut time = 100;
+        let timeout = 1000;
+        let server_addr = 22222;
+        let mut nat = Nat::new(
+            NO_PORT_PRESERVATION | NO_PORT_PARITY,
+            [11111],
+            90000..=99999,
+            50000..=50009,
+            rng,
+            8,
+            timeout,
+        );
+        nat.port_allocation = PortAllocation::LeastRecentlyFreed;
+        let in_addr = nat.assign_internal_address();
+        // A long lived mapping holds onto its port throughout.
+        let (_, held_port) = nat.send_internal_packet(in_addr, 4000, server_addr, 80, time).as_external().unwrap();
+        assert_eq!(held_port, 50000);
+
+        // Rapidly open and close short lived mappings.
+        let mut ports = Vec::new();
+        for i in 0..27 {
+            let (_, port) = nat.send_internal_packet(in_addr, 5000 + i, server_addr, 80, time).as_external().unwrap();
+            ports.push(port);
+            // Keep the long lived mapping alive, and let the short lived one time out.
+            time += timeout / 2;
+            nat.send_internal_packet(in_addr, 4000, server_addr, 80, time);
+            time += timeout / 2 + 1;
+            nat.advance_time(time);
+            nat.send_internal_packet(in_addr, 4000, server_addr, 80, time);
+        }
+        // Every other port is used before any freed port is reused.
+        assert_eq!(ports[..9], (50001..=50009).collect::<Vec<_>>());
+        for (i, port) in ports.iter().enumerate().skip(9) {
+            assert_eq!(*port, ports[i - 9]);
+        }
+        assert!(!ports.contains(&held_port));
+        assert_eq!(nat.predict_next_port(in_addr, 7000, server_addr, 80, time), Some(ports[27 - 9]));
+    }
+    #[test]
+    fn address_accounting() {
+        use nat_emulation::predefines::PORT_RESTRICTED_CONE_NAT;
+        use nat_emulation::{Accounting, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+        let nat_addrs = [11111, 11112];
+        let server_addr = 22222;
+        let mut nat = Nat::new(
+            PORT_RESTRICTED_CONE_NAT,
+            nat_addrs,
+            90000..=99999,
+            nat_emulation::port_ranges::PRIVATE,
+            rng,
+            usize::MAX,
+            timeout,
+        );
+        let hosts = nat.assign_n_internal_addresses(8);
+
+        let mut expected = [Accounting::default(); 2];
+        for &host in &hosts {
+            let (ex_addr, ex_port) = nat
+                .send_internal_packet_sized(host, 5000, server_addr, 80, 100, time)
+                .as_external()
+                .unwrap();
+            nat.send_internal_packet(host, 5000, server_addr, 80, time);
+            nat.receive_external_packet_sized(server_addr, 80, ex_addr, ex_port, false, 1500, time);
+            // Dropped packets are not counted.
+            nat.receive_external_packet_sized(server_addr, 81, ex_addr, ex_port, false, 1500, time);
+            let accounting = &mut expected[nat_addrs.iter().position(|addr| *addr == ex_addr).unwrap()];
+            accounting.outbound_packets += 2;
+            accounting.outbound_bytes += 100;
+            accounting.inbound_packets += 1;
+            accounting.inbound_bytes += 1500;
+        }
+        // Neither are hairpinned packets.
+        nat.send_internal_packet_sized(hosts[0], 5000, nat_addrs[0], 5000, 100, time);
+        assert_eq!(nat.address_accounting(nat_addrs[0]), expected[0]);
+        assert_eq!(nat.address_accounting(nat_addrs[1]), expected[1]);
+        assert_eq!(expected[0].outbound_packets + expected[1].outbound_packets, 16);
+        assert_eq!(nat.address_accounting(33333), Accounting::default());
+
+        nat.reset_accounting();
+        assert_eq!(nat.address_accounting(nat_addrs[0]), Accounting::default());
+    }
+
+    #[test]
+    fn collision_resolver() {
+        use nat_emulation::predefines::EASY_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat, PortPreservation};
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+        let nat_ex_addr = 11111;
+        let server_ex_addr = 22222;
+
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut nat = Nat::new(EASY_NAT, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        nat.set_collision_resolver(|desired_port, taken| {
+            assert!(taken.contains(&desired_port));
+            Some(desired_port + 1000)
+        });
+        let hosts = nat.assign_n_internal_addresses(3);
+        let translation = nat.send_internal_packet(hosts[0], 50000, server_ex_addr, 80, time);
+        assert_eq!(translation.as_external(), Some((nat_ex_addr, 50000)));
+        assert_eq!(nat.last_port_preservation(), Some(PortPreservation::Preserved));
+        let translation = nat.send_internal_packet(hosts[1], 50000, server_ex_addr, 80, time);
+        assert_eq!(translation.as_external(), Some((nat_ex_addr, 51000)));
+        assert_eq!(nat.last_port_preservation(), Some(PortPreservation::Resolved));
+        assert_eq!(nat.predict_next_port(hosts[2], 50000, server_ex_addr, 80, time), None);
+        // The resolver chooses the port of the second host, which is taken.
+        let translation = nat.send_internal_packet(hosts[2], 50000, server_ex_addr, 80, time);
+        assert_eq!(translation.as_external(), None);
+        assert!(translation.is_drop());
+
+        nat.set_collision_resolver(|_, _| None);
+        let translation = nat.send_internal_packet(hosts[2], 51000, server_ex_addr, 80, time);
+        assert!(translation.is_drop());
+        nat.clear_collision_resolver();
+        let translation = nat.send_internal_packet(hosts[2], 51000, server_ex_addr, 80, time);
+        assert!(translation.as_external().is_some_and(|(_, port)| port != 51000));
+    }
+
+    #[test]
+    fn fairness_eviction() {
+        use nat_emulation::predefines::SYMMETRIC_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, EvictionPolicy, Nat};
+        let timeout = 1000 * 60 * 2;
+
+        for (age_weight, long_lived_survives) in [(1, true), (0, false)] {
+            let rng = rand::rngs::mock::StepRng::new(0, 1);
+            let mut nat = Nat::new(SYMMETRIC_NAT, [11111], 90000..=99999, PRIVATE, rng, 4, timeout);
+            nat.eviction_policy = EvictionPolicy::Fairness { age_weight, idle_weight: 1 };
+            let client_in_addr = nat.assign_internal_address();
+            // A long-lived flow that goes briefly idle at time 1000.
+            let (_, long_lived_port) = nat.send_internal_packet(client_in_addr, 5000, 22222, 80, 100).as_external().unwrap();
+            for time in (200..=1000).step_by(100) {
+                nat.send_internal_packet(client_in_addr, 5000, 22222, 80, time);
+            }
+            // A burst of short flows, each sent a single packet, forces evictions.
+            for i in 0..10 {
+                nat.send_internal_packet(client_in_addr, 6000 + i, 22222, 80, 1500 + i as i64);
+            }
+            assert_eq!(nat.recent_removals().len(), 7);
+            let delivered = nat.receive_external_packet(22222, 80, 11111, long_lived_port, false, 1510);
+            assert_eq!(delivered == Some((client_in_addr, 5000)), long_lived_survives);
+        }
+    }
+
+    #[test]
+    fn class_dependent_mapping() {
+        use nat_emulation::flags::CLASS_DEPENDENT_MAPPING;
+        use nat_emulation::predefines::EASY_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+        let nat_ex_addr = 11111;
+        let server_ex_addr = 22222;
+        let expedited_forwarding = 46;
+
+        for flags in [EASY_NAT, EASY_NAT | CLASS_DEPENDENT_MAPPING] {
+            let rng = rand::rngs::mock::StepRng::new(0, 1);
+            let mut nat = Nat::new(flags, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+            let client_in_addr = nat.assign_internal_address();
+            let best_effort = nat
+                .send_internal_packet(client_in_addr, 50000, server_ex_addr, 80, time)
+                .as_external()
+                .unwrap();
+            let expedited = nat
+                .send_internal_packet_with_class(client_in_addr, 50000, server_ex_addr, 80, expedited_forwarding, time)
+                .as_external()
+                .unwrap();
+            assert_eq!(best_effort, (nat_ex_addr, 50000));
+            if flags & CLASS_DEPENDENT_MAPPING > 0 {
+                assert_ne!(expedited, best_effort);
+                assert_eq!(nat.mappings().len(), 2);
+            } else {
+                assert_eq!(expedited, best_effort);
+                assert_eq!(nat.mappings().len(), 1);
+            }
+            // Each class keeps reusing its own mapping.
+            let translation = nat.send_internal_packet_with_class(client_in_addr, 50000, server_ex_addr, 443, expedited_forwarding, time);
+            assert_eq!(translation.as_external(), Some(expedited));
+            let translation = nat.send_internal_packet(client_in_addr, 50000, server_ex_addr, 443, time);
+            assert_eq!(translation.as_external(), Some(best_effort));
+            assert_eq!(
+                nat.predict_next_port(client_in_addr, 50000, server_ex_addr, 8080, time),
+                Some(best_effort.1)
+            );
+        }
+    }
+
+    #[test]
+    fn config_round_trip() {
+        use nat_emulation::predefines::SYMMETRIC_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, ConstructError, EvictionPolicy, ForwardConflictPolicy, Nat, PortAllocation, TokenBucket};
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+        let nat_addrs = [11111, 11112];
+        let server_ex_addr = 22222;
+
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut nat = Nat::new(SYMMETRIC_NAT, nat_addrs, 90000..=99999, PRIVATE, rng, 64, timeout);
+        nat.set_external_dynamic_ports(vec![20000..=29999, 40000..=49999]).unwrap();
+        nat.port_allocation = PortAllocation::Sequential;
+        nat.eviction_policy = EvictionPolicy::LeastPackets;
+        nat.rate_limit = Some(TokenBucket::new(4, 1000));
+        nat.external_address_timeouts[1] = Some(1000);
+        nat.add_static_mapping(nat_addrs[0], 8080, 90000, 80, ForwardConflictPolicy::Reject, time)
+            .unwrap();
+        nat.add_inbound_whitelist(server_ex_addr);
+        let config = nat.config();
+
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut copy = Nat::<_, 2>::from_config(config.clone(), rng).unwrap();
+        assert_eq!(copy.config(), config);
+        let hosts = nat.assign_n_internal_addresses(3);
+        assert_eq!(copy.assign_n_internal_addresses(3), hosts);
+        for (i, &host) in hosts.iter().enumerate() {
+            for dest_port in [80, 443] {
+                let translation = nat.send_internal_packet(host, 5000 + i as u16, server_ex_addr, dest_port, time);
+                assert_eq!(
+                    copy.send_internal_packet(host, 5000 + i as u16, server_ex_addr, dest_port, time),
+                    translation
+                );
+            }
+        }
+        assert_eq!(config.static_mappings.get(&(nat_addrs[0], 8080)), Some(&(90000, 80)));
+        // The rate limit was exhausted by the packets above, which is state and not configuration.
+        assert!(nat.send_internal_packet(hosts[0], 6000, server_ex_addr, 80, time).is_drop());
+        assert_eq!(nat.config(), config);
+        assert!(!nat.mappings().is_empty());
+        assert!(Nat::<_, 2>::from_config(nat.config(), rand::rngs::mock::StepRng::new(0, 1))
+            .unwrap()
+            .mappings()
+            .is_empty());
+
+        let mut invalid = config.clone();
+        invalid.external_dynamic_ports = vec![20000..=20001, 40000..=40001];
+        assert!(Nat::<_, 2>::from_config(invalid, rand::rngs::mock::StepRng::new(0, 1)).is_err());
+        let mut invalid = config;
+        invalid.port_block_size = Some(0);
+        let result = Nat::<_, 2>::from_config(invalid, rand::rngs::mock::StepRng::new(0, 1));
+        assert_eq!(result.err(), Some(ConstructError::ZeroPortBlockSize));
+    }
+
+    #[test]
+    fn mostly_forwarded_port_range() {
+        use nat_emulation::flags::NO_PORT_PRESERVATION;
+        use nat_emulation::{ForwardConflictPolicy, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+        let nat_ex_addr = 11111;
+        let server_ex_addr = 22222;
+
+        let mut nat = Nat::new(NO_PORT_PRESERVATION, [nat_ex_addr], 90000..=99999, 1024..=1535, rng, 8, timeout);
+        let hosts = nat.assign_n_internal_addresses(4);
+        for port in 1024..=1533 {
+            nat.add_static_mapping(nat_ex_addr, port, hosts[0], port, ForwardConflictPolicy::Reject, time)
+                .unwrap();
+        }
+        // Nearly every random draw is forwarded, so the NAT finds the two remaining ports.
+        let mut ports: Vec<u16> = hosts[1..3]
+            .iter()
+            .map(|&host| nat.send_internal_packet(host, 5000, server_ex_addr, 80, time).as_external().unwrap().1)
+            .collect();
+        ports.sort_unstable();
+        assert_eq!(ports, [1534, 1535]);
+        // With both in use one of them is taken over.
+        let translation = nat.send_internal_packet(hosts[3], 5000, server_ex_addr, 80, time);
+        assert!(translation.as_external().is_some_and(|(_, port)| port >= 1534));
+
+        // If every port is forwarded the packet is dropped.
+        for port in 1534..=1535 {
+            nat.add_static_mapping(nat_ex_addr, port, hosts[0], port, ForwardConflictPolicy::Evict, time)
+                .unwrap();
+        }
+        assert!(nat.send_internal_packet(hosts[1], 6000, server_ex_addr, 80, time).is_drop());
+    }
+
+    #[test]
+    fn mostly_excluded_port_range() {
+        use nat_emulation::flags::NO_PORT_PRESERVATION;
+        use nat_emulation::Nat;
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+        let nat_ex_addr = 11111;
+        let server_ex_addr = 22222;
+
+        let mut nat = Nat::new(NO_PORT_PRESERVATION, [nat_ex_addr], 90000..=99999, 1024..=1535, rng, 8, timeout);
+        nat.excluded_external_ports.push(1024..=1534);
+        let hosts = nat.assign_n_internal_addresses(2);
+        let translation = nat.send_internal_packet(hosts[0], 5000, server_ex_addr, 80, time);
+        assert_eq!(translation.as_external(), Some((nat_ex_addr, 1535)));
+        // The only allocatable port is in use, so the new mapping takes it over.
+        let translation = nat.send_internal_packet(hosts[1], 5000, server_ex_addr, 80, time);
+        assert_eq!(translation.as_external(), Some((nat_ex_addr, 1535)));
+        assert_eq!(nat.mappings().len(), 1);
+    }
+
+    #[test]
+    fn reboot_keeps_hashed_ports() {
+        use nat_emulation::flags::DETERMINISTIC_PORT_HASH;
+        use nat_emulation::predefines::SYMMETRIC_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat};
+        let timeout = 1000 * 60 * 2;
+        let server_addr = 22222;
+
+        let rng = rand::rngs::mock::StepRng::new(7, 0x9e37_79b9_7f4a_7c15);
+        let mut nat = Nat::new(
+            SYMMETRIC_NAT | DETERMINISTIC_PORT_HASH,
+            [11111],
+            90000..=99999,
+            PRIVATE,
+            rng,
+            usize::MAX,
+            timeout,
+        );
+        let hosts = nat.assign_n_internal_addresses(4);
+        let send_all = |nat: &mut Nat<_, 1>| {
+            let mut ports = Vec::new();
+            for &host in &hosts {
+                for server_port in [80, 443] {
+                    ports.push(nat.send_internal_packet(host, 5000, server_addr, server_port, 100).as_external().unwrap());
+                }
+            }
+            ports
+        };
+        let before = send_all(&mut nat);
+        // The rng is not restored, so only the hash seed can reproduce the ports.
+        nat.reboot();
+        assert!(nat.mappings().is_empty());
+        assert_eq!(send_all(&mut nat), before);
+    }
+
+    #[test]
+    fn is_inbound_open_router_listen_ports() {
+        use nat_emulation::predefines::PORT_RESTRICTED_CONE_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, InboundDestType, Nat};
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+        let nat_ex_addr = 11111;
+        let peer_ex_addr = 22222;
+
+        let mut nat = Nat::new(PORT_RESTRICTED_CONE_NAT, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+        let dmz_in_addr = nat.assign_internal_address();
+        nat.dmz_host = Some(dmz_in_addr);
+        nat.router_listen_ports.insert(443);
+
+        // The NAT keeps packets to its own listen ports instead of forwarding them to the DMZ host.
+        assert!(!nat.is_inbound_open(nat_ex_addr, 443, peer_ex_addr, 5000, time));
+        let dest = nat.receive_external_packet_dest(peer_ex_addr, 5000, nat_ex_addr, 443, false, time);
+        assert_eq!(dest, InboundDestType::Router);
+        // Every other port is still forwarded to the DMZ host.
+        assert!(nat.is_inbound_open(nat_ex_addr, 8080, peer_ex_addr, 5000, time));
+        let dest = nat.receive_external_packet_dest(peer_ex_addr, 5000, nat_ex_addr, 8080, false, time);
+        assert_eq!(dest.as_internal(), Some((dmz_in_addr, 8080)));
+    }
+
+    #[test]
+    fn config_encoding() {
+        use nat_emulation::predefines::HARD_NAT;
+        use nat_emulation::{
+            port_ranges::PRIVATE, AdaptiveFlags, DecodeError, EvictionPolicy, ForwardConflictPolicy, Nat, NatConfig, PortDistribution, TokenBucket,
+            CONFIG_FORMAT_VERSION,
+        };
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+        let nat_addrs = [11111, 11112];
+
+        let rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut nat = Nat::new(HARD_NAT, nat_addrs, 90000..=99999, PRIVATE, rng, 64, timeout);
+        nat.inbound_mapping_rate_limit = Some(TokenBucket::new(2, 500));
+        nat.port_distribution = PortDistribution::Window { offset: 100, width: 2000 };
+        nat.eviction_policy = EvictionPolicy::Fairness { age_weight: 2, idle_weight: -3 };
+        nat.adaptive_flags = Some(AdaptiveFlags {
+            normal_flags: HARD_NAT,
+            loaded_flags: 0,
+            high_water: 48,
+            low_water: 16,
+        });
+        nat.loss_rate = 0.25;
+        nat.external_address_timeouts[0] = Some(1000);
+        nat.router_listen_ports.extend([22, 53, 80]);
+        for port in [8080, 8081, 8082] {
+            nat.add_static_mapping(nat_addrs[1], port, 90001, port, ForwardConflictPolicy::Reject, time)
+                .unwrap();
+        }
+        let config = nat.config();
+        let data = config.encode();
+        assert_eq!(data[0], CONFIG_FORMAT_VERSION);
+        assert_eq!(NatConfig::decode(&data), Ok(config.clone()));
+        // Sets and maps are written in order, so equal configurations encode identically.
+        assert_eq!(NatConfig::decode(&data).unwrap().encode(), data);
+
+        assert_eq!(NatConfig::decode(&data[..data.len() - 1]), Err(DecodeError::InvalidLength));
+        let mut trailing = data.clone();
+        trailing.push(0);
+        assert_eq!(NatConfig::decode(&trailing), Err(DecodeError::InvalidLength));
+        let mut version = data.clone();
+        version[0] = CONFIG_FORMAT_VERSION + 1;
+        assert_eq!(
+            NatConfig::decode(&version),
+            Err(DecodeError::UnsupportedVersion(CONFIG_FORMAT_VERSION + 1))
+        );
+        // The last byte is the tag of the unset `port_hash_seed`.
+        let mut invalid = data;
+        *invalid.last_mut().unwrap() = 2;
+        assert_eq!(NatConfig::decode(&invalid), Err(DecodeError::InvalidValue));
+    }
+
+    #[test]
+    fn config_encoding_keeps_port_hash_seed() {
+        use nat_emulation::flags::DETERMINISTIC_PORT_HASH;
+        use nat_emulation::predefines::SYMMETRIC_NAT;
+        use nat_emulation::{port_ranges::PRIVATE, Nat, NatConfig};
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+        let server_ex_addr = 22222;
+
+        let rng = rand::rngs::mock::StepRng::new(7, 0x9e37_79b9_7f4a_7c15);
+        let mut nat = Nat::new(
+            SYMMETRIC_NAT | DETERMINISTIC_PORT_HASH,
+            [11111],
+            90000..=99999,
+            PRIVATE,
+            rng,
+            usize::MAX,
+            timeout,
+        );
+        let host = nat.assign_internal_address();
+        let translation = nat.send_internal_packet(host, 5000, server_ex_addr, 80, time);
+        let config = nat.config();
+        assert!(config.port_hash_seed.is_some());
+        let decoded = NatConfig::decode(&config.encode()).unwrap();
+        assert_eq!(decoded, config);
+
+        let rng = rand::rngs::mock::StepRng::new(7, 0x9e37_79b9_7f4a_7c15);
+        let mut copy = Nat::<_, 1>::from_config(decoded, rng).unwrap();
+        assert_eq!(copy.assign_internal_address(), host);
+        // The copy's rng is now ahead of the original's, which must not change how ports are hashed.
+        copy.assign_internal_address();
+        assert_eq!(copy.send_internal_packet(host, 5000, server_ex_addr, 80, time), translation);
+        for port in [443, 8080] {
+            assert_eq!(
+                copy.send_internal_packet(host, 5000, server_ex_addr, port, time),
+                nat.send_internal_packet(host, 5000, server_ex_addr, port, time)
+            );
+        }
+    }
+
+    #[test]
+    fn unallocatable_dynamic_ports() {
+        use nat_emulation::flags::{DETERMINISTIC_PORT_HASH, NO_PORT_PRESERVATION};
+        use nat_emulation::{ForwardConflictPolicy, Nat, PortAllocation};
+        let time = 100;
+        let timeout = 1000 * 60 * 2;
+        let nat_ex_addr = 11111;
+        let server_ex_addr = 22222;
+
+        let allocators = [
+            (NO_PORT_PRESERVATION | DETERMINISTIC_PORT_HASH, PortAllocation::Random),
+            (NO_PORT_PRESERVATION, PortAllocation::Sequential),
+            (NO_PORT_PRESERVATION, PortAllocation::LeastRecentlyFreed),
+        ];
+        for (flags, port_allocation) in allocators {
+            let rng = rand::rngs::mock::StepRng::new(0, 1);
+            let mut nat = Nat::new(flags, [nat_ex_addr], 90000..=99999, 1024..=1535, rng, 8, timeout);
+            nat.port_allocation = port_allocation;
+            nat.excluded_external_ports.push(1024..=1534);
+            let hosts = nat.assign_n_internal_addresses(2);
+            nat.add_static_mapping(nat_ex_addr, 1535, hosts[0], 80, ForwardConflictPolicy::Reject, time)
+                .unwrap();
+            // Every dynamic port is excluded or forwarded, so there is nowhere to put a mapping.
+            assert!(nat.send_internal_packet(hosts[1], 5000, server_ex_addr, 80, time).is_drop());
+            assert!(nat.mappings().is_empty());
+            let translation = nat.receive_external_packet(server_ex_addr, 80, nat_ex_addr, 1535, false, time);
+            assert_eq!(translation, Some((hosts[0], 80)));
+
+            // Every port with the parity of the source port is excluded.
+            let rng = rand::rngs::mock::StepRng::new(0, 1);
+            let mut nat = Nat::new(flags, [nat_ex_addr], 90000..=99999, 1024..=1535, rng, 8, timeout);
+            nat.port_allocation = port_allocation;
+            nat.excluded_external_ports.extend((1024..=1534).step_by(2).map(|port| port..=port));
+            let host = nat.assign_internal_address();
+            assert!(nat.send_internal_packet(host, 5000, server_ex_addr, 80, time).is_drop());
+            assert!(nat.mappings().is_empty());
+        }
+    }
 }
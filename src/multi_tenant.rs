@@ -0,0 +1,200 @@
+use crate::flags::NO_WELL_KNOWN_PRESERVATION;
+use crate::nat::{ConstructError, DestType, Nat};
+use rand::RngCore;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::ops::RangeInclusive;
+
+/// The error returned when a subscriber cannot be added to a `MultiTenantNat`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SubscriberError {
+    /// A subscriber with the same key already exists.
+    AlreadyExists,
+    /// Every port block of the shared external address is owned by another subscriber.
+    NoFreePortBlock,
+    /// The subscriber's NAT could not be constructed within its port block.
+    Construct(ConstructError),
+}
+impl std::fmt::Display for SubscriberError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SubscriberError::AlreadyExists => write!(f, "The subscriber already exists"),
+            SubscriberError::NoFreePortBlock => write!(f, "Every port block of the shared external address is taken"),
+            SubscriberError::Construct(error) => write!(f, "{}", error),
+        }
+    }
+}
+impl std::error::Error for SubscriberError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SubscriberError::Construct(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Several logical NATs, one per subscriber, that share a single external address by each owning
+/// a disjoint block of its ports. This emulates how carrier-grade NATs partition a scarce IPv4
+/// address between their subscribers.
+///
+/// Every subscriber has its own `Nat` with its own flags, internal network and mapping table,
+/// which only ever allocates external ports from the subscriber's port block. Inbound packets are
+/// delivered to the subscriber that owns the port block of their destination port. To keep every
+/// mapping within its block, subscriber NATs never preserve a source port outside of their block,
+/// and always have the `NO_WELL_KNOWN_PRESERVATION` behavior.
+///
+/// Packets a subscriber addresses to the shared external address are hairpinned by the
+/// subscriber's own NAT, so packets between subscribers are dropped.
+pub struct MultiTenantNat<K: Eq + Hash + Clone, R: RngCore> {
+    external_addr: u32,
+    external_dynamic_ports: RangeInclusive<u16>,
+    block_size: u16,
+    subscribers: HashMap<K, Nat<R, 1>>,
+    /// The owner of every allocated port block, keyed by the first port of the block.
+    block_owners: BTreeMap<u16, K>,
+}
+impl<K: Eq + Hash + Clone, R: RngCore> MultiTenantNat<K, R> {
+    /// Creates a new multi-tenant NAT without any subscribers.
+    /// * `external_addr`: The external address shared by every subscriber.
+    /// * `external_dynamic_ports`: The ports of the shared address that are divided into blocks.
+    /// * `block_size`: The number of consecutive ports each subscriber is given. Any ports at the
+    ///   end of `external_dynamic_ports` that do not fill a whole block are never used.
+    pub fn new(external_addr: u32, external_dynamic_ports: RangeInclusive<u16>, block_size: u16) -> Self {
+        debug_assert!(block_size > 0, "The block size must be greater than 0");
+        Self {
+            external_addr,
+            external_dynamic_ports,
+            block_size,
+            subscribers: HashMap::new(),
+            block_owners: BTreeMap::new(),
+        }
+    }
+    #[inline]
+    pub fn external_addr(&self) -> u32 {
+        self.external_addr
+    }
+    /// Adds a subscriber with its own NAT, which is given the lowest free port block.
+    /// The arguments are the same as those of `Nat::new`, except for the external address and
+    /// external dynamic ports, which are the shared address and the subscriber's port block.
+    ///
+    /// Return value is the subscriber's NAT, or an error if the subscriber already exists, if
+    /// every port block is taken, or if the NAT's configuration is invalid within a single block.
+    pub fn add_subscriber(
+        &mut self,
+        key: K,
+        flags: u32,
+        internal_addresses: RangeInclusive<u32>,
+        rng: R,
+        mapping_max_size: usize,
+        mapping_timeout: i64,
+    ) -> Result<&mut Nat<R, 1>, SubscriberError> {
+        if self.subscribers.contains_key(&key) {
+            return Err(SubscriberError::AlreadyExists);
+        }
+        let block = self.free_port_block().ok_or(SubscriberError::NoFreePortBlock)?;
+        let mut nat = Nat::try_new(
+            flags | NO_WELL_KNOWN_PRESERVATION,
+            [self.external_addr],
+            internal_addresses,
+            block.clone(),
+            rng,
+            mapping_max_size,
+            mapping_timeout,
+        )
+        .map_err(SubscriberError::Construct)?;
+        // Excluded source ports are never preserved, which keeps every mapping within the block.
+        if *block.start() > 1 {
+            nat.excluded_external_ports.push(1..=*block.start() - 1);
+        }
+        if *block.end() < u16::MAX {
+            nat.excluded_external_ports.push(*block.end() + 1..=u16::MAX);
+        }
+        self.block_owners.insert(*block.start(), key.clone());
+        Ok(self.subscribers.entry(key).or_insert(nat))
+    }
+    /// Finds the lowest port block that no subscriber owns.
+    fn free_port_block(&self) -> Option<RangeInclusive<u16>> {
+        let block_count = self.external_dynamic_ports.len() / self.block_size as usize;
+        (0..block_count)
+            .map(|block_idx| self.external_dynamic_ports.start() + block_idx as u16 * self.block_size)
+            .find(|start| !self.block_owners.contains_key(start))
+            .map(|start| start..=start + (self.block_size - 1))
+    }
+    /// Removes a subscriber, freeing its port block for a future subscriber.
+    /// Return value is the subscriber's NAT, or `None` if there was no such subscriber.
+    pub fn remove_subscriber(&mut self, key: &K) -> Option<Nat<R, 1>> {
+        let nat = self.subscribers.remove(key)?;
+        self.block_owners.retain(|_, owner| owner != key);
+        Some(nat)
+    }
+    #[inline]
+    pub fn subscriber(&self, key: &K) -> Option<&Nat<R, 1>> {
+        self.subscribers.get(key)
+    }
+    #[inline]
+    pub fn subscriber_mut(&mut self, key: &K) -> Option<&mut Nat<R, 1>> {
+        self.subscribers.get_mut(key)
+    }
+    /// Returns the port block owned by the given subscriber.
+    pub fn port_block_of(&self, key: &K) -> Option<RangeInclusive<u16>> {
+        let nat = self.subscribers.get(key)?;
+        nat.external_dynamic_ports().first().cloned()
+    }
+    /// Returns the subscriber that owns the port block containing the given external port.
+    pub fn owner_of(&self, external_port: u16) -> Option<&K> {
+        let (start, owner) = self.block_owners.range(..=external_port).next_back()?;
+        if external_port - start < self.block_size {
+            return Some(owner);
+        }
+        None
+    }
+    /// Sends a packet from a host on the internal network of the given subscriber, see
+    /// `Nat::send_internal_packet`.
+    ///
+    /// Return value is `DestType::Drop` if there is no such subscriber, and otherwise the same as
+    /// `Nat::send_internal_packet`.
+    pub fn send_internal_packet(
+        &mut self,
+        key: &K,
+        internal_src_addr: u32,
+        internal_src_port: u16,
+        external_dest_addr: u32,
+        external_dest_port: u16,
+        current_time: i64,
+    ) -> DestType {
+        match self.subscribers.get_mut(key) {
+            Some(nat) => nat.send_internal_packet(internal_src_addr, internal_src_port, external_dest_addr, external_dest_port, current_time),
+            None => DestType::Drop,
+        }
+    }
+    /// Delivers an inbound packet to the subscriber that owns the port block of its destination
+    /// port, see `Nat::receive_external_packet`.
+    ///
+    /// Return value is `Some((subscriber, internal_dest_addr, internal_dest_port))` if the packet
+    /// was accepted by the subscriber's NAT, and `None` if no subscriber owns the destination port
+    /// or if the subscriber's NAT dropped the packet.
+    pub fn receive_external_packet(
+        &mut self,
+        external_src_addr: u32,
+        external_src_port: u16,
+        external_dest_addr: u32,
+        external_dest_port: u16,
+        disable_filtering: bool,
+        current_time: i64,
+    ) -> Option<(K, u32, u16)> {
+        if external_dest_addr != self.external_addr {
+            return None;
+        }
+        let key = self.owner_of(external_dest_port)?.clone();
+        let nat = self.subscribers.get_mut(&key)?;
+        let (internal_dest_addr, internal_dest_port) = nat.receive_external_packet(
+            external_src_addr,
+            external_src_port,
+            external_dest_addr,
+            external_dest_port,
+            disable_filtering,
+            current_time,
+        )?;
+        Some((key, internal_dest_addr, internal_dest_port))
+    }
+}
@@ -1,11 +1,14 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ops::RangeInclusive;
 
 use rand::RngCore;
 
+use crate::behavior::{BehaviorSet, MappingBehavior};
 use crate::flags::*;
-use crate::nat_flags::port_ranges;
+use crate::nat_flags::{port_ranges, validate_flags, FlagConflict};
+use crate::token_bucket::TokenBucket;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum DestType {
     External {
         external_src_addr: u32,
@@ -18,30 +21,1183 @@ pub enum DestType {
         internal_dest_port: u16,
     },
     Drop,
+    /// The NAT is full and needs a new mapping for the packet, so the sender should retry after
+    /// `retry_after` has elapsed, which is when the NAT's soonest expiring mapping times out.
+    /// See `Nat::defer_when_full`.
+    Deferred {
+        retry_after: i64,
+    },
+    /// The packet was addressed to one of the NAT's own external addresses on one of its
+    /// `router_listen_ports`, and should be delivered to the NAT's own local services.
+    /// See `Nat::router_loopback`.
+    Router,
 }
 impl DestType {
     #[inline]
     pub fn is_external(&self) -> bool {
-        match self {
-            DestType::External { .. } => true,
-            _ => false,
+        matches!(self, DestType::External { .. })
+    }
+    #[inline]
+    pub fn is_internal(&self) -> bool {
+        matches!(self, DestType::Internal { .. })
+    }
+    #[inline]
+    pub fn is_drop(&self) -> bool {
+        matches!(self, DestType::Drop)
+    }
+    #[inline]
+    pub fn is_deferred(&self) -> bool {
+        matches!(self, DestType::Deferred { .. })
+    }
+    #[inline]
+    pub fn is_router(&self) -> bool {
+        matches!(self, DestType::Router)
+    }
+    /// Returns `Some((external_src_addr, external_src_port))` if the packet is to be routed to the
+    /// external network.
+    #[inline]
+    pub fn as_external(&self) -> Option<(u32, u16)> {
+        match *self {
+            DestType::External { external_src_addr, external_src_port } => Some((external_src_addr, external_src_port)),
+            _ => None,
+        }
+    }
+    /// Returns `Some((external_src_addr, external_src_port, internal_dest_addr, internal_dest_port))`
+    /// if the packet is to be routed to the internal network.
+    #[inline]
+    pub fn as_internal(&self) -> Option<(u32, u16, u32, u16)> {
+        match *self {
+            DestType::Internal {
+                external_src_addr,
+                external_src_port,
+                internal_dest_addr,
+                internal_dest_port,
+            } => Some((external_src_addr, external_src_port, internal_dest_addr, internal_dest_port)),
+            _ => None,
         }
     }
+}
+
+/// Where an inbound packet should be routed to, see `Nat::receive_external_packet_dest`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum InboundDestType {
+    /// The packet is to be delivered to the internal network. The source is the address and port
+    /// the internal host will observe the packet coming from, which is only different from the
+    /// packet's actual source if the NAT has the `INBOUND_SOURCE_PORT_REWRITE` behavior.
+    Internal {
+        internal_dest_addr: u32,
+        internal_dest_port: u16,
+        internal_src_addr: u32,
+        internal_src_port: u16,
+    },
+    /// The packet is addressed to one of the NAT's `router_listen_ports`, and should be delivered
+    /// to the NAT's own local services.
+    Router,
+    /// The packet was addressed to the NAT, but no mapping or port forward admitted it, and the
+    /// NAT signals this by sending an ICMP port unreachable message back to the packet's source.
+    /// See `Nat::icmp_unreachable`.
+    Unreachable,
+    Drop,
+}
+impl InboundDestType {
     #[inline]
     pub fn is_internal(&self) -> bool {
+        matches!(self, InboundDestType::Internal { .. })
+    }
+    #[inline]
+    pub fn is_router(&self) -> bool {
+        matches!(self, InboundDestType::Router)
+    }
+    #[inline]
+    pub fn is_unreachable(&self) -> bool {
+        matches!(self, InboundDestType::Unreachable)
+    }
+    #[inline]
+    pub fn is_drop(&self) -> bool {
+        matches!(self, InboundDestType::Drop)
+    }
+    /// Returns `Some((internal_dest_addr, internal_dest_port))` if the packet is to be routed to
+    /// the internal network.
+    #[inline]
+    pub fn as_internal(&self) -> Option<(u32, u16)> {
+        match *self {
+            InboundDestType::Internal { internal_dest_addr, internal_dest_port, .. } => Some((internal_dest_addr, internal_dest_port)),
+            _ => None,
+        }
+    }
+    /// Returns `Some((internal_dest_addr, internal_dest_port, internal_src_addr, internal_src_port))`
+    /// if the packet is to be routed to the internal network.
+    #[inline]
+    pub fn as_internal_with_source(&self) -> Option<(u32, u16, u32, u16)> {
+        match *self {
+            InboundDestType::Internal {
+                internal_dest_addr,
+                internal_dest_port,
+                internal_src_addr,
+                internal_src_port,
+            } => Some((internal_dest_addr, internal_dest_port, internal_src_addr, internal_src_port)),
+            _ => None,
+        }
+    }
+}
+/// The addresses and ports of a packet, see `Nat::route_internal_packet_into`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Packet {
+    pub src_addr: u32,
+    pub src_port: u16,
+    pub dest_addr: u32,
+    pub dest_port: u16,
+}
+/// Receives the packets a NAT routes, for integrating a NAT into an event loop that prefers to be
+/// pushed packets over dispatching on a `DestType`, see `Nat::route_internal_packet_into` and
+/// `Nat::route_external_packet_into`. Every packet is given to exactly one of these methods.
+///
+/// # Example
+/// ```
+/// use nat_emulation::predefines::PORT_RESTRICTED_CONE_NAT;
+/// use nat_emulation::{Nat, Packet, PacketSink};
+/// #[derive(Default)]
+/// struct Collector {
+///     external: Vec<Packet>,
+///     internal: Vec<Packet>,
+///     dropped: Vec<Packet>,
+/// }
+/// impl PacketSink for Collector {
+///     fn deliver_external(&mut self, packet: Packet) {
+///         self.external.push(packet);
+///     }
+///     fn deliver_internal(&mut self, packet: Packet) {
+///         self.internal.push(packet);
+///     }
+///     fn drop(&mut self, packet: Packet) {
+///         self.dropped.push(packet);
+///     }
+/// }
+/// let rng = rand::rngs::mock::StepRng::new(0, 1);
+/// let mut nat = Nat::new(PORT_RESTRICTED_CONE_NAT, [11111], 90000..=99999, 49152..=65535, rng, usize::MAX, 1000 * 60 * 2);
+/// let internal_addr = nat.assign_internal_address();
+/// let mut sink = Collector::default();
+///
+/// let packet = Packet { src_addr: internal_addr, src_port: 5000, dest_addr: 22222, dest_port: 80 };
+/// nat.route_internal_packet_into(packet, 100, &mut sink);
+/// let reply = Packet { src_addr: 22222, src_port: 80, dest_addr: 11111, dest_port: sink.external[0].src_port };
+/// nat.route_external_packet_into(reply, false, 100, &mut sink);
+/// assert_eq!(sink.internal[0].dest_addr, internal_addr);
+/// let unsolicited = Packet { src_addr: 33333, src_port: 80, ..reply };
+/// nat.route_external_packet_into(unsolicited, false, 100, &mut sink);
+/// assert_eq!(sink.dropped, vec![unsolicited]);
+/// ```
+pub trait PacketSink {
+    /// Called with a translated packet that is to be routed to the external network.
+    fn deliver_external(&mut self, packet: Packet);
+    /// Called with a translated packet that is to be routed to the NAT's internal network.
+    fn deliver_internal(&mut self, packet: Packet);
+    /// Called with the original packet when the NAT drops it.
+    fn drop(&mut self, packet: Packet);
+    /// Called with the original packet when it is addressed to the NAT's own local services, see
+    /// `Nat::router_listen_ports`. The packet is dropped by default.
+    fn deliver_router(&mut self, packet: Packet) {
+        self.drop(packet);
+    }
+    /// Called with the original packet when the NAT rejects it with an ICMP port unreachable
+    /// message, see `Nat::icmp_unreachable`. The packet is dropped by default.
+    fn reject(&mut self, packet: Packet) {
+        self.drop(packet);
+    }
+    /// Called with the original packet when the NAT is full and the sender should retry after
+    /// `retry_after`, see `Nat::defer_when_full`. The packet is dropped by default.
+    fn defer(&mut self, packet: Packet, retry_after: i64) {
+        let _ = retry_after;
+        self.drop(packet);
+    }
+}
+/// The outcome of routing an inbound packet, see `Nat::route_external_packet_with`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Routed {
+    Delivered,
+    Router,
+    /// The packet was addressed to the NAT, but no mapping or port forward admitted it.
+    Unadmitted,
+    Dropped,
+}
+
+/// Why an outbound packet could not be given a mapping, see `Nat::map_outbound`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Unmapped {
+    Dropped,
+    Deferred { retry_after: i64 },
+}
+
+/// The order in which a NAT considers its external addresses when it is free to choose any of them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AddressOrder {
+    /// Addresses are considered in a random order, drawn from the NAT's rng every time.
+    Random,
+    /// Addresses are considered in a fixed order, starting with the address after the one that
+    /// was most recently chosen. This does not draw from the NAT's rng.
+    RoundRobin,
+}
+
+/// The number of attempts the NAT makes at drawing an unused random port before it evicts whichever
+/// mapping is using the port it drew.
+const RANDOM_PORT_ATTEMPTS: usize = 32;
+
+/// Returns the smallest number of external dynamic ports a NAT with the given configuration may be
+/// constructed with.
+///
+/// When the NAT cannot preserve a port it makes a limited number of attempts at drawing an unused
+/// random port before evicting an existing mapping. If it has fewer ports than its routing table
+/// can hold on each external address, these evictions become pathological. Port parity halves the
+/// number of ports the NAT can draw from, so it doubles the minimum. Routing tables too large to
+/// ever fill, such as those of `usize::MAX` mappings, only require as many ports as
+/// `port_ranges::PRIVATE` contains.
+pub fn min_external_dynamic_ports(flags: u32, external_address_count: usize, mapping_max_size: usize) -> usize {
+    let per_address = mapping_max_size.div_ceil(external_address_count.max(1));
+    let parity_factor = if flags & NO_PORT_PARITY == 0 {
+        2
+    } else {
+        1
+    };
+    per_address.saturating_mul(parity_factor).min(port_ranges::PRIVATE.len())
+}
+/// The error returned when a NAT is configured with too few external dynamic ports, see
+/// `min_external_dynamic_ports`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PortRangeTooSmall {
+    /// The number of ports in the configured range.
+    pub actual: usize,
+    /// The minimum number of ports this NAT's configuration requires.
+    pub required: usize,
+}
+impl std::fmt::Display for PortRangeTooSmall {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The external_dynamic_ports range contains {} ports but this NAT requires at least {}, consider a larger range such as port_ranges::PRIVATE",
+            self.actual, self.required
+        )
+    }
+}
+impl std::error::Error for PortRangeTooSmall {}
+/// The error returned when a NAT is constructed with an invalid configuration, see `Nat::try_new`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ConstructError {
+    /// The flags contain contradictory behaviors, see `validate_flags`.
+    ContradictoryFlags(FlagConflict),
+    /// The NAT was given no external addresses.
+    NoExternalAddresses,
+    /// The NAT was given more external addresses than its capacity `M`.
+    TooManyExternalAddresses { count: usize, capacity: usize },
+    /// The `internal_addresses` range is empty.
+    EmptyInternalRange,
+    /// The `external_dynamic_ports` range is empty.
+    EmptyPortRange,
+    /// The `external_dynamic_ports` range is too small, see `min_external_dynamic_ports`.
+    PortRangeTooSmall(PortRangeTooSmall),
+    /// The `mapping_max_size` is 0.
+    ZeroMappingMaxSize,
+    /// The `mapping_timeout` is negative.
+    NegativeMappingTimeout,
+    /// The `port_block_size` is 0, see `Nat::from_config`.
+    ZeroPortBlockSize,
+}
+impl std::fmt::Display for ConstructError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConstructError::ContradictoryFlags(conflict) => write!(f, "The flags must not contain contradictory behaviors: {}", conflict),
+            ConstructError::NoExternalAddresses => write!(f, "The NAT must have at least one external address"),
+            ConstructError::TooManyExternalAddresses { count, capacity } => write!(
+                f,
+                "The NAT was given {} external addresses but only has capacity for {}, the external_addresses array must have length less than or equal to M",
+                count, capacity
+            ),
+            ConstructError::EmptyInternalRange => write!(f, "The internal_addresses range must be nonempty"),
+            ConstructError::EmptyPortRange => write!(f, "The external_dynamic_ports range must be nonempty"),
+            ConstructError::PortRangeTooSmall(error) => error.fmt(f),
+            ConstructError::ZeroMappingMaxSize => write!(f, "The mapping max size must be greater than 0"),
+            ConstructError::NegativeMappingTimeout => write!(f, "The mapping timeout must not be negative"),
+            ConstructError::ZeroPortBlockSize => write!(f, "The port block size must be greater than 0"),
+        }
+    }
+}
+impl std::error::Error for ConstructError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConstructError::ContradictoryFlags(conflict) => Some(conflict),
+            ConstructError::PortRangeTooSmall(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+/// Checks every constructor argument of a NAT with the given number of external addresses.
+fn validate_config(
+    flags: u32,
+    external_address_count: usize,
+    internal_addresses: &RangeInclusive<u32>,
+    external_dynamic_ports: &RangeInclusive<u16>,
+    mapping_max_size: usize,
+    mapping_timeout: i64,
+) -> Result<(), ConstructError> {
+    if let Err(conflict) = validate_flags(flags) {
+        if !conflict.is_no_op() {
+            return Err(ConstructError::ContradictoryFlags(conflict));
+        }
+    }
+    if external_address_count == 0 {
+        return Err(ConstructError::NoExternalAddresses);
+    } else if internal_addresses.is_empty() {
+        return Err(ConstructError::EmptyInternalRange);
+    } else if external_dynamic_ports.is_empty() {
+        return Err(ConstructError::EmptyPortRange);
+    } else if mapping_max_size == 0 {
+        return Err(ConstructError::ZeroMappingMaxSize);
+    } else if mapping_timeout < 0 {
+        return Err(ConstructError::NegativeMappingTimeout);
+    }
+    check_external_dynamic_ports(flags, external_dynamic_ports, external_address_count, mapping_max_size).map_err(ConstructError::PortRangeTooSmall)
+}
+/// Checks that the given external dynamic port range is large enough for a NAT with the given
+/// configuration, see `min_external_dynamic_ports`.
+pub fn check_external_dynamic_ports(
+    flags: u32,
+    external_dynamic_ports: &RangeInclusive<u16>,
+    external_address_count: usize,
+    mapping_max_size: usize,
+) -> Result<(), PortRangeTooSmall> {
+    let actual = external_dynamic_ports.len();
+    let required = min_external_dynamic_ports(flags, external_address_count, mapping_max_size);
+    if actual < required {
+        Err(PortRangeTooSmall { actual, required })
+    } else {
+        Ok(())
+    }
+}
+
+/// The well-known port range, as a set of port ranges.
+static WELL_KNOWN_PORTS: [RangeInclusive<u16>; 1] = [port_ranges::WELL_KNOWN];
+/// The total number of ports in a set of disjoint port ranges.
+fn ports_len(ranges: &[RangeInclusive<u16>]) -> u32 {
+    ranges.iter().map(|range| range.len() as u32).sum()
+}
+/// Returns the port at the given offset into a set of disjoint port ranges, as if the ranges were
+/// concatenated in order.
+fn nth_port(ranges: &[RangeInclusive<u16>], offset: u32) -> u16 {
+    let mut offset = offset;
+    for range in ranges {
+        let len = range.len() as u32;
+        if offset < len {
+            return range.start() + offset as u16;
+        }
+        offset -= len;
+    }
+    debug_assert!(false, "The offset must be less than the number of ports");
+    *ranges[0].start()
+}
+/// The inverse of `nth_port`, returns `None` if the port is not in any of the ranges.
+fn port_offset(ranges: &[RangeInclusive<u16>], port: u16) -> Option<u32> {
+    let mut base = 0;
+    for range in ranges {
+        if range.contains(&port) {
+            return Some(base + (port - range.start()) as u32);
+        }
+        base += range.len() as u32;
+    }
+    None
+}
+/// Forces the given port to have the same parity as `src_port`, moving to whichever neighbor of
+/// the port remains within the given ranges. If neither does the port is returned unchanged.
+fn match_parity(ranges: &[RangeInclusive<u16>], port: u16, src_port: u16) -> u16 {
+    let preferred = (port & !1u16) | (src_port & 1u16);
+    let fallback = if preferred < port {
+        port.checked_add(1)
+    } else {
+        port.checked_sub(1)
+    };
+    [Some(preferred), fallback]
+        .into_iter()
+        .flatten()
+        .find(|port| ranges.iter().any(|range| range.contains(port)))
+        .unwrap_or(port)
+}
+
+/// Two sets of flags a NAT switches between depending on how many mappings it holds, see
+/// `Nat::adaptive_flags`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct AdaptiveFlags {
+    /// The flags the NAT uses while it is not under load.
+    pub normal_flags: u32,
+    /// The flags the NAT switches to once it holds at least `high_water` mappings.
+    pub loaded_flags: u32,
+    /// The number of mappings at or above which the NAT switches to `loaded_flags`.
+    pub high_water: usize,
+    /// The number of mappings at or below which the NAT switches back to `normal_flags`.
+    /// This should be less than `high_water` so the NAT does not flap between the two.
+    pub low_water: usize,
+}
+
+/// The distribution a NAT draws random external ports from when it cannot preserve a port.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PortDistribution {
+    /// Ports are drawn uniformly from the entire dynamic port range.
+    Uniform,
+    /// Ports are drawn uniformly from a window of `width` ports, starting `offset` ports into the
+    /// dynamic port range. The window is clamped to fit within the range.
+    Window { offset: u16, width: u16 },
+    /// Ports are drawn by taking the lowest of `draws` uniform draws from the dynamic port range,
+    /// biasing allocations towards the bottom of the range.
+    BiasedLow { draws: u8 },
+}
+
+/// The outcome of a NAT's attempt to preserve the source port of an outbound packet, see
+/// `Nat::last_port_preservation`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PortPreservation {
+    /// An existing mapping was reused, so no port needed to be chosen.
+    Reused,
+    /// The source port was preserved.
+    Preserved,
+    /// The source port was preserved by sharing it with another mapping, see
+    /// `PORT_PRESERVATION_OVERLOAD`.
+    Overloaded,
+    /// The source port was preserved by removing the mappings using it, see
+    /// `PORT_PRESERVATION_OVERRIDE`.
+    Overridden,
+    /// The NAT does not preserve ports, see `NO_PORT_PRESERVATION` and `Nat::always_preserved_ports`.
+    Disabled,
+    /// The source port is outside of the sender's port block, see `Nat::port_block_size`.
+    OutsidePortBlock,
+    /// The source port is one of the NAT's `excluded_external_ports`.
+    Excluded,
+    /// The source port is statically forwarded, see `Nat::add_static_mapping`.
+    Forwarded,
+    /// The source port is in use by another mapping on every external address the NAT could use.
+    Collision,
+    /// The port was chosen by the NAT's port oracle, see `Nat::set_port_oracle`.
+    Oracle,
+    /// The source port collided with another mapping and the port was chosen by the NAT's
+    /// collision resolver, see `Nat::set_collision_resolver`.
+    Resolved,
+}
+
+/// The internal host and external endpoint of an outbound packet that needs a new mapping, as given
+/// to a port oracle, see `Nat::set_port_oracle`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct InternalFlow {
+    pub internal_addr: u32,
+    pub internal_port: u16,
+    pub endpoint_addr: u32,
+    pub endpoint_port: u16,
+}
+type PortOracle = Box<dyn FnMut(InternalFlow) -> u16 + Send>;
+type CollisionResolver = Box<dyn FnMut(u16, &[u16]) -> Option<u16> + Send>;
+/// What a NAT does when its port oracle chooses a port that cannot be allocated, see
+/// `Nat::set_port_oracle`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum OracleConflict {
+    /// The NAT chooses a port as if it had no port oracle.
+    Fallback,
+    /// The NAT drops the packet.
+    Drop,
+}
+
+/// The direction a packet crossed a NAT in, see `Nat::register_alg`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// The packet was sent by a host on the NAT's internal network.
+    Outbound,
+    /// The packet was received from the NAT's external network.
+    Inbound,
+}
+/// The address translation a NAT applied to a packet, as given to an ALG, see `Nat::register_alg`.
+/// Every address is an `(address, port)` pair.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PacketTranslation {
+    pub direction: Direction,
+    /// The source of the packet before it crossed the NAT.
+    pub original_src: (u32, u16),
+    /// The destination of the packet before it crossed the NAT.
+    pub original_dest: (u32, u16),
+    /// The source of the packet after it crossed the NAT.
+    pub translated_src: (u32, u16),
+    /// The destination of the packet after it crossed the NAT.
+    pub translated_dest: (u32, u16),
+}
+type Alg = Box<dyn FnMut(&mut Vec<u8>, &PacketTranslation) + Send>;
+
+/// Counters of the traffic that has crossed between the internal and external networks through
+/// one of a NAT's external addresses, see `Nat::address_accounting`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Accounting {
+    /// The number of packets sent to the external network from this address.
+    pub outbound_packets: u64,
+    /// The total size of the outbound packets given a size, see `Nat::send_internal_packet_sized`.
+    pub outbound_bytes: u64,
+    /// The number of packets to this address that were delivered to the internal network.
+    pub inbound_packets: u64,
+    /// The total size of the inbound packets given a size, see
+    /// `Nat::receive_external_packet_sized`.
+    pub inbound_bytes: u64,
+}
+
+/// Everything needed to recreate a fresh NAT with identical behavior, without any of its mapping
+/// state, see `Nat::config`. This is the recipe of a NAT as opposed to its running state, so it
+/// can be stored in test fixtures or scenario descriptions and shared between simulations, see
+/// `NatConfig::encode`.
+///
+/// Port oracles, collision resolvers, ALGs and labels are not part of a configuration.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NatConfig {
+    /// The NAT's flags, or its `AdaptiveFlags::normal_flags` if it has `adaptive_flags`.
+    pub flags: u32,
+    pub external_addresses: Vec<u32>,
+    pub internal_addresses: RangeInclusive<u32>,
+    /// See `Nat::set_external_dynamic_ports`.
+    pub external_dynamic_ports: Vec<RangeInclusive<u16>>,
+    pub mapping_max_size: usize,
+    pub mapping_timeout: i64,
+    /// The NAT's port forwards, keyed by external address and port, see `Nat::add_static_mapping`.
+    pub static_mappings: HashMap<(u32, u16), (u32, u16)>,
+    /// See `Nat::add_inbound_whitelist`.
+    pub inbound_whitelist: HashSet<u32>,
+    /// Full token buckets with the same limits as the NAT's.
+    pub rate_limit: Option<TokenBucket>,
+    pub inbound_mapping_rate_limit: Option<TokenBucket>,
+    pub arbitrary_pooling_order: AddressOrder,
+    pub paired_pooling_order: AddressOrder,
+    pub unreplied_timeout: Option<i64>,
+    pub filter_timeout: Option<i64>,
+    pub filter_expiry: FilterExpiry,
+    pub forwarded_inbound_creates_mapping: bool,
+    pub port_block_size: Option<u16>,
+    pub port_distribution: PortDistribution,
+    pub port_allocation: PortAllocation,
+    pub loss_rate: f32,
+    pub non_monotonic_time: NonMonotonicTime,
+    pub track_port_allocations: bool,
+    pub endpoint_update: EndpointUpdate,
+    pub max_endpoints_per_flow: Option<usize>,
+    pub excluded_external_ports: Vec<RangeInclusive<u16>>,
+    pub internal_subnets: Vec<RangeInclusive<u32>>,
+    pub dmz_host: Option<u32>,
+    pub router_listen_ports: HashSet<u16>,
+    pub always_preserved_ports: HashSet<u16>,
+    pub drop_unassigned_internal_destinations: bool,
+    pub removal_history_size: usize,
+    pub adaptive_flags: Option<AdaptiveFlags>,
+    pub eviction_policy: EvictionPolicy,
+    pub sweep_expired_on_receive: bool,
+    pub defer_when_full: bool,
+    /// The timeout of each of the `external_addresses`, see `Nat::external_address_timeouts`.
+    pub external_address_timeouts: Vec<Option<i64>>,
+    pub router_loopback: bool,
+    pub icmp_unreachable: bool,
+    /// The seed the NAT hashes ports with, see `DETERMINISTIC_PORT_HASH` and
+    /// `INBOUND_SOURCE_PORT_REWRITE`, or `None` if it has not yet been drawn from the NAT's rng.
+    pub port_hash_seed: Option<u64>,
+}
+/// The version of the binary format written by `NatConfig::encode`.
+pub const CONFIG_FORMAT_VERSION: u8 = 2;
+impl NatConfig {
+    /// Encodes this configuration into a compact binary format, so that it can be stored in a test
+    /// fixture or shared with another simulation and recreated with `NatConfig::decode`.
+    ///
+    /// The format starts with the format version `CONFIG_FORMAT_VERSION` as a single byte. Then
+    /// every field of `NatConfig` follows in the order it is declared in. Every integer is
+    /// big-endian, a `usize` is written as a u64 and an `f32` as its IEEE 754 bits. A `bool` is a
+    /// single byte that is either 0 or 1. An `Option` is a single byte that is 0 for `None`, or 1
+    /// followed by the contained value. A range is its start followed by its end, and a tuple is
+    /// its elements in order. A `Vec`, `HashSet` or `HashMap` is its length as a u32 followed by
+    /// its elements, where the elements of sets and the keys of maps are in ascending order and
+    /// each key is followed by its value. A `TokenBucket` is its `max_packets` followed by its
+    /// `time_window`, and `AdaptiveFlags` are their fields in the order they are declared in.
+    /// Every other enum is the index of its variant in the order they are declared in as a single
+    /// byte, followed by the fields of the variant.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = ConfigWriter(vec![CONFIG_FORMAT_VERSION]);
+        w.u32(self.flags);
+        w.seq(&self.external_addresses, |w, addr| w.u32(*addr));
+        w.u32_range(&self.internal_addresses);
+        w.seq(&self.external_dynamic_ports, ConfigWriter::u16_range);
+        w.u64(self.mapping_max_size as u64);
+        w.i64(self.mapping_timeout);
+        let mut static_mappings: Vec<_> = self.static_mappings.iter().collect();
+        static_mappings.sort_unstable();
+        w.seq(&static_mappings, |w, ((external_addr, external_port), (internal_addr, internal_port))| {
+            w.u32(*external_addr);
+            w.u16(*external_port);
+            w.u32(*internal_addr);
+            w.u16(*internal_port);
+        });
+        let mut inbound_whitelist: Vec<_> = self.inbound_whitelist.iter().collect();
+        inbound_whitelist.sort_unstable();
+        w.seq(&inbound_whitelist, |w, addr| w.u32(**addr));
+        w.option(&self.rate_limit, ConfigWriter::token_bucket);
+        w.option(&self.inbound_mapping_rate_limit, ConfigWriter::token_bucket);
+        w.address_order(self.arbitrary_pooling_order);
+        w.address_order(self.paired_pooling_order);
+        w.option(&self.unreplied_timeout, |w, timeout| w.i64(*timeout));
+        w.option(&self.filter_timeout, |w, timeout| w.i64(*timeout));
+        w.u8(match self.filter_expiry {
+            FilterExpiry::Open => 0,
+            FilterExpiry::Closed => 1,
+        });
+        w.bool(self.forwarded_inbound_creates_mapping);
+        w.option(&self.port_block_size, |w, size| w.u16(*size));
+        match self.port_distribution {
+            PortDistribution::Uniform => w.u8(0),
+            PortDistribution::Window { offset, width } => {
+                w.u8(1);
+                w.u16(offset);
+                w.u16(width);
+            }
+            PortDistribution::BiasedLow { draws } => {
+                w.u8(2);
+                w.u8(draws);
+            }
+        }
+        w.u8(match self.port_allocation {
+            PortAllocation::Random => 0,
+            PortAllocation::Sequential => 1,
+            PortAllocation::LeastRecentlyFreed => 2,
+        });
+        w.u32(self.loss_rate.to_bits());
+        w.u8(match self.non_monotonic_time {
+            NonMonotonicTime::Trust => 0,
+            NonMonotonicTime::Clamp => 1,
+            NonMonotonicTime::Ignore => 2,
+        });
+        w.bool(self.track_port_allocations);
+        w.u8(match self.endpoint_update {
+            EndpointUpdate::FixedAtCreation => 0,
+            EndpointUpdate::UpdateOnSend => 1,
+        });
+        w.option(&self.max_endpoints_per_flow, |w, max| w.u64(*max as u64));
+        w.seq(&self.excluded_external_ports, ConfigWriter::u16_range);
+        w.seq(&self.internal_subnets, ConfigWriter::u32_range);
+        w.option(&self.dmz_host, |w, addr| w.u32(*addr));
+        let mut router_listen_ports: Vec<_> = self.router_listen_ports.iter().collect();
+        router_listen_ports.sort_unstable();
+        w.seq(&router_listen_ports, |w, port| w.u16(**port));
+        let mut always_preserved_ports: Vec<_> = self.always_preserved_ports.iter().collect();
+        always_preserved_ports.sort_unstable();
+        w.seq(&always_preserved_ports, |w, port| w.u16(**port));
+        w.bool(self.drop_unassigned_internal_destinations);
+        w.u64(self.removal_history_size as u64);
+        w.option(&self.adaptive_flags, |w, adaptive| {
+            w.u32(adaptive.normal_flags);
+            w.u32(adaptive.loaded_flags);
+            w.u64(adaptive.high_water as u64);
+            w.u64(adaptive.low_water as u64);
+        });
+        match self.eviction_policy {
+            EvictionPolicy::Random => w.u8(0),
+            EvictionPolicy::LeastPackets => w.u8(1),
+            EvictionPolicy::Fairness { age_weight, idle_weight } => {
+                w.u8(2);
+                w.i64(age_weight);
+                w.i64(idle_weight);
+            }
+        }
+        w.bool(self.sweep_expired_on_receive);
+        w.bool(self.defer_when_full);
+        w.seq(&self.external_address_timeouts, |w, timeout| {
+            w.option(timeout, |w, timeout| w.i64(*timeout))
+        });
+        w.bool(self.router_loopback);
+        w.bool(self.icmp_unreachable);
+        w.option(&self.port_hash_seed, |w, seed| w.u64(*seed));
+        w.0
+    }
+    /// Decodes a configuration in the format written by `NatConfig::encode`.
+    ///
+    /// Return value is an error if `data` is malformed. The configuration itself is not
+    /// validated, that happens when a NAT is created from it, see `Nat::from_config`.
+    pub fn decode(data: &[u8]) -> Result<NatConfig, DecodeError> {
+        let mut r = ConfigReader(data);
+        let version = r.u8()?;
+        if version != CONFIG_FORMAT_VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        let config = NatConfig {
+            flags: r.u32()?,
+            external_addresses: r.seq(ConfigReader::u32)?,
+            internal_addresses: r.u32_range()?,
+            external_dynamic_ports: r.seq(ConfigReader::u16_range)?,
+            mapping_max_size: r.usize()?,
+            mapping_timeout: r.i64()?,
+            static_mappings: r.seq(|r| Ok(((r.u32()?, r.u16()?), (r.u32()?, r.u16()?))))?.into_iter().collect(),
+            inbound_whitelist: r.seq(ConfigReader::u32)?.into_iter().collect(),
+            rate_limit: r.option(ConfigReader::token_bucket)?,
+            inbound_mapping_rate_limit: r.option(ConfigReader::token_bucket)?,
+            arbitrary_pooling_order: r.address_order()?,
+            paired_pooling_order: r.address_order()?,
+            unreplied_timeout: r.option(ConfigReader::i64)?,
+            filter_timeout: r.option(ConfigReader::i64)?,
+            filter_expiry: match r.u8()? {
+                0 => FilterExpiry::Open,
+                1 => FilterExpiry::Closed,
+                _ => return Err(DecodeError::InvalidValue),
+            },
+            forwarded_inbound_creates_mapping: r.bool()?,
+            port_block_size: r.option(ConfigReader::u16)?,
+            port_distribution: match r.u8()? {
+                0 => PortDistribution::Uniform,
+                1 => PortDistribution::Window { offset: r.u16()?, width: r.u16()? },
+                2 => PortDistribution::BiasedLow { draws: r.u8()? },
+                _ => return Err(DecodeError::InvalidValue),
+            },
+            port_allocation: match r.u8()? {
+                0 => PortAllocation::Random,
+                1 => PortAllocation::Sequential,
+                2 => PortAllocation::LeastRecentlyFreed,
+                _ => return Err(DecodeError::InvalidValue),
+            },
+            loss_rate: f32::from_bits(r.u32()?),
+            non_monotonic_time: match r.u8()? {
+                0 => NonMonotonicTime::Trust,
+                1 => NonMonotonicTime::Clamp,
+                2 => NonMonotonicTime::Ignore,
+                _ => return Err(DecodeError::InvalidValue),
+            },
+            track_port_allocations: r.bool()?,
+            endpoint_update: match r.u8()? {
+                0 => EndpointUpdate::FixedAtCreation,
+                1 => EndpointUpdate::UpdateOnSend,
+                _ => return Err(DecodeError::InvalidValue),
+            },
+            max_endpoints_per_flow: r.option(ConfigReader::usize)?,
+            excluded_external_ports: r.seq(ConfigReader::u16_range)?,
+            internal_subnets: r.seq(ConfigReader::u32_range)?,
+            dmz_host: r.option(ConfigReader::u32)?,
+            router_listen_ports: r.seq(ConfigReader::u16)?.into_iter().collect(),
+            always_preserved_ports: r.seq(ConfigReader::u16)?.into_iter().collect(),
+            drop_unassigned_internal_destinations: r.bool()?,
+            removal_history_size: r.usize()?,
+            adaptive_flags: r.option(|r| {
+                Ok(AdaptiveFlags {
+                    normal_flags: r.u32()?,
+                    loaded_flags: r.u32()?,
+                    high_water: r.usize()?,
+                    low_water: r.usize()?,
+                })
+            })?,
+            eviction_policy: match r.u8()? {
+                0 => EvictionPolicy::Random,
+                1 => EvictionPolicy::LeastPackets,
+                2 => EvictionPolicy::Fairness { age_weight: r.i64()?, idle_weight: r.i64()? },
+                _ => return Err(DecodeError::InvalidValue),
+            },
+            sweep_expired_on_receive: r.bool()?,
+            defer_when_full: r.bool()?,
+            external_address_timeouts: r.seq(|r| r.option(ConfigReader::i64))?,
+            router_loopback: r.bool()?,
+            icmp_unreachable: r.bool()?,
+            port_hash_seed: r.option(ConfigReader::u64)?,
+        };
+        if !r.0.is_empty() {
+            return Err(DecodeError::InvalidLength);
+        }
+        Ok(config)
+    }
+}
+/// Writes the primitives of the binary format of `NatConfig::encode`.
+struct ConfigWriter(Vec<u8>);
+impl ConfigWriter {
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+    fn u16(&mut self, value: u16) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+    fn u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+    fn i64(&mut self, value: i64) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+    fn bool(&mut self, value: bool) {
+        self.0.push(value as u8);
+    }
+    fn u16_range(&mut self, range: &RangeInclusive<u16>) {
+        self.u16(*range.start());
+        self.u16(*range.end());
+    }
+    fn u32_range(&mut self, range: &RangeInclusive<u32>) {
+        self.u32(*range.start());
+        self.u32(*range.end());
+    }
+    fn token_bucket(&mut self, bucket: &TokenBucket) {
+        self.u32(bucket.max_packets());
+        self.i64(bucket.time_window());
+    }
+    fn address_order(&mut self, order: AddressOrder) {
+        self.u8(match order {
+            AddressOrder::Random => 0,
+            AddressOrder::RoundRobin => 1,
+        });
+    }
+    fn option<T>(&mut self, value: &Option<T>, write: impl FnOnce(&mut Self, &T)) {
+        match value {
+            Some(value) => {
+                self.u8(1);
+                write(self, value);
+            }
+            None => self.u8(0),
+        }
+    }
+    fn seq<T>(&mut self, items: &[T], mut write: impl FnMut(&mut Self, &T)) {
+        self.u32(items.len() as u32);
+        for item in items {
+            write(self, item);
+        }
+    }
+}
+/// Reads the primitives of the binary format of `NatConfig::encode`, consuming them from the
+/// front of the remaining data.
+struct ConfigReader<'a>(&'a [u8]);
+impl<'a> ConfigReader<'a> {
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        let bytes = self.0.get(..N).ok_or(DecodeError::InvalidLength)?;
+        self.0 = &self.0[N..];
+        Ok(bytes.try_into().unwrap())
+    }
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.bytes::<1>()?[0])
+    }
+    fn u16(&mut self) -> Result<u16, DecodeError> {
+        Ok(u16::from_be_bytes(self.bytes()?))
+    }
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(u32::from_be_bytes(self.bytes()?))
+    }
+    fn u64(&mut self) -> Result<u64, DecodeError> {
+        Ok(u64::from_be_bytes(self.bytes()?))
+    }
+    fn i64(&mut self) -> Result<i64, DecodeError> {
+        Ok(i64::from_be_bytes(self.bytes()?))
+    }
+    fn usize(&mut self) -> Result<usize, DecodeError> {
+        usize::try_from(self.u64()?).map_err(|_| DecodeError::InvalidValue)
+    }
+    fn bool(&mut self) -> Result<bool, DecodeError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(DecodeError::InvalidValue),
+        }
+    }
+    fn u16_range(&mut self) -> Result<RangeInclusive<u16>, DecodeError> {
+        Ok(self.u16()?..=self.u16()?)
+    }
+    fn u32_range(&mut self) -> Result<RangeInclusive<u32>, DecodeError> {
+        Ok(self.u32()?..=self.u32()?)
+    }
+    fn token_bucket(&mut self) -> Result<TokenBucket, DecodeError> {
+        let max_packets = self.u32()?;
+        let time_window = self.i64()?;
+        if time_window <= 0 {
+            return Err(DecodeError::InvalidValue);
+        }
+        Ok(TokenBucket::new(max_packets, time_window))
+    }
+    fn address_order(&mut self) -> Result<AddressOrder, DecodeError> {
+        match self.u8()? {
+            0 => Ok(AddressOrder::Random),
+            1 => Ok(AddressOrder::RoundRobin),
+            _ => Err(DecodeError::InvalidValue),
+        }
+    }
+    fn option<T>(&mut self, read: impl FnOnce(&mut Self) -> Result<T, DecodeError>) -> Result<Option<T>, DecodeError> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(read(self)?)),
+            _ => Err(DecodeError::InvalidValue),
+        }
+    }
+    fn seq<T>(&mut self, mut read: impl FnMut(&mut Self) -> Result<T, DecodeError>) -> Result<Vec<T>, DecodeError> {
+        let len = self.u32()?;
+        // The length is not trusted to preallocate, malformed data runs out of bytes instead.
+        let mut items = Vec::new();
+        for _ in 0..len {
+            items.push(read(self)?);
+        }
+        Ok(items)
+    }
+}
+
+/// How a NAT chooses the external port of a new mapping when it cannot preserve a port.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PortAllocation {
+    /// Ports are drawn at random according to the NAT's `port_distribution`.
+    Random,
+    /// Every internal host has a counter that starts at the beginning of the dynamic port range.
+    /// Each new mapping takes the first unused port at or after the counter, and the counter then
+    /// moves past it, wrapping around at the end of the range. This emulates the many NATs whose
+    /// ports increase with every new mapping, which makes them easy to predict.
+    Sequential,
+    /// Each new mapping takes the unused port that was freed the longest time ago, where ports that
+    /// have never been used count as freed before any other, and ties are broken by taking the
+    /// lowest port. This keeps recently freed ports in quarantine for as long as possible, so late
+    /// packets of an old flow are unlikely to be delivered to a new one. This emulates the port
+    /// selection heuristics of Linux conntrack.
+    LeastRecentlyFreed,
+}
+
+/// Whether the endpoint a mapping has sent to is updated by later outbound packets, see
+/// `Nat::endpoint_update`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EndpointUpdate {
+    /// Every distinct destination a mapping sends to is recorded for the lifetime of the mapping,
+    /// so filtering admits packets from any of them.
+    FixedAtCreation,
+    /// Only the most recent destination a mapping has sent to is recorded, so filtering only
+    /// admits packets from the last destination.
+    UpdateOnSend,
+}
+
+/// What a mapping's filtering becomes once its filter window has lapsed, see `Nat::filter_timeout`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FilterExpiry {
+    /// The mapping reverts to endpoint-independent filtering, admitting packets from any source
+    /// until an outbound packet through it restarts the filter window.
+    Open,
+    /// The mapping stops admitting inbound packets from any source until an outbound packet
+    /// through it restarts the filter window, even though it still exists for outbound packets.
+    Closed,
+}
+
+/// How a NAT handles a `current_time` that is earlier than the latest one it has been given, see
+/// `Nat::non_monotonic_time`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum NonMonotonicTime {
+    /// Timestamps are used as given, so a packet with an earlier timestamp sees the mappings as
+    /// they were at that time. A mapping's last use time never moves backward though, so a packet
+    /// that arrives out of order cannot shorten the lifetime of the mapping it refreshes.
+    Trust,
+    /// A timestamp earlier than the latest one is replaced by the latest one, so the NAT's clock
+    /// never moves backward and mappings are neither expired early nor kept alive for longer.
+    Clamp,
+    /// Packets with a timestamp earlier than the latest one are dropped without modifying the NAT.
+    Ignore,
+}
+
+/// How `Nat::add_static_mapping` resolves a port forward on an external port that is already in use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ForwardConflictPolicy {
+    /// Evict every mapping currently using the port, and replace any existing port forward.
+    Evict,
+    /// Refuse to add the port forward and report the conflict.
+    Reject,
+    /// Add the port forward alongside the mappings currently using the port. Those mappings take
+    /// precedence until they time out, and the port forward only receives the inbound packets they
+    /// would not accept. Any existing port forward is replaced.
+    Shadow,
+}
+/// The error returned when a port forward conflicts with the current state of the NAT, see
+/// `ForwardConflictPolicy::Reject`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ForwardConflict {
+    /// The external port is already forwarded to the given internal address and port.
+    AlreadyForwarded { internal_addr: u32, internal_port: u16 },
+    /// The external port is in use by the given number of live mappings.
+    InUse { mappings: usize },
+}
+impl std::fmt::Display for ForwardConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            DestType::Internal { .. } => true,
-            _ => false,
+            ForwardConflict::AlreadyForwarded { internal_addr, internal_port } => {
+                write!(f, "The external port is already forwarded to {}:{}", internal_addr, internal_port)
+            }
+            ForwardConflict::InUse { mappings } => write!(f, "The external port is in use by {} mappings", mappings),
         }
     }
+}
+impl std::error::Error for ForwardConflict {}
+
+/// A snapshot of a single entry in a NAT's mapping table, see `Nat::mappings`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Mapping {
+    pub external_addr: u32,
+    pub external_port: u16,
+    pub internal_addr: u32,
+    pub internal_port: u16,
+    /// The external endpoint this mapping has sent to, which its filtering is based on.
+    pub endpoint_addr: u32,
+    pub endpoint_port: u16,
+    /// The last time this mapping was refreshed.
+    pub last_used_time: i64,
+    /// Whether an inbound packet has been delivered through this mapping.
+    pub replied: bool,
+}
+impl Mapping {
+    /// Everything that identifies this mapping, as opposed to its state.
     #[inline]
-    pub fn is_drop(&self) -> bool {
+    fn key(&self) -> (u32, u16, u32, u16, u32, u16) {
+        (
+            self.external_addr,
+            self.external_port,
+            self.internal_addr,
+            self.internal_port,
+            self.endpoint_addr,
+            self.endpoint_port,
+        )
+    }
+}
+/// The difference between the mapping tables of two NATs, see `Nat::diff_mappings`.
+/// Every list is sorted by external address and port, then by internal address and port, then by
+/// endpoint, so diffs are deterministic.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct MappingDiff {
+    /// Mappings that only exist in the NAT `diff_mappings` was called on.
+    pub only_in_self: Vec<Mapping>,
+    /// Mappings that only exist in the NAT that was passed to `diff_mappings`.
+    pub only_in_other: Vec<Mapping>,
+    /// Mappings that exist in both NATs but have a different `last_used_time` or `replied` state,
+    /// as the pair `(self, other)`.
+    pub changed: Vec<(Mapping, Mapping)>,
+}
+impl MappingDiff {
+    /// Whether both mapping tables were identical.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.only_in_self.is_empty() && self.only_in_other.is_empty() && self.changed.is_empty()
+    }
+}
+/// The version of the binary format written by `Nat::encode_mappings`.
+pub const MAPPING_FORMAT_VERSION: u8 = 1;
+/// The size in bytes of a single mapping in the binary format of `Nat::encode_mappings`.
+const ENCODED_MAPPING_LEN: usize = 27;
+/// The error returned when a mapping table or a configuration cannot be loaded, see
+/// `Nat::decode_mappings` and `NatConfig::decode`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DecodeError {
+    /// The header names a version of the format this library cannot read.
+    UnsupportedVersion(u8),
+    /// The data is shorter or longer than its header says it should be.
+    InvalidLength,
+    /// A value in the data does not encode any value of its type, such as an unknown enum variant.
+    InvalidValue,
+    /// A mapping is on an address that is not one of this NAT's external addresses.
+    UnknownExternalAddress(u32),
+    /// There are more mappings than this NAT can hold.
+    TooManyMappings { count: usize, capacity: usize },
+}
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::UnsupportedVersion(version) => write!(f, "Format version {} is not supported", version),
+            DecodeError::InvalidLength => write!(f, "The data does not match the length given by its header"),
+            DecodeError::InvalidValue => write!(f, "The data contains a value that is not valid for its type"),
+            DecodeError::UnknownExternalAddress(addr) => write!(f, "{} is not an external address of this NAT", addr),
+            DecodeError::TooManyMappings { count, capacity } => write!(f, "{} mappings were given but the NAT can only hold {}", count, capacity),
+        }
+    }
+}
+impl std::error::Error for DecodeError {}
+
+/// The error returned when hosts cannot be added to a NAT's internal network, see
+/// `Nat::preload_hosts`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PreloadError {
+    /// The internal address is outside of the NAT's internal address range.
+    InternalAddressOutOfRange(u32),
+    /// The internal address has already been assigned, or was given more than once.
+    AlreadyAssigned(u32),
+    /// The index does not refer to one of the NAT's external addresses.
+    InvalidExternalIndex(usize),
+}
+impl std::fmt::Display for PreloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            DestType::Drop => true,
-            _ => false,
+            PreloadError::InternalAddressOutOfRange(addr) => write!(f, "{} is outside of the internal address range", addr),
+            PreloadError::AlreadyAssigned(addr) => write!(f, "{} has already been assigned", addr),
+            PreloadError::InvalidExternalIndex(idx) => write!(f, "{} is not the index of an external address", idx),
         }
     }
 }
+impl std::error::Error for PreloadError {}
+
+/// How a NAT chooses which mapping to evict when it is full and needs to make room for a new one,
+/// see `Nat::eviction_policy`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EvictionPolicy {
+    /// A mapping is chosen at random with the NAT's rng.
+    Random,
+    /// The mapping that has passed the fewest packets in either direction is evicted, and among
+    /// those the least recently used. This emulates NATs that reclaim their least active mappings
+    /// by traffic volume when under pressure, so a busy flow survives a burst of new ones.
+    LeastPackets,
+    /// Every mapping is scored as `age_weight * age - idle_weight * idle`, where `age` is the time
+    /// since the mapping was created and `idle` is the time since it was last used, and the lowest
+    /// scoring mapping is evicted, and among those the least recently used. This emulates NATs
+    /// tuned to keep persistent connections such as VoIP calls alive under port pressure from a
+    /// burst of short flows, which least recently used eviction would sacrifice as soon as they go
+    /// briefly idle. With an `age_weight` of 0 this is least recently used eviction.
+    Fairness { age_weight: i64, idle_weight: i64 },
+}
+
+/// Why a NAT removed a mapping, see `Nat::recent_removals`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RemovalReason {
+    /// The mapping timed out.
+    Expired,
+    /// The mapping was evicted to make room for a new mapping because the NAT was full, see
+    /// `Nat::eviction_policy`.
+    Evicted,
+    /// A new mapping took the mapping's external port, such as with `PORT_PRESERVATION_OVERRIDE`.
+    Overridden,
+    /// An inbound packet was filtered, see `FILTERED_INBOUND_DESTROYS_MAPPING`.
+    Filtered,
+    /// A port forward took the mapping's external port, see `ForwardConflictPolicy::Evict`.
+    Forwarded,
+    /// The mapping's internal host was removed, see `Nat::remove_internal_address`.
+    HostRemoved,
+    /// The mapping delivered its one inbound packet, see `SINGLE_USE_MAPPINGS`.
+    Consumed,
+}
+/// A mapping a NAT has removed, see `Nat::recent_removals`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RemovedMapping {
+    /// The mapping as it was when it was removed.
+    pub mapping: Mapping,
+    pub reason: RemovalReason,
+    /// The timestamp of the packet or call that removed the mapping.
+    pub time: i64,
+}
+/// Appends a removed mapping to a history of removals that keeps at least its last `capacity`
+/// entries. The history is trimmed in batches so that recording a removal is amortized O(1).
+fn record_removal(history: &mut Vec<RemovedMapping>, capacity: usize, mapping: Mapping, reason: RemovalReason, time: i64) {
+    if capacity == 0 {
+        return;
+    }
+    history.push(RemovedMapping { mapping, reason, time });
+    if history.len() > 2 * capacity {
+        history.drain(..history.len() - capacity);
+    }
+}
+
+/// The splitmix64 finalizer, used as a cheap and stable hash function.
+#[inline]
+fn mix_u64(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// An internal address assigned by the NAT.
+struct Host {
+    /// The external address this host is paired with, used when the NAT has an "IP address
+    /// pooling" behavior of "Paired".
+    external_addr_idx: usize,
+    /// The block of external ports reserved for this host, if the NAT allocates port blocks.
+    port_block: Option<RangeInclusive<u16>>,
+    /// The port this host's next mapping will be allocated at or after, if the NAT allocates ports
+    /// sequentially.
+    next_port: Option<u16>,
+}
 
 struct Entry {
     internal_addr: u32,
@@ -50,27 +1206,271 @@ struct Entry {
     endpoint_addr: u32,
     endpoint_port: u16,
     last_used_time: i64,
+    /// When this mapping was created, see `EvictionPolicy::Fairness`.
+    created_time: i64,
+    /// The last time an outbound packet was sent through this mapping, which starts its filter
+    /// window, see `Nat::filter_timeout`.
+    filter_last_refreshed: i64,
+    /// Whether an inbound packet has been delivered through this mapping, meaning traffic has been
+    /// observed in both directions.
+    replied: bool,
+    /// The traffic class of the packet that created this mapping, see `CLASS_DEPENDENT_MAPPING`.
+    traffic_class: u8,
+    /// The number of packets that have passed through this mapping in either direction, including
+    /// the packet that created it, see `EvictionPolicy::LeastPackets`.
+    packets: u64,
+    /// This mapping's own inbound rate limit, created when it first admits an inbound packet, see
+    /// `Nat::inbound_mapping_rate_limit`.
+    inbound_bucket: Option<TokenBucket>,
+}
+/// The oldest permitted last use time for mappings that have and have not been replied to, at
+/// some moment in time, see `Nat::expiry_at`.
+#[derive(Clone, Copy, Debug)]
+struct Expiry {
+    replied: i64,
+    unreplied: i64,
+}
+impl Entry {
+    /// Whether this mapping has timed out as of the moment `expiry` was computed for. This is the
+    /// single definition of expiry shared by the paths that remove expired mappings and the
+    /// read-only paths that merely skip over them.
+    #[inline]
+    fn is_expired(&self, expiry: Expiry) -> bool {
+        let route_expiry = if self.replied {
+            expiry.replied
+        } else {
+            expiry.unreplied
+        };
+        self.last_used_time < route_expiry
+    }
+    /// Whether this mapping's filtering would accept an inbound packet from the given source.
+    /// `filter_expiry` is the oldest permitted filter refresh time, along with the filtering the
+    /// mapping falls back to if its filter was refreshed before then.
+    #[inline]
+    fn accepts(&self, flags: u32, src_addr: u32, src_port: u16, filter_expiry: Option<(i64, FilterExpiry)>) -> bool {
+        if let Some((expiry, lapsed)) = filter_expiry {
+            if self.filter_last_refreshed < expiry {
+                return lapsed == FilterExpiry::Open;
+            }
+        }
+        if flags & RELAX_FILTERING_AFTER_BIDIRECTIONAL > 0 && self.replied {
+            return true;
+        }
+        (flags & ADDRESS_DEPENDENT_FILTERING == 0 || self.endpoint_addr == src_addr)
+            && (flags & PORT_DEPENDENT_FILTERING == 0 || self.endpoint_port == src_port)
+    }
+    #[inline]
+    fn snapshot(&self, external_addr: u32) -> Mapping {
+        Mapping {
+            external_addr,
+            external_port: self.external_port,
+            internal_addr: self.internal_addr,
+            internal_port: self.internal_port,
+            endpoint_addr: self.endpoint_addr,
+            endpoint_port: self.endpoint_port,
+            last_used_time: self.last_used_time,
+            replied: self.replied,
+        }
+    }
 }
 pub struct Nat<R: RngCore, const M: usize> {
     external_addresses_len: usize,
     external_addresses: [u32; M],
     map: [Vec<Entry>; M],
-    intranet: HashMap<u32, usize>,
+    /// The time every port of every external address was last freed, meaning the last mapping
+    /// using it was removed, see `PortAllocation::LeastRecentlyFreed`.
+    port_free_times: [HashMap<u16, i64>; M],
+    accounting: [Accounting; M],
+    intranet: HashMap<u32, Host>,
     rng: R,
-    assigned_external_ports: RangeInclusive<u16>,
+    /// Sorted, disjoint and nonempty.
+    assigned_external_ports: Vec<RangeInclusive<u16>>,
     assigned_internal_addresses: RangeInclusive<u32>,
     map_cur_size: usize,
     map_max_size: usize,
+    map_peak_size: usize,
+    port_histogram: BTreeMap<u16, usize>,
+    removal_history: Vec<RemovedMapping>,
+    latest_time: i64,
+    last_port_preservation: Option<PortPreservation>,
+    port_oracle: Option<(PortOracle, OracleConflict)>,
+    collision_resolver: Option<CollisionResolver>,
+    algs: HashMap<u16, Alg>,
+    /// Human readable names of addresses, see `label`.
+    labels: HashMap<u32, String>,
+    pooling_cursor: usize,
+    /// The number of hosts that have been assigned an internal address, see `paired_pooling_order`.
+    hosts_assigned: usize,
+    port_hash_seed: Option<u64>,
     /// This field defines the set of behaviors this NAT will exhibit.
     /// Some NATs will dynamically change their behavior during runtime in response to arbitrary
     /// triggers. This classified as a Non-deterministic NAT by rfc4787, and it is awful.
-    /// If you wish to emulate such a behavior then you may mutate this field.
+    /// If you wish to emulate such a behavior then you may mutate this field, or set
+    /// `adaptive_flags` to have the NAT change it based on load.
     pub flags: u32,
     /// This is the mapping timeout duration for this NAT, an allocated mapping from internal to
     /// external address will last for at most this long.
     /// Some NATs may dynamically change this value based on arbitrary network conditions.
-    /// If you wish to emulate such a behavior then you may mutate this field.
+    /// If you wish to emulate such a behavior then you may mutate this field, or call
+    /// `set_mapping_timeout` to also remove mappings that have timed out under the new value.
     pub mapping_timeout: i64,
+    /// An optional rate limit on all packets passing through this NAT, in either direction.
+    /// Packets that arrive while the bucket is empty are dropped, modeling consumer routers
+    /// whose CPU chokes under load. This is `None` by default.
+    pub rate_limit: Option<TokenBucket>,
+    /// An optional rate limit on the inbound packets of each mapping. Every mapping is given its
+    /// own copy of this bucket when it first admits an inbound packet, and inbound packets that
+    /// its mapping and filtering would admit are dropped while that copy is empty. Outbound
+    /// packets are unaffected. This models the anti-abuse throttling of carrier-grade NATs, and
+    /// unlike `rate_limit` a flood through one mapping does not affect any other mapping. Changes
+    /// to this field only apply to mappings that have not yet admitted an inbound packet.
+    /// This is `None` by default.
+    pub inbound_mapping_rate_limit: Option<TokenBucket>,
+    /// The order in which external addresses are considered for a new mapping when this NAT has
+    /// an "IP address pooling" behavior of "Arbitrary". This is `AddressOrder::Random` by default.
+    /// `AddressOrder::RoundRobin` makes multi-address egress deterministic regardless of the rng.
+    pub arbitrary_pooling_order: AddressOrder,
+    /// The order in which new hosts are paired with external addresses when this NAT has an "IP
+    /// address pooling" behavior of "Paired", see `assign_internal_address`. This is
+    /// `AddressOrder::Random` by default. `AddressOrder::RoundRobin` pairs the Nth assigned host
+    /// with the external address at index N modulo the number of external addresses, which makes
+    /// the pairing independent of the rng.
+    pub paired_pooling_order: AddressOrder,
+    /// The mapping timeout duration for mappings that have never had an inbound packet delivered
+    /// through them. Such mappings likely belong to failed connections, and many NATs age them out
+    /// faster than established ones. If this is `None`, which it is by default, `mapping_timeout`
+    /// is used for all mappings.
+    pub unreplied_timeout: Option<i64>,
+    /// The duration a mapping's filtering stays in effect after the last outbound packet through
+    /// it, independently of `mapping_timeout`. Once this window lapses the mapping's filtering
+    /// is replaced according to `filter_expiry` even though the mapping itself persists.
+    /// If this is `None`, which it is by default, filtering lasts as long as the mapping.
+    pub filter_timeout: Option<i64>,
+    /// What a mapping's filtering becomes once its `filter_timeout` has lapsed.
+    /// This is `FilterExpiry::Open` by default.
+    pub filter_expiry: FilterExpiry,
+    static_mappings: HashMap<(u32, u16), (u32, u16)>,
+    inbound_whitelist: HashSet<u32>,
+    /// Whether an inbound packet delivered through a port forward creates a mapping for its
+    /// sender, see `add_static_mapping`. This is true by default. If it is false port forwards are
+    /// stateless, and replies from the internal host are mapped like any other outbound packet.
+    pub forwarded_inbound_creates_mapping: bool,
+    /// If set, every internal address assigned after this is set will be reserved a block of this
+    /// many consecutive external dynamic ports on its paired external address. Mappings for that
+    /// address will only ever use ports from its block, and will only preserve source ports that
+    /// fall within its block. This emulates the deterministic port-block allocation of many
+    /// carrier-grade NATs. A host implicitly has an "IP address pooling" behavior of "Paired" if
+    /// it has a port block.
+    ///
+    /// If every block is taken, new internal addresses are not reserved a block and may use any
+    /// port. The block size must be greater than 0. This is `None` by default.
+    pub port_block_size: Option<u16>,
+    /// The distribution random external ports are drawn from when the NAT cannot preserve a
+    /// port. Useful for calibrating this NAT against the port histogram of a real device.
+    /// This is `PortDistribution::Uniform` by default.
+    pub port_distribution: PortDistribution,
+    /// How the NAT chooses the external port of a new mapping when it cannot preserve a port.
+    /// This is independent of the NAT's mapping behavior, so a symmetric NAT may allocate ports
+    /// sequentially. It has no effect if the NAT has the `DETERMINISTIC_PORT_HASH` behavior.
+    /// This is `PortAllocation::Random` by default.
+    pub port_allocation: PortAllocation,
+    /// The fraction of packets, from 0.0 to 1.0, this NAT drops at random in either direction,
+    /// regardless of its mapping state. Loss is decided with the NAT's rng so it is deterministic
+    /// for a given seed. This is 0.0 by default, in which case no random numbers are drawn.
+    pub loss_rate: f32,
+    /// How this NAT handles a packet whose `current_time` is earlier than the latest `current_time`
+    /// it has been given, which happens when emulating jittery or skewed clocks. Only the methods
+    /// that route packets, `open_mapping` and `advance_time` advance the NAT's clock.
+    /// This is `NonMonotonicTime::Trust` by default.
+    pub non_monotonic_time: NonMonotonicTime,
+    /// Whether every external port the NAT chooses for a new mapping is counted in the
+    /// `port_allocation_histogram`. This is false by default.
+    pub track_port_allocations: bool,
+    /// Whether reusing an existing mapping to send to a new destination records that destination
+    /// alongside the previous ones, or replaces the previous destination. This only makes a
+    /// difference for filtering when the NAT has endpoint-independent or partially dependent
+    /// mapping behavior. This is `EndpointUpdate::FixedAtCreation` by default.
+    pub endpoint_update: EndpointUpdate,
+    /// The maximum number of distinct endpoints a single internal address and port may have live
+    /// mappings to at once. Outbound packets to any further endpoint are dropped until one of
+    /// those mappings times out. This emulates the crude anti-scanning measures of some NATs,
+    /// which break applications that fan out to many peers from one local port.
+    /// This is `None` by default.
+    pub max_endpoints_per_flow: Option<usize>,
+    /// Ports within the external dynamic port range that the NAT will never allocate to a mapping,
+    /// such as the ports of services running on the NAT itself. A source port that is excluded
+    /// will not be preserved. At least one dynamic port must remain unexcluded, otherwise packets
+    /// that need a new random port are dropped. This is empty by default.
+    pub excluded_external_ports: Vec<RangeInclusive<u16>>,
+    /// Additional subnets of the NAT's internal network, beside the range internal addresses are
+    /// assigned from. Outbound packets addressed to any of these are routed internally instead of
+    /// being translated, which emulates a LAN made of several disjoint subnets behind one NAT.
+    /// Addresses in these subnets are never assigned by the NAT. This is empty by default.
+    pub internal_subnets: Vec<RangeInclusive<u32>>,
+    /// The internal address of a "DMZ host", which receives every inbound packet addressed to a
+    /// port that no mapping or port forward is using, on the same port it was addressed to. Such
+    /// packets are treated as if they were port forwarded, see `add_static_mapping`.
+    /// This is `None` by default.
+    pub dmz_host: Option<u32>,
+    /// External ports on which the NAT itself runs local services, such as a web interface.
+    /// Inbound packets addressed to one of these ports are delivered to the NAT, see
+    /// `InboundDestType::Router`, unless a live mapping or a port forward uses the port.
+    /// Local services take precedence over the `dmz_host`. This is empty by default.
+    pub router_listen_ports: HashSet<u16>,
+    /// Source ports the NAT attempts to preserve even if it has the `NO_PORT_PRESERVATION`
+    /// behavior. This emulates NATs with application level gateways that special-case certain
+    /// protocols, such as IKE on port 500 or SIP on port 5060, while randomizing every other port.
+    /// These ports are preserved just like any other port would be by a NAT without
+    /// `NO_PORT_PRESERVATION`, so they may still collide. This is empty by default.
+    pub always_preserved_ports: HashSet<u16>,
+    /// Whether packets sent to an address in the NAT's internal address range that has not been
+    /// assigned to a host are dropped, as if address resolution had failed on the LAN. If this is
+    /// false they are routed internally like any other packet to the internal network. Addresses
+    /// in the `internal_subnets` are never assigned, so packets to them are always routed.
+    /// This is false by default.
+    pub drop_unassigned_internal_destinations: bool,
+    /// The number of most recently removed mappings this NAT remembers, see `recent_removals`.
+    /// Setting this to 0 disables the history. This is 16 by default.
+    pub removal_history_size: usize,
+    /// If set, the NAT overwrites its `flags` before processing each packet based on how many
+    /// mappings it holds, which emulates an enterprise NAT that switches to a more restrictive
+    /// behavior to shed state under load, such as from endpoint-independent to
+    /// address-and-port-dependent mapping. The count includes mappings that have timed out but
+    /// have not yet been removed, see `advance_time`. Existing mappings are kept when the flags
+    /// change. This is `None` by default.
+    pub adaptive_flags: Option<AdaptiveFlags>,
+    /// How the NAT chooses which mapping to evict once it holds `mapping_max_size` mappings and
+    /// needs to create another. This is `EvictionPolicy::Random` by default.
+    pub eviction_policy: EvictionPolicy,
+    /// Whether routing an inbound packet removes the timed out mappings it passes over in the
+    /// routing table of the packet's destination address. If this is false those mappings are
+    /// skipped without being removed, and are only removed by outbound packets or `advance_time`.
+    /// This is true by default.
+    pub sweep_expired_on_receive: bool,
+    /// Whether an outbound packet that needs a new mapping while the NAT holds `mapping_max_size`
+    /// mappings is refused with `DestType::Deferred` instead of evicting a mapping, so the sender
+    /// can retry once a mapping has timed out. This emulates NATs that briefly queue packets under
+    /// table pressure. Inbound packets through port forwards and `send_internal_packet_with_port`
+    /// still evict mappings. This is false by default.
+    pub defer_when_full: bool,
+    /// The mapping timeout of mappings on each external address, indexed like `external_addresses`.
+    /// Mappings on an address with a timeout of `None` use `mapping_timeout`. This emulates
+    /// multi-WAN NATs whose links age mappings differently, such as a cellular backup link that
+    /// times out faster than the primary link. The `unreplied_timeout` applies to every address
+    /// regardless. Every timeout is `None` by default.
+    pub external_address_timeouts: [Option<i64>; M],
+    /// Whether a packet an internal host sends to one of the NAT's own external addresses, on one
+    /// of the `router_listen_ports`, is delivered to the NAT's local services as
+    /// `DestType::Router`. If false such packets are dropped. Like any other packet to one of the
+    /// NAT's external addresses the packet is subject to hairpinning, see `NO_HAIRPINNING`, and
+    /// the sender is given a mapping. This is true by default.
+    pub router_loopback: bool,
+    /// Whether the NAT rejects inbound packets that are addressed to it but that no mapping or
+    /// port forward admits, such as filtered packets, by sending an ICMP port unreachable message
+    /// back to their source, as opposed to silently dropping them. Applications that observe these
+    /// messages fail fast instead of timing out. If true `receive_external_packet_dest` returns
+    /// `InboundDestType::Unreachable` for such packets. This is false by default.
+    pub icmp_unreachable: bool,
 }
 impl<R: RngCore> Nat<R, 1> {
     /// Creates a NAT object that has address translation disabled.
@@ -93,7 +1493,8 @@ impl<R: RngCore> Nat<R, 1> {
 }
 impl<R: RngCore, const M: usize> Nat<R, M> {
     /// Creates a new NAT struct with a total number of external addresses that is less than the constant `M`.
-    /// See `Nat::new` for more details.
+    /// See `Nat::new` for more details. Panics if the configuration is invalid, see
+    /// `Nat::try_with_capacity`.
     #[inline]
     pub fn with_capacity(
         flags: u32,
@@ -104,13 +1505,42 @@ impl<R: RngCore, const M: usize> Nat<R, M> {
         mapping_max_size: usize,
         mapping_timeout: i64,
     ) -> Self {
-        debug_assert!(
-            external_addresses.len() <= M,
-            "The external_addresses array must have length less than or equal to M"
-        );
+        Self::try_with_capacity(
+            flags,
+            external_addresses,
+            internal_addresses,
+            external_dynamic_ports,
+            rng,
+            mapping_max_size,
+            mapping_timeout,
+        )
+        .unwrap_or_else(|error| panic!("{}", error))
+    }
+    /// Identical to `with_capacity`, except an invalid configuration is returned as an error
+    /// instead of panicking. See `Nat::try_new`.
+    pub fn try_with_capacity(
+        flags: u32,
+        external_addresses: &[u32],
+        internal_addresses: RangeInclusive<u32>,
+        external_dynamic_ports: RangeInclusive<u16>,
+        rng: R,
+        mapping_max_size: usize,
+        mapping_timeout: i64,
+    ) -> Result<Self, ConstructError> {
+        if external_addresses.len() > M {
+            return Err(ConstructError::TooManyExternalAddresses { count: external_addresses.len(), capacity: M });
+        }
+        validate_config(
+            flags,
+            external_addresses.len(),
+            &internal_addresses,
+            &external_dynamic_ports,
+            mapping_max_size,
+            mapping_timeout,
+        )?;
         let mut external_addresses_mem = [0; M];
         external_addresses_mem[..external_addresses.len()].copy_from_slice(external_addresses);
-        let mut ret = Self::new(
+        let mut ret = Self::new_unchecked(
             flags,
             external_addresses_mem,
             internal_addresses,
@@ -120,20 +1550,27 @@ impl<R: RngCore, const M: usize> Nat<R, M> {
             mapping_timeout,
         );
         ret.external_addresses_len = external_addresses.len();
-        ret
+        Ok(ret)
     }
     /// Creates a new NAT struct.
-    /// * `flags`: The set of behaviors this NAT should exhibit, see module `flags`.
+    /// * `flags`: The set of behaviors this NAT should exhibit, see module `flags`. Contradictory
+    ///   flags are rejected, see `validate_flags`.
     /// * `external_addresses`: The list of external IP addresses the NAT is allowed to use.
     /// * `internal_addresses`: The range of internal IP addresses the NAT is allowed to
     ///   assign clients inside of its internal network.
     /// * `external_dynamic_ports`: The list of dynamic ports that the NAT is allowed to use on the
     ///   external network. The NAT may use ports outside of this range for port preservation.
-    /// * `rng_seed`: Deterministic seed for the NAT's random number generator used for generating
-    ///   dynamic ports, internal addresses and external addresses.
+    ///   It must contain at least `min_external_dynamic_ports` ports.
+    /// * `rng`: The NAT's random number generator, used for generating dynamic ports, internal
+    ///   addresses and external addresses. A seeded rng makes the NAT deterministic, and the order
+    ///   in which the NAT draws from it is kept stable so seeded results do not change.
+    /// * `mapping_max_size`: The maximum number of mappings the NAT will hold at once. When it is
+    ///   full the NAT evicts a mapping to make room for a new one, see `eviction_policy`.
     /// * `mapping_timeout`: How long the NAT keeps an address translation mapping open for. It has
     ///   unspecified units, the caller is expected to use the same unit of time for this value as
     ///   they do for all other `current_time` timestamps in this library.
+    ///
+    /// Panics if the configuration is invalid, see `Nat::try_new`.
     #[inline]
     pub fn new(
         flags: u32,
@@ -144,44 +1581,709 @@ impl<R: RngCore, const M: usize> Nat<R, M> {
         mapping_max_size: usize,
         mapping_timeout: i64,
     ) -> Self {
-        debug_assert!(mapping_max_size > 0, "The mapping max size must be greateer than 0");
-        debug_assert!(
-            internal_addresses.start() <= internal_addresses.end(),
-            "The internal_addresses range must be nonempty"
-        );
-        debug_assert!(
-            external_dynamic_ports.start() <= external_dynamic_ports.end(),
-            "The external_dynamic_ports range must be nonempty"
-        );
-        Self {
-            external_addresses_len: M,
-            external_addresses: external_addresses,
-            map: std::array::from_fn(|_| Vec::new()),
-            map_cur_size: 0,
-            map_max_size: usize::MAX,
-            mapping_timeout,
-            rng,
-            assigned_external_ports: external_dynamic_ports,
-            assigned_internal_addresses: internal_addresses,
-            intranet: HashMap::new(),
+        Self::try_new(
             flags,
-        }
+            external_addresses,
+            internal_addresses,
+            external_dynamic_ports,
+            rng,
+            mapping_max_size,
+            mapping_timeout,
+        )
+        .unwrap_or_else(|error| panic!("{}", error))
     }
-    #[inline]
+    /// Identical to `new`, except an invalid configuration is returned as an error instead of
+    /// panicking. The configuration is validated in all build modes, so this should be preferred
+    /// whenever the configuration comes from untrusted input.
+    ///
+    /// Return value is `Err` if the flags are contradictory, if `M` is 0, if either range is empty,
+    /// if `external_dynamic_ports` is smaller than `min_external_dynamic_ports`, if
+    /// `mapping_max_size` is 0 or if `mapping_timeout` is negative.
+    pub fn try_new(
+        flags: u32,
+        external_addresses: [u32; M],
+        internal_addresses: RangeInclusive<u32>,
+        external_dynamic_ports: RangeInclusive<u16>,
+        rng: R,
+        mapping_max_size: usize,
+        mapping_timeout: i64,
+    ) -> Result<Self, ConstructError> {
+        validate_config(flags, M, &internal_addresses, &external_dynamic_ports, mapping_max_size, mapping_timeout)?;
+        Ok(Self::new_unchecked(
+            flags,
+            external_addresses,
+            internal_addresses,
+            external_dynamic_ports,
+            rng,
+            mapping_max_size,
+            mapping_timeout,
+        ))
+    }
+    fn new_unchecked(
+        flags: u32,
+        external_addresses: [u32; M],
+        internal_addresses: RangeInclusive<u32>,
+        external_dynamic_ports: RangeInclusive<u16>,
+        rng: R,
+        mapping_max_size: usize,
+        mapping_timeout: i64,
+    ) -> Self {
+        Self {
+            external_addresses_len: M,
+            external_addresses,
+            map: std::array::from_fn(|_| Vec::new()),
+            port_free_times: std::array::from_fn(|_| HashMap::new()),
+            accounting: [Accounting::default(); M],
+            map_cur_size: 0,
+            map_max_size: mapping_max_size,
+            map_peak_size: 0,
+            port_histogram: BTreeMap::new(),
+            removal_history: Vec::new(),
+            latest_time: i64::MIN,
+            last_port_preservation: None,
+            port_oracle: None,
+            collision_resolver: None,
+            algs: HashMap::new(),
+            labels: HashMap::new(),
+            mapping_timeout,
+            rng,
+            assigned_external_ports: vec![external_dynamic_ports],
+            assigned_internal_addresses: internal_addresses,
+            intranet: HashMap::new(),
+            flags,
+            rate_limit: None,
+            inbound_mapping_rate_limit: None,
+            pooling_cursor: 0,
+            hosts_assigned: 0,
+            port_hash_seed: None,
+            arbitrary_pooling_order: AddressOrder::Random,
+            paired_pooling_order: AddressOrder::Random,
+            unreplied_timeout: None,
+            filter_timeout: None,
+            filter_expiry: FilterExpiry::Open,
+            static_mappings: HashMap::new(),
+            inbound_whitelist: HashSet::new(),
+            forwarded_inbound_creates_mapping: true,
+            port_block_size: None,
+            port_distribution: PortDistribution::Uniform,
+            port_allocation: PortAllocation::Random,
+            loss_rate: 0.0,
+            non_monotonic_time: NonMonotonicTime::Trust,
+            track_port_allocations: false,
+            endpoint_update: EndpointUpdate::FixedAtCreation,
+            max_endpoints_per_flow: None,
+            excluded_external_ports: Vec::new(),
+            internal_subnets: Vec::new(),
+            dmz_host: None,
+            router_listen_ports: HashSet::new(),
+            always_preserved_ports: HashSet::new(),
+            drop_unassigned_internal_destinations: false,
+            removal_history_size: 16,
+            adaptive_flags: None,
+            eviction_policy: EvictionPolicy::Random,
+            sweep_expired_on_receive: true,
+            defer_when_full: false,
+            external_address_timeouts: [None; M],
+            router_loopback: true,
+            icmp_unreachable: false,
+        }
+    }
+    /// Creates a new NAT from a configuration returned by `config`, which behaves identically to
+    /// the NAT the configuration was taken from when it was fresh and given an identical rng.
+    ///
+    /// Return value is `Err` if the configuration is invalid, see `Nat::try_with_capacity` and
+    /// `Nat::set_external_dynamic_ports`, or if its `port_block_size` is 0.
+    pub fn from_config(config: NatConfig, rng: R) -> Result<Self, ConstructError> {
+        if config.port_block_size == Some(0) {
+            return Err(ConstructError::ZeroPortBlockSize);
+        }
+        // The hull of the ranges holds at least as many ports as the ranges themselves, which are
+        // validated on their own below.
+        let hull = match (config.external_dynamic_ports.first(), config.external_dynamic_ports.last()) {
+            (Some(first), Some(last)) => *first.start()..=*last.end(),
+            _ => return Err(ConstructError::EmptyPortRange),
+        };
+        let mut nat = Self::try_with_capacity(
+            config.flags,
+            &config.external_addresses,
+            config.internal_addresses,
+            hull,
+            rng,
+            config.mapping_max_size,
+            config.mapping_timeout,
+        )?;
+        nat.set_external_dynamic_ports(config.external_dynamic_ports)?;
+        for (timeout, address_timeout) in nat.external_address_timeouts.iter_mut().zip(config.external_address_timeouts) {
+            *timeout = address_timeout;
+        }
+        nat.static_mappings = config.static_mappings;
+        nat.inbound_whitelist = config.inbound_whitelist;
+        nat.rate_limit = config.rate_limit;
+        nat.inbound_mapping_rate_limit = config.inbound_mapping_rate_limit;
+        nat.arbitrary_pooling_order = config.arbitrary_pooling_order;
+        nat.paired_pooling_order = config.paired_pooling_order;
+        nat.unreplied_timeout = config.unreplied_timeout;
+        nat.filter_timeout = config.filter_timeout;
+        nat.filter_expiry = config.filter_expiry;
+        nat.forwarded_inbound_creates_mapping = config.forwarded_inbound_creates_mapping;
+        nat.port_block_size = config.port_block_size;
+        nat.port_distribution = config.port_distribution;
+        nat.port_allocation = config.port_allocation;
+        nat.loss_rate = config.loss_rate;
+        nat.non_monotonic_time = config.non_monotonic_time;
+        nat.track_port_allocations = config.track_port_allocations;
+        nat.endpoint_update = config.endpoint_update;
+        nat.max_endpoints_per_flow = config.max_endpoints_per_flow;
+        nat.excluded_external_ports = config.excluded_external_ports;
+        nat.internal_subnets = config.internal_subnets;
+        nat.dmz_host = config.dmz_host;
+        nat.router_listen_ports = config.router_listen_ports;
+        nat.always_preserved_ports = config.always_preserved_ports;
+        nat.drop_unassigned_internal_destinations = config.drop_unassigned_internal_destinations;
+        nat.removal_history_size = config.removal_history_size;
+        nat.adaptive_flags = config.adaptive_flags;
+        nat.eviction_policy = config.eviction_policy;
+        nat.sweep_expired_on_receive = config.sweep_expired_on_receive;
+        nat.defer_when_full = config.defer_when_full;
+        nat.router_loopback = config.router_loopback;
+        nat.icmp_unreachable = config.icmp_unreachable;
+        nat.port_hash_seed = config.port_hash_seed;
+        Ok(nat)
+    }
+    /// Returns everything needed to recreate a fresh NAT with identical behavior using
+    /// `from_config`, without any mappings, hosts or other state. Rate limits are returned as full
+    /// token buckets.
+    pub fn config(&self) -> NatConfig {
+        let fresh_bucket = |bucket: &TokenBucket| TokenBucket::new(bucket.max_packets(), bucket.time_window());
+        NatConfig {
+            flags: self.adaptive_flags.map_or(self.flags, |adaptive| adaptive.normal_flags),
+            external_addresses: self.external_addresses().to_vec(),
+            internal_addresses: self.assigned_internal_addresses.clone(),
+            external_dynamic_ports: self.assigned_external_ports.clone(),
+            mapping_max_size: self.map_max_size,
+            mapping_timeout: self.mapping_timeout,
+            static_mappings: self.static_mappings.clone(),
+            inbound_whitelist: self.inbound_whitelist.clone(),
+            rate_limit: self.rate_limit.as_ref().map(fresh_bucket),
+            inbound_mapping_rate_limit: self.inbound_mapping_rate_limit.as_ref().map(fresh_bucket),
+            arbitrary_pooling_order: self.arbitrary_pooling_order,
+            paired_pooling_order: self.paired_pooling_order,
+            unreplied_timeout: self.unreplied_timeout,
+            filter_timeout: self.filter_timeout,
+            filter_expiry: self.filter_expiry,
+            forwarded_inbound_creates_mapping: self.forwarded_inbound_creates_mapping,
+            port_block_size: self.port_block_size,
+            port_distribution: self.port_distribution,
+            port_allocation: self.port_allocation,
+            loss_rate: self.loss_rate,
+            non_monotonic_time: self.non_monotonic_time,
+            track_port_allocations: self.track_port_allocations,
+            endpoint_update: self.endpoint_update,
+            max_endpoints_per_flow: self.max_endpoints_per_flow,
+            excluded_external_ports: self.excluded_external_ports.clone(),
+            internal_subnets: self.internal_subnets.clone(),
+            dmz_host: self.dmz_host,
+            router_listen_ports: self.router_listen_ports.clone(),
+            always_preserved_ports: self.always_preserved_ports.clone(),
+            drop_unassigned_internal_destinations: self.drop_unassigned_internal_destinations,
+            removal_history_size: self.removal_history_size,
+            adaptive_flags: self.adaptive_flags,
+            eviction_policy: self.eviction_policy,
+            sweep_expired_on_receive: self.sweep_expired_on_receive,
+            defer_when_full: self.defer_when_full,
+            external_address_timeouts: self.external_address_timeouts[..self.external_addresses_len].to_vec(),
+            router_loopback: self.router_loopback,
+            icmp_unreachable: self.icmp_unreachable,
+            port_hash_seed: self.port_hash_seed,
+        }
+    }
+    #[inline]
     pub fn external_addresses(&self) -> &[u32] {
         &self.external_addresses[..self.external_addresses_len]
     }
+    /// The number of external addresses this NAT is currently using, which is the length of
+    /// `external_addresses`.
+    #[inline]
+    pub fn external_address_count(&self) -> usize {
+        self.external_addresses_len
+    }
+    /// The maximum number of external addresses this NAT can hold, which is the constant `M`.
+    /// This is greater than `external_address_count` if this NAT was created with `with_capacity`.
+    #[inline]
+    pub fn external_address_capacity(&self) -> usize {
+        M
+    }
     #[inline]
     pub fn internal_addresses(&self) -> &RangeInclusive<u32> {
         &self.assigned_internal_addresses
     }
+    /// The sorted, disjoint ranges of ports the NAT may allocate on the external network, see
+    /// `set_external_dynamic_ports`.
     #[inline]
-    pub fn external_dynamic_ports(&self) -> &RangeInclusive<u16> {
+    pub fn external_dynamic_ports(&self) -> &[RangeInclusive<u16>] {
         &self.assigned_external_ports
     }
+    /// Replaces the NAT's external dynamic ports with a set of ranges, for emulating NATs that draw
+    /// their ports from several non-contiguous ranges, for instance to avoid registered ports.
+    /// Every way of allocating a port spans all of the ranges, and the ranges together must contain
+    /// at least `min_external_dynamic_ports` ports. Overlapping or adjacent ranges are merged.
+    /// Existing mappings and port blocks are left unchanged.
+    ///
+    /// Return value is `Err` if the ranges contain no ports or too few ports, in which case the
+    /// NAT is left unchanged.
+    pub fn set_external_dynamic_ports(&mut self, ranges: Vec<RangeInclusive<u16>>) -> Result<(), ConstructError> {
+        let mut ranges: Vec<RangeInclusive<u16>> = ranges.into_iter().filter(|range| !range.is_empty()).collect();
+        ranges.sort_unstable_by_key(|range| *range.start());
+        let mut merged: Vec<RangeInclusive<u16>> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if *range.start() as u32 <= *last.end() as u32 + 1 => {
+                    *last = *last.start()..=*last.end().max(range.end());
+                }
+                _ => merged.push(range),
+            }
+        }
+        if merged.is_empty() {
+            return Err(ConstructError::EmptyPortRange);
+        }
+        let actual = ports_len(&merged) as usize;
+        let required = min_external_dynamic_ports(self.flags, self.external_addresses_len, self.map_max_size);
+        if actual < required {
+            return Err(ConstructError::PortRangeTooSmall(PortRangeTooSmall { actual, required }));
+        }
+        self.assigned_external_ports = merged;
+        Ok(())
+    }
+    /// Reports whether the source port of the most recent outbound packet that was mapped was
+    /// preserved, and if not, why. This is `None` until the first outbound packet is mapped.
+    /// Useful for understanding when port prediction will work against a NAT.
+    #[inline]
+    pub fn last_port_preservation(&self) -> Option<PortPreservation> {
+        self.last_port_preservation
+    }
+    /// Scripts the external port of every new mapping. Whenever the NAT needs to choose the
+    /// external port of a new mapping it calls `oracle` first, before attempting any port
+    /// preservation or drawing any random numbers. This is intended for golden tests, where the
+    /// expected ports can then be written plainly in the test's source.
+    ///
+    /// The oracle's port is used on the external address the NAT would otherwise use for a random
+    /// port. If it is in use by another mapping, is statically forwarded or is excluded, the NAT
+    /// resolves it according to `on_conflict`.
+    pub fn set_port_oracle(&mut self, oracle: impl FnMut(InternalFlow) -> u16 + Send + 'static, on_conflict: OracleConflict) {
+        self.port_oracle = Some((Box::new(oracle), on_conflict));
+    }
+    /// Removes the NAT's port oracle, see `set_port_oracle`.
+    pub fn clear_port_oracle(&mut self) {
+        self.port_oracle = None;
+    }
+    /// Takes over what the NAT does when the source port of an outbound packet cannot be
+    /// preserved because another mapping is using it on every external address the NAT could use.
+    /// This allows modeling proprietary router behaviors that none of the NAT's flags describe.
+    ///
+    /// `resolver` is called with the desired port and every port in use by a mapping on the first
+    /// external address the NAT considered, sorted in ascending order. It returns the external port
+    /// of the new mapping, or `None` to drop the packet. The packet is also dropped if the returned
+    /// port is taken, statically forwarded or excluded.
+    ///
+    /// The resolver replaces `PORT_PRESERVATION_OVER_PARITY` and the fallback to a random port, but
+    /// not `PORT_PRESERVATION_OVERLOAD` or `PORT_PRESERVATION_OVERRIDE`, and it is not called when
+    /// the source port is statically forwarded.
+    pub fn set_collision_resolver(&mut self, resolver: impl FnMut(u16, &[u16]) -> Option<u16> + Send + 'static) {
+        self.collision_resolver = Some(Box::new(resolver));
+    }
+    /// Removes the NAT's collision resolver, see `set_collision_resolver`.
+    pub fn clear_collision_resolver(&mut self) {
+        self.collision_resolver = None;
+    }
+    /// Registers an application level gateway for the given port, replacing any ALG already
+    /// registered for it. Protocols such as FTP and SIP embed addresses and ports in their
+    /// payloads, which real NATs rewrite with an ALG so that they stay consistent with the
+    /// translation applied to the packet's header.
+    ///
+    /// The ALG is called by `send_internal_packet_with_payload` for every packet the NAT accepts
+    /// that is destined for `port`, and by `receive_external_packet_with_payload` for every packet
+    /// the NAT accepts that was sent from `port`. It is given the packet's payload to rewrite and
+    /// the translation the NAT applied. This crate has no notion of transport protocols, so an ALG
+    /// applies to every packet to or from its port.
+    pub fn register_alg(&mut self, port: u16, alg: impl FnMut(&mut Vec<u8>, &PacketTranslation) + Send + 'static) {
+        self.algs.insert(port, Box::new(alg));
+    }
+    /// Removes the ALG registered for the given port, see `register_alg`.
+    /// Return value is whether there was an ALG registered for the port.
+    pub fn unregister_alg(&mut self, port: u16) -> bool {
+        self.algs.remove(&port).is_some()
+    }
+    /// The NAT's random number generator. If `R` is `Clone` this can be used to checkpoint the
+    /// NAT's random sequence, and `rng_mut` can later restore it.
+    #[inline]
+    pub fn rng(&self) -> &R {
+        &self.rng
+    }
+    /// Allows the NAT's random number generator to be reseeded or restored mid-simulation.
+    #[inline]
+    pub fn rng_mut(&mut self) -> &mut R {
+        &mut self.rng
+    }
+    /// The largest number of mappings this NAT has held at once since it was created or since
+    /// `reset_peak_mapping_count` was last called. Mappings that have timed out but have not yet
+    /// been removed are counted.
+    #[inline]
+    pub fn peak_mapping_count(&self) -> usize {
+        self.map_peak_size
+    }
+    /// Resets the `peak_mapping_count` to the number of mappings this NAT currently holds.
+    #[inline]
+    pub fn reset_peak_mapping_count(&mut self) {
+        self.map_peak_size = self.map_cur_size;
+    }
+    /// Sets the `mapping_timeout` of this NAT and immediately removes every mapping that has timed
+    /// out under the new timeout as of `current_time`. Mutating `mapping_timeout` directly only
+    /// expires mappings lazily as their routing table is next accessed, which can be observed
+    /// through `peak_mapping_count` and through eviction when the NAT is full. This emulates a NAT
+    /// that shortens its timeouts under load. The `unreplied_timeout` is unchanged.
+    ///
+    /// Return value is `Err` if `mapping_timeout` is negative, in which case the NAT is left
+    /// unchanged.
+    pub fn set_mapping_timeout(&mut self, mapping_timeout: i64, current_time: i64) -> Result<(), ConstructError> {
+        if mapping_timeout < 0 {
+            return Err(ConstructError::NegativeMappingTimeout);
+        }
+        self.mapping_timeout = mapping_timeout;
+        self.remove_expired(current_time);
+        Ok(())
+    }
+    /// Advances the NAT's clock to `current_time` without a packet, and immediately removes every
+    /// mapping that has timed out by then. Mappings are otherwise only removed lazily as packets
+    /// touch their routing table, so calling this on every tick of a simulation keeps the NAT's
+    /// state, such as `peak_mapping_count` and eviction when full, in step with the clock.
+    /// A `current_time` earlier than the latest one is handled according to `non_monotonic_time`.
+    pub fn advance_time(&mut self, current_time: i64) {
+        if let Some(current_time) = self.observe_time(current_time) {
+            self.remove_expired(current_time);
+            self.adapt_flags();
+        }
+    }
+    /// Emulates the NAT rebooting, which loses its entire mapping table. Every mapping is removed
+    /// without being recorded in the `recent_removals`, the NAT's clock is reset, and so is every
+    /// piece of state the NAT uses to choose external addresses and ports, such as each host's
+    /// counter for `PortAllocation::Sequential`. The NAT's configuration, including its hosts,
+    /// static mappings and flags, survives the reboot, although `adaptive_flags` will switch back
+    /// to their normal flags. The seed of `DETERMINISTIC_PORT_HASH` is configuration too, so
+    /// hashed ports stay the same across reboots without restoring the rng.
+    ///
+    /// The external ports the NAT allocates are a pure function of its rng, its configuration
+    /// and its mapping table. So if the rng is restored to the state it had when the NAT was last
+    /// empty, see `rng_mut`, and the same packets are sent again at the same times, the NAT will
+    /// rebuild an identical mapping table. This emulates routers that deterministically rebuild
+    /// their tables after restarting.
+    pub fn reboot(&mut self) {
+        for routing_table in &mut self.map[..self.external_addresses_len] {
+            routing_table.clear();
+        }
+        for free_times in &mut self.port_free_times {
+            free_times.clear();
+        }
+        self.map_cur_size = 0;
+        for host in self.intranet.values_mut() {
+            host.next_port = None;
+        }
+        self.latest_time = i64::MIN;
+        self.last_port_preservation = None;
+        self.pooling_cursor = 0;
+        self.adapt_flags();
+    }
+    /// Switches the NAT's `flags` according to its `adaptive_flags`, if it has any.
+    fn adapt_flags(&mut self) {
+        if let Some(adaptive) = self.adaptive_flags {
+            if self.map_cur_size >= adaptive.high_water {
+                self.flags = adaptive.loaded_flags;
+            } else if self.map_cur_size <= adaptive.low_water {
+                self.flags = adaptive.normal_flags;
+            }
+        }
+    }
+    /// Returns the mapping timeout of mappings on the given external address, and of mappings on
+    /// it that have never been replied to, see `external_address_timeouts`.
+    #[inline]
+    fn timeouts_of(&self, address_idx: usize) -> (i64, i64) {
+        let mapping_timeout = self.external_address_timeouts[address_idx].unwrap_or(self.mapping_timeout);
+        (mapping_timeout, self.unreplied_timeout.unwrap_or(mapping_timeout))
+    }
+    /// Returns the oldest permitted last use times of mappings on the given external address as of
+    /// `current_time`, according to the `mapping_timeout`, `external_address_timeouts` and
+    /// `unreplied_timeout`.
+    #[inline]
+    fn expiry_at(&self, address_idx: usize, current_time: i64) -> Expiry {
+        let (replied_timeout, unreplied_timeout) = self.timeouts_of(address_idx);
+        Expiry {
+            replied: current_time.saturating_sub(replied_timeout),
+            unreplied: current_time.saturating_sub(unreplied_timeout),
+        }
+    }
+    /// Removes every mapping that has timed out as of `current_time`.
+    fn remove_expired(&mut self, current_time: i64) {
+        for address_idx in 0..self.external_addresses_len {
+            let expiry = self.expiry_at(address_idx, current_time);
+            self.remove_routes(address_idx, RemovalReason::Expired, current_time, |route| route.is_expired(expiry));
+        }
+    }
+    /// Returns a snapshot of every mapping this NAT currently holds, including mappings that have
+    /// timed out but have not yet been removed, see `advance_time`. Port forwards are not included.
+    /// The mappings are sorted in the same order as the lists of a `MappingDiff`.
+    pub fn mappings(&self) -> Vec<Mapping> {
+        let mut mappings = Vec::with_capacity(self.map_cur_size);
+        for (external_addr, routing_table) in self.external_addresses[..self.external_addresses_len].iter().zip(&self.map) {
+            mappings.extend(routing_table.iter().map(|route| route.snapshot(*external_addr)));
+        }
+        mappings.sort_unstable_by_key(Mapping::key);
+        mappings
+    }
+    /// Names an address on either network, so that it is printed as `name` instead of as an
+    /// integer in diagnostic output such as `dump_table`. Labeling an address again replaces its
+    /// name. This has no effect on the NAT's behavior.
+    pub fn label(&mut self, addr: u32, name: &str) {
+        self.labels.insert(addr, name.to_string());
+    }
+    /// Returns the name of the given address, see `label`.
+    pub fn label_of(&self, addr: u32) -> Option<&str> {
+        self.labels.get(&addr).map(String::as_str)
+    }
+    /// Formats an address and port, using the address's label if it has one.
+    fn format_endpoint(&self, addr: u32, port: u16) -> String {
+        match self.label_of(addr) {
+            Some(name) => format!("{}:{}", name, port),
+            None => format!("{}:{}", addr, port),
+        }
+    }
+    /// Returns a human readable table of every mapping this NAT currently holds, one per line in
+    /// the same order as `mappings`, for debugging. Addresses are printed using their `label` if
+    /// they have one. Each line has the form
+    /// `external:port -> internal:port via endpoint:port, last used at time`, followed by
+    /// `, replied` if an inbound packet has been delivered through the mapping.
+    pub fn dump_table(&self) -> String {
+        let mut table = String::new();
+        for mapping in self.mappings() {
+            table += &format!(
+                "{} -> {} via {}, last used at {}{}\n",
+                self.format_endpoint(mapping.external_addr, mapping.external_port),
+                self.format_endpoint(mapping.internal_addr, mapping.internal_port),
+                self.format_endpoint(mapping.endpoint_addr, mapping.endpoint_port),
+                mapping.last_used_time,
+                if mapping.replied {
+                    ", replied"
+                } else {
+                    ""
+                },
+            );
+        }
+        table
+    }
+    /// Compares the mapping table of this NAT against the mapping table of `other`, which is
+    /// useful for pinpointing where two runs of a simulation diverged, or for checking the final
+    /// state of a simulation against a golden snapshot. Like `mappings`, this includes mappings
+    /// that have timed out but have not yet been removed, so call `advance_time` on both NATs first
+    /// to compare only live mappings.
+    ///
+    /// Return value is the mappings only one of the NATs holds, and the mappings both NATs hold
+    /// but whose state differs. It is empty if the mapping tables are identical.
+    pub fn diff_mappings<R2: RngCore, const M2: usize>(&self, other: &Nat<R2, M2>) -> MappingDiff {
+        let mut diff = MappingDiff::default();
+        let mut theirs = other.mappings().into_iter().peekable();
+        for mine in self.mappings() {
+            while let Some(their) = theirs.next_if(|their| their.key() < mine.key()) {
+                diff.only_in_other.push(their);
+            }
+            match theirs.next_if(|their| their.key() == mine.key()) {
+                Some(their) if their != mine => diff.changed.push((mine, their)),
+                Some(_) => {}
+                None => diff.only_in_self.push(mine),
+            }
+        }
+        diff.only_in_other.extend(theirs);
+        diff
+    }
+    /// Encodes every mapping this NAT currently holds into a compact binary format, for analysis
+    /// by tools written in other languages. Like `mappings`, this includes mappings that have timed
+    /// out but have not yet been removed. Port forwards and hosts are not included.
+    ///
+    /// The format starts with a 5 byte header, the format version `MAPPING_FORMAT_VERSION` as a
+    /// single byte followed by the number of mappings as a u32. Then every mapping follows in the
+    /// order of `mappings`, as 27 bytes made of the fields `external_addr: u32`,
+    /// `external_port: u16`, `internal_addr: u32`, `internal_port: u16`, `endpoint_addr: u32`,
+    /// `endpoint_port: u16`, `last_used_time: i64` and `replied: u8`, in that order.
+    /// Every integer is big-endian, and `replied` is either 0 or 1.
+    pub fn encode_mappings(&self) -> Vec<u8> {
+        let mappings = self.mappings();
+        let mut data = Vec::with_capacity(5 + mappings.len() * ENCODED_MAPPING_LEN);
+        data.push(MAPPING_FORMAT_VERSION);
+        data.extend_from_slice(&(mappings.len() as u32).to_be_bytes());
+        for mapping in mappings {
+            data.extend_from_slice(&mapping.external_addr.to_be_bytes());
+            data.extend_from_slice(&mapping.external_port.to_be_bytes());
+            data.extend_from_slice(&mapping.internal_addr.to_be_bytes());
+            data.extend_from_slice(&mapping.internal_port.to_be_bytes());
+            data.extend_from_slice(&mapping.endpoint_addr.to_be_bytes());
+            data.extend_from_slice(&mapping.endpoint_port.to_be_bytes());
+            data.extend_from_slice(&mapping.last_used_time.to_be_bytes());
+            data.push(mapping.replied as u8);
+        }
+        data
+    }
+    /// Replaces every mapping this NAT holds with the mappings encoded in `data`, in the format
+    /// written by `encode_mappings`. Internal addresses that have not been assigned by this NAT are
+    /// added to its internal network, paired with the external address of their first mapping.
+    /// The filter window of every mapping restarts at its `last_used_time`, see `filter_timeout`.
+    ///
+    /// Return value is an error if `data` is malformed, or if the mappings do not fit this NAT,
+    /// in which case the NAT is left unmodified.
+    pub fn decode_mappings(&mut self, data: &[u8]) -> Result<(), DecodeError> {
+        let (&version, data) = data.split_first().ok_or(DecodeError::InvalidLength)?;
+        if version != MAPPING_FORMAT_VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        let count = data.get(..4).ok_or(DecodeError::InvalidLength)?;
+        let count = u32::from_be_bytes(count.try_into().unwrap()) as usize;
+        let entries = &data[4..];
+        if count.checked_mul(ENCODED_MAPPING_LEN) != Some(entries.len()) {
+            return Err(DecodeError::InvalidLength);
+        }
+        if count > self.map_max_size {
+            return Err(DecodeError::TooManyMappings { count, capacity: self.map_max_size });
+        }
+        let mut map: [Vec<Entry>; M] = std::array::from_fn(|_| Vec::new());
+        for entry in entries.chunks_exact(ENCODED_MAPPING_LEN) {
+            let external_addr = u32::from_be_bytes(entry[0..4].try_into().unwrap());
+            let Some(address_idx) = self.external_addresses().iter().position(|addr| *addr == external_addr) else {
+                return Err(DecodeError::UnknownExternalAddress(external_addr));
+            };
+            let last_used_time = i64::from_be_bytes(entry[18..26].try_into().unwrap());
+            map[address_idx].push(Entry {
+                external_port: u16::from_be_bytes(entry[4..6].try_into().unwrap()),
+                internal_addr: u32::from_be_bytes(entry[6..10].try_into().unwrap()),
+                internal_port: u16::from_be_bytes(entry[10..12].try_into().unwrap()),
+                endpoint_addr: u32::from_be_bytes(entry[12..16].try_into().unwrap()),
+                endpoint_port: u16::from_be_bytes(entry[16..18].try_into().unwrap()),
+                last_used_time,
+                // Encoded mappings do not record when they were created.
+                created_time: last_used_time,
+                filter_last_refreshed: last_used_time,
+                replied: entry[26] != 0,
+                traffic_class: 0,
+                packets: 0,
+                inbound_bucket: None,
+            });
+        }
+        for (address_idx, routing_table) in map.iter().enumerate() {
+            for route in routing_table {
+                self.intranet.entry(route.internal_addr).or_insert(Host {
+                    external_addr_idx: address_idx,
+                    port_block: None,
+                    next_port: None,
+                });
+            }
+        }
+        self.map = map;
+        self.map_cur_size = count;
+        self.map_peak_size = self.map_peak_size.max(count);
+        Ok(())
+    }
+    /// Returns up to the last `removal_history_size` mappings this NAT has removed, oldest first,
+    /// along with why and when each was removed. This is useful for finding out what the NAT
+    /// killed during a burst of traffic when a connection unexpectedly dies. Mappings that timed out
+    /// are only recorded once they are actually removed, which happens lazily as packets touch
+    /// their routing table unless `advance_time` is called.
+    #[inline]
+    pub fn recent_removals(&self) -> &[RemovedMapping] {
+        &self.removal_history[self.removal_history.len().saturating_sub(self.removal_history_size)..]
+    }
+    /// Returns the `recent_removals` and clears the history.
+    pub fn drain_recent_removals(&mut self) -> Vec<RemovedMapping> {
+        let start = self.removal_history.len().saturating_sub(self.removal_history_size);
+        let mut removals = std::mem::take(&mut self.removal_history);
+        removals.drain(..start);
+        removals
+    }
+    /// Returns how many times each external port was chosen for a new mapping while
+    /// `track_port_allocations` was true, since `reset_port_allocation_histogram` was last called.
+    /// Reused mappings and ports forced by `send_internal_packet_with_port` are not counted.
+    /// This is useful for comparing the NAT's port distribution against measurements of real NATs.
+    #[inline]
+    pub fn port_allocation_histogram(&self) -> &BTreeMap<u16, usize> {
+        &self.port_histogram
+    }
+    /// Clears the `port_allocation_histogram`.
+    #[inline]
+    pub fn reset_port_allocation_histogram(&mut self) {
+        self.port_histogram.clear();
+    }
+    /// Returns the traffic that has crossed this NAT through the given external address since it
+    /// was created or since `reset_accounting` was last called. This emulates how carriers meter
+    /// the traffic of each public address. Outbound packets are counted once they are translated
+    /// for the external network, and inbound packets once they are delivered to the internal
+    /// network, so hairpinned and dropped packets are not counted. Packet sizes are only known
+    /// for packets routed with `send_internal_packet_sized` and `receive_external_packet_sized`.
+    ///
+    /// Return value is all zeros if `external_addr` is not one of this NAT's external addresses.
+    pub fn address_accounting(&self, external_addr: u32) -> Accounting {
+        match self.external_addresses().iter().position(|addr| *addr == external_addr) {
+            Some(address_idx) => self.accounting[address_idx],
+            None => Accounting::default(),
+        }
+    }
+    /// Resets every counter of the `address_accounting` to zero.
+    pub fn reset_accounting(&mut self) {
+        self.accounting = [Accounting::default(); M];
+    }
+    /// Counts a packet that crossed through the given external address, see `address_accounting`.
+    fn account(&mut self, external_addr: u32, direction: Direction, packets: u64, bytes: u64) {
+        let Some(address_idx) = self.external_addresses().iter().position(|addr| *addr == external_addr) else {
+            return;
+        };
+        let accounting = &mut self.accounting[address_idx];
+        match direction {
+            Direction::Outbound => {
+                accounting.outbound_packets += packets;
+                accounting.outbound_bytes += bytes;
+            }
+            Direction::Inbound => {
+                accounting.inbound_packets += packets;
+                accounting.inbound_bytes += bytes;
+            }
+        }
+    }
+    /// Decodes the current `flags` of this NAT into the individual behaviors they specify.
+    #[inline]
+    pub fn effective_behaviors(&self) -> BehaviorSet {
+        BehaviorSet::from_flags(self.flags)
+    }
+    /// Randomly assigns an unused internal address to a new host on the NAT's internal network.
+    /// Panics if every address in the NAT's internal address range has already been assigned.
+    #[inline]
     pub fn assign_internal_address(&mut self) -> u32 {
+        self.try_assign_internal_address()
+            .expect("The internal_addresses range has been exhausted")
+    }
+    /// Assigns up to `n` new internal addresses, see `assign_internal_address`. Fewer than `n`
+    /// addresses are returned if the NAT's internal address range is exhausted.
+    pub fn assign_n_internal_addresses(&mut self, n: usize) -> Vec<u32> {
+        let mut addresses = Vec::with_capacity(n);
+        while addresses.len() < n {
+            match self.try_assign_internal_address() {
+                Some(addr) => addresses.push(addr),
+                None => break,
+            }
+        }
+        addresses
+    }
+    /// Randomly assigns an unused internal address to a new host on the NAT's internal network,
+    /// and pairs it with an external address according to the `paired_pooling_order`.
+    /// Returns `None` if every address in the NAT's internal address range has been assigned.
+    pub fn try_assign_internal_address(&mut self) -> Option<u32> {
         // Instead of dealing with u32 overflow we just cast up to a u64 and sidestep the problem.
         let addr_len = *self.assigned_internal_addresses.end() - *self.assigned_internal_addresses.start();
+        if self.intranet.len() as u64 > addr_len as u64 {
+            return None;
+        }
         loop {
             let random_addr = if addr_len == u32::MAX {
                 self.rng.next_u32()
@@ -191,23 +2293,297 @@ impl<R: RngCore, const M: usize> Nat<R, M> {
             if self.intranet.contains_key(&random_addr) {
                 continue;
             }
-            // Randomly assign this connection an external ip address, we will only use this
-            // assigned addr when IP_POOLING_BEHAVIOR_ARBITRARY is false
+            // Assign this connection an external ip address, we will only use this assigned addr
+            // when IP_POOLING_BEHAVIOR_ARBITRARY is false
             let ex_addr_idx = if M == 1 {
                 0
+            } else if self.paired_pooling_order == AddressOrder::RoundRobin {
+                self.hosts_assigned % self.external_addresses_len
             } else {
                 (self.rng.next_u64() as usize) % self.external_addresses_len
             };
-            self.intranet.insert(random_addr, ex_addr_idx);
-            return random_addr;
+            self.hosts_assigned += 1;
+            let port_block = self
+                .port_block_size
+                .and_then(|block_size| self.allocate_port_block(ex_addr_idx, block_size));
+            self.intranet
+                .insert(random_addr, Host { external_addr_idx: ex_addr_idx, port_block, next_port: None });
+            return Some(random_addr);
+        }
+    }
+    /// Adds the given hosts to the NAT's internal network, each paired with the external address
+    /// at the given index of `external_addresses`, without drawing from the rng. This makes
+    /// topologies with several hosts and "Paired" IP address pooling fully deterministic.
+    /// * `hosts`: Pairs of an internal address and the index of its external address.
+    ///
+    /// Return value is an error if any internal address is outside of the NAT's internal address
+    /// range or has already been assigned, or if any index is not that of an external address.
+    /// In that case no host is added.
+    pub fn preload_hosts(&mut self, hosts: &[(u32, usize)]) -> Result<(), PreloadError> {
+        let mut seen = HashSet::new();
+        for &(internal_addr, external_addr_idx) in hosts {
+            if !self.assigned_internal_addresses.contains(&internal_addr) {
+                return Err(PreloadError::InternalAddressOutOfRange(internal_addr));
+            } else if self.intranet.contains_key(&internal_addr) || !seen.insert(internal_addr) {
+                return Err(PreloadError::AlreadyAssigned(internal_addr));
+            } else if external_addr_idx >= self.external_addresses_len {
+                return Err(PreloadError::InvalidExternalIndex(external_addr_idx));
+            }
+        }
+        for &(internal_addr, external_addr_idx) in hosts {
+            let port_block = self
+                .port_block_size
+                .and_then(|block_size| self.allocate_port_block(external_addr_idx, block_size));
+            self.intranet
+                .insert(internal_addr, Host { external_addr_idx, port_block, next_port: None });
+        }
+        Ok(())
+    }
+    /// Finds the lowest block of external dynamic ports on the given external address that is not
+    /// reserved by any other host.
+    fn allocate_port_block(&self, external_addr_idx: usize, block_size: u16) -> Option<RangeInclusive<u16>> {
+        debug_assert!(block_size > 0, "The port block size must be greater than 0");
+        if block_size == 0 {
+            return None;
+        }
+        let mut in_use = HashSet::new();
+        for host in self.intranet.values() {
+            if let (true, Some(block)) = (host.external_addr_idx == external_addr_idx, &host.port_block) {
+                in_use.insert(*block.start());
+            }
+        }
+        // Blocks never straddle two of the dynamic port ranges.
+        for range in &self.assigned_external_ports {
+            let block_count = range.len() / block_size as usize;
+            for block_idx in 0..block_count {
+                let start = range.start() + block_idx as u16 * block_size;
+                if !in_use.contains(&start) {
+                    return Some(start..=start + (block_size - 1));
+                }
+            }
         }
+        None
+    }
+    /// Returns the block of external ports reserved for the given internal address, if the NAT
+    /// allocates port blocks, see `port_block_size`.
+    #[inline]
+    pub fn port_block_for(&self, internal_addr: u32) -> Option<&RangeInclusive<u16>> {
+        self.intranet.get(&internal_addr)?.port_block.as_ref()
+    }
+    /// Returns the ranges of ports a new mapping for the given internal address and port may draw
+    /// from when its port is not preserved.
+    fn dynamic_port_ranges(&self, internal_addr: u32, src_port: u16) -> &[RangeInclusive<u16>] {
+        if let Some(block) = self.port_block_for(internal_addr) {
+            std::slice::from_ref(block)
+        } else if self.flags & NO_WELL_KNOWN_PRESERVATION == 0 && port_ranges::WELL_KNOWN.contains(&src_port) {
+            &WELL_KNOWN_PORTS
+        } else {
+            &self.assigned_external_ports
+        }
+    }
+    /// Returns every external port currently allocated to a mapping from the given internal
+    /// address, across all external addresses, in ascending order. Mappings that have timed out
+    /// but have not yet been removed are included.
+    pub fn external_ports_for(&self, internal_addr: u32) -> Vec<u16> {
+        let mut ports: Vec<u16> = self.map[..self.external_addresses_len]
+            .iter()
+            .flatten()
+            .filter(|route| route.internal_addr == internal_addr)
+            .map(|route| route.external_port)
+            .collect();
+        ports.sort_unstable();
+        ports.dedup();
+        ports
+    }
+    /// Returns the number of ports in the NAT's `external_dynamic_ports` that are free on the given
+    /// external address, meaning no mapping is using them and they are neither statically forwarded
+    /// nor excluded. Mappings that have timed out but have not yet been removed are considered to be
+    /// using their ports, see `advance_time`. Ports reserved to a host's port block are free if
+    /// that host is not using them. This is useful for visualizing port space fragmentation.
+    ///
+    /// Return value is 0 if `external_addr` is not one of this NAT's external addresses.
+    pub fn free_port_count(&self, external_addr: u32) -> usize {
+        let Some((address_idx, in_use)) = self.ports_in_use(external_addr) else {
+            return 0;
+        };
+        self.assigned_external_ports
+            .iter()
+            .flat_map(|range| range.clone())
+            .filter(|port| !in_use.contains(port) && !self.is_unallocatable(address_idx, *port))
+            .count()
+    }
+    /// Returns the lowest port in the NAT's `external_dynamic_ports` at or after `from` that is free
+    /// on the given external address, see `free_port_count`.
+    ///
+    /// Return value is `None` if there is no such port, or if `external_addr` is not one of this
+    /// NAT's external addresses.
+    pub fn next_free_port(&self, external_addr: u32, from: u16) -> Option<u16> {
+        let (address_idx, in_use) = self.ports_in_use(external_addr)?;
+        self.assigned_external_ports
+            .iter()
+            .filter(|range| *range.end() >= from)
+            .flat_map(|range| from.max(*range.start())..=*range.end())
+            .find(|port| !in_use.contains(port) && !self.is_unallocatable(address_idx, *port))
+    }
+    /// Returns the index of the given external address and every port its mappings are using.
+    fn ports_in_use(&self, external_addr: u32) -> Option<(usize, HashSet<u16>)> {
+        let address_idx = self.external_addresses().iter().position(|addr| *addr == external_addr)?;
+        Some((address_idx, self.map[address_idx].iter().map(|route| route.external_port).collect()))
+    }
+    /// Statically forwards all inbound packets addressed to `external_addr` and `external_port` to
+    /// `internal_addr` and `internal_port`, regardless of filtering. This emulates user configured
+    /// port forwarding or a port opened through UPnP.
+    ///
+    /// If `forwarded_inbound_creates_mapping` is true a forwarded inbound packet will create a
+    /// mapping for its sender, so replies from the internal host will leave through the forwarded
+    /// port. The NAT will never allocate a forwarded port to any other mapping.
+    ///
+    /// If the external port is already forwarded, or is in use by live mappings of hosts other
+    /// than `internal_addr` and `internal_port`, the conflict is resolved according to `policy`.
+    /// `current_time` is used to determine which mappings are still live.
+    ///
+    /// Return value is `Err` only if `policy` is `ForwardConflictPolicy::Reject` and there was a
+    /// conflict, in which case the NAT is left unchanged.
+    pub fn add_static_mapping(
+        &mut self,
+        external_addr: u32,
+        external_port: u16,
+        internal_addr: u32,
+        internal_port: u16,
+        policy: ForwardConflictPolicy,
+        current_time: i64,
+    ) -> Result<(), ForwardConflict> {
+        let Some(addr_idx) = self.external_addresses().iter().position(|addr| *addr == external_addr) else {
+            debug_assert!(false, "The external_addr must be one of this NAT's external addresses");
+            return Ok(());
+        };
+        let expiry = self.expiry_at(addr_idx, current_time);
+        let conflicts = |route: &Entry| {
+            route.external_port == external_port
+                && !(route.internal_addr == internal_addr && route.internal_port == internal_port)
+                && !route.is_expired(expiry)
+        };
+        match policy {
+            ForwardConflictPolicy::Evict => {
+                self.remove_routes(addr_idx, RemovalReason::Forwarded, current_time, conflicts);
+            }
+            ForwardConflictPolicy::Reject => {
+                if let Some(&(internal_addr, internal_port)) = self.static_mappings.get(&(external_addr, external_port)) {
+                    return Err(ForwardConflict::AlreadyForwarded { internal_addr, internal_port });
+                }
+                let mappings = self.map[addr_idx].iter().filter(|route| conflicts(route)).count();
+                if mappings > 0 {
+                    return Err(ForwardConflict::InUse { mappings });
+                }
+            }
+            ForwardConflictPolicy::Shadow => {}
+        }
+        self.static_mappings
+            .insert((external_addr, external_port), (internal_addr, internal_port));
+        Ok(())
+    }
+    /// Removes a port forward created by `add_static_mapping`, returning the internal address and
+    /// port it forwarded to. Mappings created by forwarded inbound packets are left to time out.
+    pub fn remove_static_mapping(&mut self, external_addr: u32, external_port: u16) -> Option<(u32, u16)> {
+        self.static_mappings.remove(&(external_addr, external_port))
+    }
+    /// Permanently disables filtering for all inbound packets sent from `external_addr`, as if
+    /// they were all received with `disable_filtering` set. Packets from this address are still
+    /// only delivered through existing mappings. This emulates the allow-list of trusted sources
+    /// found on many enterprise security appliances.
+    pub fn add_inbound_whitelist(&mut self, external_addr: u32) {
+        self.inbound_whitelist.insert(external_addr);
+    }
+    /// Removes an address added with `add_inbound_whitelist`, returning whether it was present.
+    pub fn remove_inbound_whitelist(&mut self, external_addr: u32) -> bool {
+        self.inbound_whitelist.remove(&external_addr)
     }
     #[inline]
+    fn is_statically_mapped(&self, external_address_idx: usize, external_port: u16) -> bool {
+        !self.static_mappings.is_empty()
+            && self
+                .static_mappings
+                .contains_key(&(self.external_addresses[external_address_idx], external_port))
+    }
+    /// Whether the given internal address and port has any mapping that has not timed out.
+    fn has_live_mapping(&self, internal_addr: u32, internal_port: u16, current_time: i64) -> bool {
+        self.map[..self.external_addresses_len]
+            .iter()
+            .enumerate()
+            .any(|(address_idx, routing_table)| {
+                let expiry = self.expiry_at(address_idx, current_time);
+                routing_table
+                    .iter()
+                    .any(|route| route.internal_addr == internal_addr && route.internal_port == internal_port && !route.is_expired(expiry))
+            })
+    }
+    /// Whether the given address is on the NAT's internal network, see `internal_subnets`.
+    #[inline]
+    fn is_internal(&self, addr: u32) -> bool {
+        self.assigned_internal_addresses.contains(&addr) || self.internal_subnets.iter().any(|subnet| subnet.contains(&addr))
+    }
+    #[inline]
+    fn is_excluded(&self, external_port: u16) -> bool {
+        self.excluded_external_ports.iter().any(|range| range.contains(&external_port))
+    }
+    /// Whether the given external port may not be allocated to a new mapping because it is either
+    /// statically forwarded or excluded.
+    #[inline]
+    fn is_unallocatable(&self, external_address_idx: usize, external_port: u16) -> bool {
+        self.is_statically_mapped(external_address_idx, external_port) || self.is_excluded(external_port)
+    }
+    /// Removes a host from the NAT's internal network, along with every mapping it held, so no
+    /// further inbound packets can be delivered to it through those mappings. Port forwards to the
+    /// host created with `add_static_mapping` are configuration and are left in place.
     pub fn remove_internal_address(&mut self, internal_addr: u32) {
         self.intranet.remove(&internal_addr);
+        for address_idx in 0..self.external_addresses_len {
+            self.remove_routes(address_idx, RemovalReason::HostRemoved, self.latest_time, |route| {
+                route.internal_addr == internal_addr
+            });
+        }
+    }
+    /// Removes every mapping in the routing table of the given external address that matches
+    /// `remove`, recording each one in the removal history.
+    fn remove_routes(&mut self, address_idx: usize, reason: RemovalReason, current_time: i64, mut remove: impl FnMut(&Entry) -> bool) {
+        let external_addr = self.external_addresses[address_idx];
+        let (history, capacity) = (&mut self.removal_history, self.removal_history_size);
+        let routing_table = &mut self.map[address_idx];
+        let len_before = routing_table.len();
+        let mut removed_ports = Vec::new();
+        routing_table.retain(|route| {
+            if remove(route) {
+                record_removal(history, capacity, route.snapshot(external_addr), reason, current_time);
+                removed_ports.push(route.external_port);
+                return false;
+            }
+            true
+        });
+        self.map_cur_size -= len_before - routing_table.len();
+        for port in removed_ports {
+            self.mark_if_freed(address_idx, port, current_time);
+        }
+    }
+    /// Removes the mapping at index `i` of the routing table of the given external address by
+    /// swapping the last mapping into its place, and records it in the removal history.
+    fn swap_remove_route(&mut self, address_idx: usize, i: usize, reason: RemovalReason, current_time: i64) {
+        let route = self.map[address_idx].swap_remove(i);
+        self.map_cur_size -= 1;
+        let mapping = route.snapshot(self.external_addresses[address_idx]);
+        record_removal(&mut self.removal_history, self.removal_history_size, mapping, reason, current_time);
+        self.mark_if_freed(address_idx, route.external_port, current_time);
     }
+    /// Records that the given port was freed at `current_time` if no mapping is using it anymore.
+    fn mark_if_freed(&mut self, address_idx: usize, port: u16, current_time: i64) {
+        if !self.map[address_idx].iter().any(|route| route.external_port == port) {
+            self.port_free_times[address_idx].insert(port, current_time);
+        }
+    }
+    #[inline(always)]
+    #[allow(clippy::too_many_arguments)]
     fn remap(
         &mut self,
+        flags: u32,
         internal_addr: u32,
         internal_port: u16,
         external_addr: u32,
@@ -216,9 +2592,26 @@ impl<R: RngCore, const M: usize> Nat<R, M> {
         dest_port: u16,
         current_time: i64,
     ) -> DestType {
-        if let Some((dest_addr, dest_port)) = self.receive_external_packet(external_addr, external_port, dest_addr, dest_port, false, current_time) {
+        let mut recipient = None;
+        let routed = self.route_external_packet_with(
+            flags,
+            external_addr,
+            external_port,
+            dest_addr,
+            dest_port,
+            false,
+            current_time,
+            |internal_addr, internal_port| {
+                recipient = Some((internal_addr, internal_port));
+                false
+            },
+        );
+        if routed == Routed::Router && self.router_loopback {
+            return DestType::Router;
+        }
+        if let Some((dest_addr, dest_port)) = recipient {
             // Packet is for an internal recipient. We assume we are doing hairpinning because the caller has already checked `NO_HAIRPINNING`.
-            if self.flags & INTERNAL_ADDRESS_AND_PORT_HAIRPINNING > 0 {
+            if flags & INTERNAL_ADDRESS_AND_PORT_HAIRPINNING > 0 {
                 DestType::Internal {
                     external_src_addr: internal_addr,
                     external_src_port: internal_port,
@@ -243,9 +2636,42 @@ impl<R: RngCore, const M: usize> Nat<R, M> {
             }
         }
     }
-    #[inline]
-    fn select_inet_address(&mut self, paired_addr_idx: Option<usize>, src_port: u16) -> (usize, u16) {
-        if self.flags & NO_PORT_PRESERVATION == 0 {
+    #[inline(always)]
+    #[allow(clippy::too_many_arguments)]
+    fn select_inet_address(
+        &mut self,
+        flags: u32,
+        paired_addr_idx: Option<usize>,
+        internal_addr: u32,
+        src_port: u16,
+        dest_addr: u32,
+        dest_port: u16,
+        current_time: i64,
+    ) -> Option<(usize, u16)> {
+        if let Some((oracle, on_conflict)) = &mut self.port_oracle {
+            let on_conflict = *on_conflict;
+            let port = oracle(InternalFlow {
+                internal_addr,
+                internal_port: src_port,
+                endpoint_addr: dest_addr,
+                endpoint_port: dest_port,
+            });
+            let addr_idx = self.choose_address(paired_addr_idx);
+            let taken = self.is_unallocatable(addr_idx, port) || self.map[addr_idx].iter().any(|route| route.external_port == port);
+            if !taken {
+                self.last_port_preservation = Some(PortPreservation::Oracle);
+                return Some((addr_idx, port));
+            } else if on_conflict == OracleConflict::Drop {
+                return None;
+            }
+        }
+        if flags & NO_PORT_PRESERVATION > 0 && !self.always_preserved_ports.contains(&src_port) {
+            self.last_port_preservation = Some(PortPreservation::Disabled);
+        } else if self.port_block_for(internal_addr).is_some_and(|block| !block.contains(&src_port)) {
+            self.last_port_preservation = Some(PortPreservation::OutsidePortBlock);
+        } else if self.is_excluded(src_port) {
+            self.last_port_preservation = Some(PortPreservation::Excluded);
+        } else {
             let mut addr_perm: [usize; M] = std::array::from_fn(|i| i);
             let mut addr_perm_len = self.external_addresses_len;
             if let Some(idx) = paired_addr_idx {
@@ -254,87 +2680,331 @@ impl<R: RngCore, const M: usize> Nat<R, M> {
                 addr_perm[0] = idx;
                 addr_perm_len = 1;
             } else {
-                // If this NAT has the behavior of "Arbitrary" then we want to randomly
-                // choose which addr to assign to this route.
-                for i in (1..addr_perm_len).rev() {
-                    addr_perm.swap(i, self.rng.next_u64() as usize % (i + 1))
+                match self.arbitrary_pooling_order {
+                    AddressOrder::Random => {
+                        // If this NAT has the behavior of "Arbitrary" then we want to randomly
+                        // choose which addr to assign to this route.
+                        self.shuffle(&mut addr_perm[..addr_perm_len]);
+                    }
+                    AddressOrder::RoundRobin => {
+                        for (i, idx) in addr_perm[..addr_perm_len].iter_mut().enumerate() {
+                            *idx = (self.pooling_cursor + i) % self.external_addresses_len;
+                        }
+                    }
                 }
             }
             'next_addr: for external_address_idx in &addr_perm[..addr_perm_len] {
+                if self.is_statically_mapped(*external_address_idx, src_port) {
+                    continue 'next_addr;
+                }
                 for route in &self.map[*external_address_idx] {
                     if route.external_port == src_port {
                         // This addr and port combination collides so consider something else.
                         continue 'next_addr;
                     }
                 }
-                return (*external_address_idx, src_port);
+                self.last_port_preservation = Some(PortPreservation::Preserved);
+                return Some((*external_address_idx, src_port));
             }
-            if self.flags & PORT_PRESERVATION_OVERLOAD > 0 {
+            if self.is_statically_mapped(addr_perm[0], src_port) {
+                // Port forwards can never be overloaded or overridden so we must fall back to a
+                // random port.
+                self.last_port_preservation = Some(PortPreservation::Forwarded);
+            } else if flags & PORT_PRESERVATION_OVERLOAD > 0 {
                 // src_port is currently used by all of our IP addresses, so overload that port.
-                return (addr_perm[0], src_port);
-            } else if self.flags & PORT_PRESERVATION_OVERRIDE > 0 {
-                let routing_table = &mut self.map[addr_perm[0]];
-                for i in 0..routing_table.len() {
-                    if routing_table[i].external_port == src_port {
-                        // In port preservation override mode we remove everyone else who is
-                        // using the chosen src_port.
-                        self.map_cur_size -= 1;
-                        routing_table.swap_remove(i);
-                    }
+                self.last_port_preservation = Some(PortPreservation::Overloaded);
+                return Some((addr_perm[0], src_port));
+            } else if flags & PORT_PRESERVATION_OVERRIDE > 0 {
+                // In port preservation override mode we remove everyone else who is
+                // using the chosen src_port.
+                self.remove_routes(addr_perm[0], RemovalReason::Overridden, current_time, |route| {
+                    route.external_port == src_port
+                });
+                self.last_port_preservation = Some(PortPreservation::Overridden);
+                return Some((addr_perm[0], src_port));
+            } else if let Some(resolver) = &mut self.collision_resolver {
+                let addr_idx = addr_perm[0];
+                let mut taken: Vec<u16> = self.map[addr_idx].iter().map(|route| route.external_port).collect();
+                taken.sort_unstable();
+                taken.dedup();
+                let port = resolver(src_port, &taken).filter(|port| taken.binary_search(port).is_err())?;
+                if self.is_unallocatable(addr_idx, port) {
+                    return None;
+                }
+                self.last_port_preservation = Some(PortPreservation::Resolved);
+                return Some((addr_idx, port));
+            } else {
+                self.last_port_preservation = Some(PortPreservation::Collision);
+            }
+            if flags & PORT_PRESERVATION_OVER_PARITY > 0 {
+                let addr_idx = addr_perm[0];
+                let routing_table = &self.map[addr_idx];
+                let nearest_port = self.nearest_port(addr_idx, internal_addr, src_port, |port| {
+                    routing_table.iter().any(|route| route.external_port == port)
+                });
+                if let Some(port) = nearest_port {
+                    return Some((addr_idx, port));
                 }
-                return (addr_perm[0], src_port);
             }
         }
+        if flags & DETERMINISTIC_PORT_HASH > 0 {
+            return self.select_hashed_inet_address(flags, paired_addr_idx, internal_addr, src_port, dest_addr, dest_port, current_time);
+        } else if self.port_allocation == PortAllocation::Sequential {
+            return self.select_sequential_inet_address(flags, paired_addr_idx, internal_addr, src_port, current_time);
+        } else if self.port_allocation == PortAllocation::LeastRecentlyFreed {
+            let addr_idx = self.choose_address(paired_addr_idx);
+            let routing_table = &self.map[addr_idx];
+            let port = self.least_recently_freed_port(flags, addr_idx, internal_addr, src_port, |port| {
+                routing_table.iter().any(|route| route.external_port == port)
+            })?;
+            self.remove_routes(addr_idx, RemovalReason::Overridden, current_time, |route| route.external_port == port);
+            return Some((addr_idx, port));
+        }
         // If we can't do any port preservation we have to randomly generate the port and address
         let mut random_addr;
         let mut random_port;
-        let mut attempt_until_force = 32;
+        let mut attempt_until_force = RANDOM_PORT_ATTEMPTS;
         'regen: loop {
             attempt_until_force -= 1;
-            random_addr = paired_addr_idx.unwrap_or_else(|| {
-                if M == 1 {
-                    0
-                } else {
-                    (self.rng.next_u32() % self.external_addresses_len as u32) as usize
-                }
-            });
-            let range = if self.flags & NO_WELL_KNOWN_PRESERVATION == 0 && port_ranges::WELL_KNOWN.contains(&src_port) {
-                &port_ranges::WELL_KNOWN
-            } else {
-                &self.assigned_external_ports
-            };
-            random_port = (self.rng.next_u32() % range.len() as u32) as u16 + range.start();
-            if self.flags & NO_PORT_PARITY == 0 {
+            random_addr = self.choose_address(paired_addr_idx);
+            let offset = self.draw_port_offset(ports_len(self.dynamic_port_ranges(internal_addr, src_port)));
+            let ranges = self.dynamic_port_ranges(internal_addr, src_port);
+            random_port = nth_port(ranges, offset);
+            if flags & NO_PORT_PARITY == 0 {
                 // Force the port to have the same parity as the src_port.
-                random_port = (random_port & !1u16) | (src_port & 1u16);
+                random_port = match_parity(ranges, random_port, src_port);
+                if random_port & 1 != src_port & 1 && attempt_until_force > 0 {
+                    // The port is alone in its range, so draw another.
+                    continue 'regen;
+                }
             }
-            let routing_table = &mut self.map[random_addr];
-            for i in 0..routing_table.len() {
-                if routing_table[i].external_port == random_port {
-                    if attempt_until_force > 0 {
-                        continue 'regen;
-                    }
-                    // Remove this mapping so our random port is unique.
-                    self.map_cur_size -= 1;
-                    routing_table.swap_remove(i);
-                    break;
+            if self.is_unallocatable(random_addr, random_port) {
+                if attempt_until_force > 0 {
+                    continue 'regen;
                 }
+                // Most of the dynamic ports are forwarded or excluded, so look for one that is not.
+                random_port = self.scan_allocatable_port(random_addr, internal_addr, src_port)?;
+            }
+            if let Some(i) = self.map[random_addr].iter().position(|route| route.external_port == random_port) {
+                if attempt_until_force > 0 {
+                    continue 'regen;
+                }
+                // Remove this mapping so our random port is unique.
+                self.swap_remove_route(random_addr, i, RemovalReason::Overridden, current_time);
             }
             break;
         }
-        return (random_addr, random_port);
+        Some((random_addr, random_port))
     }
-    /// * `internal_src_addr`: The source address of the sender on the NAT's internal network.
-    /// * `internal_src_port`: The source port of the sender on the NAT's internal network.
-    /// * `external_dest_addr`: The destination address of the receiver on either the internal or
-    ///   external network.
-    /// * `external_dest_port`: The destination port of the receiver on either the internal or the
-    ///   external network.
-    /// * `current_time`: A timestamp of the packet's arrival to the NAT, used to process timeouts.
-    ///
-    /// Return value is `DestType::Drop` if the packet would be dropped by the NAT, this happens if
-    /// the packet was destined for an internal recipient that could not be routed to.
-    ///
+    /// Finds an allocatable dynamic port for a new mapping of the given sender on the given external
+    /// address once every random draw has failed, preferring a port no mapping is using. Returns
+    /// `None` if every dynamic port is statically forwarded or excluded.
+    fn scan_allocatable_port(&self, external_address_idx: usize, internal_addr: u32, src_port: u16) -> Option<u16> {
+        let mut allocatable = self
+            .dynamic_port_ranges(internal_addr, src_port)
+            .iter()
+            .flat_map(|range| range.clone())
+            .filter(|port| !self.is_unallocatable(external_address_idx, *port));
+        let first = allocatable.clone().next()?;
+        let routing_table = &self.map[external_address_idx];
+        let unused = allocatable.find(|port| routing_table.iter().all(|route| route.external_port != *port));
+        Some(unused.unwrap_or(first))
+    }
+    /// Chooses the external address a new mapping should use when its port is not preserved.
+    fn choose_address(&mut self, paired_addr_idx: Option<usize>) -> usize {
+        paired_addr_idx.unwrap_or_else(|| {
+            if M == 1 {
+                0
+            } else if self.arbitrary_pooling_order == AddressOrder::RoundRobin {
+                self.pooling_cursor
+            } else {
+                (self.rng.next_u32() % self.external_addresses_len as u32) as usize
+            }
+        })
+    }
+    /// Shuffles the given slice in place with a Fisher-Yates shuffle.
+    ///
+    /// The order in which this draws from the rng is stable, so that a seeded NAT always picks
+    /// the same external addresses: exactly one `next_u64` is drawn for every index `i` from
+    /// `slice.len() - 1` down to 1, and element `i` is swapped with element `draw % (i + 1)`.
+    /// Changing this would silently change the results of seeded simulations.
+    fn shuffle(&mut self, slice: &mut [usize]) {
+        for i in (1..slice.len()).rev() {
+            slice.swap(i, self.rng.next_u64() as usize % (i + 1))
+        }
+    }
+    /// Draws a random offset into a port range of the given length according to the NAT's
+    /// `port_distribution`.
+    fn draw_port_offset(&mut self, range_len: u32) -> u32 {
+        match self.port_distribution {
+            PortDistribution::Uniform => self.rng.next_u32() % range_len,
+            PortDistribution::Window { offset, width } => {
+                let offset = offset as u32 % range_len;
+                let width = (width as u32).clamp(1, range_len - offset);
+                offset + self.rng.next_u32() % width
+            }
+            PortDistribution::BiasedLow { draws } => (0..draws.max(1)).map(|_| self.rng.next_u32() % range_len).min().unwrap(),
+        }
+    }
+    /// Derives the external port of a new mapping from a seeded hash of its internal address and
+    /// port and its endpoint. Collisions are resolved by linearly probing forward through the port
+    /// range, and if every port is taken the mapping using the hashed port is evicted.
+    #[allow(clippy::too_many_arguments)]
+    fn select_hashed_inet_address(
+        &mut self,
+        flags: u32,
+        paired_addr_idx: Option<usize>,
+        internal_addr: u32,
+        src_port: u16,
+        dest_addr: u32,
+        dest_port: u16,
+        current_time: i64,
+    ) -> Option<(usize, u16)> {
+        let addr_idx = self.choose_address(paired_addr_idx);
+        let seed = *self.port_hash_seed.get_or_insert_with(|| self.rng.next_u64());
+        let routing_table = &self.map[addr_idx];
+        let port = self.hashed_port(flags, addr_idx, seed, internal_addr, src_port, dest_addr, dest_port, |port| {
+            routing_table.iter().any(|route| route.external_port == port)
+        })?;
+        self.remove_routes(addr_idx, RemovalReason::Overridden, current_time, |route| route.external_port == port);
+        Some((addr_idx, port))
+    }
+    /// Returns the first port in the hashed probe sequence of a new mapping that is neither
+    /// unallocatable nor `in_use`. If there is no such port the first allocatable port is returned,
+    /// and if every port is unallocatable `None` is returned.
+    #[allow(clippy::too_many_arguments)]
+    fn hashed_port(
+        &self,
+        flags: u32,
+        addr_idx: usize,
+        seed: u64,
+        internal_addr: u32,
+        src_port: u16,
+        dest_addr: u32,
+        dest_port: u16,
+        in_use: impl Fn(u16) -> bool,
+    ) -> Option<u16> {
+        let hash = mix_u64(seed ^ mix_u64(((internal_addr as u64) << 16) | src_port as u64) ^ mix_u64(((dest_addr as u64) << 16) | dest_port as u64));
+        let ranges = self.dynamic_port_ranges(internal_addr, src_port);
+        let range_len = ports_len(ranges) as u64;
+        let port_at = |offset: u64| {
+            let port = nth_port(ranges, ((hash % range_len + offset) % range_len) as u32);
+            if flags & NO_PORT_PARITY == 0 {
+                // Force the port to have the same parity as the src_port.
+                match_parity(ranges, port, src_port)
+            } else {
+                port
+            }
+        };
+        let mut first_allocatable = None;
+        for offset in 0..range_len {
+            let port = port_at(offset);
+            if self.is_unallocatable(addr_idx, port) {
+                continue;
+            }
+            first_allocatable.get_or_insert(port);
+            if !in_use(port) {
+                return Some(port);
+            }
+        }
+        first_allocatable
+    }
+    /// Returns the closest port above `src_port` that is neither unallocatable nor `in_use`,
+    /// regardless of parity, wrapping around the dynamic port range. If there is no such port
+    /// `None` is returned.
+    fn nearest_port(&self, addr_idx: usize, internal_addr: u32, src_port: u16, in_use: impl Fn(u16) -> bool) -> Option<u16> {
+        let ranges = self.dynamic_port_ranges(internal_addr, src_port);
+        let range_len = ports_len(ranges);
+        // The ranges are sorted, so this is the offset of the lowest port above src_port.
+        let start_offset = ranges
+            .iter()
+            .find(|range| *range.end() > src_port)
+            .and_then(|range| port_offset(ranges, (src_port + 1).max(*range.start())))
+            .unwrap_or(0);
+        (0..range_len)
+            .map(|i| nth_port(ranges, (start_offset + i) % range_len))
+            .find(|port| *port != src_port && !self.is_unallocatable(addr_idx, *port) && !in_use(*port))
+    }
+    /// Takes the next port from the internal host's sequential port counter, evicting the mapping
+    /// using it if every port is taken.
+    fn select_sequential_inet_address(
+        &mut self,
+        flags: u32,
+        paired_addr_idx: Option<usize>,
+        internal_addr: u32,
+        src_port: u16,
+        current_time: i64,
+    ) -> Option<(usize, u16)> {
+        let addr_idx = self.choose_address(paired_addr_idx);
+        let routing_table = &self.map[addr_idx];
+        let port = self.sequential_port(flags, addr_idx, internal_addr, src_port, |port| {
+            routing_table.iter().any(|route| route.external_port == port)
+        })?;
+        let ranges = self.dynamic_port_ranges(internal_addr, src_port);
+        let next_port = port_offset(ranges, port).map(|offset| nth_port(ranges, (offset + 1) % ports_len(ranges)));
+        if let Some(host) = self.intranet.get_mut(&internal_addr) {
+            host.next_port = next_port;
+        }
+        self.remove_routes(addr_idx, RemovalReason::Overridden, current_time, |route| route.external_port == port);
+        Some((addr_idx, port))
+    }
+    /// Returns the first port at or after the internal host's sequential port counter that is
+    /// neither unallocatable nor `in_use`. If there is no such port the first allocatable port is
+    /// returned, and if every port is unallocatable `None` is returned.
+    fn sequential_port(&self, flags: u32, addr_idx: usize, internal_addr: u32, src_port: u16, in_use: impl Fn(u16) -> bool) -> Option<u16> {
+        let ranges = self.dynamic_port_ranges(internal_addr, src_port);
+        let range_len = ports_len(ranges);
+        let next_port = self.intranet.get(&internal_addr).and_then(|host| host.next_port);
+        let start_offset = next_port.and_then(|port| port_offset(ranges, port)).unwrap_or(0);
+        let mut first_allocatable = None;
+        for i in 0..range_len {
+            let port = nth_port(ranges, (start_offset + i) % range_len);
+            if flags & NO_PORT_PARITY == 0 && port & 1 != src_port & 1 {
+                continue;
+            }
+            if self.is_unallocatable(addr_idx, port) {
+                continue;
+            }
+            first_allocatable.get_or_insert(port);
+            if !in_use(port) {
+                return Some(port);
+            }
+        }
+        first_allocatable
+    }
+    /// Returns the port that is neither unallocatable nor `in_use` which was freed the longest time
+    /// ago, see `PortAllocation::LeastRecentlyFreed`. If there is no such port the first
+    /// allocatable port is returned, and if every port is unallocatable `None` is returned.
+    fn least_recently_freed_port(&self, flags: u32, addr_idx: usize, internal_addr: u32, src_port: u16, in_use: impl Fn(u16) -> bool) -> Option<u16> {
+        let ranges = self.dynamic_port_ranges(internal_addr, src_port);
+        let free_times = &self.port_free_times[addr_idx];
+        let allocatable = ranges
+            .iter()
+            .flat_map(|range| range.clone())
+            .filter(|port| flags & NO_PORT_PARITY > 0 || port & 1 == src_port & 1)
+            .filter(|port| !self.is_unallocatable(addr_idx, *port));
+        let least_recently_freed = allocatable
+            .clone()
+            .filter(|port| !in_use(*port))
+            .min_by_key(|port| free_times.get(port).copied().unwrap_or(i64::MIN));
+        least_recently_freed.or_else(|| allocatable.clone().next())
+    }
+    /// * `internal_src_addr`: The source address of the sender on the NAT's internal network.
+    /// * `internal_src_port`: The source port of the sender on the NAT's internal network.
+    /// * `external_dest_addr`: The destination address of the receiver on either the internal or
+    ///   external network.
+    /// * `external_dest_port`: The destination port of the receiver on either the internal or the
+    ///   external network.
+    /// * `current_time`: A timestamp of the packet's arrival to the NAT, used to process timeouts.
+    ///
+    /// Return value is `DestType::Drop` if the packet would be dropped by the NAT, this happens if
+    /// the packet was destined for an internal recipient that could not be routed to, see
+    /// `drop_unassigned_internal_destinations`, if the
+    /// packet exceeded the NAT's `rate_limit`, if the packet was lost, see `loss_rate`, if the
+    /// sender has reached its `max_endpoints_per_flow`, if the NAT's port oracle chose a port
+    /// that was taken, see `set_port_oracle`, or if the packet's timestamp went backward, see
+    /// `non_monotonic_time`.
+    ///
     /// Return value is `DestType::External` if the packet was accepted, and needs to be routed to a
     /// recipient on the external network, which is usually the internet. Within the packet is the
     /// translated address and port of the sender. The caller is expected to overwrite the source IP
@@ -343,6 +3013,16 @@ impl<R: RngCore, const M: usize> Nat<R, M> {
     /// Return value is `DestType::Internal` if the packet was accepted, and needs to be routed to a
     /// recipient on the NAT's internal network. The caller is expected to overwrite the source and
     /// destination information contained in the enum onto the packet.
+    ///
+    /// Return value is `DestType::Deferred` if the NAT is full and has `defer_when_full` set, in
+    /// which case the caller may send the packet again later.
+    ///
+    /// Return value is `DestType::Router` if the packet was addressed to one of the NAT's own
+    /// `router_listen_ports` on one of its external addresses, see `router_loopback`.
+    ///
+    /// A packet addressed to one of the NAT's external addresses is hairpinned unless the NAT has
+    /// the `NO_HAIRPINNING` behavior. It is delivered to the internal host whose mapping or port
+    /// forward admits it, then to the NAT's local services, and is otherwise dropped.
     pub fn send_internal_packet(
         &mut self,
         internal_src_addr: u32,
@@ -351,103 +3031,732 @@ impl<R: RngCore, const M: usize> Nat<R, M> {
         external_dest_port: u16,
         current_time: i64,
     ) -> DestType {
-        if self.assigned_internal_addresses.contains(&external_dest_addr) {
+        self.send_outbound(
+            internal_src_addr,
+            internal_src_port,
+            external_dest_addr,
+            external_dest_port,
+            None,
+            0,
+            current_time,
+        )
+    }
+    /// Identical to `send_internal_packet`, except that if the packet is sent to the external
+    /// network its `size` is added to the `address_accounting` of its external source address.
+    pub fn send_internal_packet_sized(
+        &mut self,
+        internal_src_addr: u32,
+        internal_src_port: u16,
+        external_dest_addr: u32,
+        external_dest_port: u16,
+        size: u64,
+        current_time: i64,
+    ) -> DestType {
+        let dest = self.send_internal_packet(internal_src_addr, internal_src_port, external_dest_addr, external_dest_port, current_time);
+        if let Some((external_src_addr, _)) = dest.as_external() {
+            self.account(external_src_addr, Direction::Outbound, 0, size);
+        }
+        dest
+    }
+    /// Identical to `send_internal_packet`, except that the packet is marked with the given
+    /// `traffic_class`, such as its DSCP value. If the NAT has the `CLASS_DEPENDENT_MAPPING`
+    /// behavior, packets of the same flow with different traffic classes use separate mappings.
+    /// `send_internal_packet` sends packets of class 0.
+    pub fn send_internal_packet_with_class(
+        &mut self,
+        internal_src_addr: u32,
+        internal_src_port: u16,
+        external_dest_addr: u32,
+        external_dest_port: u16,
+        traffic_class: u8,
+        current_time: i64,
+    ) -> DestType {
+        self.send_outbound(
+            internal_src_addr,
+            internal_src_port,
+            external_dest_addr,
+            external_dest_port,
+            None,
+            traffic_class,
+            current_time,
+        )
+    }
+    /// Identical to `send_internal_packet`, except that if the packet is accepted and an ALG is
+    /// registered for `external_dest_port`, the ALG is given `payload` and the translation that
+    /// was applied to the packet, see `register_alg`. Packets that are hairpinned are also
+    /// considered outbound.
+    pub fn send_internal_packet_with_payload(
+        &mut self,
+        internal_src_addr: u32,
+        internal_src_port: u16,
+        external_dest_addr: u32,
+        external_dest_port: u16,
+        payload: &mut Vec<u8>,
+        current_time: i64,
+    ) -> DestType {
+        let dest = self.send_internal_packet(internal_src_addr, internal_src_port, external_dest_addr, external_dest_port, current_time);
+        let (translated_src, translated_dest) = match dest {
+            DestType::External { external_src_addr, external_src_port } => {
+                ((external_src_addr, external_src_port), (external_dest_addr, external_dest_port))
+            }
+            DestType::Internal {
+                external_src_addr,
+                external_src_port,
+                internal_dest_addr,
+                internal_dest_port,
+            } => ((external_src_addr, external_src_port), (internal_dest_addr, internal_dest_port)),
+            _ => return dest,
+        };
+        if let Some(alg) = self.algs.get_mut(&external_dest_port) {
+            let translation = PacketTranslation {
+                direction: Direction::Outbound,
+                original_src: (internal_src_addr, internal_src_port),
+                original_dest: (external_dest_addr, external_dest_port),
+                translated_src,
+                translated_dest,
+            };
+            alg(payload, &translation);
+        }
+        dest
+    }
+    /// Identical to `send_internal_packet`, except that the packet's mapping is installed on the
+    /// given `external_port` of the sender's paired external address instead of letting the NAT
+    /// choose. Any existing mapping of this flow to the same endpoint is replaced, and any mapping
+    /// of another flow using that port is evicted. This is useful for replaying
+    /// a captured session exactly, see `set_port_oracle` for scripting every port of a NAT.
+    ///
+    /// The port must be within the NAT's `external_dynamic_ports`, or the sender's port block or
+    /// the well-known port range if those apply, or be the sender's own source port. It must not
+    /// be statically forwarded or excluded. Return value is `DestType::Drop` if it is not, and
+    /// otherwise the same as `send_internal_packet`, except `max_endpoints_per_flow` and the
+    /// NAT's port oracle are ignored.
+    pub fn send_internal_packet_with_port(
+        &mut self,
+        internal_src_addr: u32,
+        internal_src_port: u16,
+        external_dest_addr: u32,
+        external_dest_port: u16,
+        external_port: u16,
+        current_time: i64,
+    ) -> DestType {
+        self.send_outbound(
+            internal_src_addr,
+            internal_src_port,
+            external_dest_addr,
+            external_dest_port,
+            Some(external_port),
+            0,
+            current_time,
+        )
+    }
+    #[allow(clippy::too_many_arguments)]
+    fn send_outbound(
+        &mut self,
+        internal_src_addr: u32,
+        internal_src_port: u16,
+        external_dest_addr: u32,
+        external_dest_port: u16,
+        forced_external_port: Option<u16>,
+        traffic_class: u8,
+        current_time: i64,
+    ) -> DestType {
+        let Some(current_time) = self.observe_time(current_time) else {
+            return DestType::Drop;
+        };
+        self.adapt_flags();
+        self.send_outbound_with_flags(
+            self.flags,
+            internal_src_addr,
+            internal_src_port,
+            external_dest_addr,
+            external_dest_port,
+            forced_external_port,
+            traffic_class,
+            current_time,
+        )
+    }
+    /// Identical to `send_outbound`, except the NAT behaves as if its `flags` were `flags`, and
+    /// `current_time` must already have been observed. This is inlined into `ConstNat` so that
+    /// its flag checks become constants.
+    #[inline(always)]
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn send_outbound_with_flags(
+        &mut self,
+        flags: u32,
+        internal_src_addr: u32,
+        internal_src_port: u16,
+        external_dest_addr: u32,
+        external_dest_port: u16,
+        forced_external_port: Option<u16>,
+        traffic_class: u8,
+        current_time: i64,
+    ) -> DestType {
+        if self.drops_packet(current_time) {
+            return DestType::Drop;
+        }
+        if self.is_internal(external_dest_addr) {
+            if self.drop_unassigned_internal_destinations
+                && self.assigned_internal_addresses.contains(&external_dest_addr)
+                && !self.intranet.contains_key(&external_dest_addr)
+            {
+                return DestType::Drop;
+            }
             return DestType::Internal {
                 external_src_addr: internal_src_addr,
                 external_src_port: internal_src_port,
                 internal_dest_addr: external_dest_addr,
                 internal_dest_port: external_dest_port,
             };
-        } else if self.flags & NO_HAIRPINNING > 0 && self.external_addresses().contains(&external_dest_addr) {
-            return DestType::Drop;
+        } else if self.external_addresses().contains(&external_dest_addr) {
+            let can_hairpin = flags & NO_HAIRPINNING == 0
+                && (flags & HAIRPINNING_REQUIRES_MAPPING == 0 || self.has_live_mapping(internal_src_addr, internal_src_port, current_time));
+            if !can_hairpin {
+                return DestType::Drop;
+            }
         }
-        let mut previous_mapping = if let Some(external_src_addr_idx) = self.intranet.get(&internal_src_addr) {
-            if self.flags & IP_POOLING_BEHAVIOR_ARBITRARY > 0 {
+        let mapping = if let Some(external_port) = forced_external_port {
+            self.force_outbound(
+                flags,
+                internal_src_addr,
+                internal_src_port,
+                external_dest_addr,
+                external_dest_port,
+                external_port,
+                traffic_class,
+                current_time,
+            )
+            .ok_or(Unmapped::Dropped)
+        } else {
+            self.map_outbound(
+                flags,
+                internal_src_addr,
+                internal_src_port,
+                external_dest_addr,
+                external_dest_port,
+                traffic_class,
+                current_time,
+            )
+        };
+        match mapping {
+            Ok((external_address_idx, external_port)) => {
+                let external_addr = self.external_addresses[external_address_idx];
+                let dest = self.remap(
+                    flags,
+                    internal_src_addr,
+                    internal_src_port,
+                    external_addr,
+                    external_port,
+                    external_dest_addr,
+                    external_dest_port,
+                    current_time,
+                );
+                if dest.is_external() {
+                    self.account(external_addr, Direction::Outbound, 1, 0);
+                }
+                dest
+            }
+            Err(Unmapped::Deferred { retry_after }) => DestType::Deferred { retry_after },
+            Err(Unmapped::Dropped) => DestType::Drop,
+        }
+    }
+    /// Advances the NAT's clock to `current_time` and returns the time the packet should be
+    /// processed at according to `non_monotonic_time`, or `None` if the packet should be ignored.
+    pub(crate) fn observe_time(&mut self, current_time: i64) -> Option<i64> {
+        if current_time >= self.latest_time {
+            self.latest_time = current_time;
+            return Some(current_time);
+        }
+        match self.non_monotonic_time {
+            NonMonotonicTime::Trust => Some(current_time),
+            NonMonotonicTime::Clamp => Some(self.latest_time),
+            NonMonotonicTime::Ignore => None,
+        }
+    }
+    /// Decides whether a packet should be dropped regardless of the NAT's mapping state, because of
+    /// either random loss or rate limiting.
+    fn drops_packet(&mut self, current_time: i64) -> bool {
+        // Only draw from the rng when loss is enabled so that seeded runs are unaffected otherwise.
+        if self.loss_rate > 0.0 && (self.rng.next_u32() as f64) < self.loss_rate as f64 * (u32::MAX as f64 + 1.0) {
+            return true;
+        }
+        self.rate_limit.as_mut().is_some_and(|bucket| !bucket.try_consume(current_time))
+    }
+    /// Finds or creates the mapping an outbound packet from the given internal address and port to
+    /// the given endpoint would use. Returns the index of the external address and the external
+    /// port of that mapping, or an error if the internal address is not assigned, has reached its
+    /// `max_endpoints_per_flow`, if the port oracle chose a port that was taken, or if the mapping
+    /// was deferred because the NAT is full, see `defer_when_full`.
+    #[inline(always)]
+    #[allow(clippy::too_many_arguments)]
+    fn map_outbound(
+        &mut self,
+        flags: u32,
+        internal_src_addr: u32,
+        internal_src_port: u16,
+        external_dest_addr: u32,
+        external_dest_port: u16,
+        traffic_class: u8,
+        current_time: i64,
+    ) -> Result<(usize, u16), Unmapped> {
+        let mut previous_mapping = if let Some(host) = self.intranet.get(&internal_src_addr) {
+            // Port blocks are reserved on a single external address so they imply pairing.
+            if flags & IP_POOLING_BEHAVIOR_ARBITRARY > 0 && host.port_block.is_none() {
                 None
             } else {
-                Some((*external_src_addr_idx, None))
+                Some((host.external_addr_idx, None))
             }
         } else {
-            return DestType::Drop;
+            return Err(Unmapped::Dropped);
         };
 
-        let expiry = current_time - self.mapping_timeout;
+        // Removing expired routes only ever moves routes from later in a table, so an index to a
+        // route we have already passed remains valid.
+        let mut reusable_route = None;
+        let mut flow_endpoints = 0;
+        let class_independent = flags & CLASS_DEPENDENT_MAPPING == 0;
         for address_idx in 0..self.external_addresses_len {
-            let routing_table = &mut self.map[address_idx];
+            let expiry = self.expiry_at(address_idx, current_time);
             let mut i = 0;
-            while i < routing_table.len() {
-                let route = &mut routing_table[i];
-                if route.last_used_time < expiry {
-                    self.map_cur_size -= 1;
-                    routing_table.swap_remove(i);
+            while i < self.map[address_idx].len() {
+                if self.map[address_idx][i].is_expired(expiry) {
+                    self.swap_remove_route(address_idx, i, RemovalReason::Expired, current_time);
                     continue;
-                } else if route.internal_addr == internal_src_addr && route.internal_port == internal_src_port {
+                }
+                let route = &mut self.map[address_idx][i];
+                let same_class = class_independent || route.traffic_class == traffic_class;
+                if route.internal_addr == internal_src_addr && route.internal_port == internal_src_port && same_class {
+                    flow_endpoints += 1;
                     let addr_match = route.endpoint_addr == external_dest_addr;
                     let port_match = route.endpoint_port == external_dest_port;
                     let route_ex_port = route.external_port;
                     if addr_match && port_match {
-                        if self.flags & OUTBOUND_REFRESH_BEHAVIOR_FALSE == 0 {
-                            route.last_used_time = current_time;
+                        route.packets += 1;
+                        if flags & OUTBOUND_REFRESH_BEHAVIOR_FALSE == 0 {
+                            route.last_used_time = route.last_used_time.max(current_time);
                         }
-                        let route_ex_addr = self.external_addresses[address_idx];
-                        return self.remap(
-                            internal_src_addr,
-                            internal_src_port,
-                            route_ex_addr,
-                            route_ex_port,
-                            external_dest_addr,
-                            external_dest_port,
-                            current_time,
-                        );
-                    } else if (self.flags & ADDRESS_DEPENDENT_MAPPING == 0 || addr_match) && (self.flags & PORT_DEPENDENT_MAPPING == 0 || port_match)
-                    {
+                        route.filter_last_refreshed = route.filter_last_refreshed.max(current_time);
+                        self.last_port_preservation = Some(PortPreservation::Reused);
+                        return Ok((address_idx, route_ex_port));
+                    } else if (flags & ADDRESS_DEPENDENT_MAPPING == 0 || addr_match) && (flags & PORT_DEPENDENT_MAPPING == 0 || port_match) {
                         previous_mapping.replace((address_idx, Some(route_ex_port)));
+                        reusable_route = Some((address_idx, i));
                     }
                 }
                 i += 1;
             }
         }
+        if self.max_endpoints_per_flow.is_some_and(|max| flow_endpoints >= max) {
+            return Err(Unmapped::Dropped);
+        }
+        if let (EndpointUpdate::UpdateOnSend, Some((address_idx, i))) = (self.endpoint_update, reusable_route) {
+            let route = &mut self.map[address_idx][i];
+            route.endpoint_addr = external_dest_addr;
+            route.endpoint_port = external_dest_port;
+            route.replied = false;
+            route.packets += 1;
+            if flags & OUTBOUND_REFRESH_BEHAVIOR_FALSE == 0 {
+                route.last_used_time = route.last_used_time.max(current_time);
+            }
+            route.filter_last_refreshed = route.filter_last_refreshed.max(current_time);
+            self.last_port_preservation = Some(PortPreservation::Reused);
+            return Ok((address_idx, route.external_port));
+        }
+        if self.defer_when_full && self.map_cur_size >= self.map_max_size {
+            // Every expired mapping was removed above, so waiting is the only way to make room.
+            let soonest_expiry = self.map[..self.external_addresses_len]
+                .iter()
+                .enumerate()
+                .flat_map(|(address_idx, routing_table)| {
+                    let (replied_timeout, unreplied_timeout) = self.timeouts_of(address_idx);
+                    routing_table.iter().map(move |route| {
+                        let timeout = if route.replied {
+                            replied_timeout
+                        } else {
+                            unreplied_timeout
+                        };
+                        route.last_used_time.saturating_add(timeout).saturating_add(1)
+                    })
+                })
+                .min();
+            let retry_after = soonest_expiry.map_or(1, |time| time.saturating_sub(current_time).max(1));
+            return Err(Unmapped::Deferred { retry_after });
+        }
         let (external_address_idx, external_port) = {
             // Attempt to reuse the previous mapping if we can.
             // This allows us to do address pairing and Endpoint-independent mapping.
             if let Some((ex_addr_idx, Some(ex_port))) = previous_mapping {
+                self.last_port_preservation = Some(PortPreservation::Reused);
                 (ex_addr_idx, ex_port)
             } else {
-                self.select_inet_address(previous_mapping.map(|a| a.0), internal_src_port)
+                let selection = self
+                    .select_inet_address(
+                        flags,
+                        previous_mapping.map(|a| a.0),
+                        internal_src_addr,
+                        internal_src_port,
+                        external_dest_addr,
+                        external_dest_port,
+                        current_time,
+                    )
+                    .ok_or(Unmapped::Dropped)?;
+                if self.track_port_allocations {
+                    *self.port_histogram.entry(selection.1).or_default() += 1;
+                }
+                selection
             }
         };
-        let external_addr = self.external_addresses[external_address_idx];
+        if previous_mapping.is_none() {
+            self.pooling_cursor = (external_address_idx + 1) % self.external_addresses_len;
+        }
+        self.insert_entry(
+            external_address_idx,
+            Entry {
+                internal_addr: internal_src_addr,
+                internal_port: internal_src_port,
+                external_port,
+                endpoint_addr: external_dest_addr,
+                endpoint_port: external_dest_port,
+                last_used_time: current_time,
+                created_time: current_time,
+                filter_last_refreshed: current_time,
+                replied: false,
+                packets: 1,
+                traffic_class,
+                inbound_bucket: None,
+            },
+        );
+        Ok((external_address_idx, external_port))
+    }
+    /// Installs a mapping for an outbound packet on the given external port, evicting whatever is
+    /// in its way. Returns the index of the external address and the external port of the mapping,
+    /// or `None` if the internal address is not assigned or the port may not be allocated to it.
+    #[inline(always)]
+    #[allow(clippy::too_many_arguments)]
+    fn force_outbound(
+        &mut self,
+        flags: u32,
+        internal_src_addr: u32,
+        internal_src_port: u16,
+        external_dest_addr: u32,
+        external_dest_port: u16,
+        external_port: u16,
+        traffic_class: u8,
+        current_time: i64,
+    ) -> Option<(usize, u16)> {
+        let external_address_idx = self.intranet.get(&internal_src_addr)?.external_addr_idx;
+        let allowed = external_port == internal_src_port
+            || port_offset(self.dynamic_port_ranges(internal_src_addr, internal_src_port), external_port).is_some();
+        if !allowed || self.is_unallocatable(external_address_idx, external_port) {
+            return None;
+        }
+        let class_independent = flags & CLASS_DEPENDENT_MAPPING == 0;
+        for address_idx in 0..self.external_addresses_len {
+            self.remove_routes(address_idx, RemovalReason::Overridden, current_time, |route| {
+                let same_flow = route.internal_addr == internal_src_addr
+                    && route.internal_port == internal_src_port
+                    && (class_independent || route.traffic_class == traffic_class)
+                    && route.endpoint_addr == external_dest_addr
+                    && route.endpoint_port == external_dest_port;
+                same_flow || (address_idx == external_address_idx && route.external_port == external_port)
+            });
+        }
+        self.insert_entry(
+            external_address_idx,
+            Entry {
+                internal_addr: internal_src_addr,
+                internal_port: internal_src_port,
+                external_port,
+                endpoint_addr: external_dest_addr,
+                endpoint_port: external_dest_port,
+                last_used_time: current_time,
+                created_time: current_time,
+                filter_last_refreshed: current_time,
+                replied: false,
+                packets: 1,
+                traffic_class,
+                inbound_bucket: None,
+            },
+        );
+        Some((external_address_idx, external_port))
+    }
+    /// Adds a new mapping to the routing table of the given external address, evicting other
+    /// mappings according to the `eviction_policy` if the NAT is full. Evictions are recorded at the
+    /// new mapping's creation time.
+    fn insert_entry(&mut self, external_address_idx: usize, entry: Entry) {
+        let now = entry.last_used_time;
         while self.map_cur_size >= self.map_max_size {
-            let idx = if M == 1 {
-                0
-            } else {
-                (self.rng.next_u32() % self.external_addresses_len as u32) as usize
+            let victim = match self.eviction_policy {
+                EvictionPolicy::Random => {
+                    let idx = if M == 1 {
+                        0
+                    } else {
+                        (self.rng.next_u32() % self.external_addresses_len as u32) as usize
+                    };
+                    let len = self.map[idx].len();
+                    (len > 0).then(|| (idx, (self.rng.next_u32() % len as u32) as usize))
+                }
+                EvictionPolicy::LeastPackets => self.min_route_by_key(|route| (route.packets, route.last_used_time)),
+                EvictionPolicy::Fairness { age_weight, idle_weight } => self.min_route_by_key(|route| {
+                    let age = now.saturating_sub(route.created_time);
+                    let idle = now.saturating_sub(route.last_used_time);
+                    let score = age_weight.saturating_mul(age).saturating_sub(idle_weight.saturating_mul(idle));
+                    (score, route.last_used_time)
+                }),
             };
-            let routing_table = &mut self.map[idx];
-            if !routing_table.is_empty() {
-                let idx = (self.rng.next_u32() % routing_table.len() as u32) as usize;
-                self.map_cur_size -= 1;
-                routing_table.swap_remove(idx);
+            if let Some((idx, i)) = victim {
+                self.swap_remove_route(idx, i, RemovalReason::Evicted, now);
             }
         }
         self.map_cur_size += 1;
-        self.map[external_address_idx].push(Entry {
-            internal_addr: internal_src_addr,
-            internal_port: internal_src_port,
-            external_port,
-            endpoint_addr: external_dest_addr,
-            endpoint_port: external_dest_port,
-            last_used_time: current_time,
-        });
-        return self.remap(
-            internal_src_addr,
-            internal_src_port,
-            external_addr,
-            external_port,
-            external_dest_addr,
-            external_dest_port,
-            current_time,
-        );
+        self.map_peak_size = self.map_peak_size.max(self.map_cur_size);
+        self.map[external_address_idx].push(entry);
+    }
+    /// Finds the external address index and routing table index of the mapping with the lowest key.
+    fn min_route_by_key<K: Ord>(&self, key: impl Fn(&Entry) -> K) -> Option<(usize, usize)> {
+        self.map[..self.external_addresses_len]
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, routing_table)| routing_table.iter().enumerate().map(move |(i, route)| (idx, i, route)))
+            .min_by_key(|(_, _, route)| key(route))
+            .map(|(idx, i, _)| (idx, i))
+    }
+    /// Creates or refreshes a mapping exactly as if the internal host had sent an outbound packet
+    /// to the given endpoint, but without producing a packet to route. This is useful for emulating
+    /// relay-assisted connection setup, where a hole is punched ahead of time.
+    /// * `internal_addr`: The address of the host on the NAT's internal network.
+    /// * `internal_port`: The port of the host on the NAT's internal network.
+    /// * `endpoint_addr`: The address of the endpoint on the external network.
+    /// * `endpoint_port`: The port of the endpoint on the external network.
+    /// * `current_time`: A timestamp of when the mapping is opened, used to process timeouts.
+    ///
+    /// Return value is `None` if `internal_addr` has not been assigned by this NAT, if it has
+    /// reached its `max_endpoints_per_flow`, if the NAT's port oracle chose a port that was
+    /// taken, see `set_port_oracle`, or if the NAT is full, see `defer_when_full`.
+    ///
+    /// Return value is `Some((external_addr, external_port))` if the mapping was opened, these are
+    /// the translated address and port the endpoint would observe.
+    pub fn open_mapping(
+        &mut self,
+        internal_addr: u32,
+        internal_port: u16,
+        endpoint_addr: u32,
+        endpoint_port: u16,
+        current_time: i64,
+    ) -> Option<(u32, u16)> {
+        let current_time = self.observe_time(current_time)?;
+        self.adapt_flags();
+        self.open_mapping_with_flags(self.flags, internal_addr, internal_port, endpoint_addr, endpoint_port, current_time)
+    }
+    /// Identical to `open_mapping`, except the NAT behaves as if its `flags` were `flags`, and
+    /// `current_time` must already have been observed. This is inlined into `ConstNat` so that its
+    /// flag checks become constants.
+    #[inline(always)]
+    pub(crate) fn open_mapping_with_flags(
+        &mut self,
+        flags: u32,
+        internal_addr: u32,
+        internal_port: u16,
+        endpoint_addr: u32,
+        endpoint_port: u16,
+        current_time: i64,
+    ) -> Option<(u32, u16)> {
+        self.map_outbound(flags, internal_addr, internal_port, endpoint_addr, endpoint_port, 0, current_time)
+            .ok()
+            .map(|(external_address_idx, external_port)| (self.external_addresses[external_address_idx], external_port))
+    }
+    /// Sends a packet from the given internal host to a server exactly like
+    /// `send_internal_packet`, and returns the source address and port the server observes the
+    /// packet coming from. This is the server reflexive candidate a host would learn from a STUN
+    /// server, which makes this convenient for gathering ICE candidates in a test harness.
+    /// If the server is on the NAT's internal network, the observed source is the one the packet
+    /// is delivered with, which may be untranslated, see `INTERNAL_ADDRESS_AND_PORT_HAIRPINNING`.
+    ///
+    /// Return value is `None` if the packet was dropped, see `send_internal_packet`.
+    pub fn reflexive_address(
+        &mut self,
+        internal_addr: u32,
+        internal_port: u16,
+        server_addr: u32,
+        server_port: u16,
+        current_time: i64,
+    ) -> Option<(u32, u16)> {
+        match self.send_internal_packet(internal_addr, internal_port, server_addr, server_port, current_time) {
+            DestType::External { external_src_addr, external_src_port } | DestType::Internal { external_src_addr, external_src_port, .. } => {
+                Some((external_src_addr, external_src_port))
+            }
+            DestType::Drop | DestType::Deferred { .. } | DestType::Router => None,
+        }
+    }
+    /// Sends a packet from the given internal host to each of the given external endpoints in
+    /// order, and classifies the NAT's mapping behavior from the external addresses and ports the
+    /// endpoints observe, see `reflexive_address`. This only exercises the mapping dimension of
+    /// the NAT, which makes it narrower than a full STUN classification.
+    /// * `endpoints`: The external endpoints to send to. Two endpoints that differ only in their
+    ///   address reveal address dependence, and two that differ only in their port reveal port
+    ///   dependence, so at least three endpoints such as `(a, p)`, `(b, p)` and `(a, q)` are needed
+    ///   to tell every behavior apart. A pair of endpoints that differ in both their address and
+    ///   port, and observe different mappings, is attributed to whichever dimensions no other
+    ///   pair has isolated.
+    ///
+    /// Endpoints whose packets are dropped are ignored. This reports the behavior that was
+    /// actually observed, so a dependent NAT that happens to translate every packet to the same
+    /// port, such as through port preservation, is reported as `EndpointIndependent`.
+    pub fn observe_mapping_behavior(
+        &mut self,
+        internal_addr: u32,
+        internal_port: u16,
+        endpoints: &[(u32, u16)],
+        current_time: i64,
+    ) -> MappingBehavior {
+        let mut observed = Vec::with_capacity(endpoints.len());
+        for &(endpoint_addr, endpoint_port) in endpoints {
+            if let Some(reflexive) = self.reflexive_address(internal_addr, internal_port, endpoint_addr, endpoint_port, current_time) {
+                observed.push(((endpoint_addr, endpoint_port), reflexive));
+            }
+        }
+        let mut address_dependent = None;
+        let mut port_dependent = None;
+        let mut unexplained_change = false;
+        for (i, &(endpoint0, reflexive0)) in observed.iter().enumerate() {
+            for &(endpoint1, reflexive1) in &observed[i + 1..] {
+                let changed = reflexive0 != reflexive1;
+                match (endpoint0.0 != endpoint1.0, endpoint0.1 != endpoint1.1) {
+                    (true, false) => address_dependent = Some(address_dependent.unwrap_or(false) || changed),
+                    (false, true) => port_dependent = Some(port_dependent.unwrap_or(false) || changed),
+                    (true, true) => unexplained_change |= changed,
+                    (false, false) => {}
+                }
+            }
+        }
+        if unexplained_change && address_dependent != Some(true) && port_dependent != Some(true) {
+            address_dependent = address_dependent.or(Some(true));
+            port_dependent = port_dependent.or(Some(true));
+        }
+        match (address_dependent.unwrap_or(false), port_dependent.unwrap_or(false)) {
+            (false, false) => MappingBehavior::EndpointIndependent,
+            (true, false) => MappingBehavior::AddressDependent,
+            (false, true) => MappingBehavior::PortDependent,
+            (true, true) => MappingBehavior::AddressAndPortDependent,
+        }
+    }
+    /// Predicts the external port a subsequent `send_internal_packet` or `open_mapping` with the
+    /// same arguments would be translated to, without creating or modifying any mapping. This is
+    /// intended for tooling that studies port prediction against NATs.
+    /// * `internal_addr`: The address of the host on the NAT's internal network.
+    /// * `internal_port`: The port of the host on the NAT's internal network.
+    /// * `endpoint_addr`: The address of the endpoint on the external network.
+    /// * `endpoint_port`: The port of the endpoint on the external network.
+    /// * `current_time`: A timestamp of when the packet would be sent, used to process timeouts.
+    ///
+    /// Return value is `None` if `internal_addr` has not been assigned by this NAT, if it has
+    /// reached its `max_endpoints_per_flow`, if the port would be drawn at random and so is
+    /// unpredictable, or if the NAT has a port oracle, see `set_port_oracle`, or if the source port
+    /// would collide and the NAT has a collision resolver, see `set_collision_resolver`.
+    ///
+    /// Return value is `Some(external_port)` if an existing mapping would be reused, if the source
+    /// port would be preserved, if the port would be derived from `DETERMINISTIC_PORT_HASH`, or if
+    /// the port would be allocated sequentially or by least recently freed, see `PortAllocation`.
+    /// This may draw the NAT's hash seed from its rng, which would otherwise happen when the first
+    /// hashed port is allocated.
+    pub fn predict_next_port(
+        &mut self,
+        internal_addr: u32,
+        internal_port: u16,
+        endpoint_addr: u32,
+        endpoint_port: u16,
+        current_time: i64,
+    ) -> Option<u16> {
+        let host = self.intranet.get(&internal_addr)?;
+        let paired_addr_idx = if self.flags & IP_POOLING_BEHAVIOR_ARBITRARY > 0 && host.port_block.is_none() {
+            None
+        } else {
+            Some(host.external_addr_idx)
+        };
+        let expiries: [Expiry; M] = std::array::from_fn(|address_idx| self.expiry_at(address_idx, current_time));
+        let mut reused_port = None;
+        let mut flow_endpoints = 0;
+        for (routing_table, &expiry) in self.map[..self.external_addresses_len].iter().zip(&expiries) {
+            for route in routing_table {
+                let other_class = self.flags & CLASS_DEPENDENT_MAPPING > 0 && route.traffic_class != 0;
+                if route.is_expired(expiry) || route.internal_addr != internal_addr || route.internal_port != internal_port || other_class {
+                    continue;
+                }
+                flow_endpoints += 1;
+                let addr_match = route.endpoint_addr == endpoint_addr;
+                let port_match = route.endpoint_port == endpoint_port;
+                if addr_match && port_match {
+                    return Some(route.external_port);
+                } else if (self.flags & ADDRESS_DEPENDENT_MAPPING == 0 || addr_match) && (self.flags & PORT_DEPENDENT_MAPPING == 0 || port_match) {
+                    reused_port = Some(route.external_port);
+                }
+            }
+        }
+        if self.max_endpoints_per_flow.is_some_and(|max| flow_endpoints >= max) {
+            return None;
+        } else if reused_port.is_some() || self.port_oracle.is_some() {
+            return reused_port;
+        }
+        let in_use = |addr_idx: usize, port: u16| {
+            self.map[addr_idx]
+                .iter()
+                .any(|route| route.external_port == port && !route.is_expired(expiries[addr_idx]))
+        };
+        // The first external address the NAT would consider, if it would not be chosen at random.
+        let first_addr_idx = if let Some(idx) = paired_addr_idx {
+            Some(idx)
+        } else if self.external_addresses_len == 1 {
+            Some(0)
+        } else if self.arbitrary_pooling_order == AddressOrder::RoundRobin {
+            Some(self.pooling_cursor)
+        } else {
+            None
+        };
+        let preservable = self.port_block_for(internal_addr).is_none_or(|block| block.contains(&internal_port)) && !self.is_excluded(internal_port);
+        if (self.flags & NO_PORT_PRESERVATION == 0 || self.always_preserved_ports.contains(&internal_port)) && preservable {
+            let candidates = paired_addr_idx.map_or(0..self.external_addresses_len, |idx| idx..idx + 1);
+            let mut free = candidates.clone().filter(|idx| !self.is_statically_mapped(*idx, internal_port));
+            if free.any(|idx| !in_use(idx, internal_port)) {
+                return Some(internal_port);
+            }
+            let forwarded = match first_addr_idx {
+                Some(idx) => self.is_statically_mapped(idx, internal_port),
+                None if candidates.clone().all(|idx| self.is_statically_mapped(idx, internal_port)) => true,
+                None if candidates.clone().all(|idx| !self.is_statically_mapped(idx, internal_port)) => false,
+                None => return None,
+            };
+            if !forwarded && self.flags & (PORT_PRESERVATION_OVERLOAD | PORT_PRESERVATION_OVERRIDE) > 0 {
+                return Some(internal_port);
+            } else if !forwarded && self.collision_resolver.is_some() {
+                return None;
+            }
+            if self.flags & PORT_PRESERVATION_OVER_PARITY > 0 {
+                let addr_idx = first_addr_idx?;
+                if let Some(port) = self.nearest_port(addr_idx, internal_addr, internal_port, |port| in_use(addr_idx, port)) {
+                    return Some(port);
+                }
+            }
+        }
+        if self.flags & DETERMINISTIC_PORT_HASH > 0 {
+            let addr_idx = first_addr_idx?;
+            let seed = *self.port_hash_seed.get_or_insert_with(|| self.rng.next_u64());
+            return self.hashed_port(
+                self.flags,
+                addr_idx,
+                seed,
+                internal_addr,
+                internal_port,
+                endpoint_addr,
+                endpoint_port,
+                |port| in_use(addr_idx, port),
+            );
+        } else if self.port_allocation == PortAllocation::Sequential {
+            let addr_idx = first_addr_idx?;
+            return self.sequential_port(self.flags, addr_idx, internal_addr, internal_port, |port| in_use(addr_idx, port));
+        } else if self.port_allocation == PortAllocation::LeastRecentlyFreed {
+            let addr_idx = first_addr_idx?;
+            return self.least_recently_freed_port(self.flags, addr_idx, internal_addr, internal_port, |port| in_use(addr_idx, port));
+        }
+        None
     }
     /// * `external_src_addr`: The source address of the sender on the external network.
     /// * `external_src_port`: The source port of the sender on the external network.
@@ -456,17 +3765,20 @@ impl<R: RngCore, const M: usize> Nat<R, M> {
     /// * `external_dest_port`: The translated destination port of the receiver on the external
     ///   network.
     /// * `disable_filtering`: If true the NAT will disable its firewall for this one packet.
-    ///    Certain NATs will read IP payloads and disable filtering if the packet is from a
-    ///    permitted protocol like ICMP. It is up to the caller to emulate this behavior if they wish.
+    ///   Certain NATs will read IP payloads and disable filtering if the packet is from a
+    ///   permitted protocol like ICMP. It is up to the caller to emulate this behavior if they wish.
     /// * `current_time`: A timestamp of the packet's arrival to the NAT, used to process timeouts.
     ///
     /// Return value is `None` if the packet would be dropped by the NAT, either because there is no
-    /// recipient with the specified external dest_addr and dest_port, or because the packet was
-    /// actively filtered out by a firewall.
+    /// recipient with the specified external dest_addr and dest_port, because the packet was
+    /// actively filtered out by a firewall, because it exceeded the NAT's `rate_limit` or its
+    /// mapping's `inbound_mapping_rate_limit`, because it was lost, see `loss_rate`, or because its timestamp went backward, see `non_monotonic_time`.
     ///
     /// Return value is `Some((internal_dest_addr, internal_dest_port))` if the packet was accepted,
     /// The caller must overwrite the `external_dest_addr` and `external_dest_port` fields of the
-    /// packet with the returned `internal_dest_addr` and `internal_dest_port` values.
+    /// packet with the returned `internal_dest_addr` and `internal_dest_port` values. If the NAT has
+    /// the `INBOUND_SOURCE_PORT_REWRITE` behavior, use `receive_external_packet_dest` to also learn
+    /// the rewritten source of the packet.
     pub fn receive_external_packet(
         &mut self,
         external_src_addr: u32,
@@ -476,6 +3788,354 @@ impl<R: RngCore, const M: usize> Nat<R, M> {
         disable_filtering: bool,
         current_time: i64,
     ) -> Option<(u32, u16)> {
+        self.receive_external_packet_dest(
+            external_src_addr,
+            external_src_port,
+            external_dest_addr,
+            external_dest_port,
+            disable_filtering,
+            current_time,
+        )
+        .as_internal()
+    }
+    /// Identical to `receive_external_packet`, except packets addressed to the NAT's own
+    /// `router_listen_ports` are distinguished from dropped packets.
+    ///
+    /// Return value is `InboundDestType::Drop` if the packet would be dropped by the NAT, see
+    /// `receive_external_packet`.
+    ///
+    /// Return value is `InboundDestType::Router` if the packet should be delivered to the NAT's own
+    /// local services, see `router_listen_ports`.
+    ///
+    /// Return value is `InboundDestType::Unreachable` if the NAT has `icmp_unreachable` set, and
+    /// the packet was addressed to the NAT but was not admitted by any mapping or port forward.
+    /// Packets that are lost or rate limited are still dropped.
+    ///
+    /// Return value is `InboundDestType::Internal` if the packet was accepted, and needs to be
+    /// routed to a recipient on the NAT's internal network. The caller must overwrite the
+    /// destination of the packet with the contained `internal_dest_addr` and `internal_dest_port`,
+    /// and the source of the packet with the contained `internal_src_addr` and `internal_src_port`.
+    pub fn receive_external_packet_dest(
+        &mut self,
+        external_src_addr: u32,
+        external_src_port: u16,
+        external_dest_addr: u32,
+        external_dest_port: u16,
+        disable_filtering: bool,
+        current_time: i64,
+    ) -> InboundDestType {
+        let Some(current_time) = self.observe_time(current_time) else {
+            return InboundDestType::Drop;
+        };
+        self.adapt_flags();
+        self.receive_external_packet_with_flags(
+            self.flags,
+            external_src_addr,
+            external_src_port,
+            external_dest_addr,
+            external_dest_port,
+            disable_filtering,
+            current_time,
+        )
+    }
+    /// Identical to `receive_external_packet_dest`, except the NAT behaves as if its `flags` were
+    /// `flags`, and `current_time` must already have been observed. This is inlined into
+    /// `ConstNat` so that its flag checks become constants.
+    #[inline(always)]
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn receive_external_packet_with_flags(
+        &mut self,
+        flags: u32,
+        external_src_addr: u32,
+        external_src_port: u16,
+        external_dest_addr: u32,
+        external_dest_port: u16,
+        disable_filtering: bool,
+        current_time: i64,
+    ) -> InboundDestType {
+        if self.drops_packet(current_time) {
+            return InboundDestType::Drop;
+        }
+        let mut recipient = None;
+        let routed = self.route_external_packet_with(
+            flags,
+            external_src_addr,
+            external_src_port,
+            external_dest_addr,
+            external_dest_port,
+            disable_filtering,
+            current_time,
+            |internal_addr, internal_port| {
+                recipient = Some((internal_addr, internal_port));
+                false
+            },
+        );
+        match (routed, recipient) {
+            (Routed::Router, _) => InboundDestType::Router,
+            (Routed::Unadmitted, _) if self.icmp_unreachable => InboundDestType::Unreachable,
+            (_, Some((internal_dest_addr, internal_dest_port))) => {
+                self.account(external_dest_addr, Direction::Inbound, 1, 0);
+                let (internal_src_addr, internal_src_port) = self.inbound_source(flags, external_src_addr, external_src_port);
+                InboundDestType::Internal {
+                    internal_dest_addr,
+                    internal_dest_port,
+                    internal_src_addr,
+                    internal_src_port,
+                }
+            }
+            (_, None) => InboundDestType::Drop,
+        }
+    }
+    /// Identical to `receive_external_packet_dest`, except that if the packet is delivered to the
+    /// internal network its `size` is added to the `address_accounting` of `external_dest_addr`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn receive_external_packet_sized(
+        &mut self,
+        external_src_addr: u32,
+        external_src_port: u16,
+        external_dest_addr: u32,
+        external_dest_port: u16,
+        disable_filtering: bool,
+        size: u64,
+        current_time: i64,
+    ) -> InboundDestType {
+        let dest = self.receive_external_packet_dest(
+            external_src_addr,
+            external_src_port,
+            external_dest_addr,
+            external_dest_port,
+            disable_filtering,
+            current_time,
+        );
+        if dest.is_internal() {
+            self.account(external_dest_addr, Direction::Inbound, 0, size);
+        }
+        dest
+    }
+    /// Identical to `receive_external_packet_dest`, except that if the packet is delivered to the
+    /// internal network and an ALG is registered for `external_src_port`, the ALG is given
+    /// `payload` and the translation that was applied to the packet, see `register_alg`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn receive_external_packet_with_payload(
+        &mut self,
+        external_src_addr: u32,
+        external_src_port: u16,
+        external_dest_addr: u32,
+        external_dest_port: u16,
+        disable_filtering: bool,
+        payload: &mut Vec<u8>,
+        current_time: i64,
+    ) -> InboundDestType {
+        let dest = self.receive_external_packet_dest(
+            external_src_addr,
+            external_src_port,
+            external_dest_addr,
+            external_dest_port,
+            disable_filtering,
+            current_time,
+        );
+        if let (Some((internal_dest_addr, internal_dest_port, internal_src_addr, internal_src_port)), Some(alg)) =
+            (dest.as_internal_with_source(), self.algs.get_mut(&external_src_port))
+        {
+            let translation = PacketTranslation {
+                direction: Direction::Inbound,
+                original_src: (external_src_addr, external_src_port),
+                original_dest: (external_dest_addr, external_dest_port),
+                translated_src: (internal_src_addr, internal_src_port),
+                translated_dest: (internal_dest_addr, internal_dest_port),
+            };
+            alg(payload, &translation);
+        }
+        dest
+    }
+    /// Identical to `send_internal_packet`, except the translated packet is pushed to `sink`
+    /// instead of being returned.
+    pub fn route_internal_packet_into(&mut self, packet: Packet, current_time: i64, sink: &mut dyn PacketSink) {
+        let dest = self.send_internal_packet(packet.src_addr, packet.src_port, packet.dest_addr, packet.dest_port, current_time);
+        match dest {
+            DestType::External { external_src_addr, external_src_port } => sink.deliver_external(Packet {
+                src_addr: external_src_addr,
+                src_port: external_src_port,
+                ..packet
+            }),
+            DestType::Internal {
+                external_src_addr,
+                external_src_port,
+                internal_dest_addr,
+                internal_dest_port,
+            } => sink.deliver_internal(Packet {
+                src_addr: external_src_addr,
+                src_port: external_src_port,
+                dest_addr: internal_dest_addr,
+                dest_port: internal_dest_port,
+            }),
+            DestType::Drop => sink.drop(packet),
+            DestType::Deferred { retry_after } => sink.defer(packet, retry_after),
+            DestType::Router => sink.deliver_router(packet),
+        }
+    }
+    /// Identical to `receive_external_packet_dest`, except the translated packet is pushed to
+    /// `sink` instead of being returned.
+    pub fn route_external_packet_into(&mut self, packet: Packet, disable_filtering: bool, current_time: i64, sink: &mut dyn PacketSink) {
+        let dest = self.receive_external_packet_dest(
+            packet.src_addr,
+            packet.src_port,
+            packet.dest_addr,
+            packet.dest_port,
+            disable_filtering,
+            current_time,
+        );
+        match dest {
+            InboundDestType::Internal {
+                internal_dest_addr,
+                internal_dest_port,
+                internal_src_addr,
+                internal_src_port,
+            } => sink.deliver_internal(Packet {
+                src_addr: internal_src_addr,
+                src_port: internal_src_port,
+                dest_addr: internal_dest_addr,
+                dest_port: internal_dest_port,
+            }),
+            InboundDestType::Router => sink.deliver_router(packet),
+            InboundDestType::Unreachable => sink.reject(packet),
+            InboundDestType::Drop => sink.drop(packet),
+        }
+    }
+    /// Returns the source an internal host observes a delivered inbound packet coming from, see
+    /// `INBOUND_SOURCE_PORT_REWRITE`.
+    #[inline(always)]
+    fn inbound_source(&mut self, flags: u32, external_src_addr: u32, external_src_port: u16) -> (u32, u16) {
+        if flags & INBOUND_SOURCE_PORT_REWRITE == 0 {
+            return (external_src_addr, external_src_port);
+        }
+        let seed = *self.port_hash_seed.get_or_insert_with(|| self.rng.next_u64());
+        let hash = mix_u64(seed ^ mix_u64(((external_src_addr as u64) << 16) | external_src_port as u64));
+        let ranges = &self.assigned_external_ports;
+        let port = nth_port(ranges, (hash % ports_len(ranges) as u64) as u32);
+        (external_src_addr, port)
+    }
+    /// Identical to `receive_external_packet`, except the packet is delivered to every internal
+    /// host it may be delivered to rather than an arbitrary one of them. Multiple hosts may share
+    /// an external port when the NAT has the `PORT_PRESERVATION_OVERLOAD` behavior, in which case
+    /// `receive_external_packet` only delivers to one of them. This is useful for reasoning about
+    /// that ambiguity explicitly.
+    ///
+    /// Return value is every distinct `(internal_dest_addr, internal_dest_port)` the packet was
+    /// delivered to, which is empty if the packet was dropped.
+    pub fn receive_external_packet_all(
+        &mut self,
+        external_src_addr: u32,
+        external_src_port: u16,
+        external_dest_addr: u32,
+        external_dest_port: u16,
+        disable_filtering: bool,
+        current_time: i64,
+    ) -> Vec<(u32, u16)> {
+        let mut recipients = Vec::new();
+        let Some(current_time) = self.observe_time(current_time) else {
+            return recipients;
+        };
+        self.adapt_flags();
+        if self.drops_packet(current_time) {
+            return recipients;
+        }
+        self.route_external_packet_with(
+            self.flags,
+            external_src_addr,
+            external_src_port,
+            external_dest_addr,
+            external_dest_port,
+            disable_filtering,
+            current_time,
+            |internal_addr, internal_port| {
+                if !recipients.contains(&(internal_addr, internal_port)) {
+                    recipients.push((internal_addr, internal_port));
+                }
+                true
+            },
+        );
+        if !recipients.is_empty() {
+            self.account(external_dest_addr, Direction::Inbound, 1, 0);
+        }
+        recipients
+    }
+    /// Returns whether an inbound packet from the given source to the given external address and
+    /// port would currently be delivered to an internal host, honoring filtering and timeouts.
+    /// Unlike `receive_external_packet` this does not deliver the packet, refresh any timeouts,
+    /// consume from the `rate_limit`, or otherwise modify the NAT, so it can be used to probe the
+    /// NAT's filtering state non-destructively.
+    pub fn is_inbound_open(&self, external_addr: u32, external_port: u16, from_addr: u32, from_port: u16, current_time: i64) -> bool {
+        let Some(dest_address_idx) = self.external_addresses().iter().position(|addr| *addr == external_addr) else {
+            return false;
+        };
+        if self.static_mappings.contains_key(&(external_addr, external_port)) {
+            return true;
+        }
+        let expiry = self.expiry_at(dest_address_idx, current_time);
+        let filter_expiry = self.filter_expiry_time(current_time);
+        let whitelisted = self.inbound_whitelist.contains(&from_addr);
+        let mut port_in_use = false;
+        for route in &self.map[dest_address_idx] {
+            if !route.is_expired(expiry) && route.external_port == external_port {
+                if whitelisted || route.accepts(self.flags, from_addr, from_port, filter_expiry) {
+                    return true;
+                }
+                port_in_use = true;
+            }
+        }
+        // Ports the NAT listens on itself are never forwarded to the DMZ host.
+        self.dmz_host.is_some() && !port_in_use && !self.router_listen_ports.contains(&external_port)
+    }
+    /// Returns whether an outbound packet from the given internal address and port to a new
+    /// destination would currently reuse the external address and port of one of its existing
+    /// mappings, instead of allocating a new one. This is true if the NAT has endpoint-independent
+    /// mapping behavior and the flow has a mapping that has not timed out. Applications use this
+    /// to decide whether a port learned from one peer, such as a STUN server, can be given to
+    /// another peer for hole punching. Like `is_inbound_open` this does not modify the NAT.
+    pub fn is_mapping_reusable(&self, internal_addr: u32, internal_port: u16, current_time: i64) -> bool {
+        self.flags & ADDRESS_AND_PORT_DEPENDENT_MAPPING == 0 && self.has_live_mapping(internal_addr, internal_port, current_time)
+    }
+    /// Returns whether a mapping that has not timed out at `current_time` is using the given
+    /// external address and port. Mappings that have timed out but have not yet been removed are
+    /// considered free, and statically forwarded ports are not mappings, see `add_static_mapping`.
+    /// This is useful for asserting whether two flows collided. Like `is_inbound_open` this does
+    /// not modify the NAT.
+    ///
+    /// Return value is false if `external_addr` is not one of this NAT's external addresses.
+    pub fn is_external_port_allocated(&self, external_addr: u32, external_port: u16, current_time: i64) -> bool {
+        let Some(address_idx) = self.external_addresses().iter().position(|addr| *addr == external_addr) else {
+            return false;
+        };
+        let expiry = self.expiry_at(address_idx, current_time);
+        self.map[address_idx]
+            .iter()
+            .any(|route| route.external_port == external_port && !route.is_expired(expiry))
+    }
+    /// The oldest permitted filter refresh time at `current_time`, and what lapsed filters become.
+    #[inline]
+    fn filter_expiry_time(&self, current_time: i64) -> Option<(i64, FilterExpiry)> {
+        self.filter_timeout
+            .map(|timeout| (current_time.saturating_sub(timeout), self.filter_expiry))
+    }
+    /// Routes an inbound packet, calling `deliver` with the internal address and port of each
+    /// recipient it is delivered to. `deliver` returns whether the packet should continue to be
+    /// delivered to any other recipients sharing the same external port.
+    /// Returns whether the packet was delivered to anyone, or to the NAT itself, and if not,
+    /// whether it was addressed to the NAT.
+    #[inline(always)]
+    #[allow(clippy::too_many_arguments)]
+    fn route_external_packet_with(
+        &mut self,
+        flags: u32,
+        external_src_addr: u32,
+        external_src_port: u16,
+        external_dest_addr: u32,
+        external_dest_port: u16,
+        disable_filtering: bool,
+        current_time: i64,
+        mut deliver: impl FnMut(u32, u16) -> bool,
+    ) -> Routed {
         let mut dest_address_idx = usize::MAX;
         for i in 0..self.external_addresses_len {
             if self.external_addresses[i] == external_dest_addr {
@@ -485,47 +4145,108 @@ impl<R: RngCore, const M: usize> Nat<R, M> {
         }
         if dest_address_idx == usize::MAX {
             // This packet was not addressed to this NAT.
-            return None;
+            return Routed::Dropped;
         }
-        let routing_table = &mut self.map[dest_address_idx];
+        let disable_filtering = disable_filtering || self.inbound_whitelist.contains(&external_src_addr);
+        let filter_expiry = self.filter_expiry_time(current_time);
 
-        let expiry = current_time - self.mapping_timeout;
+        let expiry = self.expiry_at(dest_address_idx, current_time);
         let mut needs_destruction = false;
+        let mut port_in_use = false;
+        let mut delivered = false;
+        let mut throttled = false;
         let mut i = 0;
-        while i < routing_table.len() {
-            let route = &mut routing_table[i];
-            if route.last_used_time < expiry {
-                self.map_cur_size -= 1;
-                routing_table.swap_remove(i);
+        while i < self.map[dest_address_idx].len() {
+            if self.map[dest_address_idx][i].is_expired(expiry) {
+                if self.sweep_expired_on_receive {
+                    self.swap_remove_route(dest_address_idx, i, RemovalReason::Expired, current_time);
+                } else {
+                    i += 1;
+                }
                 continue;
-            } else if route.external_port == external_dest_port {
-                if disable_filtering
-                    || ((self.flags & ADDRESS_DEPENDENT_FILTERING == 0 || route.endpoint_addr == external_src_addr)
-                        && (self.flags & PORT_DEPENDENT_FILTERING == 0 || route.endpoint_port == external_src_port))
-                {
-                    if self.flags & INBOUND_REFRESH_BEHAVIOR_FALSE == 0 {
-                        route.last_used_time = current_time;
+            }
+            let route = &mut self.map[dest_address_idx][i];
+            if route.external_port == external_dest_port {
+                port_in_use = true;
+                if disable_filtering || route.accepts(flags, external_src_addr, external_src_port, filter_expiry) {
+                    if let Some(template) = &self.inbound_mapping_rate_limit {
+                        let bucket = route.inbound_bucket.get_or_insert_with(|| template.clone());
+                        if !bucket.try_consume(current_time) {
+                            throttled = true;
+                            i += 1;
+                            continue;
+                        }
+                    }
+                    route.replied = true;
+                    route.packets += 1;
+                    if flags & INBOUND_REFRESH_BEHAVIOR_FALSE == 0 {
+                        route.last_used_time = route.last_used_time.max(current_time);
+                    }
+                    delivered = true;
+                    let deliver_more = deliver(route.internal_addr, route.internal_port);
+                    if flags & SINGLE_USE_MAPPINGS > 0 {
+                        self.swap_remove_route(dest_address_idx, i, RemovalReason::Consumed, current_time);
+                        if !deliver_more {
+                            return Routed::Delivered;
+                        }
+                        continue;
                     }
-                    return Some((route.internal_addr, route.internal_port));
-                } else if self.flags & FILTERED_INBOUND_DESTROYS_MAPPING > 0 {
+                    if !deliver_more {
+                        return Routed::Delivered;
+                    }
+                } else if flags & FILTERED_INBOUND_DESTROYS_MAPPING > 0 {
                     needs_destruction = true;
                 }
             }
             i += 1;
         }
+        if delivered {
+            return Routed::Delivered;
+        } else if throttled {
+            return Routed::Dropped;
+        }
         // We could not find a valid recipient or the packet was filtered.
+        let forward = self.static_mappings.get(&(external_dest_addr, external_dest_port)).copied();
+        if forward.is_none() && !port_in_use && self.router_listen_ports.contains(&external_dest_port) {
+            return Routed::Router;
+        }
+        let forward = forward.or_else(|| {
+            let dmz_host = self.dmz_host.filter(|_| !port_in_use);
+            dmz_host.map(|internal_addr| (internal_addr, external_dest_port))
+        });
+        if let Some((internal_addr, internal_port)) = forward {
+            if self.forwarded_inbound_creates_mapping {
+                self.insert_entry(
+                    dest_address_idx,
+                    Entry {
+                        internal_addr,
+                        internal_port,
+                        external_port: external_dest_port,
+                        endpoint_addr: external_src_addr,
+                        endpoint_port: external_src_port,
+                        last_used_time: current_time,
+                        created_time: current_time,
+                        filter_last_refreshed: current_time,
+                        replied: true,
+                        packets: 1,
+                        traffic_class: 0,
+                        inbound_bucket: None,
+                    },
+                );
+            }
+            deliver(internal_addr, internal_port);
+            return Routed::Delivered;
+        }
         if needs_destruction {
             let mut i = 0;
-            while i < routing_table.len() {
-                let route = &routing_table[i];
-                if route.external_port == external_dest_port {
-                    self.map_cur_size -= 1;
-                    routing_table.swap_remove(i);
+            while i < self.map[dest_address_idx].len() {
+                if self.map[dest_address_idx][i].external_port == external_dest_port {
+                    self.swap_remove_route(dest_address_idx, i, RemovalReason::Filtered, current_time);
                 } else {
                     i += 1;
                 }
             }
         }
-        return None;
+        Routed::Unadmitted
     }
 }
@@ -0,0 +1,197 @@
+use crate::nat::{DestType, Nat};
+use crate::port_ranges;
+use rand::RngCore;
+use std::collections::HashMap;
+use std::ops::{Deref, RangeInclusive};
+
+/// The well-known prefix `64:ff9b::/96` used to embed IPv4 addresses into IPv6 addresses, as
+/// defined by rfc6052.
+pub const NAT64_WELL_KNOWN_PREFIX: u128 = 0x0064_ff9b << 96;
+
+/// The addresses used internally to stand in for IPv6 hosts. This is the reserved block
+/// `240.0.0.0/4`, excluding the limited broadcast address, which no real IPv4 endpoint may use.
+const SURROGATE_ADDRESSES: RangeInclusive<u32> = 0xf000_0000..=0xffff_fffe;
+
+/// A simplified model of a stateful NAT64 as described by rfc6146, which translates the traffic
+/// of IPv6 hosts on its internal network to a single IPv4 address on its external network.
+///
+/// IPv6 hosts address IPv4 endpoints by embedding the endpoint's IPv4 address into the low 32 bits
+/// of an address within a /96 prefix, usually `NAT64_WELL_KNOWN_PREFIX`, as a DNS64 resolver
+/// would. Internally every IPv6 host is represented by a surrogate IPv4 address within a wrapped
+/// `Nat`, so the NAT64 exhibits the same mapping and filtering behaviors as a `Nat` with the same
+/// flags, with external ports demultiplexing the internal IPv6 hosts.
+///
+/// Traffic between two IPv6 hosts never needs translation, so packets an IPv6 host addresses to
+/// the NAT64's own IPv4 address are dropped rather than hairpinned. The state and configuration of
+/// the wrapped NAT can be read through `Deref`, but it cannot be modified, since hosts added or
+/// removed directly on the wrapped NAT would not be known to the NAT64.
+pub struct Nat64<R: RngCore> {
+    inner: Nat<R, 1>,
+    prefix: u128,
+    to_surrogate: HashMap<u128, u32>,
+    from_surrogate: HashMap<u32, u128>,
+}
+impl<R: RngCore> Nat64<R> {
+    /// Creates a new NAT64 struct.
+    /// * `flags`: The set of behaviors this NAT64 should exhibit, see module `flags`.
+    /// * `prefix`: The /96 prefix IPv4 addresses are embedded into, only the upper 96 bits are used.
+    /// * `external_address`: The IPv4 address the NAT64 translates all IPv6 hosts to.
+    /// * `external_dynamic_ports`: The list of dynamic ports that the NAT64 is allowed to use on
+    ///   the external network.
+    /// * `rng`: The NAT64's random number generator.
+    /// * `mapping_max_size`: The maximum number of mappings the NAT64 will hold at once.
+    /// * `mapping_timeout`: How long the NAT64 keeps an address translation mapping open for.
+    ///
+    /// See `Nat::new` for more details.
+    #[inline]
+    pub fn new(
+        flags: u32,
+        prefix: u128,
+        external_address: u32,
+        external_dynamic_ports: RangeInclusive<u16>,
+        rng: R,
+        mapping_max_size: usize,
+        mapping_timeout: i64,
+    ) -> Self {
+        Self {
+            inner: Nat::new(
+                flags,
+                [external_address],
+                SURROGATE_ADDRESSES,
+                external_dynamic_ports,
+                rng,
+                mapping_max_size,
+                mapping_timeout,
+            ),
+            prefix: prefix & !(u32::MAX as u128),
+            to_surrogate: HashMap::new(),
+            from_surrogate: HashMap::new(),
+        }
+    }
+    /// Creates a new NAT64 struct using `NAT64_WELL_KNOWN_PREFIX` that may use any port.
+    #[inline]
+    pub fn with_well_known_prefix(flags: u32, external_address: u32, rng: R, mapping_max_size: usize, mapping_timeout: i64) -> Self {
+        Self::new(
+            flags,
+            NAT64_WELL_KNOWN_PREFIX,
+            external_address,
+            port_ranges::REGISTERED_AND_PRIVATE,
+            rng,
+            mapping_max_size,
+            mapping_timeout,
+        )
+    }
+    #[inline]
+    pub fn prefix(&self) -> u128 {
+        self.prefix
+    }
+    /// Embeds the given IPv4 address into this NAT64's prefix, producing the IPv6 address an
+    /// internal host would use to reach it.
+    #[inline]
+    pub fn synthesize(&self, ipv4_addr: u32) -> u128 {
+        self.prefix | ipv4_addr as u128
+    }
+    /// Extracts the IPv4 address embedded in the given IPv6 address.
+    ///
+    /// Return value is `None` if the address is not within this NAT64's prefix.
+    #[inline]
+    pub fn extract(&self, ipv6_addr: u128) -> Option<u32> {
+        if ipv6_addr & !(u32::MAX as u128) == self.prefix {
+            Some(ipv6_addr as u32)
+        } else {
+            None
+        }
+    }
+    /// Adds an IPv6 host to the NAT64's internal network so its traffic may be translated.
+    /// Adding a host twice has no effect.
+    /// Panics if more hosts are added than the NAT64 can represent.
+    pub fn add_internal_address(&mut self, internal_addr: u128) {
+        if !self.to_surrogate.contains_key(&internal_addr) {
+            let surrogate = self.inner.assign_internal_address();
+            self.to_surrogate.insert(internal_addr, surrogate);
+            self.from_surrogate.insert(surrogate, internal_addr);
+        }
+    }
+    /// Removes an IPv6 host from the NAT64's internal network, along with every mapping it held.
+    pub fn remove_internal_address(&mut self, internal_addr: u128) {
+        if let Some(surrogate) = self.to_surrogate.remove(&internal_addr) {
+            self.from_surrogate.remove(&surrogate);
+            self.inner.remove_internal_address(surrogate);
+        }
+    }
+    /// * `internal_src_addr`: The IPv6 source address of the sender on the NAT64's internal network.
+    /// * `internal_src_port`: The source port of the sender on the NAT64's internal network.
+    /// * `external_dest_addr`: The IPv6 destination address of the receiver, which must embed the
+    ///   receiver's IPv4 address within the NAT64's prefix.
+    /// * `external_dest_port`: The destination port of the receiver on the external network.
+    /// * `current_time`: A timestamp of the packet's arrival to the NAT64, used to process timeouts.
+    ///
+    /// Return value is `None` if the packet would be dropped by the NAT64, this happens if the
+    /// sender was not added with `add_internal_address`, if the destination is not within the
+    /// NAT64's prefix or is the NAT64's own address, or for any reason a `Nat` would drop it.
+    ///
+    /// Return value is `Some((external_src_addr, external_src_port, external_dest_addr))` if the
+    /// packet was accepted. The caller is expected to translate the packet to IPv4 using these
+    /// addresses and port.
+    pub fn send_internal_packet(
+        &mut self,
+        internal_src_addr: u128,
+        internal_src_port: u16,
+        external_dest_addr: u128,
+        external_dest_port: u16,
+        current_time: i64,
+    ) -> Option<(u32, u16, u32)> {
+        let surrogate = *self.to_surrogate.get(&internal_src_addr)?;
+        let ipv4_dest_addr = self.extract(external_dest_addr)?;
+        if SURROGATE_ADDRESSES.contains(&ipv4_dest_addr) || self.inner.external_addresses().contains(&ipv4_dest_addr) {
+            return None;
+        }
+        match self
+            .inner
+            .send_internal_packet(surrogate, internal_src_port, ipv4_dest_addr, external_dest_port, current_time)
+        {
+            DestType::External { external_src_addr, external_src_port } => Some((external_src_addr, external_src_port, ipv4_dest_addr)),
+            _ => None,
+        }
+    }
+    /// * `external_src_addr`: The IPv4 source address of the sender on the external network.
+    /// * `external_src_port`: The source port of the sender on the external network.
+    /// * `external_dest_addr`: The IPv4 destination address of the receiver, the NAT64's address.
+    /// * `external_dest_port`: The translated destination port of the receiver.
+    /// * `disable_filtering`: If true the NAT64 will disable its firewall for this one packet.
+    /// * `current_time`: A timestamp of the packet's arrival to the NAT64, used to process timeouts.
+    ///
+    /// Return value is `None` if the packet would be dropped by the NAT64.
+    ///
+    /// Return value is `Some((internal_src_addr, internal_dest_addr, internal_dest_port))` if the
+    /// packet was accepted. `internal_src_addr` is the sender's address synthesized within the
+    /// NAT64's prefix. The caller is expected to translate the packet to IPv6 using these
+    /// addresses and port.
+    pub fn receive_external_packet(
+        &mut self,
+        external_src_addr: u32,
+        external_src_port: u16,
+        external_dest_addr: u32,
+        external_dest_port: u16,
+        disable_filtering: bool,
+        current_time: i64,
+    ) -> Option<(u128, u128, u16)> {
+        let (surrogate, internal_dest_port) = self.inner.receive_external_packet(
+            external_src_addr,
+            external_src_port,
+            external_dest_addr,
+            external_dest_port,
+            disable_filtering,
+            current_time,
+        )?;
+        let internal_dest_addr = *self.from_surrogate.get(&surrogate)?;
+        Some((self.synthesize(external_src_addr), internal_dest_addr, internal_dest_port))
+    }
+}
+impl<R: RngCore> Deref for Nat64<R> {
+    type Target = Nat<R, 1>;
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
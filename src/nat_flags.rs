@@ -45,6 +45,10 @@ pub mod flags {
     pub const ADDRESS_DEPENDENT_FILTERING: u32 = 1 << 3;
     /// If true, the NAT will drop incoming packets that do not have the same src_port as the
     /// mapping for the given dest_addr and dest_port.
+    ///
+    /// The port a mapping filters on is the destination port of the outbound packets sent through
+    /// it. When a mapping is reused for a new destination, which destinations are admitted depends
+    /// on `Nat::endpoint_update`.
     pub const PORT_DEPENDENT_FILTERING: u32 = 1 << 4;
     /// If true, the NAT will do both address and port dependent filtering.
     pub const ADDRESS_AND_PORT_DEPENDENT_FILTERING: u32 = ADDRESS_DEPENDENT_FILTERING | PORT_DEPENDENT_FILTERING;
@@ -80,6 +84,17 @@ pub mod flags {
     pub const NO_PORT_PRESERVATION: u32 = 1 << 10;
     /// If true, the NAT will make no attempt to preserve parity of the source port number of an
     /// outbound packet. Preserving port parity can help some protocols, as described in rfc4787.
+    ///
+    /// A preserved port always has the parity of the source port, so the two only contend when the
+    /// source port cannot be preserved. By default parity wins, and the NAT allocates some other
+    /// port of the same parity. See `PORT_PRESERVATION_OVER_PARITY` for the opposite precedence.
+    ///
+    /// If false, every external port the NAT allocates has the parity of the source port, whether
+    /// it is preserved, drawn at random, hashed or allocated sequentially, and a port moved to the
+    /// right parity never leaves the port ranges it was drawn from. A random port that is alone in
+    /// its range and has the other parity is drawn again. The only exceptions are
+    /// `PORT_PRESERVATION_OVER_PARITY`, ports chosen by a port oracle, and a NAT that repeatedly
+    /// fails to draw a free port of the right parity.
     pub const NO_PORT_PARITY: u32 = 1 << 11;
     /// If true, the NAT will guarantee source port preservation by overwritting older mappings that
     /// are using the same combination of external address and source port as the newer mapping.
@@ -99,6 +114,69 @@ pub mod flags {
     /// attempt to generate a source port which is also in this range.
     /// If true, the NAT will not do this.
     pub const NO_WELL_KNOWN_PRESERVATION: u32 = 1 << 14;
+
+    /// If true, once a mapping has seen an outbound packet and has had an inbound packet delivered
+    /// through it, the NAT stops applying address and port dependent filtering to that mapping.
+    /// This emulates adaptive firewalls that relax once they observe bidirectional traffic,
+    /// allowing a second peer to join an existing flow.
+    ///
+    /// Unlike every other flag this makes the NAT easier to punch through, not harder.
+    /// This flag has no effect if neither `ADDRESS_DEPENDENT_FILTERING` nor
+    /// `PORT_DEPENDENT_FILTERING` are true.
+    pub const RELAX_FILTERING_AFTER_BIDIRECTIONAL: u32 = 1 << 15;
+    /// If true, whenever the NAT cannot preserve a source port it derives the external port
+    /// from a hash of the mapping's internal address, internal port, destination address and
+    /// destination port, instead of drawing it at random. Collisions are resolved by probing
+    /// forward through the port range.
+    ///
+    /// This emulates symmetric NATs whose port allocations are destination dependent but
+    /// reproducible. The hash is seeded from the NAT's rng the first time it is needed, so
+    /// different NAT instances will allocate different ports.
+    pub const DETERMINISTIC_PORT_HASH: u32 = 1 << 16;
+    /// If true, whenever the NAT cannot preserve a source port because it is taken, it allocates
+    /// the closest free port above the source port regardless of its parity, instead of a port of
+    /// the same parity elsewhere in the port range. This keeps the external port as close to the
+    /// source port as possible at the cost of port parity, and takes precedence over
+    /// `DETERMINISTIC_PORT_HASH` and any other way of allocating an unpreserved port.
+    /// If every port above the source port is taken the search wraps around the port range, and
+    /// if every port is taken the NAT allocates a port as if this flag were false.
+    ///
+    /// This flag has no effect if `NO_PORT_PRESERVATION` is true, or if the source port cannot be
+    /// preserved for reasons other than a collision, for instance because it is excluded.
+    pub const PORT_PRESERVATION_OVER_PARITY: u32 = 1 << 17;
+    /// If true, the NAT will only hairpin an intranet to intranet packet if its sender already had
+    /// a live mapping before the packet was sent, and will otherwise drop the packet without
+    /// creating a mapping. This emulates NATs that hairpin within their translation table rather
+    /// than their forwarding plane, so a host must have talked to the internet before it can
+    /// reach its neighbors through their external addresses.
+    ///
+    /// This flag has no effect if `NO_HAIRPINNING` is true.
+    pub const HAIRPINNING_REQUIRES_MAPPING: u32 = 1 << 18;
+    /// If true, the NAT also rewrites the source port of inbound packets before delivering them to
+    /// the internal host, to a port derived from a hash of the packet's source address and port.
+    /// The source address is left unchanged. Outbound packets are not translated back, so an
+    /// internal host that replies to the source it observed sends to the wrong port.
+    ///
+    /// This emulates rare middleboxes that mangle ports in both directions, which breaks any
+    /// application that identifies its peers by their source port. The hash is seeded from the
+    /// NAT's rng the first time it is needed, so different NAT instances will rewrite differently.
+    pub const INBOUND_SOURCE_PORT_REWRITE: u32 = 1 << 19;
+    /// If true, a mapping is removed as soon as it has delivered a single inbound packet, so it
+    /// admits exactly one reply. A later outbound packet from the same flow creates a new mapping.
+    /// Mappings that are never replied to still time out as usual, see `Nat::mapping_timeout`.
+    ///
+    /// This emulates strict one-shot firewalls, such as those some security appliances apply to
+    /// DNS, which would otherwise require a `mapping_timeout` of 0 that expires mappings before
+    /// their first reply can arrive.
+    pub const SINGLE_USE_MAPPINGS: u32 = 1 << 20;
+    /// If true, outbound packets of the same flow that are marked with different traffic classes
+    /// are given separate mappings, and so separate external ports, see
+    /// `Nat::send_internal_packet_with_class`. Packets sent without a traffic class are of class 0.
+    ///
+    /// This emulates QoS-aware enterprise NATs that keep a mapping per DSCP class, so an
+    /// application that marks some of its packets differently, such as the media of a call, is
+    /// observed by its peers from more than one external port.
+    pub const CLASS_DEPENDENT_MAPPING: u32 = 1 << 21;
 }
 /// This is a set of pre-defined flags for common NAT types. Each constant represents some
 /// common NAT or firewall types one might want to emulate with this library. These are provided for
@@ -131,6 +209,8 @@ pub mod predefines {
     /// match firewall.send_internal_packet(client_addr, client_port, server_addr, server_port, time) {
     ///     DestType::Internal { .. } => assert!(false),
     ///     DestType::Drop => assert!(false),
+    ///     DestType::Deferred { .. } => assert!(false),
+    ///     DestType::Router => assert!(false),
     ///     DestType::External { external_src_addr, external_src_port } => {
     ///         assert_eq!(external_src_addr, client_addr);
     ///         assert_eq!(external_src_port, client_port);
@@ -202,8 +282,49 @@ pub mod predefines {
     /// assert!(translation.is_none());
     /// ```
     pub const PORT_RESTRICTED_FIREWALL: u32 = STATEFUL_FIREWALL | ADDRESS_AND_PORT_DEPENDENT_FILTERING;
-    /// Equivalent to: `PORT_RESTRICTED_FIREWALL | PORT_PRESERVATION_OVERLOAD`
-    pub const MISBEHAVING_FIREWALL: u32 = PORT_RESTRICTED_FIREWALL | PORT_PRESERVATION_OVERLOAD;
+    /// Equivalent to: `PORT_RESTRICTED_FIREWALL`
+    ///
+    /// This is the "symmetric UDP firewall" of rfc3489, the firewall counterpart of
+    /// `SYMMETRIC_NAT`. It performs no translation, so its mapping behavior is irrelevant, but it
+    /// only admits inbound packets from the exact endpoints each internal endpoint has sent to.
+    /// It is only meaningful with `Nat::no_address_translation`, and is an alias of
+    /// `PORT_RESTRICTED_FIREWALL` provided so both classification schemes can be named.
+    ///
+    /// # Example
+    /// ```
+    /// use nat_emulation::predefines::SYMMETRIC_FIREWALL;
+    /// use nat_emulation::Nat;
+    /// let rng = rand::rngs::mock::StepRng::new(0, 1);
+    /// let mut time = 100;
+    /// let timeout = 1000 * 60 * 2;
+    ///
+    /// let client_addr = 11111;
+    /// let client_port = 2000;
+    /// let server0_addr = 22222;
+    /// let server1_addr = 33333;
+    /// let server_port = 80;
+    /// let mut firewall = Nat::no_address_translation(SYMMETRIC_FIREWALL, client_addr, rng, usize::MAX, timeout);
+    /// assert_eq!(firewall.assign_internal_address(), client_addr);
+    ///
+    /// time += 100;
+    /// let translation0 = firewall.send_internal_packet(client_addr, client_port, server0_addr, server_port, time);
+    /// let translation1 = firewall.send_internal_packet(client_addr, client_port, server1_addr, server_port, time);
+    /// assert_eq!(translation0.as_external(), Some((client_addr, client_port)));
+    /// assert_eq!(translation1.as_external(), Some((client_addr, client_port)));
+    ///
+    /// time += 100;
+    /// let translation = firewall.receive_external_packet(server0_addr, server_port, client_addr, client_port, false, time);
+    /// assert_eq!(translation, Some((client_addr, client_port)));
+    /// let translation = firewall.receive_external_packet(server1_addr, server_port, client_addr, client_port, false, time);
+    /// assert_eq!(translation, Some((client_addr, client_port)));
+    /// let translation = firewall.receive_external_packet(server1_addr, server_port + 1, client_addr, client_port, false, time);
+    /// assert!(translation.is_none());
+    /// ```
+    pub const SYMMETRIC_FIREWALL: u32 = PORT_RESTRICTED_FIREWALL;
+    /// Equivalent to: `ADDRESS_AND_PORT_DEPENDENT_FILTERING | PORT_PRESERVATION_OVERLOAD`
+    ///
+    /// This is a `PORT_RESTRICTED_FIREWALL` that overloads ports instead of overriding them.
+    pub const MISBEHAVING_FIREWALL: u32 = ADDRESS_AND_PORT_DEPENDENT_FILTERING | PORT_PRESERVATION_OVERLOAD;
 
     /// Equivalent to not setting any flags: `0u32`
     ///
@@ -230,6 +351,8 @@ pub mod predefines {
     /// match nat.send_internal_packet(client_in_addr, client_in_port, server_ex_addr, server_ex_port, time) {
     ///     DestType::Internal { .. } => assert!(false),
     ///     DestType::Drop => assert!(false),
+    ///     DestType::Deferred { .. } => assert!(false),
+    ///     DestType::Router => assert!(false),
     ///     DestType::External { external_src_addr, external_src_port } => {
     ///         assert_eq!(external_src_addr, nat_ex_addr);
     ///         // Note that the NAT gave us an external port outside of its assigned port range. NATs
@@ -251,6 +374,39 @@ pub mod predefines {
     /// assert!(translation.is_none());
     /// ```
     pub const EASY_NAT: u32 = 0;
+    /// Equivalent to: `EASY_NAT | INTERNAL_ADDRESS_AND_PORT_HAIRPINNING`
+    ///
+    /// An endpoint-independent NAT that hairpins packets between its internal hosts without
+    /// translating their source, so LAN peers that discovered each other's external addresses
+    /// can talk directly and see each other's internal addresses.
+    ///
+    /// # Example
+    /// ```
+    /// use nat_emulation::predefines::HAIRPIN_FRIENDLY_NAT;
+    /// use nat_emulation::{port_ranges::PRIVATE, DestType, Nat};
+    /// let rng = rand::rngs::mock::StepRng::new(0, 1);
+    /// let time = 100;
+    /// let timeout = 1000 * 60 * 2;
+    ///
+    /// let nat_ex_addr = 11111;
+    /// let mut nat = Nat::new(HAIRPIN_FRIENDLY_NAT, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+    /// let alice_in_addr = nat.assign_internal_address();
+    /// let bob_in_addr = nat.assign_internal_address();
+    /// let server_ex_addr = 22222;
+    ///
+    /// // Both peers learn their external addresses from a server, and exchange them.
+    /// let alice_ex = nat.send_internal_packet(alice_in_addr, 5000, server_ex_addr, 3478, time).as_external().unwrap();
+    /// let bob_ex = nat.send_internal_packet(bob_in_addr, 6000, server_ex_addr, 3478, time).as_external().unwrap();
+    ///
+    /// // Each peer's packets are hairpinned to the other, tagged with their internal source.
+    /// let translation = nat.send_internal_packet(alice_in_addr, 5000, bob_ex.0, bob_ex.1, time);
+    /// assert_eq!(translation.as_internal(), Some((alice_in_addr, 5000, bob_in_addr, 6000)));
+    /// let translation = nat.send_internal_packet(bob_in_addr, 6000, alice_ex.0, alice_ex.1, time);
+    /// assert_eq!(translation.as_internal(), Some((bob_in_addr, 6000, alice_in_addr, 5000)));
+    /// // Replies to the internal source are routed directly on the LAN.
+    /// assert!(nat.send_internal_packet(bob_in_addr, 6000, alice_in_addr, 5000, time).is_internal());
+    /// ```
+    pub const HAIRPIN_FRIENDLY_NAT: u32 = EASY_NAT | INTERNAL_ADDRESS_AND_PORT_HAIRPINNING;
 
     /// Equivalent to: `NO_PORT_PRESERVATION`
     ///
@@ -273,6 +429,8 @@ pub mod predefines {
     /// match nat.send_internal_packet(client_in_addr, client_in_port, server_ex_addr, server_ex_port, time) {
     ///     DestType::Internal { .. } => assert!(false),
     ///     DestType::Drop => assert!(false),
+    ///     DestType::Deferred { .. } => assert!(false),
+    ///     DestType::Router => assert!(false),
     ///     DestType::External { external_src_addr, external_src_port } => {
     ///         assert_eq!(external_src_addr, nat_ex_addr);
     ///         // Our definition of a full cone NAT does not have port preservation.
@@ -290,6 +448,39 @@ pub mod predefines {
     pub const RESTRICTED_CONE_NAT: u32 = FULL_CONE_NAT | RESTRICTED_FIREWALL;
     /// Equivalent to: `FULL_CONE_NAT | PORT_RESTRICTED_FIREWALL`
     pub const PORT_RESTRICTED_CONE_NAT: u32 = FULL_CONE_NAT | PORT_RESTRICTED_FIREWALL;
+    /// Equivalent to: `PORT_RESTRICTED_CONE_NAT | ADDRESS_DEPENDENT_MAPPING`
+    ///
+    /// A NAT with "address dependent" mapping behavior, which sits between an endpoint-independent
+    /// NAT and a symmetric NAT. Every port of one destination address shares a single external
+    /// port, but each destination address gets a new one.
+    ///
+    /// # Example
+    /// ```
+    /// use nat_emulation::predefines::ADDRESS_DEPENDENT_NAT;
+    /// use nat_emulation::{port_ranges::PRIVATE, Nat};
+    /// let rng = rand::rngs::mock::StepRng::new(0, 1);
+    /// let time = 100;
+    /// let timeout = 1000 * 60 * 2;
+    ///
+    /// let nat_ex_addr = 11111;
+    /// let mut nat = Nat::new(ADDRESS_DEPENDENT_NAT, [nat_ex_addr], 90000..=99999, PRIVATE, rng, usize::MAX, timeout);
+    /// let client_in_addr = nat.assign_internal_address();
+    /// let client_in_port = 25565;
+    /// let peer0_ex_addr = 22222;
+    /// let peer1_ex_addr = 33333;
+    ///
+    /// let (_, ex_src_port0) = nat.send_internal_packet(client_in_addr, client_in_port, peer0_ex_addr, 80, time).as_external().unwrap();
+    /// let (_, ex_src_port1) = nat.send_internal_packet(client_in_addr, client_in_port, peer0_ex_addr, 17, time).as_external().unwrap();
+    /// let (_, ex_src_port2) = nat.send_internal_packet(client_in_addr, client_in_port, peer1_ex_addr, 80, time).as_external().unwrap();
+    /// // The external port is reused across the ports of one peer, but not across peers.
+    /// assert_eq!(ex_src_port0, ex_src_port1);
+    /// assert!(ex_src_port0 != ex_src_port2);
+    ///
+    /// // Filtering still only accepts the exact endpoints the client has sent to.
+    /// assert!(nat.receive_external_packet(peer0_ex_addr, 17, nat_ex_addr, ex_src_port0, false, time).is_some());
+    /// assert!(nat.receive_external_packet(peer0_ex_addr, 443, nat_ex_addr, ex_src_port0, false, time).is_none());
+    /// ```
+    pub const ADDRESS_DEPENDENT_NAT: u32 = PORT_RESTRICTED_CONE_NAT | ADDRESS_DEPENDENT_MAPPING;
     /// Equivalent to: `PORT_RESTRICTED_CONE_NAT | ADDRESS_AND_PORT_DEPENDENT_MAPPING`
     ///
     /// # Example
@@ -408,6 +599,8 @@ pub mod predefines {
     /// match nat.send_internal_packet(client_in_addr, client_in_port, server_ex_addr, server_ex_port0, time) {
     ///     DestType::Internal { .. } => assert!(false),
     ///     DestType::Drop => assert!(false),
+    ///     DestType::Deferred { .. } => assert!(false),
+    ///     DestType::Router => assert!(false),
     ///     DestType::External { external_src_addr, external_src_port } => {
     ///         assert_eq!(external_src_addr, nat_ex_addr);
     ///
@@ -424,6 +617,40 @@ pub mod predefines {
     /// ```
     pub const MISBEHAVING_NAT: u32 =
         HARD_NAT | INTERNAL_ADDRESS_AND_PORT_HAIRPINNING | OUTBOUND_REFRESH_BEHAVIOR_FALSE | FILTERED_INBOUND_DESTROYS_MAPPING;
+
+    /// Every predefine in this module paired with its name.
+    pub const ALL: &[(&str, u32)] = &[
+        ("STATEFUL_FIREWALL", STATEFUL_FIREWALL),
+        ("RESTRICTED_FIREWALL", RESTRICTED_FIREWALL),
+        ("PORT_RESTRICTED_FIREWALL", PORT_RESTRICTED_FIREWALL),
+        ("SYMMETRIC_FIREWALL", SYMMETRIC_FIREWALL),
+        ("MISBEHAVING_FIREWALL", MISBEHAVING_FIREWALL),
+        ("EASY_NAT", EASY_NAT),
+        ("HAIRPIN_FRIENDLY_NAT", HAIRPIN_FRIENDLY_NAT),
+        ("FULL_CONE_NAT", FULL_CONE_NAT),
+        ("RESTRICTED_CONE_NAT", RESTRICTED_CONE_NAT),
+        ("PORT_RESTRICTED_CONE_NAT", PORT_RESTRICTED_CONE_NAT),
+        ("ADDRESS_DEPENDENT_NAT", ADDRESS_DEPENDENT_NAT),
+        ("SYMMETRIC_NAT", SYMMETRIC_NAT),
+        ("HARD_NAT", HARD_NAT),
+        ("MISBEHAVING_NAT", MISBEHAVING_NAT),
+    ];
+    /// Returns the name of the predefine that is exactly equal to the given set of flags, or `None`
+    /// if there is no such predefine. If several predefines are equal, such as `SYMMETRIC_FIREWALL`
+    /// and `PORT_RESTRICTED_FIREWALL`, the first of them in `ALL` is named.
+    /// `BehaviorSet::from_flags` can be used to describe flags that do not match a predefine.
+    ///
+    /// # Example
+    /// ```
+    /// use nat_emulation::flags::*;
+    /// use nat_emulation::predefines::{name_of, SYMMETRIC_NAT};
+    /// assert_eq!(name_of(SYMMETRIC_NAT), Some("SYMMETRIC_NAT"));
+    /// assert_eq!(name_of(ADDRESS_DEPENDENT_FILTERING | PORT_PRESERVATION_OVERRIDE), Some("RESTRICTED_FIREWALL"));
+    /// assert_eq!(name_of(NO_HAIRPINNING), None);
+    /// ```
+    pub fn name_of(flags: u32) -> Option<&'static str> {
+        ALL.iter().find(|(_, predefine)| *predefine == flags).map(|(name, _)| *name)
+    }
 }
 /// The standard set of different port ranges used on the internet.
 pub mod port_ranges {
@@ -440,3 +667,148 @@ pub mod port_ranges {
     /// The valid port numbers from 1 to 65535.
     pub const ALL: RangeInclusive<u16> = 1..=65535;
 }
+
+/// A combination of flags that either contradict each other or where one flag silently has no
+/// effect because of another.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FlagConflict {
+    /// `PORT_PRESERVATION_OVERRIDE` and `PORT_PRESERVATION_OVERLOAD` are mutually exclusive
+    /// strategies for resolving a port preservation collision.
+    OverrideAndOverload,
+    /// `PORT_PRESERVATION_OVERRIDE` has no effect because `NO_PORT_PRESERVATION` is set.
+    OverrideWithoutPreservation,
+    /// `PORT_PRESERVATION_OVERLOAD` has no effect because `NO_PORT_PRESERVATION` is set.
+    OverloadWithoutPreservation,
+    /// `INTERNAL_ADDRESS_AND_PORT_HAIRPINNING` has no effect because `NO_HAIRPINNING` is set.
+    InternalHairpinningWithoutHairpinning,
+    /// `PORT_PRESERVATION_OVER_PARITY` has no effect because `NO_PORT_PRESERVATION` is set.
+    OverParityWithoutPreservation,
+    /// `HAIRPINNING_REQUIRES_MAPPING` has no effect because `NO_HAIRPINNING` is set.
+    MappingRequiredWithoutHairpinning,
+}
+impl FlagConflict {
+    /// Returns true if this conflict only means one of the flags has no effect.
+    ///
+    /// These combinations are well defined and some predefines purposefully contain them, since
+    /// they are built by layering a NAT on top of a firewall. NAT constructors will accept these,
+    /// but will reject any other conflict.
+    pub const fn is_no_op(&self) -> bool {
+        !matches!(self, FlagConflict::OverrideAndOverload)
+    }
+}
+impl std::fmt::Display for FlagConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FlagConflict::OverrideAndOverload => write!(f, "PORT_PRESERVATION_OVERRIDE and PORT_PRESERVATION_OVERLOAD are mutually exclusive"),
+            FlagConflict::OverrideWithoutPreservation => write!(f, "PORT_PRESERVATION_OVERRIDE has no effect when NO_PORT_PRESERVATION is set"),
+            FlagConflict::OverloadWithoutPreservation => write!(f, "PORT_PRESERVATION_OVERLOAD has no effect when NO_PORT_PRESERVATION is set"),
+            FlagConflict::InternalHairpinningWithoutHairpinning => {
+                write!(f, "INTERNAL_ADDRESS_AND_PORT_HAIRPINNING has no effect when NO_HAIRPINNING is set")
+            }
+            FlagConflict::OverParityWithoutPreservation => {
+                write!(f, "PORT_PRESERVATION_OVER_PARITY has no effect when NO_PORT_PRESERVATION is set")
+            }
+            FlagConflict::MappingRequiredWithoutHairpinning => {
+                write!(f, "HAIRPINNING_REQUIRES_MAPPING has no effect when NO_HAIRPINNING is set")
+            }
+        }
+    }
+}
+impl std::error::Error for FlagConflict {}
+
+/// Checks a set of flags for known contradictory or silently ignored combinations.
+/// Returns the first conflict found, contradictions are always reported before no-ops.
+///
+/// See `FlagConflict` for the list of known conflicts.
+pub const fn validate_flags(flags: u32) -> Result<(), FlagConflict> {
+    use flags::*;
+    if flags & NO_PORT_PRESERVATION == 0 {
+        if flags & PORT_PRESERVATION_OVERRIDE > 0 && flags & PORT_PRESERVATION_OVERLOAD > 0 {
+            return Err(FlagConflict::OverrideAndOverload);
+        }
+    } else if flags & PORT_PRESERVATION_OVERRIDE > 0 {
+        return Err(FlagConflict::OverrideWithoutPreservation);
+    } else if flags & PORT_PRESERVATION_OVERLOAD > 0 {
+        return Err(FlagConflict::OverloadWithoutPreservation);
+    } else if flags & PORT_PRESERVATION_OVER_PARITY > 0 {
+        return Err(FlagConflict::OverParityWithoutPreservation);
+    }
+    if flags & NO_HAIRPINNING > 0 && flags & INTERNAL_ADDRESS_AND_PORT_HAIRPINNING > 0 {
+        return Err(FlagConflict::InternalHairpinningWithoutHairpinning);
+    } else if flags & NO_HAIRPINNING > 0 && flags & HAIRPINNING_REQUIRES_MAPPING > 0 {
+        return Err(FlagConflict::MappingRequiredWithoutHairpinning);
+    }
+    Ok(())
+}
+
+/// Returns a single number summarizing how difficult a NAT with the given flags is to traverse
+/// with UDP hole punching, for ranking NAT configurations against each other. Every set flag adds
+/// a fixed weight, so a NAT with a superset of another NAT's flags never scores lower. Flags that
+/// have no effect because of another flag, see `FlagConflict::is_no_op`, add nothing.
+///
+/// The weights follow the traversal difficulty discussed by rfc4787:
+/// * Address and port dependent mapping weigh the most, `40` each. A peer cannot learn the
+///   external port a mapping will use for it from a third party, so traversal requires port
+///   prediction.
+/// * `FILTERED_INBOUND_DESTROYS_MAPPING` and `SINGLE_USE_MAPPINGS` weigh `30` each, since the
+///   early packets of a simultaneous open tear down the very mapping they are trying to reach.
+/// * `IP_POOLING_BEHAVIOR_ARBITRARY` weighs `20`, the external address itself becomes unpredictable.
+/// * `NO_PORT_PRESERVATION` weighs `15`, port preservation lets peers guess external ports.
+/// * Address and port dependent filtering weigh `10` each, simultaneous open traverses them as
+///   long as both sides send first.
+/// * `OUTBOUND_REFRESH_BEHAVIOR_FALSE` weighs `10`, keepalives no longer hold mappings open.
+/// * `NO_HAIRPINNING` weighs `6`, peers behind the same NAT must discover each other locally. It
+///   weighs as much as the two hairpinning flags it makes a no-op, so that adding it never lowers
+///   the score.
+/// * `INBOUND_SOURCE_PORT_REWRITE` weighs `5`, peers must not rely on the source port of packets.
+/// * `PORT_PRESERVATION_OVERRIDE` and `PORT_PRESERVATION_OVERLOAD` weigh `5` each, they make
+///   mappings disappear or make inbound delivery ambiguous.
+/// * `INBOUND_REFRESH_BEHAVIOR_FALSE`, `INTERNAL_ADDRESS_AND_PORT_HAIRPINNING` and
+///   `HAIRPINNING_REQUIRES_MAPPING` weigh `3` each.
+/// * `NO_PORT_PARITY`, `NO_WELL_KNOWN_PRESERVATION` and `CLASS_DEPENDENT_MAPPING` weigh `1` each,
+///   they barely matter to traversal.
+/// * `RELAX_FILTERING_AFTER_BIDIRECTIONAL`, `DETERMINISTIC_PORT_HASH` and
+///   `PORT_PRESERVATION_OVER_PARITY` weigh `0`, since they only ever make a NAT easier to
+///   traverse.
+pub const fn hardness_score(flags: u32) -> u32 {
+    use flags::*;
+    const WEIGHTS: [(u32, u32); 19] = [
+        (ADDRESS_DEPENDENT_MAPPING, 40),
+        (PORT_DEPENDENT_MAPPING, 40),
+        (FILTERED_INBOUND_DESTROYS_MAPPING, 30),
+        (SINGLE_USE_MAPPINGS, 30),
+        (IP_POOLING_BEHAVIOR_ARBITRARY, 20),
+        (NO_PORT_PRESERVATION, 15),
+        (ADDRESS_DEPENDENT_FILTERING, 10),
+        (PORT_DEPENDENT_FILTERING, 10),
+        (OUTBOUND_REFRESH_BEHAVIOR_FALSE, 10),
+        (NO_HAIRPINNING, 6),
+        (INBOUND_SOURCE_PORT_REWRITE, 5),
+        (PORT_PRESERVATION_OVERRIDE, 5),
+        (PORT_PRESERVATION_OVERLOAD, 5),
+        (INBOUND_REFRESH_BEHAVIOR_FALSE, 3),
+        (INTERNAL_ADDRESS_AND_PORT_HAIRPINNING, 3),
+        (HAIRPINNING_REQUIRES_MAPPING, 3),
+        (NO_PORT_PARITY, 1),
+        (NO_WELL_KNOWN_PRESERVATION, 1),
+        (CLASS_DEPENDENT_MAPPING, 1),
+    ];
+    let mut flags = flags;
+    if flags & NO_PORT_PRESERVATION > 0 {
+        flags &= !(PORT_PRESERVATION_OVERRIDE | PORT_PRESERVATION_OVERLOAD);
+    } else if flags & PORT_PRESERVATION_OVERLOAD > 0 {
+        flags &= !PORT_PRESERVATION_OVERRIDE;
+    }
+    if flags & NO_HAIRPINNING > 0 {
+        flags &= !(INTERNAL_ADDRESS_AND_PORT_HAIRPINNING | HAIRPINNING_REQUIRES_MAPPING);
+    }
+    let mut score = 0;
+    let mut i = 0;
+    while i < WEIGHTS.len() {
+        if flags & WEIGHTS[i].0 > 0 {
+            score += WEIGHTS[i].1;
+        }
+        i += 1;
+    }
+    score
+}
@@ -0,0 +1,253 @@
+use crate::nat::{DestType, Nat};
+use crate::nat_flags::port_ranges::PRIVATE;
+use rand::rngs::mock::StepRng;
+use rand::RngCore;
+use std::collections::HashMap;
+
+/// The internal address range of every NAT in a `Scenario`, `10.0.0.0/8`.
+const INTERNAL_ADDRESSES: std::ops::RangeInclusive<u32> = 0x0a00_0000..=0x0aff_ffff;
+/// The external address of the first NAT in a `Scenario`, `203.0.113.1`. Each following NAT has
+/// the next address.
+const FIRST_EXTERNAL_ADDRESS: u32 = 0xcb00_7101;
+
+/// The last packet a `Scenario` sent or received, which its expectations are checked against.
+#[derive(Clone, Copy, Debug)]
+enum Event {
+    Sent {
+        translation: DestType,
+        previous_external: Option<(u32, u16)>,
+    },
+    Received {
+        delivered: bool,
+    },
+}
+
+/// A fluent test harness for scripting packets through NATs by name, which manages time and
+/// panics with a descriptive message as soon as an expectation does not hold.
+/// Every NAT has a single external address, the `PRIVATE` external dynamic ports and an internal
+/// network of `10.0.0.0/8`, and every host sends from a single internal port.
+///
+/// # Example
+/// ```
+/// use nat_emulation::predefines::{EASY_NAT, SYMMETRIC_NAT};
+/// use nat_emulation::Scenario;
+/// let stun_server = (22222, 3478);
+/// let peer = (33333, 5000);
+///
+/// Scenario::new()
+///     .nat("A", SYMMETRIC_NAT)
+///     .host("alice", "A")
+///     .nat("B", EASY_NAT)
+///     .host("bob", "B")
+///     .at(100)
+///     .send("alice", stun_server)
+///     .send("alice", peer)
+///     .expect_external_port_changes()
+///     .send("bob", stun_server)
+///     .send("bob", peer)
+///     .expect_external_port_unchanged()
+///     .after(10)
+///     .receive("bob", peer)
+///     .expect_delivered()
+///     .receive("alice", peer)
+///     .expect_delivered()
+///     // Alice's mapping for the peer only admits the peer.
+///     .receive("alice", stun_server)
+///     .expect_dropped()
+///     .after(1000 * 60 * 2 + 1)
+///     .receive("alice", peer)
+///     .expect_dropped();
+/// ```
+pub struct Scenario<R: RngCore> {
+    new_rng: Box<dyn FnMut() -> R>,
+    /// The mapping timeout of NATs added after this is set. This is 2 minutes by default, in
+    /// milliseconds.
+    pub mapping_timeout: i64,
+    /// The internal port hosts added after this is set send from. This is 5000 by default.
+    pub internal_port: u16,
+    nats: HashMap<String, Nat<R, 1>>,
+    /// The NAT, internal address and internal port of every host.
+    hosts: HashMap<String, (String, u32, u16)>,
+    /// The external address and port each host's last packet to the external network was sent from.
+    last_external: HashMap<String, (u32, u16)>,
+    time: i64,
+    last_event: Option<Event>,
+}
+impl Scenario<StepRng> {
+    /// Creates a scenario without any NATs where every NAT draws from `StepRng::new(0, 1)`, so
+    /// every run of the scenario is identical.
+    pub fn new() -> Self {
+        Self::with_rng(|| StepRng::new(0, 1))
+    }
+}
+impl Default for Scenario<StepRng> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl<R: RngCore> Scenario<R> {
+    /// Creates a scenario without any NATs where every NAT is given a rng created by `new_rng`.
+    pub fn with_rng(new_rng: impl FnMut() -> R + 'static) -> Self {
+        Self {
+            new_rng: Box::new(new_rng),
+            mapping_timeout: 1000 * 60 * 2,
+            internal_port: 5000,
+            nats: HashMap::new(),
+            hosts: HashMap::new(),
+            last_external: HashMap::new(),
+            time: 0,
+            last_event: None,
+        }
+    }
+    /// Adds a NAT with the given flags and the next unused external address.
+    pub fn nat(mut self, name: &str, flags: u32) -> Self {
+        assert!(!self.nats.contains_key(name), "NAT {} already exists", name);
+        let external_addr = FIRST_EXTERNAL_ADDRESS + self.nats.len() as u32;
+        let rng = (self.new_rng)();
+        let nat = Nat::new(flags, [external_addr], INTERNAL_ADDRESSES, PRIVATE, rng, usize::MAX, self.mapping_timeout);
+        self.nats.insert(name.to_string(), nat);
+        self
+    }
+    /// Adds a host on the internal network of the given NAT.
+    pub fn host(mut self, name: &str, nat: &str) -> Self {
+        assert!(!self.hosts.contains_key(name), "Host {} already exists", name);
+        let internal_addr = self.nat_mut(nat).assign_internal_address();
+        self.hosts.insert(name.to_string(), (nat.to_string(), internal_addr, self.internal_port));
+        self
+    }
+    /// Sets the current time. Time may not go backward.
+    pub fn at(mut self, time: i64) -> Self {
+        assert!(time >= self.time, "Time went backward from {} to {}", self.time, time);
+        self.time = time;
+        self
+    }
+    /// Advances the current time by `duration`.
+    pub fn after(self, duration: i64) -> Self {
+        let time = self.time + duration;
+        self.at(time)
+    }
+    #[inline]
+    pub fn time(&self) -> i64 {
+        self.time
+    }
+    /// Returns the NAT with the given name, for assertions and configuration this harness does
+    /// not cover. Panics if there is no such NAT.
+    pub fn nat_mut(&mut self, name: &str) -> &mut Nat<R, 1> {
+        self.nats.get_mut(name).unwrap_or_else(|| panic!("There is no NAT named {}", name))
+    }
+    /// Returns the NAT, internal address and internal port of the given host.
+    /// Panics if there is no such host.
+    fn host_info(&self, name: &str) -> (String, u32, u16) {
+        self.hosts.get(name).cloned().unwrap_or_else(|| panic!("There is no host named {}", name))
+    }
+    /// Sends a packet from the given host to an address and port, which may be on the external
+    /// network or be another NAT's external address and port. See `Nat::send_internal_packet`.
+    pub fn send(self, host: &str, to: (u32, u16)) -> Self {
+        let time = self.time;
+        self.send_at(host, to, time)
+    }
+    /// Identical to `send`, except the packet arrives at the given host's NAT at `arrival_time`
+    /// instead of the current time, which may be earlier than the current time. This emulates
+    /// packets that are reordered or delayed on their way to the NAT, see
+    /// `Nat::non_monotonic_time`. The current time is unchanged.
+    pub fn send_at(mut self, host: &str, to: (u32, u16), arrival_time: i64) -> Self {
+        let (nat, internal_addr, internal_port) = self.host_info(host);
+        let translation = self
+            .nat_mut(&nat)
+            .send_internal_packet(internal_addr, internal_port, to.0, to.1, arrival_time);
+        let previous_external = match translation.as_external() {
+            Some(external) => self.last_external.insert(host.to_string(), external),
+            None => self.last_external.get(host).copied(),
+        };
+        self.last_event = Some(Event::Sent { translation, previous_external });
+        self
+    }
+    /// Sends a packet from an address and port on the external network to the external address
+    /// and port the given host last sent from. See `Nat::receive_external_packet`.
+    pub fn receive(self, host: &str, from: (u32, u16)) -> Self {
+        let time = self.time;
+        self.receive_at(host, from, time)
+    }
+    /// Identical to `receive`, except the packet arrives at the given host's NAT at `arrival_time`
+    /// instead of the current time, see `send_at`. The current time is unchanged.
+    pub fn receive_at(mut self, host: &str, from: (u32, u16), arrival_time: i64) -> Self {
+        let (nat, internal_addr, internal_port) = self.host_info(host);
+        let &(external_addr, external_port) = self
+            .last_external
+            .get(host)
+            .unwrap_or_else(|| panic!("Host {} has never sent a packet to the external network", host));
+        let recipient = self
+            .nat_mut(&nat)
+            .receive_external_packet(from.0, from.1, external_addr, external_port, false, arrival_time);
+        self.last_event = Some(Event::Received { delivered: recipient == Some((internal_addr, internal_port)) });
+        self
+    }
+    /// Returns the external address and port the given host last sent a packet to the external
+    /// network from.
+    pub fn external_of(&self, host: &str) -> Option<(u32, u16)> {
+        self.last_external.get(host).copied()
+    }
+    /// Returns the translation of the last packet that was sent, if the last event was a send.
+    pub fn last_translation(&self) -> Option<DestType> {
+        match self.last_event {
+            Some(Event::Sent { translation, .. }) => Some(translation),
+            _ => None,
+        }
+    }
+    /// Returns the external address and port of the last packet sent, along with the external
+    /// address and port its host sent from before. Panics if the last event was not a send that
+    /// reached the external network.
+    fn last_external_send(&self) -> ((u32, u16), Option<(u32, u16)>) {
+        match self.last_event {
+            Some(Event::Sent { translation, previous_external }) => match translation.as_external() {
+                Some(external) => (external, previous_external),
+                None => panic!("Expected the last packet to reach the external network, but it was {:?}", translation),
+            },
+            _ => panic!("Expected the last event to be a sent packet"),
+        }
+    }
+    /// Expects the last packet sent to reach the external network from a different external
+    /// address or port than the packet its host sent before it.
+    pub fn expect_external_port_changes(self) -> Self {
+        let (external, previous) = self.last_external_send();
+        assert!(
+            previous.is_some_and(|previous| previous != external),
+            "Expected the external port to change from {:?}",
+            previous
+        );
+        self
+    }
+    /// Expects the last packet sent to reach the external network from the same external address
+    /// and port as the packet its host sent before it.
+    pub fn expect_external_port_unchanged(self) -> Self {
+        let (external, previous) = self.last_external_send();
+        assert_eq!(Some(external), previous, "Expected the external port to stay the same");
+        self
+    }
+    /// Expects the last packet sent to have reached the external network.
+    pub fn expect_external(self) -> Self {
+        self.last_external_send();
+        self
+    }
+    /// Expects the last packet received to have been delivered to its host.
+    pub fn expect_delivered(self) -> Self {
+        match self.last_event {
+            Some(Event::Received { delivered }) => assert!(delivered, "Expected the last packet to be delivered"),
+            _ => panic!("Expected the last event to be a received packet"),
+        }
+        self
+    }
+    /// Expects the last packet, whether sent or received, to have been dropped.
+    pub fn expect_dropped(self) -> Self {
+        match self.last_event {
+            Some(Event::Received { delivered }) => assert!(!delivered, "Expected the last packet to be dropped"),
+            Some(Event::Sent { translation, .. }) => assert!(
+                translation.is_drop(),
+                "Expected the last packet to be dropped, but it was {:?}",
+                translation
+            ),
+            None => panic!("Expected a packet to have been sent or received"),
+        }
+        self
+    }
+}
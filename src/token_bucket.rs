@@ -0,0 +1,51 @@
+/// A token bucket rate limiter, used to emulate a NAT that drops packets under load.
+///
+/// The bucket allows for bursts of up to `max_packets` packets, and refills continuously at a
+/// rate of `max_packets` per `time_window`. Time has the same unspecified units as every other
+/// `current_time` timestamp in this library.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TokenBucket {
+    max_packets: u32,
+    time_window: i64,
+    /// The number of tokens remaining, scaled by `time_window` so refilling is exact.
+    budget: i128,
+    last_time: i64,
+}
+impl TokenBucket {
+    /// Creates a new, full token bucket that permits `max_packets` packets per `time_window`.
+    #[inline]
+    pub fn new(max_packets: u32, time_window: i64) -> Self {
+        debug_assert!(time_window > 0, "The time window must be greater than 0");
+        Self {
+            max_packets,
+            time_window,
+            budget: max_packets as i128 * time_window as i128,
+            last_time: i64::MIN,
+        }
+    }
+    #[inline]
+    pub fn max_packets(&self) -> u32 {
+        self.max_packets
+    }
+    #[inline]
+    pub fn time_window(&self) -> i64 {
+        self.time_window
+    }
+    /// Refills the bucket based on the time elapsed since the last call, and then attempts to
+    /// take one token from it. Returns false if the bucket was empty and the packet should be
+    /// dropped.
+    pub fn try_consume(&mut self, current_time: i64) -> bool {
+        if current_time > self.last_time {
+            let elapsed = current_time as i128 - self.last_time as i128;
+            let capacity = self.max_packets as i128 * self.time_window as i128;
+            self.budget = (self.budget + elapsed * self.max_packets as i128).min(capacity);
+            self.last_time = current_time;
+        }
+        if self.budget >= self.time_window as i128 {
+            self.budget -= self.time_window as i128;
+            true
+        } else {
+            false
+        }
+    }
+}